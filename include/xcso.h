@@ -0,0 +1,114 @@
+/*
+ * C bindings for the xcso library, built with `cargo rustc --release
+ * --features ffi --lib --crate-type cdylib,staticlib` as libxcso.so
+ * (xcso.dll on Windows, libxcso.dylib on macOS) and libxcso.a.
+ *
+ * Every function returns XCSO_OK or one of the XCSO_ERROR codes, and
+ * xcso_last_error() gives the message of the last error on the calling
+ * thread. Paths are UTF-8.
+ */
+
+#ifndef XCSO_H
+#define XCSO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define XCSO_OK 0
+/* Reading or writing a file failed, or an image was damaged. */
+#define XCSO_ERROR_IO (-1)
+/* An argument was null, not UTF-8 or out of range. */
+#define XCSO_ERROR_INVALID (-2)
+/* The conversion was stopped, as by a signal. */
+#define XCSO_ERROR_INTERRUPTED (-3)
+#define XCSO_ERROR_PANIC (-4)
+
+#define XCSO_FORMAT_CSO 0
+#define XCSO_FORMAT_ZSO 1
+#define XCSO_METHOD_LZ4 0
+#define XCSO_METHOD_DEFLATE 1
+
+/*
+ * Settings for xcso_compress(). Fill them in with xcso_default_options()
+ * before changing any, which also sets size.
+ */
+typedef struct xcso_options {
+    /* sizeof(xcso_options) */
+    size_t size;
+    /* LZ4 from 0 to 12, deflate from 1 to 9. */
+    uint32_t level;
+    /* XCSO_FORMAT_CSO or XCSO_FORMAT_ZSO. */
+    uint32_t format;
+    /* XCSO_METHOD_LZ4 or XCSO_METHOD_DEFLATE. */
+    uint32_t method;
+    /* 2048, 4096, 8192 or 16384. */
+    uint32_t block_size;
+    /* Blocks start on multiples of 2^align bytes, from 0 to 10. */
+    uint32_t align;
+    /* Bytes a part may grow to, or 0 to never split. */
+    uint64_t split_size;
+    /* Threads compressing blocks, or 0 for one for each core. */
+    uint32_t threads;
+} xcso_options;
+
+/*
+ * Called with the bytes of the image done so far, how many there are in
+ * all, and the user pointer given with it.
+ */
+typedef void (*xcso_progress)(uint64_t done, uint64_t total, void *user);
+
+/* What xcso_info() found out about an image. */
+typedef struct xcso_image_info {
+    uint32_t format;
+    uint32_t version;
+    uint32_t block_size;
+    uint32_t align;
+    /* Size of the image uncompressed. */
+    uint64_t total_bytes;
+    uint64_t total_blocks;
+    uint32_t parts;
+    /* Size of every part on disk, together. */
+    uint64_t stored_bytes;
+} xcso_image_info;
+
+/* Fills in opts with the settings make-xcso uses by default. */
+int xcso_default_options(xcso_options *opts);
+
+/*
+ * Compresses the ISO at iso into dest, which is the output's base name: the
+ * parts are written as dest.1.cso, dest.2.cso and so on. opts can be NULL
+ * for the defaults.
+ */
+int xcso_compress(const char *iso, const char *dest, const xcso_options *opts);
+
+/*
+ * Same as xcso_compress(), but calls progress every so often, on the
+ * calling thread, with how much of the ISO is done.
+ */
+int xcso_compress_with_progress(const char *iso, const char *dest, const xcso_options *opts,
+                                xcso_progress progress, void *user);
+
+/* Decompresses the CSO or ZSO whose first part is at cso into an ISO at iso. */
+int xcso_decompress(const char *cso, const char *iso);
+
+/* Same as xcso_decompress(), but calls progress with how much is written. */
+int xcso_decompress_with_progress(const char *cso, const char *iso, xcso_progress progress, void *user);
+
+/* Reads the header of the CSO or ZSO whose first part is at cso into info. */
+int xcso_info(const char *cso, xcso_image_info *info);
+
+/*
+ * The message of the last error on this thread, or NULL if there hasn't
+ * been one. It stays valid until the next call that fails on the thread.
+ */
+const char *xcso_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* XCSO_H */
@@ -0,0 +1,174 @@
+"""Python bindings for xcso, for ROM managers and other tools in Python.
+
+They load the C library built with ``cargo rustc --release --features ffi
+--lib --crate-type cdylib`` (see include/xcso.h) through ctypes, so there's
+nothing to build for Python. The ``python`` feature builds an extension
+module with the same functions instead. The library's found through the
+``XCSO_LIBRARY`` environment variable, next to this file, or on the system's
+library path::
+
+    import xcso
+
+    xcso.compress("halo.iso", progress=lambda done, total: print(done, total))
+    print(xcso.info("halo.1.cso"))
+    xcso.decompress("halo.1.cso", "halo.iso")
+"""
+
+import ctypes
+import ctypes.util
+import os
+import sys
+
+__all__ = ["XcsoError", "compress", "decompress", "info", "FATX_MAX_SIZE"]
+
+# Bytes a file can take up on FATX, which parts are kept under by default
+FATX_MAX_SIZE = 4290732032
+
+_FORMATS = {"cso": 0, "zso": 1}
+_METHODS = {"lz4": 0, "deflate": 1}
+
+_ERRORS = {-1: OSError, -2: ValueError, -3: InterruptedError}
+
+
+class XcsoError(Exception):
+    """A call into the library failed other than by reading or writing, by
+    an invalid argument or by being interrupted, which raise OSError,
+    ValueError and InterruptedError. ``code`` is the XCSO_ERROR code.
+    """
+
+    def __init__(self, code, message):
+        super().__init__(message)
+        self.code = code
+
+
+class _Options(ctypes.Structure):
+    _fields_ = [
+        ("size", ctypes.c_size_t),
+        ("level", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("method", ctypes.c_uint32),
+        ("block_size", ctypes.c_uint32),
+        ("align", ctypes.c_uint32),
+        ("split_size", ctypes.c_uint64),
+        ("threads", ctypes.c_uint32),
+    ]
+
+
+class _ImageInfo(ctypes.Structure):
+    _fields_ = [
+        ("format", ctypes.c_uint32),
+        ("version", ctypes.c_uint32),
+        ("block_size", ctypes.c_uint32),
+        ("align", ctypes.c_uint32),
+        ("total_bytes", ctypes.c_uint64),
+        ("total_blocks", ctypes.c_uint64),
+        ("parts", ctypes.c_uint32),
+        ("stored_bytes", ctypes.c_uint64),
+    ]
+
+
+_Progress = ctypes.CFUNCTYPE(None, ctypes.c_uint64, ctypes.c_uint64, ctypes.c_void_p)
+
+
+def _library_names():
+    if sys.platform == "win32":
+        return ["xcso.dll"]
+    if sys.platform == "darwin":
+        return ["libxcso.dylib"]
+    return ["libxcso.so"]
+
+
+def _load():
+    candidates = []
+    if os.environ.get("XCSO_LIBRARY"):
+        candidates.append(os.environ["XCSO_LIBRARY"])
+    here = os.path.dirname(os.path.abspath(__file__))
+    candidates += [os.path.join(here, name) for name in _library_names()]
+    found = ctypes.util.find_library("xcso")
+    if found:
+        candidates.append(found)
+    for candidate in candidates:
+        if os.path.sep in candidate and not os.path.exists(candidate):
+            continue
+        try:
+            return ctypes.CDLL(candidate)
+        except OSError:
+            continue
+    raise ImportError(
+        "can't find the xcso library, build it with cargo rustc --release --features ffi --lib --crate-type cdylib"
+    )
+
+
+_lib = _load()
+_lib.xcso_default_options.argtypes = [ctypes.POINTER(_Options)]
+_lib.xcso_compress_with_progress.argtypes = [
+    ctypes.c_char_p, ctypes.c_char_p, ctypes.POINTER(_Options), _Progress, ctypes.c_void_p,
+]
+_lib.xcso_decompress_with_progress.argtypes = [ctypes.c_char_p, ctypes.c_char_p, _Progress, ctypes.c_void_p]
+_lib.xcso_info.argtypes = [ctypes.c_char_p, ctypes.POINTER(_ImageInfo)]
+_lib.xcso_last_error.restype = ctypes.c_char_p
+
+
+def _check(code):
+    if code == 0:
+        return
+    message = (_lib.xcso_last_error() or b"unknown error").decode("utf-8", "replace")
+    if code in _ERRORS:
+        raise _ERRORS[code](message)
+    raise XcsoError(code, message)
+
+
+def _path(path):
+    return os.fsencode(os.fspath(path))
+
+
+def _progress(callback):
+    if callback is None:
+        return _Progress()
+    return _Progress(lambda done, total, _: callback(done, total))
+
+
+def compress(path, dest=None, *, level=None, format="cso", method="lz4", block_size=2048, align=2,
+             split_size=FATX_MAX_SIZE, threads=None, progress=None):
+    """Compresses the ISO at ``path``, returning the path of the first part.
+    ``dest`` is the output's base name, which defaults to the ISO's without
+    ``.iso``: the parts are written as ``dest.1.cso``, ``dest.2.cso`` and so
+    on. ``split_size=None`` never splits, and ``progress`` is called with
+    the bytes done and the total every so often.
+    """
+    if dest is None:
+        dest = os.path.splitext(os.fspath(path))[0]
+    if format not in _FORMATS:
+        raise ValueError("invalid format %r, expected cso or zso" % format)
+    if method not in _METHODS:
+        raise ValueError("invalid method %r, expected lz4 or deflate" % method)
+    opts = _Options()
+    _check(_lib.xcso_default_options(ctypes.byref(opts)))
+    if level is not None:
+        opts.level = level
+    opts.format = _FORMATS[format]
+    opts.method = _METHODS[method]
+    opts.block_size = block_size
+    opts.align = align
+    opts.split_size = split_size or 0
+    opts.threads = threads or 0
+    _check(_lib.xcso_compress_with_progress(_path(path), _path(dest), ctypes.byref(opts), _progress(progress), None))
+    return "%s.1.%s" % (os.fspath(dest), format)
+
+
+def decompress(path, dest, *, progress=None):
+    """Decompresses the CSO or ZSO whose first part is at ``path`` into an
+    ISO at ``dest``, which mustn't exist yet.
+    """
+    _check(_lib.xcso_decompress_with_progress(_path(path), _path(dest), _progress(progress), None))
+
+
+def info(path):
+    """The header details of the CSO or ZSO whose first part is at ``path``,
+    as a dict.
+    """
+    raw = _ImageInfo()
+    _check(_lib.xcso_info(_path(path), ctypes.byref(raw)))
+    result = {name: getattr(raw, name) for name, _ in _ImageInfo._fields_}
+    result["format"] = {v: k for k, v in _FORMATS.items()}[raw.format]
+    return result
@@ -0,0 +1,73 @@
+//! A side index of checksums, one XXH64 for the raw contents of each block,
+//! kept next to an image as `<first part>.csochk` when it's compressed with
+//! `checksums` on. Reading the image checks each block against it, so a
+//! copy that's gone bad on an ageing drive shows which blocks went, without
+//! the ISO it was made from.
+//!
+//! The file is, little endian: the magic `CSOCHK01`, the block size (`u32`,
+//! followed by four zero bytes), the size of the image (`u64`), and then
+//! the checksum of each block (`u64` each).
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cso::CsoImage;
+use crate::error::XcsoError;
+
+const MAGIC: &[u8; 8] = b"CSOCHK01";
+const HEADER_SIZE: usize = 24;
+
+/// `game.1.csochk` for the set whose first part is `game.1.cso`.
+pub fn checksum_path(first_part: &Path) -> PathBuf {
+    first_part.with_extension("csochk")
+}
+
+/// Writes the checksums of every block of `image` to `fp`.
+pub fn write_checksums(fp: &Path, image: &CsoImage, checksums: &[u64]) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(fp).map_err(|e| XcsoError::io(fp, e))?);
+    out.write_all(MAGIC)?;
+    out.write_all(&image.block_size.to_le_bytes())?;
+    out.write_all(&[0; 4])?;
+    out.write_all(&image.total_bytes.to_le_bytes())?;
+    for checksum in checksums {
+        out.write_all(&checksum.to_le_bytes())?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Reads the checksums at `fp` for `image`, or `None` when there aren't any.
+/// Ones written for another image, such as one since compressed again by
+/// another tool, are an error.
+pub fn read_checksums(fp: &Path, image: &CsoImage) -> Result<Option<Vec<u64>>, Error> {
+    let mut raw = Vec::new();
+    match File::open(fp) {
+        Ok(mut file) => file.read_to_end(&mut raw)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("{} {}", fp.display(), what));
+    if raw.len() < HEADER_SIZE || &raw[..8] != MAGIC {
+        return Err(invalid("isn't a block checksum file"));
+    }
+
+    let block_size = u32::from_le_bytes(raw[8..12].try_into().unwrap());
+    let total_bytes = u64::from_le_bytes(raw[16..24].try_into().unwrap());
+    let entries = &raw[HEADER_SIZE..];
+    if block_size != image.block_size ||
+        total_bytes != image.total_bytes ||
+        entries.len() != image.total_blocks * 8
+    {
+        return Err(invalid("was written for another image"));
+    }
+    Ok(Some(entries.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect()))
+}
+
+/// Removes the checksums at `fp`, if there are any, so an image written
+/// without them isn't checked against the last one's.
+pub fn remove_checksums(fp: &Path) -> Result<(), Error> {
+    match fs::remove_file(fp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,434 @@
+//! The block codec: compressing blocks, and laying them out as an image
+//! with its header and index, without any files. `compress` drives it over
+//! ISOs on disk, with threads and resumable parts, while `CsoWriter`,
+//! `encode_from` and `decode_into` take an image to and from any writer or
+//! reader.
+
+use std::io::{self, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::compress::{check_options, header_version, CompressOptions};
+use crate::cso::{
+    max_addressable, pad_file, read_cso_info, write_block_index, write_cso_info, CsoImage, Method, CISO_HEADER_SIZE,
+};
+use crate::decompress::entry_pos;
+use crate::error::XcsoError;
+use crate::{deflate, lz4};
+
+/// A block on its way through the pipeline. `data` holds the bytes
+/// compressed with `method`, or the raw bytes if there is none.
+pub(crate) struct Block {
+    pub seq: usize,
+    pub data: Vec<u8>,
+    pub method: Option<Method>,
+}
+
+// Bytes of a block compared at once when looking for zeros, a cache line
+const ZERO_SCAN_WIDTH: usize = 64;
+
+/// Whether `data` is all zeros. Whole cache lines are ORed together eight
+/// bytes at a time, which the compiler turns into SIMD compares, and the
+/// scan stops at the first line that isn't blank.
+fn is_zero(data: &[u8]) -> bool {
+    let mut lines = data.chunks_exact(ZERO_SCAN_WIDTH);
+    let rest = lines.remainder();
+    let blank = |line: &[u8]| {
+        line.chunks_exact(8).fold(0, |acc, x| acc | u64::from_ne_bytes(x.try_into().unwrap())) == 0
+    };
+    lines.all(blank) && rest.iter().all(|&b| b == 0)
+}
+
+/// One way of compressing and decompressing blocks, so the block loop, the
+/// index and the parts are the same whatever the method. Each worker has
+/// one of its own, which keeps whatever state it needs from block to block.
+/// A new codec is a type implementing it and an arm in `codec`, behind a
+/// cargo feature if it brings in a dependency.
+pub trait BlockCodec: Send {
+    /// The method blocks it compresses are marked with in the index.
+    fn method(&self) -> Method;
+    /// Compresses `block` into `out`, replacing what was there.
+    fn compress(&mut self, block: &[u8], out: &mut Vec<u8>) -> Result<(), Error>;
+    /// Decompresses `data` into the `len` bytes of the block it holds.
+    /// `data` can be followed by the padding up to the next block.
+    fn decompress(&self, data: &[u8], len: usize) -> Result<Vec<u8>, Error>;
+}
+
+struct Lz4 {
+    level: u32,
+    /// Made on the first block compressed, as a codec that only reads
+    /// doesn't need one.
+    encoder: Option<lz4::Encoder>,
+}
+
+impl BlockCodec for Lz4 {
+    fn method(&self) -> Method {
+        Method::Lz4
+    }
+
+    fn compress(&mut self, block: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        let level = self.level;
+        out.clear();
+        self.encoder.get_or_insert_with(|| lz4::Encoder::new(level)).compress_into(block, out)
+    }
+
+    fn decompress(&self, data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        lz4::decompress(data, len)
+    }
+}
+
+struct Deflate {
+    level: u32,
+}
+
+impl BlockCodec for Deflate {
+    fn method(&self) -> Method {
+        Method::Deflate
+    }
+
+    fn compress(&mut self, block: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        *out = deflate::compress(block, self.level)?;
+        Ok(())
+    }
+
+    fn decompress(&self, data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        deflate::decompress(data, len)
+    }
+}
+
+/// The codec for `method`, compressing at `level`. LZ4 is the default.
+pub fn codec(method: Method, level: u32) -> Box<dyn BlockCodec> {
+    match method {
+        Method::Lz4 => Box::new(Lz4 { level, encoder: None }),
+        Method::Deflate => Box::new(Deflate { level }),
+    }
+}
+
+/// Decompresses a block of `len` bytes stored as `data` with `method`,
+/// failing if it comes out any shorter.
+pub(crate) fn decode_block(data: &[u8], method: Method, len: usize) -> Result<Vec<u8>, Error> {
+    let block = codec(method, 0).decompress(data, len)?;
+    if block.len() != len {
+        let message = format!("{} block holds {} bytes rather than {}", method.name(), block.len(), len);
+        return Err(Error::new(ErrorKind::InvalidData, message));
+    }
+    Ok(block)
+}
+
+/// Compresses `block` in place, using `scratch` for the compressed data. The
+/// raw data is swapped into `scratch` when the block is kept compressed, so
+/// no block takes an allocation of its own.
+pub(crate) fn compress_worker(
+    block: &mut Block,
+    codec: &mut dyn BlockCodec,
+    scratch: &mut Vec<u8>,
+    image: &CsoImage,
+) -> Result<(), Error> {
+    codec.compress(&block.data, scratch)?;
+
+    // If the compressed size is greater than the original, prefer the
+    // original. CSO v2 readers also take anything that fills a whole block
+    // once padded to be uncompressed.
+    let limit = match image.sizes_raw_blocks() {
+        true => block.data.len().min(image.block_size as usize + 1 - (1 << image.align)),
+        false => block.data.len(),
+    };
+    if scratch.len() < limit {
+        std::mem::swap(&mut block.data, scratch);
+        block.method = Some(codec.method());
+    }
+    Ok(())
+}
+
+/// Compresses `block` however `store` and its contents call for: not at all,
+/// as the already compressed `zero_block` when it's all zeros, or with
+/// `codec`.
+pub(crate) fn encode_block(
+    block: &mut Block,
+    codec: &mut dyn BlockCodec,
+    scratch: &mut Vec<u8>,
+    zero_block: &Block,
+    store: bool,
+    image: &CsoImage,
+) -> Result<(), Error> {
+    if store {
+        Ok(())
+    } else if block.data.len() == image.block_size as usize && is_zero(&block.data) {
+        block.data.clear();
+        block.data.extend_from_slice(&zero_block.data);
+        block.method = zero_block.method;
+        Ok(())
+    } else {
+        compress_worker(block, codec, scratch, image)
+    }
+}
+
+/// Bytes `block` takes up in a part of `image`, not counting the alignment
+/// padding in front of it.
+pub(crate) fn stored_len(block: &Block, image: &CsoImage) -> u64 {
+    let len = block.data.len() as u64;
+    match block.method.is_none() && image.sizes_raw_blocks() {
+        true => len.max(image.block_size as u64),
+        false => len,
+    }
+}
+
+/// Compresses an image written to it a bit at a time, as `write` calls of
+/// any size, into a CSO or ZSO on `inner`. The index comes before the
+/// blocks, so the size of the image has to be known up front, and the
+/// image is one part however big it gets. Nothing is complete until
+/// `finish`, which writes the index.
+pub struct CsoWriter<W: Write + Seek> {
+    inner: W,
+    image: CsoImage,
+    store: bool,
+    codec: Box<dyn BlockCodec>,
+    scratch: Vec<u8>,
+    zero_block: Block,
+    /// The block being filled.
+    block: Vec<u8>,
+    index: Vec<u32>,
+    /// Where the last block written ends, from the start of `inner`.
+    pos: u64,
+    written: u64,
+}
+
+impl<W: Write + Seek> CsoWriter<W> {
+    /// Starts an image of `total_bytes` bytes, compressed with `opts`, at
+    /// the start of `inner`.
+    pub fn new(inner: W, total_bytes: u64, opts: &CompressOptions) -> Result<CsoWriter<W>, Error> {
+        CsoWriter::with_codec(inner, total_bytes, opts, codec(opts.method, opts.level))
+    }
+
+    /// Same as `new`, but compresses blocks with `codec` rather than the
+    /// one for `opts.method`, marking them with the method it gives.
+    pub fn with_codec(
+        mut inner: W,
+        total_bytes: u64,
+        opts: &CompressOptions,
+        mut codec: Box<dyn BlockCodec>,
+    ) -> Result<CsoWriter<W>, Error> {
+        check_options(opts)?;
+        let image = CsoImage {
+            format: opts.format,
+            version: header_version(opts)?,
+            align: opts.align,
+            block_size: opts.block_size,
+            total_bytes,
+            total_blocks: CsoImage::block_count(total_bytes, opts.block_size),
+        };
+        if !image.supports(codec.method()) {
+            let name = image.format.extension().to_uppercase();
+            let msg = format!("{} version {} images cannot hold {} blocks", name, image.version, codec.method().name());
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+        // As for `compress`, every position has to fit in the index even if
+        // no block shrinks
+        let index_len = (image.total_blocks + 1) * 4;
+        let worst_case = CISO_HEADER_SIZE as u64 + index_len as u64 + total_bytes +
+            image.total_blocks as u64 * ((1 << image.align) - 1);
+        if worst_case >= max_addressable(image.align) {
+            return Err(XcsoError::SplitLimit { align: image.align }.into());
+        }
+
+        let mut scratch = Vec::new();
+        let mut zero_block = Block { seq: 0, data: vec![0; image.block_size as usize], method: None };
+        compress_worker(&mut zero_block, &mut *codec, &mut scratch, &image)?;
+
+        inner.seek(SeekFrom::Start(0))?;
+        write_cso_info(&mut inner, image)?;
+        inner.write_all(&vec![0; index_len])?;
+        Ok(CsoWriter {
+            inner,
+            image,
+            store: opts.store,
+            codec,
+            scratch,
+            zero_block,
+            block: Vec::with_capacity(image.block_size as usize),
+            index: Vec::with_capacity(image.total_blocks + 1),
+            pos: CISO_HEADER_SIZE as u64 + index_len as u64,
+            written: 0,
+        })
+    }
+
+    /// The header of the image being written.
+    pub fn header(&self) -> CsoImage {
+        self.image
+    }
+
+    /// Pads `inner` out to the alignment after `pos`.
+    fn align(&mut self) -> Result<(), Error> {
+        let align_m = (1u64 << self.image.align) - 1;
+        let aligned = (self.pos + align_m) & !align_m;
+        self.inner.write_all(&vec![0; (aligned - self.pos) as usize])?;
+        self.pos = aligned;
+        Ok(())
+    }
+
+    /// Compresses and writes the block that's been filled.
+    fn write_block(&mut self) -> Result<(), Error> {
+        let data = std::mem::replace(&mut self.block, Vec::with_capacity(self.image.block_size as usize));
+        let mut block = Block { seq: self.index.len(), data, method: None };
+        let image = self.image;
+        encode_block(&mut block, &mut *self.codec, &mut self.scratch, &self.zero_block, self.store, &image)?;
+        self.align()?;
+        self.index.push(image.index_entry((self.pos >> image.align) as u32, block.method));
+        self.inner.write_all(&block.data)?;
+        // Stored blocks of CSO v2 take up a whole block
+        let len = stored_len(&block, &image);
+        self.inner.write_all(&vec![0; len as usize - block.data.len()])?;
+        self.pos += len;
+        Ok(())
+    }
+
+    /// Writes the last block and the index, returning `inner`. Fails if
+    /// fewer bytes were written than the image was started with.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.written != self.image.total_bytes {
+            let (written, total) = (self.written, self.image.total_bytes);
+            let msg = format!("only {} of the {} bytes of the image were written", written, total);
+            return Err(Error::new(ErrorKind::UnexpectedEof, msg));
+        }
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        self.align()?;
+        self.index.push((self.pos >> self.image.align) as u32);
+        pad_file(&mut self.inner)?;
+        self.inner.seek(SeekFrom::Start(CISO_HEADER_SIZE as u64))?;
+        write_block_index(&mut self.inner, &self.index)?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for CsoWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let left = self.image.total_bytes - self.written;
+        if left == 0 && !buf.is_empty() {
+            let msg = format!("the image is only {} bytes", self.image.total_bytes);
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+        let block_size = self.image.block_size as usize;
+        let n = buf.len().min(block_size - self.block.len()).min(left as usize);
+        self.block.extend_from_slice(&buf[..n]);
+        self.written += n as u64;
+        if self.block.len() == block_size {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compresses the image `data` with `opts` into `out`, from its start, as
+/// one part however big it gets. Returns the header written.
+pub fn encode_image<W: Write + Seek>(data: &[u8], out: &mut W, opts: &CompressOptions) -> Result<CsoImage, Error> {
+    encode_from(&mut Cursor::new(data), out, opts)
+}
+
+/// Same as `encode_image`, but compresses everything in `input`, from its
+/// start to its end, without holding it in memory.
+pub fn encode_from<R: Read + Seek, W: Write + Seek>(
+    input: &mut R,
+    out: &mut W,
+    opts: &CompressOptions,
+) -> Result<CsoImage, Error> {
+    let total_bytes = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(0))?;
+    let mut writer = CsoWriter::new(out, total_bytes, opts)?;
+    io::copy(&mut input.take(total_bytes), &mut writer)?;
+    let image = writer.header();
+    writer.finish()?;
+    Ok(image)
+}
+
+/// Reads the single part image in `input`, from its start, back into the
+/// data it holds.
+pub fn decode_image<R: Read + Seek>(input: &mut R) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    decode_into(input, &mut data)?;
+    Ok(data)
+}
+
+/// Same as `decode_image`, but writes the data to `out` as it goes,
+/// returning how many bytes there were.
+pub fn decode_into<R: Read + Seek, W: Write>(input: &mut R, out: &mut W) -> Result<u64, Error> {
+    let image = read_cso_info(input)?;
+    let mut raw = vec![0; (image.total_blocks + 1) * 4];
+    input.read_exact(&mut raw)?;
+    let index: Vec<u32> = raw.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect();
+
+    let mut stored = Vec::new();
+    for block in 0..image.total_blocks {
+        let (pos, next) = (entry_pos(index[block], image.align), entry_pos(index[block + 1], image.align));
+        let len = image.block_len(block);
+        let span = match next.checked_sub(pos) {
+            Some(span) if span <= image.block_size as u64 * 2 => span,
+            _ => return Err(XcsoError::BadBlock { block }.into()),
+        };
+        stored.resize(span as usize, 0);
+        input.seek(SeekFrom::Start(pos))?;
+        input.read_exact(&mut stored)?;
+        match image.block_method(index[block], span) {
+            None if stored.len() < len => {
+                return Err(XcsoError::ShortRead { block }.into());
+            },
+            None => out.write_all(&stored[..len])?,
+            Some(method) => out.write_all(&decode_block(&stored, method, len)?)?,
+        }
+    }
+    Ok(image.total_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::{patterned, random, round_trip, settings};
+
+    #[test]
+    fn round_trips_at_block_edges() {
+        for opts in settings() {
+            for len in [1, 2047, 2048, 2049, 65_536] {
+                for data in [patterned(len), random(len, 1), vec![0; len]] {
+                    let result = round_trip(&data, &opts);
+                    assert!(result.is_ok(), "{} bytes, block size {}: {:?}", len, opts.block_size, result);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_images_are_errors() {
+        let data = patterned(10_000);
+        let mut out = Cursor::new(Vec::new());
+        encode_image(&data, &mut out, &CompressOptions::default()).unwrap();
+        let image = out.into_inner();
+        assert!(decode_image(&mut Cursor::new(&image[..image.len() / 2])).is_err());
+        assert!(decode_image(&mut Cursor::new(&data[..])).is_err());
+    }
+
+    #[test]
+    fn writer_takes_writes_of_any_size() {
+        let mut data = patterned(8000);
+        data.extend(random(4345, 3));
+        let opts = CompressOptions { level: 1, ..CompressOptions::default() };
+        let mut writer = CsoWriter::new(Cursor::new(Vec::new()), data.len() as u64, &opts).unwrap();
+        for piece in data.chunks(777) {
+            writer.write_all(piece).unwrap();
+        }
+        let mut out = Cursor::new(Vec::new());
+        encode_image(&data, &mut out, &opts).unwrap();
+        assert_eq!(writer.finish().unwrap().into_inner(), out.into_inner());
+    }
+
+    #[test]
+    fn writer_wants_the_whole_image() {
+        let mut writer = CsoWriter::new(Cursor::new(Vec::new()), 10_000, &CompressOptions::default()).unwrap();
+        writer.write_all(&patterned(5000)).unwrap();
+        assert!(writer.finish().is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! What the loaders and emulators images are made for can open, checked
+//! against the options an image is about to be made with and against the
+//! image once it's written.
+//!
+//! They all read CSO v2 the way Project Stellar writes it, with 2048 byte
+//! blocks that are either LZ4 (flagged in the index) or stored as they are,
+//! and differ in how far they go past that: the alignments they take, how
+//! they find the parts of a split set and how big a part can be. Anything
+//! else in the format, such as ZSO, deflate blocks or other block sizes,
+//! none of them open.
+
+use std::ops::RangeInclusive;
+
+use crate::compress::{header_version, CompressOptions, SplitNaming, FATX_MAX_SIZE};
+use crate::cso::{CsoImage, Format, Method, CISO_BLOCK_SIZE, MAX_ALIGN};
+use crate::decompress::CsoFile;
+
+/// Something images are made to be loaded by.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// Project Stellar.
+    Stellar,
+    /// The Cerbios BIOS.
+    Cerbios,
+    /// Stock BIOSes patched to load CSOs. They were written against images
+    /// from the original conversion scripts, and take nothing else.
+    Patched,
+    /// The xemu emulator.
+    Xemu,
+}
+
+/// What a target can open.
+struct Support {
+    name: &'static str,
+    aligns: RangeInclusive<u8>,
+    namings: &'static [SplitNaming],
+    /// The largest part it reads, for targets reading off a FATX drive.
+    max_part: Option<u64>,
+}
+
+const STELLAR: Support = Support {
+    name: "Stellar",
+    aligns: 0..=4,
+    namings: &[SplitNaming::Numbered],
+    max_part: Some(FATX_MAX_SIZE),
+};
+const CERBIOS: Support = Support {
+    name: "Cerbios",
+    aligns: 0..=MAX_ALIGN,
+    namings: &[SplitNaming::Numbered, SplitNaming::Folders],
+    max_part: Some(FATX_MAX_SIZE),
+};
+const PATCHED: Support = Support {
+    name: "patched BIOSes",
+    aligns: 2..=2,
+    namings: &[SplitNaming::Numbered],
+    max_part: Some(FATX_MAX_SIZE),
+};
+const XEMU: Support = Support { name: "xemu", aligns: 0..=2, namings: &[SplitNaming::Numbered], max_part: None };
+
+impl Target {
+    pub const ALL: [Target; 4] = [Target::Stellar, Target::Cerbios, Target::Patched, Target::Xemu];
+
+    /// The target called `name` on the command line.
+    pub fn from_name(name: &str) -> Option<Target> {
+        Target::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    /// What the target is called on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Stellar => "stellar",
+            Target::Cerbios => "cerbios",
+            Target::Patched => "patched",
+            Target::Xemu => "xemu",
+        }
+    }
+
+    /// What the target is called in messages.
+    pub fn display_name(self) -> &'static str {
+        self.support().name
+    }
+
+    fn support(self) -> &'static Support {
+        match self {
+            Target::Stellar => &STELLAR,
+            Target::Cerbios => &CERBIOS,
+            Target::Patched => &PATCHED,
+            Target::Xemu => &XEMU,
+        }
+    }
+
+    /// What's wrong with the header of an image for the target.
+    fn check_header(self, header: CsoImage) -> Vec<String> {
+        let aligns = &self.support().aligns;
+        let mut problems = Vec::new();
+        if header.format != Format::Cso {
+            let format = header.format.extension().to_uppercase();
+            problems.push(format!("{} images aren't supported, only CSO ones", format));
+        } else if header.version != 2 {
+            problems.push(format!("CSO version {} isn't supported, only version 2", header.version));
+        }
+        if header.block_size != CISO_BLOCK_SIZE as u32 {
+            problems.push(format!("{} byte blocks aren't supported, only {}", header.block_size, CISO_BLOCK_SIZE));
+        }
+        if !aligns.contains(&header.align) {
+            let supported = match aligns.start() == aligns.end() {
+                true => format!("only {}", aligns.start()),
+                false => format!("only {} to {}", aligns.start(), aligns.end()),
+            };
+            problems.push(format!("alignment {} isn't supported, {}", header.align, supported));
+        }
+        problems
+    }
+
+    /// Why images made with `opts` wouldn't load on the target, if they
+    /// wouldn't.
+    pub fn check_options(self, opts: &CompressOptions) -> Vec<String> {
+        let support = self.support();
+        let mut problems = self.check_header(CsoImage {
+            format: opts.format,
+            version: header_version(opts).unwrap_or(0),
+            align: opts.align,
+            block_size: opts.block_size,
+            total_bytes: 0,
+            total_blocks: 0,
+        });
+        if opts.method != Method::Lz4 && !opts.store {
+            problems.push(format!("{} blocks aren't supported, only lz4 ones", opts.method.name()));
+        }
+        if opts.split_size.is_some() && !support.namings.contains(&opts.split_naming) {
+            problems.push(format!("split parts aren't found when named like {}", example_part(opts.split_naming)));
+        }
+        if let Some(max_part) = support.max_part {
+            if opts.split_size.is_none_or(|x| x > max_part) {
+                problems.push(format!("parts can come out bigger than FATX allows, {} bytes", max_part));
+            }
+        }
+        problems
+    }
+
+    /// Why `cso` wouldn't load on the target, if it wouldn't.
+    pub fn check_image(self, cso: &CsoFile) -> Vec<String> {
+        let mut problems = self.check_header(cso.header());
+        if cso.methods().contains(&Method::Deflate) {
+            problems.push("deflate blocks aren't supported, only lz4 ones".to_owned());
+        }
+        if cso.is_legacy_v2() {
+            problems.push("blocks flagged the way early CSO v2 tools did aren't supported".to_owned());
+        }
+        if cso.has_shared_index() {
+            problems.push("split sets sharing one index, as older versions wrote, aren't supported".to_owned());
+        }
+        problems
+    }
+}
+
+fn example_part(naming: SplitNaming) -> &'static str {
+    match naming {
+        SplitNaming::Numbered => "game.2.cso",
+        SplitNaming::Suffixed => "game.cso2",
+        SplitNaming::Folders => "Part2/game.cso",
+    }
+}
@@ -0,0 +1,2068 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use indicatif::{BinaryBytes, ProgressBar};
+
+use crate::cso::{
+    get_cso_info, get_raw_cso_info, max_addressable, pad_file, read_cso_info, write_block_index, write_cso_info,
+    CsoImage, Format, Method, CISO_BLOCK_SIZE, CISO_HEADER_SIZE, INDEX_FLAG, MAX_ALIGN, SUPPORTED_BLOCK_SIZES,
+    XBOX_MEDIA_HEADER, XBOX_MEDIA_HEADER_XDVDFS_OFFSET,
+};
+use crate::checksums::{checksum_path, remove_checksums, write_checksums};
+use crate::codec::{codec, compress_worker, encode_block, stored_len, Block, BlockCodec};
+use crate::hash::{xxh64, Hasher, Hashes};
+use crate::log::Level;
+use crate::pack::{Removal, SYSTEM_UPDATE_DIR};
+use crate::progress::{BarProgress, Phase, Progress, ProgressSink};
+use crate::decompress::open_uncompressed;
+use crate::direct::DirectWriter;
+use crate::error::XcsoError;
+use crate::hints::{remove_seek_hints, write_seek_hints};
+use crate::journal::{journal_path, Journal};
+use crate::trailer::{write_trailer, TRAILER_SIZE};
+use crate::throttle::{RateLimit, Throttled};
+use crate::xdvdfs::{ReadSeek, Volume};
+use crate::lz4;
+
+pub const FATX_MAX_SIZE: u64 = 4290732032;
+
+/// Fastest LZ4 level; levels from 3 up switch to the HC compressor.
+pub const LEVEL_FAST: u32 = 0;
+/// Default level of the LZ4 HC compressor.
+pub const LEVEL_HC: u32 = 9;
+/// Highest level LZ4 supports; anything above behaves the same.
+pub const LEVEL_MAX: u32 = 12;
+
+/// Progress bar layout suited to the byte counts reported by the
+/// `*_with_progress` functions.
+pub const PROGRESS_TEMPLATE: &str =
+    "{wide_bar} {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, {eta} left) {msg}";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks any running conversion or verification to stop. It fails with an
+/// `Interrupted` error, and conversions remove their partial outputs. Only sets a flag, so
+/// it is safe to call from a signal handler.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether `interrupt` has been called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Stops a single conversion, where `interrupt` stops every one. Clones
+/// share one flag, so a program can keep one and cancel the conversion it
+/// handed the other to from any thread. The conversion looks at it between
+/// blocks and fails with an `Interrupted` error, leaving its partial
+/// outputs as its `CancelPolicy` says.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Asks whatever was given the token to stop. Only sets a flag.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether `cancel`, or `interrupt`, has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst) || interrupted()
+    }
+}
+
+/// What's done with the outputs of a conversion that's cancelled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CancelPolicy {
+    /// Remove them.
+    Remove,
+    /// Leave them as they were after the last block, with the index saved,
+    /// for `resume` to finish. Parts of a stream can't be resumed, so they
+    /// go either way, and a decompressed ISO is left cut short.
+    Keep,
+}
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+// How often a paused conversion looks to see whether it can carry on
+const PAUSE_POLL: Duration = Duration::from_millis(100);
+
+/// Pauses any running conversion, or lets it carry on if it's paused
+/// already. Conversions pause between blocks, once what they've written is
+/// on disk and the block index is saved, so the disk is left alone and the
+/// outputs can be resumed even if the process is stopped while paused. Only
+/// sets a flag, so it is safe to call from a signal handler.
+pub fn toggle_pause() {
+    PAUSED.fetch_xor(true, Ordering::SeqCst);
+}
+
+/// Whether conversions are paused.
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Settings used when creating a CSO image.
+#[derive(Clone, Debug)]
+pub struct CompressOptions {
+    /// LZ4 compression level, from `LEVEL_FAST` to `LEVEL_MAX`. Deflate
+    /// tops out at `deflate::MAX_LEVEL`.
+    pub level: u32,
+    pub format: Format,
+    /// ZSO images only hold LZ4 blocks.
+    pub method: Method,
+    /// Header version to write. `None` picks v2 for LZ4 CSO images, and v1
+    /// otherwise so deflate images suit the older readers that want them.
+    pub version: Option<u8>,
+    /// Size of each block, one of `SUPPORTED_BLOCK_SIZES`.
+    pub block_size: u32,
+    /// Blocks start on multiples of `1 << align` bytes, up to `MAX_ALIGN`.
+    pub align: u8,
+    /// Continue a conversion that was interrupted part way through instead
+    /// of starting over. Starts from scratch if there is no earlier output.
+    pub resume: bool,
+    /// Store every block as is instead of compressing it, for a quick split
+    /// container that loaders still accept.
+    pub store: bool,
+    /// Compress blocks `BATCH_BLOCKS` at a time, sharing each batch out
+    /// between the workers and writing it once they're all done, rather
+    /// than passing blocks through the workers one by one.
+    pub batched: bool,
+    /// Threads compressing blocks, or one for each physical core with `None`.
+    pub threads: Option<usize>,
+    /// Bytes the conversion may hold in memory, roughly, which bounds how
+    /// many blocks are held between reading and writing. With `None` there
+    /// are enough of them to keep every thread busy.
+    pub max_memory: Option<u64>,
+    /// Bytes a second to read the image at, and to write the output at, at
+    /// most.
+    pub limit_rate: Option<u64>,
+    /// Stop at the end of the file system rather than the end of the game
+    /// partition, leaving out the padding after the last file.
+    pub trim: bool,
+    /// Take the `$SystemUpdate` folder out of the image, blanking the
+    /// sectors it used.
+    pub strip_system_update: bool,
+    /// Compress the video partition of a full redump image along with the
+    /// game partition, so the CSO decompresses back to the whole dump.
+    /// Loaders only need the game partition, which is all that's kept
+    /// otherwise.
+    pub keep_video_partition: bool,
+    /// Compress the whole file without looking for an Xbox file system, for
+    /// images of other systems with 2048 byte sectors.
+    pub raw: bool,
+    /// Start a new part once an output grows past this many bytes, or never
+    /// with `None`. Defaults to `FATX_MAX_SIZE`.
+    pub split_size: Option<u64>,
+    pub split_naming: SplitNaming,
+    pub write: WriteOptions,
+    /// Write a checksum of each block next to the output, in the
+    /// `checksums` side index.
+    pub checksums: bool,
+    /// End each part with a `trailer` of the hashes of the ISO and of the
+    /// part, which means hashing the whole ISO as it's read.
+    pub trailer: bool,
+    /// Write `hints` next to the output, for reading blocks without loading
+    /// the whole index.
+    pub seek_hints: bool,
+    /// Stops the conversion when cancelled.
+    pub cancel: CancelToken,
+    pub on_cancel: CancelPolicy,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            level: LEVEL_MAX,
+            format: Format::Cso,
+            method: Method::Lz4,
+            version: None,
+            block_size: CISO_BLOCK_SIZE as u32,
+            align: 2,
+            resume: false,
+            store: false,
+            batched: false,
+            threads: None,
+            max_memory: None,
+            limit_rate: None,
+            trim: false,
+            strip_system_update: false,
+            keep_video_partition: false,
+            raw: false,
+            split_size: Some(FATX_MAX_SIZE),
+            split_naming: SplitNaming::Numbered,
+            write: WriteOptions::default(),
+            checksums: false,
+            trailer: false,
+            seek_hints: false,
+            cancel: CancelToken::new(),
+            on_cancel: CancelPolicy::Remove,
+        }
+    }
+}
+
+/// How much of an output is gathered before it's written out. Network
+/// shares in particular are far quicker with a few large writes than with
+/// one per block.
+pub const DEFAULT_WRITE_BUFFER: usize = 4 << 20;
+
+/// When outputs are flushed all the way to disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Whenever the operating system gets round to it.
+    Never,
+    /// As each part is finished, before it's renamed into place, so a part
+    /// with its final name is known to be complete.
+    End,
+    /// At every index checkpoint as well, so a conversion resumed after a
+    /// crash or a dropped share can trust all the index says was written.
+    Checkpoint,
+}
+
+/// How outputs are written.
+#[derive(Copy, Clone, Debug)]
+pub struct WriteOptions {
+    /// Bytes gathered before each write.
+    pub buffer_size: usize,
+    pub sync: SyncPolicy,
+    /// Reserve disk space for the most each part could take up before
+    /// writing it, which keeps it from being scattered over the disk. What
+    /// isn't needed is given back once the part is finished.
+    pub preallocate: bool,
+    /// Write around the operating system's file cache, in whole sectors, so
+    /// converting a library onto an external drive doesn't push everything
+    /// else out of the cache. Parts of a stream are always written through
+    /// the cache, as making room for their index reads them back.
+    pub direct: bool,
+    /// Refuse to start when the destination doesn't have room for the
+    /// largest the output could be.
+    pub check_space: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            buffer_size: DEFAULT_WRITE_BUFFER,
+            sync: SyncPolicy::End,
+            preallocate: true,
+            direct: false,
+            check_space: true,
+        }
+    }
+}
+
+/// Compresses a single block with LZ4, returning the data as earlier
+/// versions of this tool stored it in CSO v2 images: the size of the
+/// compressed LZ4 block followed by the block itself.
+pub fn compress_block_v2(block: &[u8], level: u32) -> Result<Vec<u8>, Error> {
+    let mut result = Vec::with_capacity(4 + lz4::bound(block.len()));
+    result.extend_from_slice(&[0; 4]);
+    lz4::Encoder::new(level).compress_into(block, &mut result)?;
+    let len = (result.len() - 4) as u32;
+    result[..4].copy_from_slice(&len.to_le_bytes());
+    Ok(result)
+}
+
+/// How many physical cores this process can run on. Hyperthreads share a
+/// core's compression units, so a second thread per core adds little but
+/// contention.
+pub fn physical_cores() -> usize {
+    let logical = thread::available_parallelism().map_or(1, |n| n.get());
+    sibling_cores().map_or(logical, |cores| cores.clamp(1, logical))
+}
+
+/// Counts the distinct cores the kernel lists for its CPUs.
+#[cfg(target_os = "linux")]
+fn sibling_cores() -> Option<usize> {
+    let mut cores = std::collections::BTreeSet::new();
+    for entry in fs::read_dir("/sys/devices/system/cpu").ok()? {
+        let topology = entry.ok()?.path().join("topology");
+        let read = |name: &str| fs::read_to_string(topology.join(name)).ok();
+        if let (Some(package), Some(core)) = (read("physical_package_id"), read("core_id")) {
+            cores.insert((package.trim().to_owned(), core.trim().to_owned()));
+        }
+    }
+    Some(cores.len()).filter(|&x| x > 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sibling_cores() -> Option<usize> {
+    None
+}
+
+// Blocks in flight per worker before the reader blocks
+const QUEUE_DEPTH: usize = 64;
+
+/// Blocks compressed together with `CompressOptions::batched`.
+pub const BATCH_BLOCKS: usize = 1024;
+
+// Blocks written between index checkpoints
+const CHECKPOINT_INTERVAL: usize = 4096;
+
+// How much of the source is read at a time, with blocks sliced out of it
+// rather than read one by one
+const READ_WINDOW: usize = 4 << 20;
+
+// How much of a deferred part is moved at a time to fit its index in
+const SHIFT_CHUNK: usize = 4 << 20;
+
+fn read_full(f: &mut dyn Read, buf: &mut [u8]) -> Result<usize, io::Error> {
+    let mut total = 0;
+    while total < buf.len() {
+        match f.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+/// Compresses a batch of blocks, sharing them out between a thread for each
+/// of `workers`, with its codec and scratch buffer.
+fn encode_batch(
+    batch: &mut [Block],
+    workers: &mut [(Box<dyn BlockCodec>, Vec<u8>)],
+    zero_block: &Block,
+    store: bool,
+    image: &CsoImage,
+) -> Result<(), io::Error> {
+    let share = batch.len().div_ceil(workers.len());
+    thread::scope(|s| {
+        let threads: Vec<_> = batch.chunks_mut(share).
+            zip(workers.iter_mut()).
+            map(|(blocks, (codec, scratch))| s.spawn(move || {
+                blocks.iter_mut().
+                    try_for_each(|x| encode_block(x, &mut **codec, scratch, zero_block, store, image))
+            })).
+            collect();
+        threads.into_iter().try_for_each(|x| x.join().unwrap())
+    })
+}
+
+/// How the parts of an output are named, as loaders differ in what they
+/// look for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SplitNaming {
+    /// `game.1.cso`, `game.2.cso`, ..., numbered even when there's only one.
+    Numbered,
+    /// `game.cso`, then `game.cso2`, `game.cso3`, ...
+    Suffixed,
+    /// `Part1/game.cso`, `Part2/game.cso`, ..., in folders next to where the
+    /// output would go.
+    Folders,
+}
+
+impl SplitNaming {
+    /// Returns the path of part `n` (counting from 1) of the output with
+    /// base path `dest`.
+    pub fn part_path(self, dest: &Path, n: usize, format: Format) -> PathBuf {
+        let mut fp = dest.as_os_str().to_owned();
+        match self {
+            SplitNaming::Numbered => fp.push(format!(".{}.{}", n, format.extension())),
+            SplitNaming::Suffixed if n == 1 => fp.push(format!(".{}", format.extension())),
+            SplitNaming::Suffixed => fp.push(format!(".{}{}", format.extension(), n)),
+            SplitNaming::Folders => {
+                let mut name = dest.file_name().unwrap_or_default().to_owned();
+                name.push(format!(".{}", format.extension()));
+                return dest.with_file_name(format!("Part{}", n)).join(name);
+            },
+        }
+        PathBuf::from(fp)
+    }
+}
+
+/// Returns the path of part `n` (counting from 1) of the output with base
+/// path `dest`, numbered as `dest.1.cso`.
+pub fn part_path(dest: &Path, n: usize, format: Format) -> PathBuf {
+    SplitNaming::Numbered.part_path(dest, n, format)
+}
+
+/// Parts are written under this name and only renamed to `fp` once the
+/// whole image has been converted, so an aborted run never leaves behind
+/// something that looks like a usable image.
+pub(crate) fn temp_path(fp: &Path) -> PathBuf {
+    let mut fp = fp.as_os_str().to_owned();
+    fp.push(".part");
+    PathBuf::from(fp)
+}
+
+/// Starts part `n` of the output to `dest`, holding at most `blocks` blocks
+/// from `first_block` on, or any number of them with `None`.
+fn create_part(
+    dest: &Path,
+    n: usize,
+    naming: SplitNaming,
+    image: CsoImage,
+    first_block: usize,
+    blocks: Option<usize>,
+    write: WriteOptions,
+) -> Result<PartWriter, Error> {
+    let fp = temp_path(&naming.part_path(dest, n, image.format));
+    if naming == SplitNaming::Folders {
+        fs::create_dir_all(fp.parent().unwrap())?;
+    }
+    PartWriter::create(&fp, image, first_block, blocks, write)
+}
+
+/// One output file. Every part is a complete image of its own, with a header
+/// and index covering just the blocks it holds.
+///
+/// While a part is being written its index is checkpointed every so often:
+/// the entries of the blocks written so far followed by one more entry for
+/// where the next block goes. Everything before the last non-zero entry is
+/// known to be on disk, which is what lets an interrupted conversion resume.
+///
+/// A part of a stream, where nobody knows how many blocks are coming, is
+/// written without room for its index. Once it's finished everything in it
+/// is moved along to make room, and the index entries with it.
+pub(crate) struct PartWriter {
+    file: Output,
+    sync: SyncPolicy,
+    image: CsoImage,
+    first_block: usize,
+    index: Vec<u32>,
+    write_pos: u64,
+    // Index entries already written by a checkpoint
+    flushed: usize,
+    // Whether the blocks start at the beginning of the file, with room made
+    // for the header and index by `finish`
+    deferred: bool,
+    journal: Option<Journal>,
+}
+
+/// What was found when looking at an existing part while resuming.
+enum PartState {
+    /// The part was finished and holds this many blocks.
+    Complete(usize),
+    /// The part was cut short, but this many of its blocks are intact.
+    Partial(PartWriter, usize),
+}
+
+impl PartWriter {
+    /// Creates a part that will hold at most `blocks` blocks, starting with
+    /// `first_block` of the source image. With `None` for `blocks` the index
+    /// is left until the part is finished.
+    pub fn create(
+        fp: &Path,
+        mut image: CsoImage,
+        first_block: usize,
+        blocks: Option<usize>,
+        write: WriteOptions,
+    ) -> Result<PartWriter, Error> {
+        let mut file = match write.direct && blocks.is_some() {
+            true => Output::Direct(DirectWriter::create(fp, write.buffer_size)?),
+            // Read back as well when moving the blocks of a deferred part
+            false => {
+                let file = File::options().read(true).write(true).create(true).truncate(true).open(fp);
+                let file = file.map_err(|e| XcsoError::io(fp, e))?;
+                Output::Buffered(BufWriter::with_capacity(write.buffer_size, file))
+            },
+        };
+        image.total_bytes -= first_block as u64 * image.block_size as u64;
+
+        let Some(blocks) = blocks else {
+            let index = Vec::new();
+            let (sync, write_pos, flushed, deferred, journal) = (write.sync, 0, 0, true, None);
+            return Ok(PartWriter { file, sync, image, first_block, index, write_pos, flushed, deferred, journal });
+        };
+
+        // Write the CSO header, sized to match the placeholder index until
+        // the part is finished
+        write_cso_info(&mut file, image)?;
+
+        // Followed by a placeholder block index
+        let index = vec![0; blocks+1];
+        let write_pos = write_block_index(&mut file, &index)?;
+
+        let index = Vec::with_capacity(blocks+1);
+        let (sync, deferred) = (write.sync, false);
+        let journal = Some(Journal::create(&journal_path(fp), write_pos, sync != SyncPolicy::Never)?);
+        Ok(PartWriter { file, sync, image, first_block, index, write_pos, flushed: 0, deferred, journal })
+    }
+
+    /// Opens a part left behind by an earlier run, which should start with
+    /// `first_block` of the source image, and works out how far it got.
+    fn resume(fp: &Path, image: CsoImage, first_block: usize, write: WriteOptions) -> Result<PartState, Error> {
+        let mut file = File::options().read(true).write(true).open(fp).map_err(|e| XcsoError::io(fp, e))?;
+        let header = read_cso_info(&mut file)?;
+        if header.format != image.format
+            || header.version != image.version
+            || header.block_size != image.block_size
+            || header.align != image.align
+        {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot resume {}: it was created with different settings", fp.display()),
+            ));
+        }
+
+        let blocks = header.total_blocks;
+        let mut raw = vec![0u8; (blocks + 1) * 4];
+        file.read_exact(&mut raw)?;
+        let entries: Vec<u32> = raw.chunks_exact(4).
+            map(|x| u32::from_le_bytes(x.try_into().unwrap())).
+            collect();
+
+        if entries[blocks] != 0 {
+            return Ok(PartState::Complete(blocks));
+        }
+
+        let remaining = image.total_bytes - first_block as u64 * image.block_size as u64;
+        if header.total_bytes != remaining {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot resume {}: it was created from a different image", fp.display()),
+            ));
+        }
+
+        let data_start = CISO_HEADER_SIZE as u64 + raw.len() as u64;
+        let sync = write.sync;
+        let journal_fp = journal_path(fp);
+        let (index, write_pos, journal) = match journal_fp.exists() {
+            // The journal knows which blocks really made it to disk, so the
+            // index is rebuilt from it, in case it got further than that or
+            // never got there at all
+            true => {
+                let (index, write_pos, journal) =
+                    Journal::recover(&journal_fp, &mut file, data_start, sync != SyncPolicy::Never)?;
+                let mut entries = index.clone();
+                entries.resize(blocks + 1, 0);
+                file.seek(io::SeekFrom::Start(CISO_HEADER_SIZE as u64))?;
+                write_block_index(&mut file, &entries)?;
+                (index, write_pos, Some(journal))
+            },
+            // Otherwise the last entry that made it to disk tells us where
+            // to carry on from
+            false => {
+                let done = entries.iter().position(|&x| x == 0).unwrap_or(blocks).saturating_sub(1);
+                let write_pos = match entries[0] {
+                    0 => data_start,
+                    _ => ((entries[done] & !INDEX_FLAG) as u64) << image.align,
+                };
+                (entries[..done].to_vec(), write_pos, None)
+            },
+        };
+        let done = index.len();
+        file.set_len(write_pos)?;
+        file.seek(io::SeekFrom::Start(write_pos))?;
+
+        let image = CsoImage { total_bytes: remaining, ..image };
+        let file = match write.direct {
+            true => {
+                drop(file);
+                let mut file = DirectWriter::open_existing(fp, write.buffer_size)?;
+                file.seek(io::SeekFrom::Start(write_pos))?;
+                Output::Direct(file)
+            },
+            false => Output::Buffered(BufWriter::with_capacity(write.buffer_size, file)),
+        };
+        let (flushed, deferred) = (done, false);
+        let part = PartWriter { file, sync, image, first_block, index, write_pos, flushed, deferred, journal };
+        Ok(PartState::Partial(part, done))
+    }
+
+    /// Writes the index entries of the blocks written since the last
+    /// checkpoint, plus the position the next block will be written at.
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        // A deferred part has nowhere to put its index until it's finished
+        if self.deferred {
+            return Ok(());
+        }
+
+        // The blocks go to disk before the index that points at them
+        if self.sync == SyncPolicy::Checkpoint {
+            self.file.flush()?;
+            self.file.get_ref().sync_data()?;
+        }
+
+        let align_m = (1u64 << self.image.align) - 1;
+        let next = ((self.write_pos + align_m) >> self.image.align) as u32;
+
+        let offset = CISO_HEADER_SIZE as u64 + self.flushed as u64 * 4;
+        self.file.seek(io::SeekFrom::Start(offset))?;
+        write_block_index(&mut self.file, &self.index[self.flushed..])?;
+        write_block_index(&mut self.file, &[next])?;
+        self.file.seek(io::SeekFrom::Start(self.write_pos))?;
+
+        self.flushed = self.index.len();
+        if let Some(journal) = &mut self.journal {
+            journal.record(&self.index, self.write_pos)?;
+        }
+        crate::trace!("checkpoint after {} blocks, next block at {}", self.first_block + self.flushed, self.write_pos);
+        Ok(())
+    }
+
+    /// Saves the index and waits for everything written so far to reach the
+    /// disk, before pausing.
+    fn settle(&mut self) -> Result<(), Error> {
+        self.checkpoint()?;
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    /// How big the part would end up if `block` were the last one written
+    /// to it, counting the alignment before it and the padding at the end.
+    fn size_with(&self, block: &Block) -> u64 {
+        let align_m = (1u64 << self.image.align) - 1;
+        let start = (self.write_pos + align_m) & !align_m;
+        let start = match self.deferred {
+            true => start + self.index_room(self.index.len() + 2),
+            false => start,
+        };
+        let len = stored_len(block, &self.image);
+        // pad_file always adds at least a byte, up to the next KiB
+        ((start + len) & !0x3ff) + 0x400
+    }
+
+    pub fn write_block(&mut self, block: &Block, alignment_buffer: &[u8]) -> Result<(), Error> {
+        let align_b = 1 << self.image.align;
+        let align_m = align_b - 1;
+
+        let mut align: usize = self.write_pos as usize & align_m;
+        if align > 0 {
+            align = align_b - align;
+            self.file.write_all(&alignment_buffer[..align])?;
+            self.write_pos += align as u64;
+            if let Some(journal) = &mut self.journal {
+                journal.update_zeros(align as u64);
+            }
+        }
+
+        let pos = (self.write_pos >> self.image.align) as u32;
+        self.index.push(self.image.index_entry(pos, block.method));
+
+        self.write_pos += block.data.len() as u64;
+        self.file.write_all(&block.data)?;
+        if let Some(journal) = &mut self.journal {
+            journal.update(&block.data);
+        }
+
+        // Which makes a short block at the end of a CSO v2 image look
+        // compressed, so it is padded out to a full block
+        let block_size = self.image.block_size as usize;
+        if block.method.is_none() && self.image.sizes_raw_blocks() && block.data.len() < block_size {
+            self.file.write_all(&vec![0; block_size - block.data.len()])?;
+            self.write_pos += (block_size - block.data.len()) as u64;
+            if let Some(journal) = &mut self.journal {
+                journal.update_zeros((block_size - block.data.len()) as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves room on disk for the part to grow to `len` bytes. This is
+    /// only a hint, so file systems that can't do it are left to allocate
+    /// as the part is written.
+    fn preallocate(&mut self, len: u64) {
+        if let Err(e) = allocate(self.file.get_ref(), len) {
+            crate::debug!("could not reserve {} bytes: {}", len, e);
+        }
+    }
+
+    /// Bytes taken up by the header and an index of `entries` entries, up to
+    /// where the first block of a deferred part can start.
+    fn index_room(&self, entries: usize) -> u64 {
+        let align_m = (1u64 << self.image.align) - 1;
+        (CISO_HEADER_SIZE as u64 + entries as u64 * 4 + align_m) & !align_m
+    }
+
+    /// Writes the real header and index and pads the part. The last part also
+    /// covers any partial block at the end of the `source_bytes` long image.
+    pub fn finish(&mut self, source_bytes: u64, last: bool) -> Result<(), Error> {
+        // last position (total size)
+        // ZSO readers size the final block from it, so round up rather than
+        // cutting off the tail of the block.
+        let span = crate::span!(Level::Debug, "index", "blocks={}", self.index.len());
+        let align_m = (1u64 << self.image.align) - 1;
+        self.index.push(((self.write_pos + align_m) >> self.image.align) as u32);
+
+        // Room for it all, which the blocks of a deferred part move along to make
+        let room = self.index_room(self.index.len());
+        if self.deferred {
+            self.file.flush()?;
+            let Output::Buffered(ref mut file) = self.file else {
+                unreachable!("deferred parts are always buffered");
+            };
+            shift_data(file.get_mut(), self.write_pos, room)?;
+            let moved = (room >> self.image.align) as u32;
+            self.index.iter_mut().for_each(|x| *x += moved);
+            self.write_pos += room;
+        }
+
+        let blocks = self.index.len() - 1;
+        let block_size = self.image.block_size as u64;
+        self.image.total_blocks = blocks;
+        self.image.total_bytes = if last {
+            source_bytes - self.first_block as u64 * block_size
+        } else {
+            blocks as u64 * block_size
+        };
+
+        // Seek back to the beginning to re-write the header and block index,
+        // once the blocks still buffered are out of the way, giving back
+        // whatever was reserved and not used
+        self.file.flush()?;
+        self.file.set_len(self.write_pos)?;
+        self.file.seek(io::SeekFrom::Start(0))?;
+        write_cso_info(&mut self.file, self.image)?;
+        let end = write_block_index(&mut self.file, &self.index)?;
+        if self.deferred {
+            // Blanking what's left of the blocks that were there
+            self.file.write_all(&vec![0; (room - end) as usize])?;
+        }
+        drop(span);
+
+        let _span = crate::span!(Level::Debug, "pad", "bytes={}", self.write_pos);
+        pad_file(&mut self.file)?;
+        self.file.flush()?;
+        match self.sync {
+            SyncPolicy::Never => Ok(()),
+            SyncPolicy::End | SyncPolicy::Checkpoint => self.file.get_ref().sync_all(),
+        }
+    }
+}
+
+/// Where a part is written to, through the file cache or around it.
+enum Output {
+    Buffered(BufWriter<File>),
+    Direct(DirectWriter),
+}
+
+impl Output {
+    fn get_ref(&self) -> &File {
+        match self {
+            Output::Buffered(x) => x.get_ref(),
+            Output::Direct(x) => x.get_ref(),
+        }
+    }
+
+    /// Cuts the file down to `len` bytes, once it has been flushed.
+    fn set_len(&mut self, len: u64) -> Result<(), Error> {
+        match self {
+            Output::Buffered(x) => x.get_ref().set_len(len),
+            Output::Direct(x) => x.set_len(len),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Buffered(x) => x.write(data),
+            Output::Direct(x) => x.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Buffered(x) => x.flush(),
+            Output::Direct(x) => x.flush(),
+        }
+    }
+}
+
+impl Seek for Output {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            Output::Buffered(x) => x.seek(pos),
+            Output::Direct(x) => x.seek(pos),
+        }
+    }
+}
+
+/// Allocates the first `len` bytes of `file` up front, with `fallocate` on
+/// Linux so nothing has to be written to make it happen.
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> Result<(), Error> {
+    use std::os::fd::AsRawFd;
+    match unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Allocates the first `len` bytes of `file` by extending it, which
+/// allocates on NTFS but may only leave a sparse file elsewhere.
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &File, len: u64) -> Result<(), Error> {
+    file.set_len(len)
+}
+
+/// Bytes free for this user on the file system holding `dir`.
+#[cfg(unix)]
+fn free_space(dir: &Path) -> Result<u64, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes());
+    let path = path.map_err(|e| Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        0 => Ok(stat.f_bavail as u64 * stat.f_frsize as u64),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Bytes free for this user on the volume holding `dir`.
+#[cfg(windows)]
+fn free_space(dir: &Path) -> Result<u64, Error> {
+    use std::os::windows::ffi::OsStrExt;
+    extern "system" {
+        fn GetDiskFreeSpaceExW(dir: *const u16, free: *mut u64, total: *mut u64, total_free: *mut u64) -> i32;
+    }
+    let path: Vec<u16> = dir.as_os_str().encode_wide().chain([0]).collect();
+    let mut free = 0;
+    let null = std::ptr::null_mut();
+    match unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut free, null, null) } {
+        0 => Err(Error::last_os_error()),
+        _ => Ok(free),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn free_space(_dir: &Path) -> Result<u64, Error> {
+    Err(Error::new(io::ErrorKind::Unsupported, "free space can't be checked on this system"))
+}
+
+/// Checks that the file system `dest` goes on has `needed` bytes free, on
+/// top of what the `.part` files of an earlier run already take up. Only
+/// warns when that can't be found out, as some network shares don't say.
+fn check_space(dest: &Path, needed: u64, naming: SplitNaming, format: Format) -> Result<(), Error> {
+    let dir = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let free = match free_space(dir) {
+        Ok(free) => free,
+        Err(e) => {
+            crate::warn!("couldn't check the free space in {}: {}", dir.display(), e);
+            return Ok(());
+        },
+    };
+    let written: u64 = (1..).
+        map(|n| fs::metadata(temp_path(&naming.part_path(dest, n, format))).map(|x| x.len())).
+        map_while(Result::ok).
+        sum();
+    let needed = needed.saturating_sub(written);
+    crate::debug!("{} needs up to {} bytes, {} are free", dir.display(), needed, free);
+    if free < needed {
+        return Err(Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "{} has {} free, but the output could take up to {}",
+                dir.display(),
+                BinaryBytes(free),
+                BinaryBytes(needed),
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Moves the first `len` bytes of `file` `by` bytes further in, starting
+/// from the end so nothing is overwritten before it has been moved.
+fn shift_data(file: &mut File, len: u64, by: u64) -> Result<(), Error> {
+    let _span = crate::span!(Level::Debug, "shift", "bytes={}", len);
+    let mut buf = vec![0; SHIFT_CHUNK];
+    let mut end = len;
+    while end > 0 {
+        let n = end.min(buf.len() as u64);
+        let start = end - n;
+        file.seek(io::SeekFrom::Start(start))?;
+        file.read_exact(&mut buf[..n as usize])?;
+        file.seek(io::SeekFrom::Start(start + by))?;
+        file.write_all(&buf[..n as usize])?;
+        end = start;
+    }
+    Ok(())
+}
+
+/// Ends each of the `part_count` parts of `dest` with a trailer of the ISO hashes in `source`.
+fn write_trailers(
+    dest: &Path,
+    part_count: usize,
+    format: Format,
+    naming: SplitNaming,
+    source: &Hashes,
+) -> Result<(), Error> {
+    for n in 1..=part_count {
+        write_trailer(&naming.part_path(dest, n, format), source)?;
+    }
+    Ok(())
+}
+
+/// Moves the `part_count` finished parts of `dest` into place.
+fn rename_parts(dest: &Path, part_count: usize, format: Format, naming: SplitNaming) -> Result<(), Error> {
+    for n in 1..=part_count {
+        let to = naming.part_path(dest, n, format);
+        let from = temp_path(&to);
+        crate::debug!("renaming {} to {}", from.display(), to.display());
+        fs::rename(&from, to)?;
+        _ = fs::remove_file(journal_path(&from));
+    }
+
+    // Parts left over from an earlier, larger conversion would otherwise be
+    // read as part of this image
+    let mut n = part_count + 1;
+    while fs::remove_file(naming.part_path(dest, n, format)).is_ok() {
+        crate::debug!("removed stale part {}", naming.part_path(dest, n, format).display());
+        n += 1;
+    }
+    Ok(())
+}
+
+/// Picks up the parts an interrupted conversion to `dest` left behind,
+/// returning the part to carry on writing, its number and the first block
+/// still to be written. There is no part to write if every part was
+/// finished and only needs renaming.
+fn resume_parts(
+    dest: &Path,
+    image: CsoImage,
+    naming: SplitNaming,
+    write: WriteOptions,
+) -> Result<(Option<PartWriter>, usize, usize), Error> {
+    let mut first_block = 0;
+    let mut n = 1;
+    loop {
+        let fp = temp_path(&naming.part_path(dest, n, image.format));
+        if !fp.exists() {
+            if first_block == image.total_blocks && n > 1 {
+                return Ok((None, n - 1, first_block));
+            }
+            let blocks = Some(image.total_blocks - first_block);
+            let part = create_part(dest, n, naming, image, first_block, blocks, write)?;
+            return Ok((Some(part), n, first_block));
+        }
+
+        match PartWriter::resume(&fp, image, first_block, write)? {
+            PartState::Complete(blocks) => {
+                crate::debug!("part {} is complete with {} blocks", n, blocks);
+                first_block += blocks;
+            },
+            PartState::Partial(part, done) => {
+                crate::debug!("resuming part {} after {} blocks", n, done);
+                return Ok((Some(part), n, first_block + done));
+            },
+        }
+        n += 1;
+    }
+}
+
+/// Compresses the ISO at `fp` into `<dest>.1.cso` (or `.zso`, or whatever
+/// `opts.split_naming` calls it), returning the path of that first output
+/// part.
+///
+/// Blocks are read on one thread, compressed on a pool of workers and handed
+/// back to the calling thread, which writes them out in their original order.
+/// Outputs that would grow past `opts.split_size`, the FATX file size limit
+/// by default, are split into `.1`, `.2`, ... parts, each of which is a
+/// valid image on its own. Parts are written with a `.part` suffix that is
+/// dropped once they are all done.
+///
+/// With `opts.resume` set, parts left behind by an earlier, interrupted run
+/// are kept up to the last block known to have been written in full and
+/// the conversion carries on from there.
+///
+/// A CSO or ZSO at `fp` is decompressed as it's read, so an image can be
+/// compressed again with other settings without writing out the ISO.
+pub fn compress_iso(fp: &Path, dest: &Path, opts: &CompressOptions) -> Result<PathBuf, io::Error> {
+    compress_iso_with_progress(fp, dest, opts, &ProgressBar::new(0))
+}
+
+/// Same as `compress_iso`, but reports progress on `pb` instead of a
+/// progress bar of its own, so several conversions can share one display.
+/// Progress is counted in bytes of the source image, and the message shows
+/// how fast output is being written and the compression ratio so far.
+pub fn compress_iso_with_progress(
+    fp: &Path,
+    dest: &Path,
+    opts: &CompressOptions,
+    pb: &ProgressBar,
+) -> Result<PathBuf, io::Error> {
+    compress(open_uncompressed(fp)?, dest, opts, &mut BarProgress::new(pb), false).map(|(fp, _)| fp)
+}
+
+/// Same as `compress_iso`, but tells `sink` how it's going rather than
+/// drawing a progress bar, for programs that show progress their own way.
+pub fn compress_iso_with_sink(
+    fp: &Path,
+    dest: &Path,
+    opts: &CompressOptions,
+    sink: &mut dyn ProgressSink,
+) -> Result<PathBuf, io::Error> {
+    compress(open_uncompressed(fp)?, dest, opts, sink, false).map(|(fp, _)| fp)
+}
+
+/// Same as `compress_iso_with_progress`, but also hashes the whole source
+/// file as it's read, so it can be checked against a known good dump
+/// without reading it a second time. Parts of the file that don't need
+/// compressing (the video partition, blocks an earlier run already wrote)
+/// are read just for the hashes.
+pub fn compress_iso_hashed(
+    fp: &Path,
+    dest: &Path,
+    opts: &CompressOptions,
+    pb: &ProgressBar,
+) -> Result<(PathBuf, Hashes), io::Error> {
+    let sink = &mut BarProgress::new(pb);
+    compress(open_uncompressed(fp)?, dest, opts, sink, true).map(|(fp, hashes)| (fp, hashes.unwrap()))
+}
+
+/// Compresses the ISO read from `reader` into `<dest>.1.cso` just as
+/// `compress_iso` does with a file, for images that come from somewhere
+/// that can seek but isn't a file: inside an archive, over the network or
+/// in memory. A CSO or ZSO has to be opened as a `CsoReader` first.
+pub fn compress_reader<R: Read + Seek + Send>(
+    reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+) -> Result<PathBuf, io::Error> {
+    compress_reader_with_progress(reader, dest, opts, &ProgressBar::new(0))
+}
+
+/// Same as `compress_reader`, but reports progress on `pb`.
+pub fn compress_reader_with_progress<R: Read + Seek + Send>(
+    reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    pb: &ProgressBar,
+) -> Result<PathBuf, io::Error> {
+    compress(reader, dest, opts, &mut BarProgress::new(pb), false).map(|(fp, _)| fp)
+}
+
+/// Same as `compress_reader`, but tells `sink` how it's going.
+pub fn compress_reader_with_sink<R: Read + Seek + Send>(
+    reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    sink: &mut dyn ProgressSink,
+) -> Result<PathBuf, io::Error> {
+    compress(reader, dest, opts, sink, false).map(|(fp, _)| fp)
+}
+
+/// Same as `compress_reader_with_progress`, but also hashes the image.
+pub fn compress_reader_hashed<R: Read + Seek + Send>(
+    reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    pb: &ProgressBar,
+) -> Result<(PathBuf, Hashes), io::Error> {
+    compress(reader, dest, opts, &mut BarProgress::new(pb), true).map(|(fp, hashes)| (fp, hashes.unwrap()))
+}
+
+/// Compresses an ISO read from `reader`, which doesn't have to be seekable,
+/// into `<dest>.1.cso` as `compress_iso` does. This is how an image piped
+/// in from another tool is compressed without storing it first.
+///
+/// The image has to be a plain XDVDFS one, or anything at all with
+/// `opts.raw`, as a redump only shows what it is a few hundred MB in.
+/// Resuming, trimming and stripping the system update all need to see the
+/// whole image up front, so they can't be used here either.
+///
+/// As the size of the image is only known once it has all been read, each
+/// part is written without its index, and everything in it is moved along
+/// to make room for the index once it's done.
+pub fn compress_stream<R: Read + Send>(reader: R, dest: &Path, opts: &CompressOptions) -> Result<PathBuf, io::Error> {
+    compress_stream_with_progress(reader, dest, opts, &ProgressBar::new(0))
+}
+
+/// Same as `compress_stream`, but reports progress on `pb`. The bar is never
+/// given a length, as nobody knows how long the stream is.
+pub fn compress_stream_with_progress<R: Read + Send>(
+    reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    pb: &ProgressBar,
+) -> Result<PathBuf, io::Error> {
+    stream(reader, dest, opts, &mut BarProgress::new(pb), false).map(|(fp, _)| fp)
+}
+
+/// Same as `compress_stream`, but tells `sink` how it's going. Neither the
+/// size of the image nor how many blocks it has are known until the end.
+pub fn compress_stream_with_sink<R: Read + Send>(
+    reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    sink: &mut dyn ProgressSink,
+) -> Result<PathBuf, io::Error> {
+    stream(reader, dest, opts, sink, false).map(|(fp, _)| fp)
+}
+
+/// Same as `compress_stream_with_progress`, but also hashes the stream.
+pub fn compress_stream_hashed<R: Read + Send>(
+    reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    pb: &ProgressBar,
+) -> Result<(PathBuf, Hashes), io::Error> {
+    stream(reader, dest, opts, &mut BarProgress::new(pb), true).map(|(fp, hashes)| (fp, hashes.unwrap()))
+}
+
+/// Checksums the first `blocks` blocks of the image in `f`, which starts
+/// where `f` is, as `encode` would have, for the ones an earlier run wrote.
+fn checksum_blocks(
+    f: &mut dyn Read,
+    image: &CsoImage,
+    removal: Option<&Removal>,
+    skew: u64,
+    blocks: usize,
+) -> Result<Vec<u64>, io::Error> {
+    let mut checksums = Vec::with_capacity(blocks);
+    let mut buf = vec![0; image.block_size as usize];
+    for block in 0..blocks {
+        let data = &mut buf[..image.block_len(block)];
+        f.read_exact(data)?;
+        let offset = block as u64 * image.block_size as u64;
+        if let (Some(removal), Some(offset)) = (removal, offset.checked_sub(skew)) {
+            removal.apply(offset, data);
+        }
+        checksums.push(xxh64(data));
+    }
+    Ok(checksums)
+}
+
+/// Reads `len` bytes of `f` into `hasher`.
+fn hash_bytes(f: &mut dyn Read, len: u64, hasher: &mut Hasher) -> Result<(), io::Error> {
+    let mut buf = vec![0; 1 << 20];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(buf.len() as u64) as usize;
+        f.read_exact(&mut buf[..n])?;
+        hasher.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+/// Hashes all of `f`, for when none of it is left to compress.
+fn hash_file(f: &mut dyn ReadSeek) -> Result<Hashes, io::Error> {
+    let mut hasher = Hasher::new();
+    let len = f.seek(io::SeekFrom::End(0))?;
+    f.seek(io::SeekFrom::Start(0))?;
+    hash_bytes(f, len, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+/// Rejects options no conversion could go ahead with.
+pub(crate) fn check_options(opts: &CompressOptions) -> Result<(), Error> {
+    if !SUPPORTED_BLOCK_SIZES.contains(&opts.block_size) {
+        return Err(Error::new(io::ErrorKind::InvalidInput, format!("unsupported block size {}", opts.block_size)));
+    }
+    if opts.align > MAX_ALIGN {
+        return Err(Error::new(io::ErrorKind::InvalidInput, format!("unsupported alignment {}", opts.align)));
+    }
+    in_flight_blocks(opts, worker_count(opts)).map(|_| ())
+}
+
+/// The header version `opts` ask for, or otherwise the one that suits their
+/// format and method.
+pub(crate) fn header_version(opts: &CompressOptions) -> Result<u8, Error> {
+    let version = opts.version.unwrap_or(match (opts.format, opts.method) {
+        (Format::Cso, Method::Lz4) => 2,
+        _ => 1,
+    });
+    if !matches!((opts.format, version), (Format::Cso, 1 | 2) | (Format::Zso, 1)) {
+        let name = opts.format.extension().to_uppercase();
+        return Err(Error::new(io::ErrorKind::InvalidInput, format!("unsupported {} version {}", name, version)));
+    }
+    Ok(version)
+}
+
+fn worker_count(opts: &CompressOptions) -> usize {
+    opts.threads.unwrap_or_else(physical_cores).max(1)
+}
+
+/// Room for a block of `block_size` bytes either way, so buffers can go
+/// back and forth between raw and compressed data without growing.
+fn block_buffer_size(block_size: usize) -> usize {
+    lz4::bound(block_size).max(block_size)
+}
+
+/// How many blocks can be on their way from the reader to the writer at
+/// once. A memory limit gives them what's left of it after the buffers
+/// every conversion needs, for reading, writing and each of the `workers`.
+fn in_flight_blocks(opts: &CompressOptions, workers: usize) -> Result<usize, Error> {
+    let Some(limit) = opts.max_memory else {
+        return Ok(2 * QUEUE_DEPTH * workers);
+    };
+    let block = block_buffer_size(opts.block_size as usize);
+    let fixed = (READ_WINDOW + opts.write.buffer_size + workers * block) as u64;
+    match limit.saturating_sub(fixed) / block as u64 {
+        0 => Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "a memory limit of {} is too small, at least {} is needed",
+                BinaryBytes(limit),
+                BinaryBytes(fixed + block as u64),
+            ),
+        )),
+        n => Ok(usize::try_from(n).unwrap_or(usize::MAX)),
+    }
+}
+
+fn compress<R: Read + Seek + Send>(
+    mut iso_file: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    progress: &mut dyn ProgressSink,
+    hash: bool,
+) -> Result<(PathBuf, Option<Hashes>), io::Error> {
+    let hash = hash || opts.trailer;
+
+    let format = opts.format;
+    check_options(opts)?;
+
+    let detect = crate::span!(Level::Debug, "detect");
+    let file_len = iso_file.seek(io::SeekFrom::End(0))?;
+    let mut image_details = match opts.raw {
+        true => get_raw_cso_info(&mut iso_file, format, opts.block_size)?,
+        false => get_cso_info(&mut iso_file, format, opts.block_size)?,
+    };
+    image_details.align = opts.align;
+    let partition_offset = file_len - image_details.total_bytes;
+    // Where the compressed data starts, which is ahead of the game partition
+    // by `skew` bytes when the video partition is kept
+    let image_offset = if opts.keep_video_partition { 0 } else { partition_offset };
+    let skew = partition_offset - image_offset;
+    if skew > 0 {
+        crate::debug!("keeping the {} byte video partition", skew);
+        image_details.total_bytes = file_len;
+        image_details.total_blocks = CsoImage::block_count(file_len, opts.block_size);
+        iso_file.seek(io::SeekFrom::Start(0))?;
+    }
+
+    let mut removal = None;
+    if opts.trim || opts.strip_system_update {
+        let mut volume = Volume::open(&mut iso_file, partition_offset)?;
+        if opts.strip_system_update {
+            removal = Removal::plan(&mut volume, SYSTEM_UPDATE_DIR)?;
+            if removal.is_none() {
+                crate::debug!("image has no {}", SYSTEM_UPDATE_DIR);
+            }
+        }
+        if opts.trim {
+            let used = skew + match removal {
+                Some(ref removal) => removal.used_bytes,
+                None => volume.used_bytes()?,
+            };
+            if used < image_details.total_bytes {
+                crate::debug!("trimming {} bytes of padding", image_details.total_bytes - used);
+                image_details.total_bytes = used;
+                image_details.total_blocks = CsoImage::block_count(used, opts.block_size);
+            }
+        }
+        iso_file.seek(io::SeekFrom::Start(image_offset))?;
+    }
+    crate::debug!("{} bytes of image data in {} blocks", image_details.total_bytes, image_details.total_blocks);
+    drop(detect);
+
+    let name = format.extension().to_uppercase();
+    let version = header_version(opts)?;
+    image_details.version = version;
+    if !image_details.supports(opts.method) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} version {} images cannot hold {} blocks", name, version, opts.method.name()),
+        ));
+    }
+    let total_blocks = image_details.total_blocks;
+
+    // Every position in a part has to fit in the index, so check the worst
+    // case of a part holding nothing but padded, uncompressed blocks
+    let align_pad = (1u64 << opts.align) - 1;
+    let index_bytes = (total_blocks as u64 + 1) * 4;
+    let mut data_bytes = image_details.total_bytes + total_blocks as u64 * align_pad;
+    if let Some(split_size) = opts.split_size {
+        data_bytes = data_bytes.min(split_size + opts.block_size as u64 + align_pad);
+    }
+    let worst_case = CISO_HEADER_SIZE as u64 + index_bytes + data_bytes;
+    if worst_case >= max_addressable(opts.align) {
+        return Err(XcsoError::SplitLimit { align: opts.align }.into());
+    }
+
+    let naming = opts.split_naming;
+    let dest_fp = naming.part_path(dest, 1, format);
+    let _span = crate::span!(Level::Debug, "compress", "dest={}", dest_fp.display());
+    crate::debug!(
+        "writing {} v{} with {} level {}, {} byte blocks, alignment {}",
+        name,
+        version,
+        opts.method.name(),
+        opts.level,
+        opts.block_size,
+        opts.align,
+    );
+
+    // A run that got as far as renaming its outputs has nothing left to do
+    if opts.resume && dest_fp.exists() && !temp_path(&dest_fp).exists() {
+        crate::info!("{} is already complete", dest_fp.display());
+        let hashes = if hash { Some(hash_file(&mut iso_file)?) } else { None };
+        if let (true, Some(hashes)) = (opts.trailer, &hashes) {
+            let part_count = (1..).take_while(|&n| naming.part_path(dest, n, format).exists()).count();
+            write_trailers(dest, part_count, format, naming, hashes)?;
+        }
+        if opts.seek_hints {
+            write_seek_hints(&dest_fp)?;
+        }
+        return Ok((dest_fp, hashes));
+    }
+
+    if opts.write.check_space {
+        // Every part has a header and an index of its own on top of the
+        // worst case for one part, and is padded out to a KiB
+        let parts = opts.split_size.map_or(1, |x| image_details.total_bytes / x + 1);
+        let needed = CISO_HEADER_SIZE as u64 * parts + index_bytes + parts * (4 + 0x400) +
+            image_details.total_bytes + total_blocks as u64 * align_pad;
+        check_space(dest, needed, naming, format)?;
+    }
+
+    let (part, part_count, start) = if opts.resume {
+        resume_parts(dest, image_details, naming, opts.write)?
+    } else {
+        (Some(create_part(dest, 1, naming, image_details, 0, Some(total_blocks), opts.write)?), 1, 0)
+    };
+    let part = match part {
+        Some(part) => part,
+        None => {
+            rename_parts(dest, part_count, format, naming)?;
+            if opts.checksums {
+                iso_file.seek(io::SeekFrom::Start(image_offset))?;
+                let checksums = checksum_blocks(&mut iso_file, &image_details, removal.as_ref(), skew, total_blocks)?;
+                write_checksums(&checksum_path(&dest_fp), &image_details, &checksums)?;
+            }
+            let hashes = if hash { Some(hash_file(&mut iso_file)?) } else { None };
+            if let (true, Some(hashes)) = (opts.trailer, &hashes) {
+                write_trailers(dest, part_count, format, naming, hashes)?;
+            }
+            if opts.seek_hints {
+                write_seek_hints(&dest_fp)?;
+            }
+            return Ok((dest_fp, hashes));
+        },
+    };
+
+    // The blocks an earlier run wrote are read again for their checksums
+    let checksums = match opts.checksums {
+        true => {
+            iso_file.seek(io::SeekFrom::Start(image_offset))?;
+            Some(checksum_blocks(&mut iso_file, &image_details, removal.as_ref(), skew, start)?)
+        },
+        false => None,
+    };
+
+    // Everything before the first block still to compress is only read when
+    // it has to be hashed
+    let mut hasher = hash.then(Hasher::new);
+    match hasher {
+        Some(ref mut hasher) => {
+            let skipped = image_offset + start as u64 * opts.block_size as u64;
+            iso_file.seek(io::SeekFrom::Start(0))?;
+            hash_bytes(&mut iso_file, skipped, hasher)?;
+        },
+        None => _ = iso_file.seek(io::SeekFrom::Start(image_offset + start as u64 * opts.block_size as u64))?,
+    }
+
+    let source = Source {
+        reader: &mut iso_file,
+        image: image_details,
+        removal: removal.as_ref(),
+        skew,
+        start,
+        // and so is anything trimmed off the end
+        trailing: file_len - image_offset - image_details.total_bytes,
+        streamed: false,
+        checksums,
+    };
+    let hashes = encode(source, part, part_count, dest, opts, progress, hasher)?;
+    Ok((dest_fp, hashes))
+}
+
+/// What reading the image came to, once every block was compressed.
+struct SourceRead {
+    total: u64,
+    hashes: Option<Hashes>,
+    checksums: Option<Vec<u64>>,
+}
+
+/// What's being compressed and how far along it is.
+struct Source<'a> {
+    /// Positioned at block `start`.
+    reader: &'a mut (dyn Read + Send),
+    image: CsoImage,
+    removal: Option<&'a Removal>,
+    /// How far the game partition is into the image.
+    skew: u64,
+    start: usize,
+    /// Bytes after the last block, read only to be hashed.
+    trailing: u64,
+    /// Whether the image is read as a stream, without knowing how long it
+    /// is, so it goes on until the reader runs dry.
+    streamed: bool,
+    /// Checksums of the blocks before `start`, when they're being written.
+    checksums: Option<Vec<u64>>,
+}
+
+/// Compresses `source` into `part` and the parts after it, then renames
+/// them all into place. Returns the hashes of the source when given a
+/// `hasher`, which has already seen anything before the first block.
+fn encode(
+    source: Source,
+    mut part: PartWriter,
+    mut part_count: usize,
+    dest: &Path,
+    opts: &CompressOptions,
+    progress: &mut dyn ProgressSink,
+    hasher: Option<Hasher>,
+) -> Result<Option<Hashes>, io::Error> {
+    let Source { reader, image: image_details, removal, skew, start, trailing, streamed, checksums } = source;
+    // Reads are held to the rate limit here, and writes as each block is
+    // written, each on its own
+    let mut throttled;
+    let reader: &mut (dyn Read + Send) = match opts.limit_rate {
+        Some(rate) => {
+            throttled = Throttled::new(reader, rate);
+            &mut throttled
+        },
+        None => reader,
+    };
+    let mut write_limit = opts.limit_rate.map(RateLimit::new);
+    let (format, naming) = (opts.format, opts.split_naming);
+    let total_blocks = image_details.total_blocks;
+    let alignment_buffer: Vec<u8> = vec![0; 1 << opts.align];
+
+    // The most a part starting at block `first` could take up, with every
+    // block stored uncompressed
+    let worst_case = |first: usize| {
+        let blocks = (total_blocks - first) as u64;
+        let bytes = image_details.total_bytes - first as u64 * opts.block_size as u64;
+        let align_pad = (1u64 << opts.align) - 1;
+        let len = CISO_HEADER_SIZE as u64 + (blocks + 1) * 4 + bytes + blocks * align_pad;
+        opts.split_size.map_or(len, |x| len.min(x))
+    };
+    // A stream has nothing to go by
+    let preallocate = opts.write.preallocate && !streamed;
+    if preallocate && part.index.is_empty() {
+        part.preallocate(worst_case(part.first_block));
+    }
+
+    let mut status = Progress {
+        phase: Phase::Compressing,
+        blocks_done: start,
+        total_blocks: (!streamed).then_some(total_blocks),
+        bytes_in: start as u64 * opts.block_size as u64,
+        total_bytes: (!streamed).then_some(image_details.total_bytes),
+        bytes_out: 0,
+        parts: part_count,
+    };
+    progress.update(&status);
+    let workers = worker_count(opts);
+    let in_flight = in_flight_blocks(opts, workers)?;
+    let batch_blocks = BATCH_BLOCKS.min(in_flight);
+    let (store, batched) = (opts.store, opts.batched);
+    let keep_on_cancel = opts.on_cancel == CancelPolicy::Keep && !streamed;
+    // Parts leave room for their trailer
+    let split_size = opts.split_size.map(|x| x.saturating_sub(if opts.trailer { TRAILER_SIZE } else { 0 }));
+
+    // Images are full of zero-filled blocks (padding, and the whole video
+    // partition of a redump), so compress one up front and reuse it
+    let block_size = opts.block_size as usize;
+    let mut zero_block = Block { seq: 0, data: vec![0; block_size], method: None };
+    compress_worker(&mut zero_block, &mut *codec(opts.method, opts.level), &mut Vec::new(), &image_details)?;
+    let zero_block = &zero_block;
+    let buffer_size = block_buffer_size(block_size);
+
+    let result = thread::scope(|s| -> Result<SourceRead, io::Error> {
+        let (raw_tx, raw_rx) = mpsc::sync_channel::<Block>(QUEUE_DEPTH * workers);
+        let (done_tx, done_rx) = mpsc::sync_channel::<Result<Block, io::Error>>(QUEUE_DEPTH * workers);
+        let raw_rx = Arc::new(Mutex::new(raw_rx));
+        // Buffers of blocks that have been written, for the reader to fill again
+        let (free_tx, free_rx) = mpsc::channel::<Vec<u8>>();
+
+        let reader_tx = done_tx.clone();
+        let reader = s.spawn(move || {
+            let (mut hasher, mut checksums) = (hasher, checksums);
+            let mut total = 0;
+            let mut chunk = vec![0; READ_WINDOW - READ_WINDOW % block_size];
+            let mut seq = start;
+            let mut allocated = 0;
+            while seq < total_blocks {
+                // A chunk of whole blocks, stopping short of anything trimmed
+                let want = match streamed {
+                    true => chunk.len(),
+                    false => {
+                        let left = image_details.total_bytes - seq as u64 * block_size as u64;
+                        left.min(chunk.len() as u64) as usize
+                    },
+                };
+                let read = match read_full(reader, &mut chunk[..want]) {
+                    Ok(read) if read < want && !streamed => {
+                        Err(Error::new(io::ErrorKind::UnexpectedEof, "the image is shorter than it was"))
+                    },
+                    x => x,
+                };
+                let read = match read {
+                    Ok(read) => read,
+                    Err(e) => {
+                        _ = reader_tx.send(Err(e));
+                        return None;
+                    },
+                };
+                total += read as u64;
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&chunk[..read]);
+                }
+
+                for data in chunk[..read].chunks(block_size) {
+                    // Only so many blocks are let out at once, after which
+                    // the reader waits for one to be written
+                    let mut blockbuf = match free_rx.try_recv() {
+                        Ok(x) => x,
+                        Err(_) if allocated < in_flight => {
+                            allocated += 1;
+                            Vec::with_capacity(buffer_size)
+                        },
+                        Err(_) => match free_rx.recv() {
+                            Ok(x) => x,
+                            Err(_) => return None,
+                        },
+                    };
+                    blockbuf.clear();
+                    blockbuf.extend_from_slice(data);
+                    // The game partition starts on a block of its own, even
+                    // with the video partition ahead of it
+                    let offset = seq as u64 * image_details.block_size as u64;
+                    if let (Some(removal), Some(offset)) = (removal, offset.checked_sub(skew)) {
+                        removal.apply(offset, &mut blockbuf);
+                    }
+                    if let Some(checksums) = &mut checksums {
+                        checksums.push(xxh64(&blockbuf));
+                    }
+
+                    let block = Block { seq, data: blockbuf, method: None };
+                    if raw_tx.send(block).is_err() {
+                        return None;
+                    }
+                    seq += 1;
+                }
+                // A stream ends with the first chunk that comes up short
+                if read < want {
+                    break;
+                }
+            }
+            if let Some(hasher) = &mut hasher {
+                if let Err(e) = hash_bytes(reader, trailing, hasher) {
+                    _ = reader_tx.send(Err(e));
+                    return None;
+                }
+            }
+            Some(SourceRead { total, hashes: hasher.map(|x| x.finish()), checksums })
+        });
+
+        // Batches are compressed on threads of their own instead
+        let (pipelined, batch_workers) = match batched {
+            true => (0, workers),
+            false => (workers, 0),
+        };
+        let mut batch_workers: Vec<(Box<dyn BlockCodec>, Vec<u8>)> = (0..batch_workers).
+            map(|_| (codec(opts.method, opts.level), Vec::with_capacity(buffer_size))).
+            collect();
+        for _ in 0..pipelined {
+            let raw_rx = Arc::clone(&raw_rx);
+            let done_tx = done_tx.clone();
+            let mut codec = codec(opts.method, opts.level);
+            s.spawn(move || {
+                let mut scratch = Vec::with_capacity(buffer_size);
+                loop {
+                    let mut block = match raw_rx.lock().unwrap().recv() {
+                        Ok(block) => block,
+                        Err(_) => return,
+                    };
+                    let compressed =
+                        encode_block(&mut block, &mut *codec, &mut scratch, zero_block, store, &image_details);
+                    if done_tx.send(compressed.map(|_| block)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(done_tx);
+
+        // Workers finish out of order, so hold blocks back until it's their turn
+        let mut pending: BTreeMap<usize, Block> = BTreeMap::new();
+        let mut next = start;
+
+        while next < total_blocks {
+            if opts.cancel.is_cancelled() {
+                if keep_on_cancel {
+                    part.settle()?;
+                    crate::info!("cancelled after {} blocks, keeping the parts", next);
+                }
+                return Err(XcsoError::Interrupted.into());
+            }
+            if paused() {
+                part.settle()?;
+                crate::info!("paused after {} blocks", next);
+                status.phase = Phase::Paused;
+                progress.update(&status);
+                while paused() && !opts.cancel.is_cancelled() {
+                    thread::sleep(PAUSE_POLL);
+                }
+                status.phase = Phase::Compressing;
+                continue;
+            }
+
+            let block = match pending.remove(&next) {
+                Some(block) => block,
+                None if batched => {
+                    let mut batch: Vec<Block> = raw_rx.lock().unwrap().iter().take(batch_blocks).collect();
+                    if batch.is_empty() {
+                        // A stream ends wherever the reader ran out
+                        if streamed {
+                            break;
+                        }
+                        return Err(done_rx.try_iter().find_map(Result::err).unwrap_or_else(|| {
+                            io::Error::new(io::ErrorKind::UnexpectedEof, "compression pipeline stopped early")
+                        }));
+                    }
+                    encode_batch(&mut batch, &mut batch_workers, zero_block, store, &image_details)?;
+                    pending.extend(batch.into_iter().map(|x| (x.seq, x)));
+                    continue;
+                },
+                None => {
+                    let block = match done_rx.recv() {
+                        Ok(block) => block?,
+                        // A stream ends wherever the reader ran out
+                        Err(_) if streamed && pending.is_empty() => break,
+                        Err(_) => return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "compression pipeline stopped early",
+                        )),
+                    };
+                    if block.seq != next {
+                        pending.insert(block.seq, block);
+                        continue;
+                    }
+                    block
+                },
+            };
+
+            // Start the next part rather than let this block take the
+            // current one past the split size (FATX can't hold more)
+            if split_size.is_some_and(|x| !part.index.is_empty() && part.size_with(&block) > x) {
+                crate::info!("part {} is full at {} bytes, starting the next one", part_count, part.write_pos);
+                part.finish(image_details.total_bytes, false)?;
+                part_count += 1;
+                let blocks = (!streamed).then(|| total_blocks - next);
+                part = create_part(dest, part_count, naming, image_details, next, blocks, opts.write)?;
+                if preallocate {
+                    part.preallocate(worst_case(next));
+                }
+            }
+            // The size of a stream is only known as it's written, so this is
+            // where it turns out to be too big
+            if streamed && part.size_with(&block) >= max_addressable(opts.align) {
+                return Err(XcsoError::SplitLimit { align: opts.align }.into());
+            }
+
+            part.write_block(&block, &alignment_buffer)?;
+            status.bytes_out += block.data.len() as u64;
+            if let Some(limit) = &mut write_limit {
+                limit.take(block.data.len() as u64);
+            }
+            // The reader may be gone already, with nothing left to read
+            _ = free_tx.send(block.data);
+            if (next + 1 - part.first_block).is_multiple_of(CHECKPOINT_INTERVAL) {
+                part.checkpoint()?;
+            }
+
+            let len = match streamed {
+                true => block_size as u64,
+                false => image_details.block_len(next) as u64,
+            };
+            next += 1;
+            status.blocks_done = next;
+            status.bytes_in += len;
+            status.parts = part_count;
+            progress.update(&status);
+        }
+
+        // The reader can still fail on what's left to hash after the last block
+        reader.join().unwrap().ok_or_else(|| match done_rx.try_iter().find_map(Result::err) {
+            Some(e) => e,
+            None => io::Error::new(io::ErrorKind::UnexpectedEof, "compression pipeline stopped early"),
+        })
+    });
+
+    let SourceRead { total, hashes, checksums } = match result {
+        Ok(result) => result,
+        Err(e) => {
+            crate::error!("conversion failed after {} parts: {}", part_count, e);
+            if e.kind() == io::ErrorKind::Interrupted && !keep_on_cancel {
+                drop(part);
+                for n in 1..=part_count {
+                    let fp = temp_path(&naming.part_path(dest, n, format));
+                    _ = fs::remove_file(journal_path(&fp));
+                    _ = fs::remove_file(fp);
+                }
+            }
+            return Err(e);
+        },
+    };
+
+    let source_bytes = match streamed {
+        true => total,
+        false => image_details.total_bytes,
+    };
+    status.phase = Phase::Finishing;
+    progress.update(&status);
+    part.finish(source_bytes, true)?;
+    drop(part);
+
+    rename_parts(dest, part_count, format, naming)?;
+    let checksum_fp = checksum_path(&naming.part_path(dest, 1, format));
+    match checksums {
+        Some(checksums) => {
+            let image = CsoImage {
+                total_bytes: source_bytes,
+                total_blocks: CsoImage::block_count(source_bytes, opts.block_size),
+                ..image_details
+            };
+            write_checksums(&checksum_fp, &image, &checksums)?;
+        },
+        None => remove_checksums(&checksum_fp)?,
+    }
+    if let (true, Some(hashes)) = (opts.trailer, &hashes) {
+        write_trailers(dest, part_count, format, naming, hashes)?;
+    }
+    let first_part = naming.part_path(dest, 1, format);
+    match opts.seek_hints {
+        true => _ = write_seek_hints(&first_part)?,
+        false => remove_seek_hints(&first_part)?,
+    }
+    status.phase = Phase::Done;
+    progress.update(&status);
+
+    Ok(hashes)
+}
+
+fn stream<R: Read + Send>(
+    mut reader: R,
+    dest: &Path,
+    opts: &CompressOptions,
+    progress: &mut dyn ProgressSink,
+    hash: bool,
+) -> Result<(PathBuf, Option<Hashes>), io::Error> {
+    let format = opts.format;
+    check_options(opts)?;
+    let hash = hash || opts.trailer;
+    let unsupported = [(opts.resume, "resumed"), (opts.trim, "trimmed"), (opts.strip_system_update, "stripped")];
+    if let Some((_, what)) = unsupported.iter().find(|(x, _)| *x) {
+        return Err(Error::new(io::ErrorKind::InvalidInput, format!("images read from a stream can't be {}", what)));
+    }
+
+    // Just enough of the image to tell what it is, put back in front of
+    // the rest of it
+    let detect = crate::span!(Level::Debug, "detect");
+    let mut head = vec![0; XBOX_MEDIA_HEADER_XDVDFS_OFFSET as usize + XBOX_MEDIA_HEADER.len()];
+    let read = read_full(&mut reader, &mut head)?;
+    head.truncate(read);
+    if head.len() >= 4 && Format::from_magic(u32::from_le_bytes(head[..4].try_into().unwrap())).is_some() {
+        return Err(Error::new(io::ErrorKind::InvalidInput, "compressed images can't be read from a stream"));
+    }
+    if !opts.raw && !head.ends_with(XBOX_MEDIA_HEADER) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            "no Xbox file system at the start of the stream (a redump image has to be compressed from a file)",
+        ));
+    }
+    drop(detect);
+    let mut reader = io::Cursor::new(head).chain(reader);
+
+    let name = format.extension().to_uppercase();
+    let version = header_version(opts)?;
+    // The size isn't known until the end, and nothing is read past it
+    let image = CsoImage {
+        format,
+        version,
+        align: opts.align,
+        block_size: opts.block_size,
+        total_bytes: u64::MAX,
+        total_blocks: usize::MAX,
+    };
+    if !image.supports(opts.method) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} version {} images cannot hold {} blocks", name, version, opts.method.name()),
+        ));
+    }
+
+    let naming = opts.split_naming;
+    let dest_fp = naming.part_path(dest, 1, format);
+    let _span = crate::span!(Level::Debug, "stream", "dest={}", dest_fp.display());
+    let part = create_part(dest, 1, naming, image, 0, None, opts.write)?;
+    let source = Source {
+        reader: &mut reader,
+        image,
+        removal: None,
+        skew: 0,
+        start: 0,
+        trailing: 0,
+        streamed: true,
+        checksums: opts.checksums.then(Vec::new),
+    };
+    let hashes = encode(source, part, 1, dest, opts, progress, hash.then(Hasher::new))?;
+    Ok((dest_fp, hashes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::codec::decode_image;
+    use crate::decompress::{entry_pos, CsoReader};
+    use crate::testing::{image, Scratch};
+
+    fn fast() -> CompressOptions {
+        CompressOptions { level: LEVEL_FAST, raw: true, ..CompressOptions::default() }
+    }
+
+    /// Compresses `data` to `dest` with `opts` and returns the first part.
+    fn compressed(data: &[u8], dest: &Path, opts: &CompressOptions) -> Vec<u8> {
+        fs::read(compress_reader(Cursor::new(data), dest, opts).unwrap()).unwrap()
+    }
+
+    /// Compresses `data` to `dest`, cancelled once `stop` blocks have been
+    /// written, and returns where the partial first part was left.
+    fn cut_off(data: &[u8], dest: &Path, opts: &CompressOptions, stop: usize) -> PathBuf {
+        let opts = CompressOptions { cancel: CancelToken::new(), on_cancel: CancelPolicy::Keep, ..opts.clone() };
+        let cancel = opts.cancel.clone();
+        let mut sink = |x: &Progress| if x.blocks_done >= stop { cancel.cancel() };
+        let e = compress_reader_with_sink(Cursor::new(data), dest, &opts, &mut sink).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        temp_path(&part_path(dest, 1, opts.format))
+    }
+
+    /// Resumes the conversion of `data` to `dest`, returning how many blocks
+    /// it found done and the first part it finished with.
+    fn resumed(data: &[u8], dest: &Path, opts: &CompressOptions) -> (usize, Vec<u8>) {
+        let opts = CompressOptions { resume: true, ..opts.clone() };
+        let mut done = None;
+        let mut sink = |x: &Progress| _ = done.get_or_insert(x.blocks_done);
+        let fp = compress_reader_with_sink(Cursor::new(data), dest, &opts, &mut sink).unwrap();
+        assert!(!temp_path(&fp).exists() && !journal_path(&temp_path(&fp)).exists());
+        (done.unwrap(), fs::read(fp).unwrap())
+    }
+
+    /// Leaves the index of the partial part at `fp` as its checkpoint after
+    /// `blocks` blocks had it, with the blocks written after it still there.
+    fn rewind_index(fp: &Path, blocks: usize) {
+        let mut part = fs::read(fp).unwrap();
+        let start = CISO_HEADER_SIZE as usize + (blocks + 1) * 4;
+        let end = CISO_HEADER_SIZE as usize + (read_cso_info(&mut Cursor::new(&part)).unwrap().total_blocks + 1) * 4;
+        part[start..end].fill(0);
+        fs::write(fp, part).unwrap();
+    }
+
+    #[test]
+    fn resumes_with_a_journal() {
+        let dir = Scratch::new("resume");
+        let data = image(CHECKPOINT_INTERVAL + 1000);
+        let whole = compressed(&data, &dir.join("whole"), &fast());
+
+        // Cut off half way through writing a block after the last checkpoint
+        let dest = dir.join("cut");
+        let fp = cut_off(&data, &dest, &fast(), CHECKPOINT_INTERVAL + 500);
+        File::options().append(true).open(&fp).unwrap().write_all(&[0x5a; 1000]).unwrap();
+        assert!(journal_path(&fp).exists());
+        assert_eq!(resumed(&data, &dest, &fast()), (CHECKPOINT_INTERVAL + 500, whole));
+    }
+
+    #[test]
+    fn resumes_from_the_index_without_a_journal() {
+        let dir = Scratch::new("resume-index");
+        let data = image(CHECKPOINT_INTERVAL + 1000);
+        let whole = compressed(&data, &dir.join("whole"), &fast());
+
+        let dest = dir.join("cut");
+        let fp = cut_off(&data, &dest, &fast(), CHECKPOINT_INTERVAL + 500);
+        rewind_index(&fp, CHECKPOINT_INTERVAL);
+        fs::remove_file(journal_path(&fp)).unwrap();
+        assert_eq!(resumed(&data, &dest, &fast()), (CHECKPOINT_INTERVAL, whole));
+    }
+
+    #[test]
+    fn resumes_from_the_journal_rather_than_a_stale_index() {
+        let dir = Scratch::new("resume-journal");
+        let data = image(CHECKPOINT_INTERVAL + 1000);
+        let whole = compressed(&data, &dir.join("whole"), &fast());
+
+        // The index is behind, as it was at the checkpoint before
+        let dest = dir.join("behind");
+        let fp = cut_off(&data, &dest, &fast(), CHECKPOINT_INTERVAL + 500);
+        rewind_index(&fp, CHECKPOINT_INTERVAL);
+        assert_eq!(resumed(&data, &dest, &fast()), (CHECKPOINT_INTERVAL + 500, whole.clone()));
+
+        // The index is ahead, pointing at blocks that never reached the disk
+        let dest = dir.join("ahead");
+        let fp = cut_off(&data, &dest, &fast(), CHECKPOINT_INTERVAL + 500);
+        let mut part = fs::read(&fp).unwrap();
+        let at = CISO_HEADER_SIZE as usize + CHECKPOINT_INTERVAL * 4;
+        let entry = u32::from_le_bytes(part[at..at + 4].try_into().unwrap());
+        part[((entry & !INDEX_FLAG) as usize) << fast().align..].fill(0);
+        fs::write(&fp, part).unwrap();
+        assert_eq!(resumed(&data, &dest, &fast()), (CHECKPOINT_INTERVAL, whole));
+    }
+
+    #[test]
+    fn resumes_a_split_image_after_its_first_part() {
+        let dir = Scratch::new("resume-split");
+        let data = image(CHECKPOINT_INTERVAL + 1000);
+        let opts = CompressOptions { split_size: Some(4 << 20), ..fast() };
+        let whole = compressed(&data, &dir.join("whole"), &opts);
+        let second = fs::read(part_path(&dir.join("whole"), 2, Format::Cso)).unwrap();
+
+        let dest = dir.join("cut");
+        cut_off(&data, &dest, &opts, CHECKPOINT_INTERVAL + 500);
+        assert!(temp_path(&part_path(&dest, 2, Format::Cso)).exists());
+        assert_eq!(resumed(&data, &dest, &opts).1, whole);
+        assert_eq!(fs::read(part_path(&dest, 2, Format::Cso)).unwrap(), second);
+    }
+
+    /// The index of the part `part`, with its final entry.
+    fn part_index(part: &[u8]) -> Vec<u32> {
+        let blocks = read_cso_info(&mut Cursor::new(part)).unwrap().total_blocks;
+        let index = &part[CISO_HEADER_SIZE as usize..CISO_HEADER_SIZE as usize + (blocks + 1) * 4];
+        index.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn parts_are_cut_between_blocks() {
+        let dir = Scratch::new("split");
+        let data = image(3000);
+        // The library takes split sizes far below what the command line does
+        let split_size = 1 << 20;
+        for (align, trailer) in [(0, false), (4, false), (0, true)] {
+            let opts = CompressOptions { align, trailer, split_size: Some(split_size), ..fast() };
+            let dest = dir.join(format!("game-{}-{}", align, trailer));
+            compress_reader(Cursor::new(&data), &dest, &opts).unwrap();
+            let parts: Vec<Vec<u8>> = (1..).
+                map(|n| part_path(&dest, n, Format::Cso)).
+                take_while(|x| x.exists()).
+                map(|x| fs::read(x).unwrap()).
+                collect();
+            assert!(parts.len() > 2);
+
+            let mut decoded = Vec::new();
+            for (n, part) in parts.iter().enumerate() {
+                assert!(part.len() as u64 <= split_size, "part {} is {} bytes", n + 1, part.len());
+                let index = part_index(part);
+                assert!(entry_pos(index[0], align) >= CISO_HEADER_SIZE as u64 + index.len() as u64 * 4);
+                // Every block is whole in its part, which reads as an image
+                // of its own
+                decoded.extend(decode_image(&mut Cursor::new(part)).unwrap());
+
+                // The first block of the next part is the one that didn't fit
+                let (Some(next), 0) = (parts.get(n + 1), align) else {
+                    continue;
+                };
+                let next = part_index(next);
+                let end = entry_pos(*index.last().unwrap(), align);
+                let first = entry_pos(next[1], align) - entry_pos(next[0], align);
+                let trailer = if trailer { TRAILER_SIZE } else { 0 };
+                assert!(((end + first) & !0x3ff) + 0x400 > split_size - trailer, "part {} ends early", n + 1);
+            }
+            assert_eq!(decoded, data);
+        }
+    }
+
+    /// Hands out what it reads a few bytes at a time, like a pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streams_come_out_like_files() {
+        let dir = Scratch::new("stream");
+        let data = image(3000);
+        let iso = dir.join("game.iso");
+        fs::write(&iso, &data).unwrap();
+        let split = CompressOptions { split_size: Some(1 << 20), ..fast() };
+        let zso = CompressOptions { format: Format::Zso, ..fast() };
+        let deflate = CompressOptions { method: Method::Deflate, align: 2, ..fast() };
+        for (n, opts) in [fast(), split, zso, deflate].iter().enumerate() {
+            let dest = |kind: &str| dir.join(format!("{}{}", kind, n));
+            compress_iso(&iso, &dest("file"), opts).unwrap();
+            compress_stream(&data[..], &dest("stream"), opts).unwrap();
+            compress_stream(Trickle(&data), &dest("trickle"), opts).unwrap();
+            let parts = |kind: &str| -> Vec<Vec<u8>> {
+                (1..).
+                    map(|part| part_path(&dest(kind), part, opts.format)).
+                    take_while(|x| x.exists()).
+                    map(|x| fs::read(x).unwrap()).
+                    collect()
+            };
+            let want = parts("file");
+            assert_eq!(want.len() > 1, n == 1);
+            match want.len() {
+                1 => {
+                    assert!(parts("stream") == want, "options {}", n);
+                    assert!(parts("trickle") == want, "options {}", n);
+                },
+                // A file's parts are written with room for the index of all
+                // the blocks left, so may be cut a little sooner than a
+                // stream's, which only has to fit the index of its own
+                _ => {
+                    assert!(parts("stream").iter().all(|x| x.len() <= 1 << 20));
+                    assert!(parts("trickle") == parts("stream"));
+                    let mut reader = CsoReader::open(&part_path(&dest("stream"), 1, opts.format)).unwrap();
+                    let mut decoded = Vec::new();
+                    reader.read_to_end(&mut decoded).unwrap();
+                    assert!(decoded == data);
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn part_names() {
+        let dest = Path::new("games/halo");
+        let names = |naming: SplitNaming, format| -> Vec<PathBuf> {
+            (1..=3).map(|n| naming.part_path(dest, n, format)).collect()
+        };
+        assert_eq!(
+            names(SplitNaming::Numbered, Format::Cso),
+            ["games/halo.1.cso", "games/halo.2.cso", "games/halo.3.cso"].map(PathBuf::from),
+        );
+        assert_eq!(
+            names(SplitNaming::Suffixed, Format::Cso),
+            ["games/halo.cso", "games/halo.cso2", "games/halo.cso3"].map(PathBuf::from),
+        );
+        assert_eq!(
+            names(SplitNaming::Folders, Format::Zso),
+            ["games/Part1/halo.zso", "games/Part2/halo.zso", "games/Part3/halo.zso"].map(PathBuf::from),
+        );
+        assert_eq!(part_path(dest, 2, Format::Zso), PathBuf::from("games/halo.2.zso"));
+    }
+
+    #[test]
+    fn parts_are_written_beside_the_output() {
+        let part = part_path(Path::new("games/halo"), 1, Format::Cso);
+        assert_eq!(temp_path(&part), PathBuf::from("games/halo.1.cso.part"));
+    }
+}
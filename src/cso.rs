@@ -0,0 +1,366 @@
+use std::io::{self, Error, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::XcsoError;
+
+pub const CISO_MAGIC: u32 = 0x4F534943; // CISO
+pub const ZISO_MAGIC: u32 = 0x4F53495A; // ZISO
+pub const CISO_HEADER_SIZE: u32 = 0x18; // 24
+pub const CISO_BLOCK_SIZE: usize = 0x800; // 2048
+
+/// Block sizes that can be chosen when creating an image.
+pub const SUPPORTED_BLOCK_SIZES: [u32; 4] = [0x800, 0x1000, 0x2000, 0x4000];
+// Largest block size accepted when reading images made by other tools
+const MAX_BLOCK_SIZE: u32 = 0x100000;
+
+pub const XBOX_MEDIA_HEADER: &[u8; 20] = b"MICROSOFT*XBOX*MEDIA";
+pub const XBOX_MEDIA_HEADER_REDUMP_OFFSET: u64 = 0x18310000;
+pub const XBOX_MEDIA_HEADER_XDVDFS_OFFSET: u64 = 0x10000;
+pub const REDUMP_GAME_PARTITION_OFFSET: u32 = 0x18300000;
+
+pub const INDEX_FLAG: u32 = 0x80000000;
+
+/// Largest alignment that can be chosen when creating an image.
+pub const MAX_ALIGN: u8 = 10;
+// Largest alignment whose positions still fit in 64 bits
+const MAX_READ_ALIGN: u8 = 32;
+
+/// Number of bytes a single image file can address with index positions
+/// shifted by `align`. Index entries only have 31 bits for the position, so
+/// every step up in alignment doubles the reach at the cost of padding each
+/// block out to a multiple of `1 << align` bytes.
+pub fn max_addressable(align: u8) -> u64 {
+    (INDEX_FLAG as u64) << align
+}
+
+/// Container formats that share the CSO header and block index layout.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Version 2 mixes LZ4 and deflate blocks, with the index flag marking
+    /// LZ4 ones, and stores blocks that don't shrink below the block size as
+    /// is. Version 1 only has deflate, with the flag marking uncompressed
+    /// blocks.
+    Cso,
+    /// Raw LZ4 blocks, with the index flag marking uncompressed blocks instead.
+    Zso,
+}
+
+impl Format {
+    pub fn magic(self) -> u32 {
+        match self {
+            Format::Cso => CISO_MAGIC,
+            Format::Zso => ZISO_MAGIC,
+        }
+    }
+
+    pub fn from_magic(magic: u32) -> Option<Format> {
+        match magic {
+            CISO_MAGIC => Some(Format::Cso),
+            ZISO_MAGIC => Some(Format::Zso),
+            _ => None,
+        }
+    }
+
+    pub fn version(self) -> u8 {
+        match self {
+            Format::Cso => 2,
+            Format::Zso => 1,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Cso => "cso",
+            Format::Zso => "zso",
+        }
+    }
+
+}
+
+/// How the data of compressed blocks is encoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Method {
+    Lz4,
+    /// Raw deflate, which older CSO readers expect.
+    Deflate,
+}
+
+impl Method {
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Lz4 => "lz4",
+            Method::Deflate => "deflate",
+        }
+    }
+}
+
+/// How closely an image made by another tool has to follow the format to
+/// be read.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Parsing {
+    /// Rejects anything off the spec, saying what it was.
+    Strict,
+    /// Reads whatever can be made sense of: header sizes other than 24
+    /// bytes, unknown versions, alignments past `MAX_ALIGN` and an index
+    /// without its final entry. What was off is still noted.
+    #[default]
+    Lenient,
+}
+
+impl Parsing {
+    /// Notes `quirk` of an image, or with `Strict` turns it into an error.
+    pub fn tolerate(self, quirks: &mut Vec<String>, quirk: String) -> Result<(), Error> {
+        match self {
+            Parsing::Strict => Err(Error::new(ErrorKind::InvalidData, format!("not to spec: {}", quirk))),
+            Parsing::Lenient => {
+                crate::debug!("tolerating an image that's off the spec: {}", quirk);
+                quirks.push(quirk);
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Layout of a CSO image as described by its header.
+#[derive(Copy, Clone, Debug)]
+pub struct CsoImage {
+    pub format: Format,
+    pub version: u8,
+    pub align: u8,
+    pub block_size: u32,
+    pub total_bytes: u64,
+    pub total_blocks: usize,
+}
+
+impl CsoImage {
+    /// Number of blocks needed to hold `total_bytes`, counting a partial
+    /// block at the end.
+    pub fn block_count(total_bytes: u64, block_size: u32) -> usize {
+        total_bytes.div_ceil(block_size as u64) as usize
+    }
+
+    /// Uncompressed length of block `block`, which is shorter than the block
+    /// size for a partial block at the end of the image.
+    pub fn block_len(&self, block: usize) -> usize {
+        let start = block as u64 * self.block_size as u64;
+        (self.total_bytes - start).min(self.block_size as u64) as usize
+    }
+
+    /// Whether blocks can be compressed with `method` in this image. ZSO only
+    /// has LZ4 and CSO v1 only deflate, while CSO v2 can mix the two.
+    pub fn supports(&self, method: Method) -> bool {
+        match (self.format, self.version) {
+            (Format::Zso, _) => method == Method::Lz4,
+            (Format::Cso, 0 | 1) => method == Method::Deflate,
+            (Format::Cso, _) => true,
+        }
+    }
+
+    /// Whether uncompressed blocks are told apart by taking up at least a
+    /// whole block, as in CSO v2, rather than by the index flag.
+    pub fn sizes_raw_blocks(&self) -> bool {
+        self.format == Format::Cso && self.version >= 2
+    }
+
+    /// Builds an index entry for a block stored at `pos` (already shifted by
+    /// the alignment), compressed with `method` or stored as is.
+    pub fn index_entry(&self, pos: u32, method: Option<Method>) -> u32 {
+        let flag = match self.sizes_raw_blocks() {
+            true => method == Some(Method::Lz4),
+            false => method.is_none(),
+        };
+        if flag {
+            pos | INDEX_FLAG
+        } else {
+            pos
+        }
+    }
+
+    /// How a block was stored, given its index entry and the number of bytes
+    /// up to where the next block starts. `None` means it was stored as is.
+    pub fn block_method(&self, entry: u32, span: u64) -> Option<Method> {
+        let flag = entry & INDEX_FLAG != 0;
+        match (self.format, self.version) {
+            (Format::Zso, _) => (!flag).then_some(Method::Lz4),
+            (Format::Cso, 0 | 1) => (!flag).then_some(Method::Deflate),
+            _ if span >= self.block_size as u64 => None,
+            _ if flag => Some(Method::Lz4),
+            _ => Some(Method::Deflate),
+        }
+    }
+}
+
+/// Returns `path` without its trailing `.ext`, if it has one.
+pub fn strip_extension(path: &Path, ext: &str) -> Option<PathBuf> {
+    (path.extension()? == ext).then(|| path.with_extension(""))
+}
+
+/// Locates the start of the game partition, returning 0 for plain XDVDFS
+/// images and the redump video partition size for full redump dumps.
+pub fn get_image_offset<R: Read + Seek>(f: &mut R) -> Result<u32, io::Error> {
+    let mut buf = [0u8; 20];
+
+    // Check for redump
+    f.seek(io::SeekFrom::Start(XBOX_MEDIA_HEADER_REDUMP_OFFSET))?;
+    if f.read_exact(&mut buf).is_ok() && &buf == XBOX_MEDIA_HEADER {
+        return Ok(REDUMP_GAME_PARTITION_OFFSET);
+    }
+
+    // Check for XDVDFS
+    f.seek(io::SeekFrom::Start(XBOX_MEDIA_HEADER_XDVDFS_OFFSET))?;
+    if f.read_exact(&mut buf).is_ok() && &buf == XBOX_MEDIA_HEADER {
+        return Ok(0x0);
+    }
+
+    Err(XcsoError::UnknownFormat { expected: "an Xbox image" }.into())
+}
+
+/// What a file holds, judging by its contents rather than its name.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    /// An Xbox game image, either a plain XDVDFS image or a full redump.
+    Iso,
+    /// An image that was already compressed into one of these formats.
+    Compressed(Format),
+    Unknown,
+}
+
+/// Works out what kind of image `f` holds from its magic numbers.
+pub fn identify_image<R: Read + Seek>(f: &mut R) -> Result<ImageKind, io::Error> {
+    let mut magic = [0u8; 4];
+    f.seek(io::SeekFrom::Start(0))?;
+    if f.read_exact(&mut magic).is_ok() {
+        if let Some(format) = Format::from_magic(u32::from_le_bytes(magic)) {
+            return Ok(ImageKind::Compressed(format));
+        }
+    }
+
+    match get_image_offset(f) {
+        Ok(_) => Ok(ImageKind::Iso),
+        Err(_) => Ok(ImageKind::Unknown),
+    }
+}
+
+/// Inspects an ISO and returns the CSO layout needed to compress it. The
+/// file is left positioned at the start of the game partition.
+pub fn get_cso_info<R: Read + Seek>(f: &mut R, format: Format, block_size: u32) -> Result<CsoImage, io::Error> {
+    let image_offset = get_image_offset(f)?;
+    crate::debug!("game partition starts at {:#x}", image_offset);
+    cso_info_at(f, image_offset, format, block_size)
+}
+
+/// Same as `get_cso_info`, but for an image of any kind, which is
+/// compressed from the start of the file whatever it holds.
+pub fn get_raw_cso_info<R: Read + Seek>(f: &mut R, format: Format, block_size: u32) -> Result<CsoImage, io::Error> {
+    cso_info_at(f, 0, format, block_size)
+}
+
+fn cso_info_at<R: Seek>(f: &mut R, image_offset: u32, format: Format, block_size: u32) -> Result<CsoImage, io::Error> {
+    let byte_len: u64 = f.seek(io::SeekFrom::End(0))? - image_offset as u64;
+    let blocks: usize = CsoImage::block_count(byte_len, block_size);
+
+    f.seek(io::SeekFrom::Start(image_offset as u64))?;
+
+    Ok(CsoImage {
+        format,
+        version: format.version(),
+        align: 2,
+        block_size,
+        total_bytes: byte_len,
+        total_blocks: blocks,
+    })
+}
+
+pub fn write_cso_info<W: Write>(f: &mut W, img_data: CsoImage) -> Result<(), Error> {
+    let mut buf: Vec<u8> = Vec::new();
+    buf.write_all(&img_data.format.magic().to_le_bytes())?;
+    buf.write_all(&CISO_HEADER_SIZE.to_le_bytes())?;
+    buf.write_all(&img_data.total_bytes.to_le_bytes())?;
+
+    buf.write_all(&img_data.block_size.to_le_bytes())?;
+
+    buf.write_all(&img_data.version.to_le_bytes())?;
+    buf.write_all(&img_data.align.to_le_bytes())?;
+
+    let pad: u16 = 0;
+    buf.write_all(&pad.to_le_bytes())?;
+
+    assert_eq!(CISO_HEADER_SIZE, buf.len() as u32);
+    f.write_all(&buf)
+}
+
+/// Parses a CSO or ZSO header from the start of `f`.
+pub fn read_cso_info<R: Read + Seek>(f: &mut R) -> Result<CsoImage, Error> {
+    read_cso_info_with(f, Parsing::Lenient).map(|(image, _)| image)
+}
+
+/// Same as `read_cso_info`, but also returns what in the header is off the
+/// spec, which with `Parsing::Strict` is an error instead.
+pub fn read_cso_info_with<R: Read + Seek>(f: &mut R, parsing: Parsing) -> Result<(CsoImage, Vec<String>), Error> {
+    let mut buf = [0u8; CISO_HEADER_SIZE as usize];
+    f.seek(io::SeekFrom::Start(0))?;
+    f.read_exact(&mut buf)?;
+
+    let magic = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    let format = Format::from_magic(magic)
+        .ok_or(XcsoError::UnknownFormat { expected: "a CSO or ZSO image" })?;
+
+    let total_bytes = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+    let block_size = u32::from_le_bytes(buf[16..20].try_into().unwrap());
+    if !block_size.is_power_of_two() || block_size > MAX_BLOCK_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsupported CSO block size {}", block_size)));
+    }
+    let (version, align) = (buf[20], buf[21]);
+    if align > MAX_READ_ALIGN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsupported CSO alignment {}", align)));
+    }
+
+    // Readers go by the layout rather than these, so other tools get them
+    // wrong without anyone noticing
+    let mut quirks = Vec::new();
+    let header_size = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    if header_size != CISO_HEADER_SIZE {
+        parsing.tolerate(&mut quirks, format!("the header gives its size as {} bytes, not 24", header_size))?;
+    }
+    let known = match format {
+        Format::Cso => version <= 2,
+        Format::Zso => version == 1,
+    };
+    if !known {
+        parsing.tolerate(&mut quirks, format!("version {} isn't a known {} version", version, format.extension()))?;
+    }
+    if align > MAX_ALIGN {
+        parsing.tolerate(&mut quirks, format!("alignment {} is past the largest of {}", align, MAX_ALIGN))?;
+    }
+    if buf[22..24] != [0, 0] {
+        parsing.tolerate(&mut quirks, "the reserved bytes at the end of the header aren't zero".to_owned())?;
+    }
+
+    let image = CsoImage {
+        format,
+        version,
+        align,
+        block_size,
+        total_bytes,
+        total_blocks: CsoImage::block_count(total_bytes, block_size),
+    };
+    Ok((image, quirks))
+}
+
+pub fn write_block_index<W: Write + Seek>(f: &mut W, blocks: &[u32]) -> Result<u64, Error> {
+    // In one write, as an index can run to megabytes
+    let buf: Vec<u8> = blocks.iter().flat_map(|x| x.to_le_bytes()).collect();
+    f.write_all(&buf)?;
+
+    // Get the current position
+    f.stream_position()
+}
+
+pub fn pad_file<W: Write + Seek>(f: &mut W) -> Result<(), io::Error> {
+    let end = f.seek(io::SeekFrom::End(0))?;
+    let pad_size = end & 0x3FF;
+
+    let buf: Vec<u8> = vec![0; 0x400 - pad_size as usize];
+
+    f.write_all(&buf)
+}
@@ -0,0 +1,205 @@
+//! Reads Redump datfiles (the Logiqx XML format), so source images can be
+//! checked against the hashes of known good dumps.
+//!
+//! Only the `<game>` and `<rom>` elements are looked at, which is all a
+//! Redump DAT has that matters here.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use xcso::Hashes;
+
+#[derive(Clone, Debug)]
+pub struct Rom {
+    pub game: String,
+    pub name: String,
+    pub size: Option<u64>,
+    pub crc32: Option<String>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+}
+
+/// How a source image compares to a DAT.
+#[derive(Clone, Debug)]
+pub enum DatMatch<'a> {
+    /// Every hash the DAT lists for this rom matches.
+    Match(&'a Rom),
+    /// The DAT has a rom of the same file name, but different contents, so
+    /// the image is most likely a bad or modified dump.
+    Mismatch(&'a Rom),
+    /// Nothing in the DAT looks like this image.
+    Unknown,
+}
+
+impl DatMatch<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DatMatch::Match(_) => "match",
+            DatMatch::Mismatch(_) => "mismatch",
+            DatMatch::Unknown => "unknown",
+        }
+    }
+
+    pub fn game(&self) -> Option<&str> {
+        match self {
+            DatMatch::Match(rom) | DatMatch::Mismatch(rom) => Some(&rom.game),
+            DatMatch::Unknown => None,
+        }
+    }
+}
+
+pub struct Dat {
+    roms: Vec<Rom>,
+    by_sha1: HashMap<String, usize>,
+    by_name: HashMap<String, usize>,
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#').and_then(|x| x.parse().ok()).and_then(char::from_u32),
+            },
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Splits the inside of a tag, `rom name="x" size="1"`, into its name and
+/// attributes.
+fn parse_tag(tag: &str) -> (&str, HashMap<&str, String>) {
+    let tag = tag.trim_end_matches('/');
+    let (name, mut rest) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+    let mut attrs = HashMap::new();
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(q @ ('"' | '\'')) => q,
+            _ => break,
+        };
+        let value = &value[1..];
+        let end = match value.find(quote) {
+            Some(end) => end,
+            None => break,
+        };
+        attrs.insert(key, unescape(&value[..end]));
+        rest = &value[end + 1..];
+    }
+    (name, attrs)
+}
+
+impl Dat {
+    pub fn open(fp: &Path) -> io::Result<Dat> {
+        let text = fs::read_to_string(fp)?;
+        let dat = Dat::parse(&text);
+        if dat.roms.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not look like a Redump DAT: no roms found", fp.display()),
+            ));
+        }
+        Ok(dat)
+    }
+
+    pub fn parse(text: &str) -> Dat {
+        let mut roms = Vec::new();
+        let mut game = String::new();
+
+        let mut rest = text;
+        while let Some(start) = rest.find('<') {
+            rest = &rest[start + 1..];
+            // Comments and declarations can hold anything, so skip them whole
+            if let Some(comment) = rest.strip_prefix("!--") {
+                rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+                continue;
+            }
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => break,
+            };
+            let (name, attrs) = parse_tag(&rest[..end]);
+            rest = &rest[end + 1..];
+
+            match name {
+                "game" | "machine" => game = attrs.get("name").cloned().unwrap_or_default(),
+                "rom" => {
+                    let lower = |key: &str| attrs.get(key).map(|x| x.to_ascii_lowercase());
+                    roms.push(Rom {
+                        game: game.clone(),
+                        name: attrs.get("name").cloned().unwrap_or_default(),
+                        size: attrs.get("size").and_then(|x| x.parse().ok()),
+                        crc32: lower("crc"),
+                        md5: lower("md5"),
+                        sha1: lower("sha1"),
+                    });
+                },
+                _ => {},
+            }
+        }
+
+        let mut by_sha1 = HashMap::new();
+        let mut by_name = HashMap::new();
+        for (i, rom) in roms.iter().enumerate() {
+            if let Some(ref sha1) = rom.sha1 {
+                by_sha1.entry(sha1.clone()).or_insert(i);
+            }
+            by_name.entry(rom.name.to_lowercase()).or_insert(i);
+        }
+        Dat { roms, by_sha1, by_name }
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    /// Looks up the image named `name` with `hashes`. An image is only
+    /// taken as a match if every hash and the size the DAT lists agree.
+    pub fn check(&self, name: &str, hashes: &Hashes) -> DatMatch<'_> {
+        let matches = |rom: &Rom| {
+            rom.size.is_none_or(|x| x == hashes.size)
+                && rom.crc32.as_ref().is_none_or(|x| *x == hashes.crc32_hex())
+                && rom.md5.as_ref().is_none_or(|x| *x == hashes.md5_hex())
+                && rom.sha1.as_ref().is_none_or(|x| *x == hashes.sha1_hex())
+        };
+
+        if let Some(&i) = self.by_sha1.get(&hashes.sha1_hex()) {
+            if matches(&self.roms[i]) {
+                return DatMatch::Match(&self.roms[i]);
+            }
+        }
+        match self.by_name.get(&name.to_lowercase()) {
+            Some(&i) if matches(&self.roms[i]) => DatMatch::Match(&self.roms[i]),
+            Some(&i) => DatMatch::Mismatch(&self.roms[i]),
+            None => DatMatch::Unknown,
+        }
+    }
+}
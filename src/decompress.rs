@@ -0,0 +1,931 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::checksums::{checksum_path, read_checksums};
+use crate::codec::decode_block;
+use crate::compress::{CancelPolicy, CancelToken, SplitNaming, DEFAULT_WRITE_BUFFER, PROGRESS_TEMPLATE};
+use crate::cso::{
+    identify_image, read_cso_info_with, strip_extension, CsoImage, Format, ImageKind, Method, Parsing,
+    CISO_HEADER_SIZE, INDEX_FLAG, REDUMP_GAME_PARTITION_OFFSET, XBOX_MEDIA_HEADER, XBOX_MEDIA_HEADER_XDVDFS_OFFSET,
+};
+use crate::error::XcsoError;
+use crate::hash::xxh64;
+use crate::hints::SeekHints;
+use crate::http::{is_url, HttpReader};
+use crate::trailer::{read_trailer, Trailer};
+use crate::xdvdfs::ReadSeek;
+use crate::lz4;
+
+/// Returns the path of part `n` (counting from 1) of a split set, if `fp`
+/// could be the first part: `game.1.cso`, `Part1/game.cso`, or else
+/// `game.cso` followed by `game.cso2`.
+pub fn nth_part_path(fp: &Path, n: usize) -> Option<PathBuf> {
+    [Format::Cso, Format::Zso].iter().find_map(|&format| {
+        let stem = strip_extension(fp, format.extension())?;
+        if let Some(base) = strip_extension(&stem, "1") {
+            return Some(SplitNaming::Numbered.part_path(&base, n, format));
+        }
+        let folder = stem.parent().filter(|x| x.file_name().is_some_and(|x| x.eq_ignore_ascii_case("Part1")));
+        match folder {
+            Some(folder) => Some(SplitNaming::Folders.part_path(&folder.with_file_name(stem.file_name()?), n, format)),
+            None => Some(SplitNaming::Suffixed.part_path(&stem, n, format)),
+        }
+    })
+}
+
+/// Decompresses a single block produced by `compress_block_v2`, holding at
+/// most `block_size` bytes.
+pub fn decompress_block_v2(block: &[u8], block_size: usize) -> Result<Vec<u8>, Error> {
+    if block.len() < 4 {
+        return Err(Error::new(ErrorKind::InvalidData, "truncated lz4 block"));
+    }
+
+    let size = u32::from_le_bytes(block[..4].try_into().unwrap()) as usize;
+    if size > block.len() - 4 {
+        return Err(Error::new(ErrorKind::InvalidData, "truncated lz4 block"));
+    }
+
+    lz4::decompress_up_to(&block[4..4 + size], block_size)
+}
+
+// Most bytes of padding expected after the last block, as `pad_file` leaves
+const MAX_PADDING: u64 = 0x400;
+
+fn read_block_index<R: Read + Seek>(f: &mut R, entries: usize) -> Result<Vec<u32>, Error> {
+    let mut raw = vec![0u8; entries * 4];
+    f.seek(io::SeekFrom::Start(CISO_HEADER_SIZE as u64))?;
+    f.read_exact(&mut raw)?;
+
+    Ok(raw
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+pub(crate) fn entry_pos(entry: u32, align: u8) -> u64 {
+    ((entry & !INDEX_FLAG) as u64) << align
+}
+
+/// Makes sure `index` ends with an entry for where the last block ends, in
+/// a file of `len` bytes. Some tools leave it zero, or leave it out so the
+/// first block starts where it would have been, in which case the last
+/// block is taken to run to the end of the file.
+fn check_final_entry(
+    index: &mut [u32],
+    align: u8,
+    len: u64,
+    parsing: Parsing,
+    quirks: &mut Vec<String>,
+) -> Result<(), Error> {
+    let blocks = index.len() - 1;
+    let index_end = CISO_HEADER_SIZE as u64 + index.len() as u64 * 4;
+    let first = entry_pos(index[0], align);
+    let quirk = if (index_end - 4..index_end).contains(&first) {
+        format!("the index is missing its final entry, as block 0 starts at {:#x}", first)
+    } else if blocks > 0 && index[0] != 0 && index[blocks] == 0 {
+        format!("the final index entry, at {:#x}, is zero", index_end - 4)
+    } else {
+        return Ok(());
+    };
+    parsing.tolerate(quirks, quirk)?;
+    index[blocks] = len.div_ceil(1 << align) as u32;
+    Ok(())
+}
+
+struct Part {
+    file: Box<dyn ReadSeek + Send>,
+    /// Bytes of the file ahead of the trailer, if it has one.
+    len: u64,
+    /// Bytes of the file, trailer and all.
+    size: u64,
+    align: u8,
+    first_block: usize,
+    // Index entries for the blocks in this part, plus the one following them
+    index: Vec<u32>,
+    trailer: Option<Trailer>,
+}
+
+/// The trailer of `file`, if it has one, how much of the file comes before
+/// it and how big the file is.
+fn part_len<R: Read + Seek>(file: &mut R) -> Result<(u64, u64, Option<Trailer>), Error> {
+    let size = file.seek(io::SeekFrom::End(0))?;
+    let trailer = read_trailer(file)?;
+    let len = trailer.as_ref().map_or(size, |x| x.data_len);
+    Ok((len, size, trailer))
+}
+
+/// Where the parts after the first of a split set come from: called with
+/// the number of each (counting from 2) until it has no more.
+type NextPart<'a> = dyn FnMut(usize) -> Result<Option<Box<dyn ReadSeek + Send>>, Error> + 'a;
+
+impl Part {
+    /// Where block `local` starts and how many bytes there are up to the
+    /// next block, or to the end of the file if the next one was split off.
+    fn extent(&self, local: usize) -> (u64, u64) {
+        let pos = entry_pos(self.index[local], self.align);
+        let next = entry_pos(self.index[local + 1], self.align);
+        let end = if next > pos { next.min(self.len) } else { self.len };
+        (pos, end.saturating_sub(pos))
+    }
+}
+
+/// Whether a CSO v2 image was written by an earlier version of this tool,
+/// which flagged compressed blocks in the index and gave each LZ4 block a
+/// size prefix rather than following the spec. Flagged blocks the prefix
+/// accounts for exactly, padding aside, give those images away.
+fn is_legacy_v2(header: &CsoImage, part: &mut Part) -> Result<bool, Error> {
+    if !header.sizes_raw_blocks() {
+        return Ok(false);
+    }
+
+    let mut checked = 0;
+    // The last block can run into padding at the end of the file, so it
+    // proves nothing either way
+    for local in 0..(part.index.len() - 1).saturating_sub(1) {
+        let (pos, span) = part.extent(local);
+        if part.index[local] & INDEX_FLAG == 0 {
+            // Only deflate blocks come up short without the flag
+            if span < header.block_size as u64 {
+                return Ok(false);
+            }
+            continue;
+        }
+        // A damaged index is left for `index_looks_sane` to report
+        if pos + 4 > part.len {
+            continue;
+        }
+
+        let mut size = [0u8; 4];
+        part.file.seek(io::SeekFrom::Start(pos))?;
+        part.file.read_exact(&mut size)?;
+        let stored = 4 + u32::from_le_bytes(size) as u64;
+        if stored > span || span - stored >= 1 << header.align {
+            return Ok(false);
+        }
+
+        checked += 1;
+        if checked == 16 {
+            break;
+        }
+    }
+    // With no flagged block to go by, as in an image of a single block,
+    // it's read the way the spec has it
+    Ok(checked > 0)
+}
+
+/// An opened CSO image (or split set) that can be read block by block.
+pub struct CsoFile {
+    header: CsoImage,
+    parts: Vec<Part>,
+    legacy: bool,
+    /// An older split set, whose parts share the index in the first.
+    shared_index: bool,
+    quirks: Vec<String>,
+    /// Checksums of the contents of each block, from the side index.
+    checksums: Option<Vec<u64>>,
+}
+
+impl CsoFile {
+    /// Opens a single image, or the whole set when `fp` is the first part
+    /// of a split set.
+    pub fn open(fp: &Path) -> Result<CsoFile, Error> {
+        CsoFile::open_with(fp, Parsing::Lenient)
+    }
+
+    /// Same as `open`, but following `parsing` about images that are off
+    /// the spec.
+    pub fn open_with(fp: &Path, parsing: Parsing) -> Result<CsoFile, Error> {
+        let mut next_part = |n| match nth_part_path(fp, n) {
+            Some(path) if path.exists() => {
+                let file = File::open(&path).map_err(|e| XcsoError::io(&path, e))?;
+                Ok(Some(Box::new(file) as Box<dyn ReadSeek + Send>))
+            },
+            _ => Ok(None),
+        };
+        let first = File::open(fp).map_err(|e| XcsoError::io(fp, e))?;
+        let cso = CsoFile::open_parts(Box::new(first), parsing, &mut next_part)?;
+        Ok(cso.with_checksums(&checksum_path(fp)))
+    }
+
+    /// Opens the image in `reader`, which can be anything that reads and
+    /// seeks, such as a file inside an archive or a buffer in memory.
+    pub fn from_reader<R: Read + Seek + Send + 'static>(reader: R) -> Result<CsoFile, Error> {
+        CsoFile::from_parts(vec![reader], Parsing::Lenient)
+    }
+
+    /// Opens a split set from its parts, in order, following `parsing`
+    /// about images that are off the spec. There's no side index of
+    /// checksums to go with a set opened this way.
+    pub fn from_parts<R: Read + Seek + Send + 'static>(parts: Vec<R>, parsing: Parsing) -> Result<CsoFile, Error> {
+        let mut parts = parts.into_iter().map(|x| Box::new(x) as Box<dyn ReadSeek + Send>);
+        let first = parts.next().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no parts given"))?;
+        CsoFile::open_parts(first, parsing, &mut |_| Ok(parts.next()))
+    }
+
+    fn open_parts(
+        mut first: Box<dyn ReadSeek + Send>,
+        parsing: Parsing,
+        next_part: &mut NextPart,
+    ) -> Result<CsoFile, Error> {
+        let (mut header, mut quirks) = read_cso_info_with(&mut first, parsing)?;
+        let mut index = read_block_index(&mut first, header.total_blocks + 1)?;
+        let (len, size, trailer) = part_len(&mut first)?;
+        check_final_entry(&mut index, header.align, len, parsing, &mut quirks)?;
+
+        // Older split sets share a single index in the first part, with
+        // positions restarting at zero in each following part
+        let splits: Vec<usize> = (1..header.total_blocks)
+            .filter(|&i| entry_pos(index[i], header.align) < entry_pos(index[i - 1], header.align))
+            .collect();
+
+        if !splits.is_empty() {
+            let mut bounds = vec![0];
+            bounds.extend(splits);
+            bounds.push(header.total_blocks);
+
+            let mut files = vec![first];
+            for n in 2..bounds.len() {
+                // A damaged index can look just like one of these
+                let missing = || {
+                    let message = format!("the index goes back at block {}, but there's no part {}", bounds[n - 1], n);
+                    Error::new(ErrorKind::InvalidData, message)
+                };
+                files.push(next_part(n)?.ok_or_else(missing)?);
+            }
+
+            let parts = files
+                .into_iter()
+                .enumerate()
+                .map(|(n, mut file)| {
+                    let (len, size, trailer) = part_len(&mut file)?;
+                    Ok(Part {
+                        len,
+                        size,
+                        file,
+                        align: header.align,
+                        first_block: bounds[n],
+                        index: index[bounds[n]..=bounds[n + 1]].to_vec(),
+                        trailer,
+                    })
+                })
+                .collect::<Result<Vec<Part>, Error>>()?;
+            return CsoFile::new(header, parts, quirks, true);
+        }
+
+        let mut parts = vec![Part { file: first, len, size, align: header.align, first_block: 0, index, trailer }];
+
+        // Newer split sets are made of complete images, one per part
+        for n in 2.. {
+            let Some(mut file) = next_part(n)? else {
+                break;
+            };
+
+            let in_part = |x: Error| Error::new(x.kind(), format!("part {}: {}", n, x));
+            let (part_header, mut part_quirks) = read_cso_info_with(&mut file, parsing).map_err(in_part)?;
+            if part_header.format != header.format
+                || part_header.version != header.version
+                || part_header.block_size != header.block_size
+            {
+                return Err(Error::new(ErrorKind::InvalidData, format!("part {} has a different format", n)));
+            }
+
+            let mut index = read_block_index(&mut file, part_header.total_blocks + 1)?;
+            let (len, size, trailer) = part_len(&mut file)?;
+            check_final_entry(&mut index, part_header.align, len, parsing, &mut part_quirks).map_err(in_part)?;
+            quirks.extend(part_quirks.into_iter().map(|x| format!("part {}: {}", n, x)));
+            let first_block = header.total_blocks;
+            parts.push(Part { file, len, size, align: part_header.align, first_block, index, trailer });
+            header.total_blocks += part_header.total_blocks;
+            header.total_bytes += part_header.total_bytes;
+        }
+
+        CsoFile::new(header, parts, quirks, false)
+    }
+
+    fn new(header: CsoImage, mut parts: Vec<Part>, quirks: Vec<String>, shared_index: bool) -> Result<CsoFile, Error> {
+        let legacy = is_legacy_v2(&header, &mut parts[0])?;
+        crate::debug!(
+            "opened v{} image with {} blocks in {} parts{}",
+            header.version,
+            header.total_blocks,
+            parts.len(),
+            if legacy { ", legacy v2 block flags" } else { "" },
+        );
+        Ok(CsoFile { header, parts, legacy, shared_index, quirks, checksums: None })
+    }
+
+    /// Checks blocks against the checksums at `fp` as they're read, if
+    /// there are any. Ones that don't fit the image are left out.
+    fn with_checksums(mut self, fp: &Path) -> CsoFile {
+        match read_checksums(fp, &self.header) {
+            Ok(checksums) => self.checksums = checksums,
+            Err(e) => crate::warn!("not checking the blocks: {}", e),
+        }
+        if self.checksums.is_some() {
+            crate::debug!("checking blocks against {}", fp.display());
+        }
+        self
+    }
+
+    /// Whether compressed blocks are flagged the way early CSO v2 tools did,
+    /// with the size of the LZ4 data in front of each.
+    pub fn is_legacy_v2(&self) -> bool {
+        self.legacy
+    }
+
+    /// Whether this is an older split set, whose parts share the index in
+    /// the first part.
+    pub fn has_shared_index(&self) -> bool {
+        self.shared_index
+    }
+
+    /// Whether blocks are checked against a side index of checksums.
+    pub fn has_checksums(&self) -> bool {
+        self.checksums.is_some()
+    }
+
+    /// Seek hints for the image, with a checkpoint every `interval` blocks.
+    /// Images whose blocks can't be told apart by their index alone, as in
+    /// older split sets and legacy v2 images, can't have any.
+    pub fn seek_hints(&self, interval: usize) -> Result<SeekHints, Error> {
+        if self.shared_index || self.legacy {
+            return Err(Error::new(ErrorKind::Unsupported, "this kind of image can't be read by seek hints"));
+        }
+        Ok(SeekHints {
+            interval,
+            total_blocks: self.header.total_blocks,
+            parts: self.parts.iter().map(|x| (x.first_block, x.index.len() - 1)).collect(),
+            checkpoints: self.
+                parts.
+                iter().
+                map(|x| {
+                    let blocks = &x.index[..x.index.len() - 1];
+                    blocks.iter().step_by(interval).map(|&e| entry_pos(e, x.align)).collect()
+                }).
+                collect(),
+        })
+    }
+
+    /// The trailer of the first part, which has the hashes of the ISO the
+    /// image was made from.
+    pub fn trailer(&self) -> Option<&Trailer> {
+        self.parts[0].trailer.as_ref()
+    }
+
+    /// Reads every part that has a trailer and returns the numbers
+    /// (counting from 1) of the ones that don't hash to what it says, or
+    /// that lost theirs when the first part has one.
+    pub fn check_trailers(&mut self) -> Result<Vec<usize>, Error> {
+        let expected = self.trailer().is_some();
+        let mut bad = Vec::new();
+        for (n, part) in self.parts.iter_mut().enumerate() {
+            let good = match &part.trailer {
+                Some(trailer) => trailer.check(&mut part.file)?,
+                None => !expected,
+            };
+            if !good {
+                crate::debug!("part {} doesn't match its trailer", n + 1);
+                bad.push(n + 1);
+            }
+        }
+        Ok(bad)
+    }
+
+    /// How a block was stored, given its index entry and the bytes up to
+    /// the next block. `None` means it was stored as is.
+    fn block_method(&self, entry: u32, span: u64) -> Option<Method> {
+        if self.legacy {
+            return (entry & INDEX_FLAG != 0).then_some(Method::Lz4);
+        }
+        self.header.block_method(entry, span)
+    }
+
+    fn block_methods(&self) -> impl Iterator<Item = Option<Method>> + '_ {
+        self.parts.iter().flat_map(move |part| {
+            (0..part.index.len() - 1).map(move |local| self.block_method(part.index[local], part.extent(local).1))
+        })
+    }
+
+    pub fn header(&self) -> CsoImage {
+        self.header
+    }
+
+    /// What about the image was off the spec but read anyway.
+    pub fn quirks(&self) -> &[String] {
+        &self.quirks
+    }
+
+    pub fn part_count(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// Combined size of all parts on disk.
+    pub fn stored_bytes(&self) -> Result<u64, Error> {
+        Ok(self.parts.iter().map(|x| x.size).sum())
+    }
+
+    /// Combined size of all parts, leaving out their trailers.
+    pub(crate) fn image_bytes(&self) -> u64 {
+        self.parts.iter().map(|x| x.len).sum()
+    }
+
+    /// Number of blocks stored compressed rather than as raw data.
+    pub fn compressed_blocks(&self) -> usize {
+        self.block_methods().filter(|x| x.is_some()).count()
+    }
+
+    /// Compression methods used by at least one block.
+    pub fn methods(&self) -> Vec<Method> {
+        let used: Vec<Option<Method>> = self.block_methods().collect();
+        [Method::Lz4, Method::Deflate].into_iter().filter(|&m| used.contains(&Some(m))).collect()
+    }
+
+    /// Cheap sanity check of the block index: positions never move backwards
+    /// within a part, and every block starts inside its file.
+    pub fn index_looks_sane(&self) -> Result<bool, Error> {
+        let last_part = self.parts.len() - 1;
+        for (n, part) in self.parts.iter().enumerate() {
+            let file_len = part.size;
+            let blocks = part.index.len() - 1;
+            // Leaving room for an index that's missing its final entry
+            let data_start = CISO_HEADER_SIZE as u64 + blocks as u64 * 4;
+
+            // The trailing entry of a part in an older split set belongs to
+            // the next part, so it's only checked for the last one
+            let checked = if n == last_part { blocks + 1 } else { blocks };
+            let positions: Vec<u64> = part.index[..checked].iter().map(|&e| entry_pos(e, part.align)).collect();
+
+            // A final entry made up for a file that isn't a multiple of the
+            // alignment rounds up past its end
+            let file_end = file_len.next_multiple_of(1 << part.align);
+            if positions.iter().any(|&pos| pos < data_start || pos > file_end) {
+                return Ok(false);
+            }
+            if positions.windows(2).any(|w| w[1] < w[0]) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Checks the structure of the image without decompressing anything:
+    /// that the index never goes backwards, that every block lies within its
+    /// file and has as many bytes as a block stored that way needs, and that
+    /// the final entry is where the data ends. Returns what's wrong, if
+    /// anything.
+    pub fn validate(&self) -> Result<Vec<String>, Error> {
+        let mut problems = Vec::new();
+        let block_size = self.header.block_size as u64;
+        let mut block = 0;
+        for (n, part) in self.parts.iter().enumerate() {
+            let file_len = part.len;
+            let file_end = file_len.next_multiple_of(1 << part.align);
+            let at = match self.parts.len() {
+                1 => String::new(),
+                _ => format!(" of part {}", n + 1),
+            };
+            let blocks = part.index.len() - 1;
+            let data_start = CISO_HEADER_SIZE as u64 + blocks as u64 * 4;
+            // Only the last part of an older split set has its final entry
+            let has_final = !self.shared_index || n == self.parts.len() - 1;
+            let pos_of = |local: usize| entry_pos(part.index[local], part.align);
+            if part.index.iter().all(|&x| x == 0) {
+                problems.push(format!("the index{} is all zeros, which `repair` can rebuild", at));
+                block += blocks;
+                continue;
+            }
+
+            for local in 0..blocks {
+                let pos = pos_of(local);
+                if pos < data_start {
+                    problems.push(format!("block {} starts at {:#x}{}, inside the index", block, pos, at));
+                } else if pos >= file_len {
+                    problems.push(format!("block {} starts at {:#x}, past the end{}", block, pos, at));
+                } else if local + 1 < blocks || has_final {
+                    let next = pos_of(local + 1);
+                    if next < pos {
+                        problems.push(format!("block {} starts at {:#x}{}, after the next one", block, pos, at));
+                    } else if next > file_end {
+                        problems.push(format!("block {} runs past the end{} to {:#x}", block, at, next));
+                    }
+                }
+
+                let (pos, span) = part.extent(local);
+                let needed = match self.block_method(part.index[local], span) {
+                    _ if pos >= file_len => 0,
+                    None if self.header.sizes_raw_blocks() => block_size,
+                    None => self.header.block_len(block) as u64,
+                    Some(_) => 1,
+                };
+                if span < needed {
+                    problems.push(format!("block {} only has {} of the {} bytes it needs", block, span, needed));
+                } else if !self.legacy && needed == 1 && span > block_size * 2 {
+                    problems.push(format!("block {} is {} bytes, more than a compressed block can be", block, span));
+                }
+                block += 1;
+            }
+
+            if has_final {
+                let end = pos_of(blocks);
+                let padding = MAX_PADDING.max(1 << part.align);
+                if end > file_end {
+                    problems.push(format!("the final index entry{} points past the end, at {:#x}", at, end));
+                } else if file_len - end.min(file_len) > padding {
+                    problems.push(format!(
+                        "the final index entry{} points at {:#x}, but the file goes on for another {} bytes",
+                        at, end, file_len - end,
+                    ));
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Locates the game partition in the image like `get_image_offset`
+    /// does, which is past the start for a redump compressed with its video
+    /// partition.
+    pub fn game_partition_offset(&mut self) -> Result<u64, Error> {
+        let offsets = [0, REDUMP_GAME_PARTITION_OFFSET as u64];
+        for offset in offsets {
+            let at = offset + XBOX_MEDIA_HEADER_XDVDFS_OFFSET;
+            let block_size = self.header.block_size as u64;
+            let block = (at / block_size) as usize;
+            if block >= self.header.total_blocks {
+                break;
+            }
+            let start = (at % block_size) as usize;
+            if self.read_block(block)?.get(start..start + XBOX_MEDIA_HEADER.len()) == Some(XBOX_MEDIA_HEADER) {
+                return Ok(offset);
+            }
+        }
+        Err(XcsoError::UnknownFormat { expected: "an Xbox image" }.into())
+    }
+
+    /// Reads and decompresses block `block`, returning its raw contents,
+    /// which are checked against its checksum when there are any.
+    pub fn read_block(&mut self, block: usize) -> Result<Vec<u8>, Error> {
+        let len = self.header.block_len(block);
+        let data = match self.read_stored(block)? {
+            (data, None) => data,
+            (data, Some(method)) => decode_block(&data, method, len)?,
+        };
+        // Everything reading the image counts on whole blocks
+        if data.len() != len {
+            return Err(XcsoError::BadBlock { block }.into());
+        }
+        if self.checksums.as_ref().is_some_and(|x| x[block] != xxh64(&data)) {
+            return Err(XcsoError::ChecksumMismatch { block }.into());
+        }
+        Ok(data)
+    }
+
+    /// Reads block `block` as it was stored, returning the data and what it
+    /// was compressed with (`None` for a block stored as is). Compressed
+    /// data can be followed by the padding up to the next block.
+    pub fn read_stored(&mut self, block: usize) -> Result<(Vec<u8>, Option<Method>), Error> {
+        let block_size = self.header.block_size as usize;
+        let len = self.header.block_len(block);
+        let part = self.parts.partition_point(|p| p.first_block <= block) - 1;
+        let local = block - self.parts[part].first_block;
+
+        // Raw LZ4 and deflate blocks don't record their size, so it comes
+        // from where the next block starts
+        let (pos, span) = self.parts[part].extent(local);
+        let method = self.block_method(self.parts[part].index[local], span);
+        let legacy = self.legacy;
+
+        let f = &mut self.parts[part].file;
+        f.seek(io::SeekFrom::Start(pos))?;
+        let short = |e: Error| match e.kind() {
+            ErrorKind::UnexpectedEof => XcsoError::ShortRead { block }.into(),
+            _ => e,
+        };
+
+        let method = match method {
+            Some(method) => method,
+            None => {
+                let mut buf = vec![0u8; len];
+                f.read_exact(&mut buf).map_err(short)?;
+                return Ok((buf, None));
+            },
+        };
+
+        if legacy {
+            // These carry their LZ4 block size up front
+            let mut size = [0u8; 4];
+            f.read_exact(&mut size).map_err(short)?;
+            let size = u32::from_le_bytes(size) as usize;
+            if size > block_size * 2 {
+                return Err(XcsoError::BadBlock { block }.into());
+            }
+
+            let mut buf = vec![0u8; size];
+            f.read_exact(&mut buf).map_err(short)?;
+            return Ok((buf, Some(Method::Lz4)));
+        }
+
+        if span == 0 || span > block_size as u64 * 2 {
+            return Err(XcsoError::BadBlock { block }.into());
+        }
+
+        let mut buf = vec![0u8; span as usize];
+        f.read_exact(&mut buf).map_err(short)?;
+        Ok((buf, Some(method)))
+    }
+}
+
+/// Blocks a `CsoReader` keeps unless told otherwise: 128 KiB of 2048 byte
+/// blocks, which covers the directory being walked and the file being read
+/// when extracting or serving an image.
+pub const DEFAULT_CACHE_BLOCKS: usize = 64;
+
+/// The image inside a CSO, or a split set, as a plain stream, decompressing
+/// blocks as they are read. The blocks read most recently are kept, so
+/// small reads and going back and forth between a directory and its files
+/// don't decompress the same blocks again.
+pub struct CsoReader {
+    cso: CsoFile,
+    pos: u64,
+    /// Blocks read lately and what's in them, the most recently used last.
+    cache: VecDeque<(usize, Vec<u8>)>,
+    cache_blocks: usize,
+}
+
+impl CsoReader {
+    pub fn new(cso: CsoFile) -> CsoReader {
+        CsoReader::with_cache(cso, DEFAULT_CACHE_BLOCKS)
+    }
+
+    /// Same as `new`, but keeps up to `blocks` blocks, and at least one.
+    pub fn with_cache(cso: CsoFile, blocks: usize) -> CsoReader {
+        let cache_blocks = blocks.max(1);
+        CsoReader { cso, pos: 0, cache: VecDeque::with_capacity(cache_blocks), cache_blocks }
+    }
+
+    pub fn open(fp: &Path) -> Result<CsoReader, Error> {
+        Ok(CsoReader::new(CsoFile::open(fp)?))
+    }
+
+    pub fn header(&self) -> CsoImage {
+        self.cso.header()
+    }
+
+    pub fn into_inner(self) -> CsoFile {
+        self.cso
+    }
+
+    /// What's in block `block`, from the cache or read into it.
+    fn block(&mut self, block: usize) -> io::Result<&[u8]> {
+        match self.cache.iter().position(|(n, _)| *n == block) {
+            Some(at) => {
+                let entry = self.cache.remove(at).unwrap();
+                self.cache.push_back(entry);
+            },
+            None => {
+                if self.cache.len() == self.cache_blocks {
+                    self.cache.pop_front();
+                }
+                self.cache.push_back((block, self.cso.read_block(block)?));
+            },
+        }
+        Ok(&self.cache.back().unwrap().1)
+    }
+}
+
+impl Read for CsoReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let header = self.cso.header();
+        if self.pos >= header.total_bytes || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block = (self.pos / header.block_size as u64) as usize;
+        let start = (self.pos % header.block_size as u64) as usize;
+        let data = self.block(block)?;
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for CsoReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            io::SeekFrom::Start(pos) => Some(pos),
+            io::SeekFrom::End(delta) => self.cso.header().total_bytes.checked_add_signed(delta),
+            io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Opens `fp` to read the image it holds: an ISO as it is, or a CSO
+/// (with the rest of its split set) decompressing as it goes. A URL is
+/// read from the server, which only works for ISOs.
+pub fn open_uncompressed(fp: &Path) -> Result<Box<dyn ReadSeek + Send>, Error> {
+    if is_url(fp) {
+        let mut remote = HttpReader::open(&fp.to_string_lossy())?;
+        if let ImageKind::Compressed(_) = identify_image(&mut remote)? {
+            return Err(Error::new(ErrorKind::Unsupported, "CSOs can't be read from a URL, download them first"));
+        }
+        remote.seek(io::SeekFrom::Start(0))?;
+        return Ok(Box::new(remote));
+    }
+    let mut file = File::open(fp).map_err(|e| XcsoError::io(fp, e))?;
+    match identify_image(&mut file)? {
+        ImageKind::Compressed(_) => Ok(Box::new(CsoReader::open(fp)?)),
+        _ => {
+            file.seek(io::SeekFrom::Start(0))?;
+            Ok(Box::new(file))
+        },
+    }
+}
+
+/// Decompresses the CSO at `fp` (and its second part, if split) into `dest`.
+pub fn decompress_cso(fp: &Path, dest: &Path) -> Result<(), Error> {
+    let pb = ProgressBar::new(0);
+    pb.set_style(ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap());
+    decompress_cso_with_progress(fp, dest, &pb)
+}
+
+/// Same as `decompress_cso`, but reports progress on `pb` (counting bytes of
+/// the image).
+pub fn decompress_cso_with_progress(fp: &Path, dest: &Path, pb: &ProgressBar) -> Result<(), Error> {
+    decompress_cso_with_cancel(fp, dest, pb, &CancelToken::new(), CancelPolicy::Remove)
+}
+
+/// Same as `decompress_cso_with_progress`, but stops between blocks once
+/// `cancel` is cancelled, removing `dest` or leaving it cut short as
+/// `on_cancel` says.
+pub fn decompress_cso_with_cancel(
+    fp: &Path,
+    dest: &Path,
+    pb: &ProgressBar,
+    cancel: &CancelToken,
+    on_cancel: CancelPolicy,
+) -> Result<(), Error> {
+    let mut cso = CsoFile::open(fp)?;
+    let out = File::options().write(true).create_new(true).open(dest).map_err(|e| XcsoError::io(dest, e))?;
+    let result = decompress_to(&mut cso, BufWriter::with_capacity(DEFAULT_WRITE_BUFFER, out), pb, cancel);
+    match result {
+        Err(e) if e.kind() == ErrorKind::Interrupted && on_cancel == CancelPolicy::Remove => {
+            _ = fs::remove_file(dest);
+            Err(e)
+        },
+        x => x.map(|_| ()),
+    }
+}
+
+/// Decompresses all of `cso` into `out`, which can be any writer, reporting
+/// progress on `pb` as `decompress_cso_with_progress` does and stopping
+/// once `cancel` is cancelled, with what's been written so far flushed.
+/// Returns `out`.
+pub fn decompress_to<W: Write>(
+    cso: &mut CsoFile,
+    mut out: W,
+    pb: &ProgressBar,
+    cancel: &CancelToken,
+) -> Result<W, Error> {
+    pb.set_length(cso.header().total_bytes);
+    pb.set_position(0);
+
+    for block in 0..cso.header().total_blocks {
+        if cancel.is_cancelled() {
+            out.flush()?;
+            pb.finish_and_clear();
+            return Err(XcsoError::Interrupted.into());
+        }
+        let data = cso.read_block(block)?;
+        out.write_all(&data)?;
+        pb.inc(data.len() as u64);
+    }
+    out.flush()?;
+
+    pb.finish_and_clear();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::codec::encode_image;
+    use crate::compress::CompressOptions;
+    use crate::selftest::{patterned, random};
+    use crate::testing::Scratch;
+
+    fn read_back(data: &[u8], opts: &CompressOptions) -> CsoFile {
+        let mut out = Cursor::new(Vec::new());
+        encode_image(data, &mut out, opts).unwrap();
+        CsoFile::from_reader(Cursor::new(out.into_inner())).unwrap()
+    }
+
+    #[test]
+    fn later_parts_follow_the_first() {
+        let dest = Path::new("games/halo");
+        for naming in [SplitNaming::Numbered, SplitNaming::Suffixed, SplitNaming::Folders] {
+            for format in [Format::Cso, Format::Zso] {
+                let first = naming.part_path(dest, 1, format);
+                for n in 1..=3 {
+                    assert_eq!(nth_part_path(&first, n), Some(naming.part_path(dest, n, format)), "{:?}", first);
+                }
+            }
+        }
+        assert_eq!(nth_part_path(Path::new("halo.iso"), 2), None);
+    }
+
+    #[test]
+    fn headers_say_what_was_written() {
+        let mut data = patterned(5000);
+        data.extend(random(5000, 2));
+        let opts = |method, version| CompressOptions { method, version, level: 1, ..CompressOptions::default() };
+        let v1 = read_back(&data, &opts(Method::Deflate, None));
+        assert_eq!((v1.header().version, v1.methods()), (1, vec![Method::Deflate]));
+        let v2 = read_back(&data, &opts(Method::Lz4, None));
+        assert_eq!((v2.header().version, v2.methods()), (2, vec![Method::Lz4]));
+        assert!(!v2.is_legacy_v2());
+        let v2 = read_back(&data, &opts(Method::Deflate, Some(2)));
+        assert_eq!((v2.header().version, v2.methods()), (2, vec![Method::Deflate]));
+    }
+
+    #[test]
+    fn single_block_v2_is_not_legacy() {
+        let data = patterned(2048);
+        let mut cso = read_back(&data, &CompressOptions::default());
+        assert_eq!(cso.header().version, 2);
+        assert!(!cso.is_legacy_v2());
+        assert_eq!(cso.read_block(0).unwrap(), data);
+    }
+
+    #[test]
+    fn v2_with_only_the_last_block_compressed_is_not_legacy() {
+        let mut data = random(2048, 1);
+        data.extend(patterned(2048));
+        let mut cso = read_back(&data, &CompressOptions::default());
+        assert!(!cso.is_legacy_v2());
+        assert_eq!(cso.read_block(0).unwrap(), data[..2048]);
+        assert_eq!(cso.read_block(1).unwrap(), data[2048..]);
+    }
+
+    #[test]
+    fn short_blocks_are_errors() {
+        let data = patterned(4096);
+        let mut out = Cursor::new(Vec::new());
+        let header = encode_image(&data, &mut out, &CompressOptions::default()).unwrap();
+        let mut image = out.into_inner();
+
+        // Block 0 swapped for one that only holds 100 bytes
+        let entry = u32::from_le_bytes(image[24..28].try_into().unwrap());
+        let pos = entry_pos(entry, header.align) as usize;
+        let short = lz4::compress(&[7; 100], 1).unwrap();
+        image[pos..pos + short.len()].copy_from_slice(&short);
+
+        let mut cso = CsoFile::from_reader(Cursor::new(image.clone())).unwrap();
+        assert!(cso.read_block(0).is_err());
+        let mut read = Vec::new();
+        assert!(CsoReader::new(CsoFile::from_reader(Cursor::new(image)).unwrap()).read_to_end(&mut read).is_err());
+    }
+
+    #[test]
+    fn stored_images_hold_no_compressed_blocks() {
+        let data = patterned(30_000);
+        let mut cso = read_back(&data, &CompressOptions { store: true, ..CompressOptions::default() });
+        assert_eq!(cso.compressed_blocks(), 0);
+        let mut read = Vec::new();
+        for block in 0..cso.header().total_blocks {
+            read.extend(cso.read_block(block).unwrap());
+        }
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn failures_say_which_file() {
+        let dir = Scratch::new("errors");
+        let mut out = Cursor::new(Vec::new());
+        encode_image(&patterned(5000), &mut out, &CompressOptions::default()).unwrap();
+        fs::write(dir.join("game.1.cso"), out.into_inner()).unwrap();
+        fs::write(dir.join("game.iso"), b"keep").unwrap();
+        let failed_at = |e: &Error| match XcsoError::of(e) {
+            Some(XcsoError::Io { path, .. }) => path.clone(),
+            _ => None,
+        };
+
+        let e = decompress_cso(&dir.join("game.1.cso"), &dir.join("game.iso")).unwrap_err();
+        assert_eq!((e.kind(), failed_at(&e)), (ErrorKind::AlreadyExists, Some(dir.join("game.iso"))));
+        let e = CsoFile::open(&dir.join("missing.cso")).err().unwrap();
+        assert_eq!((e.kind(), failed_at(&e)), (ErrorKind::NotFound, Some(dir.join("missing.cso"))));
+        let e = open_uncompressed(&dir.join("missing.iso")).err().unwrap();
+        assert_eq!(failed_at(&e), Some(dir.join("missing.iso")));
+
+        let mut cso = CsoFile::open(&dir.join("game.1.cso")).unwrap();
+        let e = cso.game_partition_offset().unwrap_err();
+        assert!(matches!(XcsoError::of(&e), Some(XcsoError::UnknownFormat { .. })));
+    }
+}
@@ -0,0 +1,696 @@
+//! Raw DEFLATE (RFC 1951) streams, the block encoding of CSO v1 images.
+//!
+//! Blocks are small, so the compressor keeps things simple: hash chain
+//! matching over the whole block followed by a single Huffman coded block,
+//! using whichever of the fixed or a custom code comes out smaller.
+
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::io::{Error, ErrorKind};
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+const NO_POS: u32 = u32::MAX;
+
+const END_OF_BLOCK: usize = 256;
+const LIT_CODES: usize = 286;
+const DIST_CODES: usize = 30;
+const MAX_BITS: usize = 15;
+const MAX_CLEN_BITS: usize = 7;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+// Order the code length code lengths are sent in
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Highest level that changes anything; higher levels are capped to it.
+pub const MAX_LEVEL: u32 = 9;
+
+/// A literal byte, or a back reference `dist` bytes back when `dist` is set.
+#[derive(Copy, Clone)]
+struct Token {
+    len: u16,
+    dist: u16,
+}
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut lit = vec![8u8; 288];
+    lit[144..256].fill(9);
+    lit[256..280].fill(7);
+    (lit, vec![5u8; 30])
+}
+
+fn length_code(len: usize) -> usize {
+    LENGTH_BASE.partition_point(|&b| b as usize <= len) - 1
+}
+
+fn dist_code(dist: usize) -> usize {
+    DIST_BASE.partition_point(|&b| b as usize <= dist) - 1
+}
+
+// Hash chain match finder
+struct Matcher<'a> {
+    src: &'a [u8],
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    max_chain: usize,
+}
+
+impl<'a> Matcher<'a> {
+    fn hash(&self, i: usize) -> usize {
+        let v = (self.src[i] as u32) << 16 | (self.src[i + 1] as u32) << 8 | self.src[i + 2] as u32;
+        (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, i: usize) {
+        if i + MIN_MATCH <= self.src.len() {
+            let h = self.hash(i);
+            self.prev[i] = self.head[h];
+            self.head[h] = i as u32;
+        }
+    }
+
+    /// Longest earlier match for the bytes at `i`, as `(len, dist)`.
+    fn longest(&self, i: usize) -> Option<(usize, usize)> {
+        let src = self.src;
+        let max_len = MAX_MATCH.min(src.len() - i);
+        if max_len < MIN_MATCH {
+            return None;
+        }
+
+        let mut best = (MIN_MATCH - 1, 0);
+        let mut cand = self.head[self.hash(i)];
+        let mut chain = self.max_chain;
+        while cand != NO_POS && chain > 0 {
+            let c = cand as usize;
+            if i - c > WINDOW {
+                break;
+            }
+            if src[c + best.0] == src[i + best.0] {
+                let len = src[c..c + max_len].iter().zip(&src[i..i + max_len]).take_while(|(a, b)| a == b).count();
+                if len > best.0 {
+                    best = (len, i - c);
+                    if len == max_len {
+                        break;
+                    }
+                }
+            }
+            cand = self.prev[c];
+            chain -= 1;
+        }
+
+        (best.0 >= MIN_MATCH).then_some(best)
+    }
+}
+
+fn find_tokens(src: &[u8], level: u32) -> Vec<Token> {
+    let level = level.clamp(1, MAX_LEVEL) as usize;
+    let max_chain = [4, 8, 16, 32, 64, 128, 256, 1024, 4096][level - 1];
+    let lazy = level >= 4;
+
+    let mut m = Matcher { src, head: vec![NO_POS; 1 << HASH_BITS], prev: vec![NO_POS; src.len()], max_chain };
+    let mut tokens = Vec::with_capacity(src.len() / 2);
+    let mut i = 0;
+    while i < src.len() {
+        let found = m.longest(i);
+        m.insert(i);
+
+        let (len, dist) = match found {
+            Some(found) => found,
+            None => {
+                tokens.push(Token { len: src[i] as u16, dist: 0 });
+                i += 1;
+                continue;
+            },
+        };
+
+        // Put off the match if the next byte starts a longer one
+        if lazy && len < MAX_MATCH && m.longest(i + 1).is_some_and(|(next, _)| next > len) {
+            tokens.push(Token { len: src[i] as u16, dist: 0 });
+            i += 1;
+            continue;
+        }
+
+        tokens.push(Token { len: len as u16, dist: dist as u16 });
+        for j in i + 1..i + len {
+            m.insert(j);
+        }
+        i += len;
+    }
+    tokens
+}
+
+/// Builds length limited Huffman code lengths for `freqs`.
+fn code_lengths(freqs: &[u32], limit: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let mut used: Vec<usize> = (0..freqs.len()).filter(|&s| freqs[s] > 0).collect();
+    match used.len() {
+        0 => return lengths,
+        1 => {
+            lengths[used[0]] = 1;
+            return lengths;
+        },
+        _ => {},
+    }
+
+    // Plain Huffman tree first, tracking each node's parent
+    let mut parent = vec![0usize; used.len() * 2 - 1];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> =
+        used.iter().enumerate().map(|(n, &s)| Reverse((freqs[s] as u64, n))).collect();
+    let mut next = used.len();
+    while heap.len() > 1 {
+        let Reverse((fa, a)) = heap.pop().unwrap();
+        let Reverse((fb, b)) = heap.pop().unwrap();
+        parent[a] = next;
+        parent[b] = next;
+        heap.push(Reverse((fa + fb, next)));
+        next += 1;
+    }
+
+    let root = next - 1;
+    let mut depth = vec![0usize; next];
+    for n in (0..root).rev() {
+        depth[n] = depth[parent[n]] + 1;
+    }
+
+    // Then squeeze anything too long under the limit, keeping the code
+    // complete, and hand the longest codes to the rarest symbols
+    let mut count = vec![0usize; used.len().max(limit) + 1];
+    for n in 0..used.len() {
+        count[depth[n].min(limit)] += 1;
+    }
+    let mut total: usize = (1..=limit).map(|l| count[l] << (limit - l)).sum();
+    while total > 1 << limit {
+        count[limit] -= 1;
+        for l in (1..limit).rev() {
+            if count[l] > 0 {
+                count[l] -= 1;
+                count[l + 1] += 2;
+                break;
+            }
+        }
+        total -= 1;
+    }
+
+    used.sort_by_key(|&s| freqs[s]);
+    let mut symbols = used.into_iter();
+    for l in (1..=limit).rev() {
+        for _ in 0..count[l] {
+            lengths[symbols.next().unwrap()] = l as u8;
+        }
+    }
+    lengths
+}
+
+/// Canonical codes for `lengths`, bit reversed as DEFLATE sends them.
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut count = [0u16; MAX_BITS + 1];
+    for &l in lengths.iter() {
+        count[l as usize] += 1;
+    }
+    count[0] = 0;
+
+    let mut next = [0u16; MAX_BITS + 2];
+    for bits in 1..=MAX_BITS {
+        next[bits + 1] = (next[bits] + count[bits]) << 1;
+    }
+
+    lengths.iter().map(|&l| {
+        if l == 0 {
+            return 0;
+        }
+        let code = next[l as usize];
+        next[l as usize] += 1;
+        code.reverse_bits() >> (16 - l)
+    }).collect()
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// Run length encodes the code lengths of a dynamic block header into
+/// `(symbol, extra bits value)` pairs.
+fn encode_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let l = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&x| x == l).count();
+        i += run;
+
+        let mut left = run;
+        if l == 0 {
+            while left >= 11 {
+                let n = left.min(138);
+                out.push((18, (n - 11) as u8));
+                left -= n;
+            }
+            if left >= 3 {
+                out.push((17, (left - 3) as u8));
+                left = 0;
+            }
+        } else {
+            out.push((l, 0));
+            left -= 1;
+            while left >= 3 {
+                let n = left.min(6);
+                out.push((16, (n - 3) as u8));
+                left -= n;
+            }
+        }
+        out.extend(std::iter::repeat_n((l, 0), left));
+    }
+    out
+}
+
+fn clen_extra_bits(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Bits needed to send `tokens` with the given code lengths.
+fn data_cost(lit_freqs: &[u32], dist_freqs: &[u32], lit: &[u8], dist: &[u8]) -> u64 {
+    let lit_bits: u64 = lit_freqs.iter().enumerate().map(|(s, &f)| {
+        let extra = if s > END_OF_BLOCK { LENGTH_EXTRA[s - 257] as u64 } else { 0 };
+        f as u64 * (lit[s] as u64 + extra)
+    }).sum();
+    let dist_bits: u64 = dist_freqs.iter().enumerate().
+        map(|(d, &f)| f as u64 * (dist[d] as u64 + DIST_EXTRA[d] as u64)).
+        sum();
+    lit_bits + dist_bits
+}
+
+fn write_tokens(w: &mut BitWriter, tokens: &[Token], lit: &[u8], dist: &[u8]) {
+    let lit_codes = canonical_codes(lit);
+    let dist_codes = canonical_codes(dist);
+
+    for t in tokens.iter() {
+        if t.dist == 0 {
+            let s = t.len as usize;
+            w.write(lit_codes[s] as u32, lit[s] as u32);
+            continue;
+        }
+
+        let len = t.len as usize;
+        let lc = length_code(len);
+        w.write(lit_codes[257 + lc] as u32, lit[257 + lc] as u32);
+        w.write((len - LENGTH_BASE[lc] as usize) as u32, LENGTH_EXTRA[lc] as u32);
+
+        let d = t.dist as usize;
+        let dc = dist_code(d);
+        w.write(dist_codes[dc] as u32, dist[dc] as u32);
+        w.write((d - DIST_BASE[dc] as usize) as u32, DIST_EXTRA[dc] as u32);
+    }
+    w.write(lit_codes[END_OF_BLOCK] as u32, lit[END_OF_BLOCK] as u32);
+}
+
+/// Compresses `src` into a raw DEFLATE stream. Levels run from 1 to
+/// `MAX_LEVEL`; anything outside that range is clamped.
+pub fn compress(src: &[u8], level: u32) -> Result<Vec<u8>, Error> {
+    let tokens = find_tokens(src, level);
+
+    let mut lit_freqs = vec![0u32; LIT_CODES];
+    let mut dist_freqs = vec![0u32; DIST_CODES];
+    for t in tokens.iter() {
+        if t.dist == 0 {
+            lit_freqs[t.len as usize] += 1;
+        } else {
+            lit_freqs[257 + length_code(t.len as usize)] += 1;
+            dist_freqs[dist_code(t.dist as usize)] += 1;
+        }
+    }
+    lit_freqs[END_OF_BLOCK] = 1;
+
+    // Custom code, sent in the block header
+    let lit = code_lengths(&lit_freqs, MAX_BITS);
+    let mut dist = code_lengths(&dist_freqs, MAX_BITS);
+    if dist.iter().all(|&l| l == 0) {
+        // At least one distance code has to be described, even if unused
+        dist[0] = 1;
+    }
+
+    let hlit = (257..=LIT_CODES).rev().find(|&n| lit[n - 1] != 0).unwrap_or(257).max(257);
+    let hdist = (1..=DIST_CODES).rev().find(|&n| dist[n - 1] != 0).unwrap_or(1);
+    let rle = encode_lengths(&[&lit[..hlit], &dist[..hdist]].concat());
+
+    let mut clen_freqs = vec![0u32; 19];
+    for &(s, _) in rle.iter() {
+        clen_freqs[s as usize] += 1;
+    }
+    let clen = code_lengths(&clen_freqs, MAX_CLEN_BITS);
+    let hclen = (4..=19).rev().find(|&n| clen[CLEN_ORDER[n - 1]] != 0).unwrap_or(4);
+
+    let header_cost = 14 + 3 * hclen as u64 + rle.iter().
+        map(|&(s, _)| clen[s as usize] as u64 + clen_extra_bits(s) as u64).
+        sum::<u64>();
+    let dynamic_cost = header_cost + data_cost(&lit_freqs, &dist_freqs, &lit, &dist);
+
+    let (fixed_lit, fixed_dist) = fixed_lengths();
+    let fixed_cost = data_cost(&lit_freqs, &dist_freqs, &fixed_lit, &fixed_dist);
+
+    let mut w = BitWriter { out: Vec::with_capacity(src.len() / 2), bits: 0, count: 0 };
+    // Final block
+    w.write(1, 1);
+    if fixed_cost <= dynamic_cost {
+        w.write(1, 2);
+        write_tokens(&mut w, &tokens, &fixed_lit, &fixed_dist);
+        return Ok(w.finish());
+    }
+
+    w.write(2, 2);
+    w.write((hlit - 257) as u32, 5);
+    w.write((hdist - 1) as u32, 5);
+    w.write((hclen - 4) as u32, 4);
+    for &s in CLEN_ORDER[..hclen].iter() {
+        w.write(clen[s] as u32, 3);
+    }
+
+    let clen_codes = canonical_codes(&clen);
+    for &(s, extra) in rle.iter() {
+        w.write(clen_codes[s as usize] as u32, clen[s as usize] as u32);
+        w.write(extra as u32, clen_extra_bits(s));
+    }
+
+    write_tokens(&mut w, &tokens, &lit, &dist);
+    Ok(w.finish())
+}
+
+fn corrupt() -> Error {
+    Error::new(ErrorKind::InvalidData, "corrupt deflate block")
+}
+
+struct BitReader<'a> {
+    src: &'a [u8],
+    pos: usize,
+    bits: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, n: u32) -> Result<u32, Error> {
+        while self.count < n {
+            let byte = *self.src.get(self.pos).ok_or_else(corrupt)?;
+            self.bits |= (byte as u64) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = (self.bits & ((1u64 << n) - 1)) as u32;
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        let drop = self.count % 8;
+        self.bits >>= drop;
+        self.count -= drop;
+    }
+}
+
+// Canonical code, decoded one bit at a time
+struct Huffman {
+    count: [u16; MAX_BITS + 1],
+    symbol: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, Error> {
+        let mut count = [0u16; MAX_BITS + 1];
+        for &l in lengths.iter() {
+            count[l as usize] += 1;
+        }
+
+        // Codes may be incomplete, but never over-subscribed
+        let mut left: i32 = 1;
+        for &c in count[1..].iter() {
+            left = (left << 1) - c as i32;
+            if left < 0 {
+                return Err(corrupt());
+            }
+        }
+
+        let mut offset = [0u16; MAX_BITS + 2];
+        for l in 1..=MAX_BITS {
+            offset[l + 1] = offset[l] + count[l];
+        }
+        let mut symbol = vec![0u16; lengths.len()];
+        for (s, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbol[offset[l as usize] as usize] = s as u16;
+                offset[l as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { count, symbol })
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<usize, Error> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in self.count[1..].iter() {
+            code |= r.bits(1)? as i32;
+            let count = count as i32;
+            if code - count < first {
+                return Ok(self.symbol[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt())
+    }
+}
+
+/// Decompresses a raw DEFLATE stream expected to hold at most `size` bytes.
+/// Trailing bytes after the final block are ignored.
+pub fn decompress(src: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+    inflate(src, size).map(|(out, _)| out)
+}
+
+/// How many bytes at the start of `src` make up a DEFLATE stream holding
+/// exactly `size` bytes.
+pub fn stream_len(src: &[u8], size: usize) -> Result<usize, Error> {
+    match inflate(src, size)? {
+        (out, read) if out.len() == size => Ok(read),
+        _ => Err(corrupt()),
+    }
+}
+
+/// Decompresses the stream at the start of `src`, returning what it holds
+/// and how many bytes of `src` it took up.
+fn inflate(src: &[u8], size: usize) -> Result<(Vec<u8>, usize), Error> {
+    let mut r = BitReader { src, pos: 0, bits: 0, count: 0 };
+    let mut out: Vec<u8> = Vec::with_capacity(size);
+
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let len = r.bits(16)? as usize;
+                if r.bits(16)? as usize != !len & 0xFFFF {
+                    return Err(corrupt());
+                }
+                if out.len() + len > size {
+                    return Err(corrupt());
+                }
+                for _ in 0..len {
+                    out.push(r.bits(8)? as u8);
+                }
+            },
+            1 => {
+                let (lit, dist) = fixed_lengths();
+                inflate_block(&mut r, &Huffman::new(&lit)?, &Huffman::new(&dist)?, &mut out, size)?;
+            },
+            2 => {
+                let hlit = r.bits(5)? as usize + 257;
+                let hdist = r.bits(5)? as usize + 1;
+                let hclen = r.bits(4)? as usize + 4;
+                if hlit > LIT_CODES {
+                    return Err(corrupt());
+                }
+
+                let mut clen = [0u8; 19];
+                for &s in CLEN_ORDER[..hclen].iter() {
+                    clen[s] = r.bits(3)? as u8;
+                }
+                let clen = Huffman::new(&clen)?;
+
+                let mut lengths = Vec::with_capacity(hlit + hdist);
+                while lengths.len() < hlit + hdist {
+                    let (value, repeat) = match clen.decode(&mut r)? {
+                        s @ 0..=15 => (s as u8, 1),
+                        16 => (*lengths.last().ok_or_else(corrupt)?, 3 + r.bits(2)? as usize),
+                        17 => (0, 3 + r.bits(3)? as usize),
+                        _ => (0, 11 + r.bits(7)? as usize),
+                    };
+                    if lengths.len() + repeat > hlit + hdist {
+                        return Err(corrupt());
+                    }
+                    lengths.extend(std::iter::repeat_n(value, repeat));
+                }
+                if lengths[END_OF_BLOCK] == 0 {
+                    return Err(corrupt());
+                }
+
+                let lit = Huffman::new(&lengths[..hlit])?;
+                let dist = Huffman::new(&lengths[hlit..])?;
+                inflate_block(&mut r, &lit, &dist, &mut out, size)?;
+            },
+            _ => return Err(corrupt()),
+        }
+
+        if last {
+            return Ok((out, r.pos));
+        }
+    }
+}
+
+fn inflate_block(
+    r: &mut BitReader,
+    lit: &Huffman,
+    dist: &Huffman,
+    out: &mut Vec<u8>,
+    size: usize,
+) -> Result<(), Error> {
+    loop {
+        let s = lit.decode(r)?;
+        if s < END_OF_BLOCK {
+            if out.len() >= size {
+                return Err(corrupt());
+            }
+            out.push(s as u8);
+            continue;
+        }
+        if s == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let lc = s - 257;
+        if lc >= LENGTH_BASE.len() {
+            return Err(corrupt());
+        }
+        let len = LENGTH_BASE[lc] as usize + r.bits(LENGTH_EXTRA[lc] as u32)? as usize;
+
+        let dc = dist.decode(r)?;
+        if dc >= DIST_CODES {
+            return Err(corrupt());
+        }
+        let d = DIST_BASE[dc] as usize + r.bits(DIST_EXTRA[dc] as u32)? as usize;
+        if d > out.len() || out.len() + len > size {
+            return Err(corrupt());
+        }
+
+        let start = out.len() - d;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::random;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // Streams from zlib, with a stored, a fixed and a dynamic Huffman block
+    const STORED: [u8; 11] = [1, 6, 0, 249, 255, b's', b't', b'o', b'r', b'e', b'd'];
+    const FIXED: [u8; 11] = [203, 72, 205, 201, 201, 215, 81, 200, 64, 162, 0];
+    const DYNAMIC: &str = "b58cc911833010045399005c24e024fc2001c9e8b28516dda0e8bdb84881c7bcba7b66ab10ab7b7f2113f5004d3b\
+                           3e75dd32a8a984c2d88b7160213361be4d7e09f6d60392a5ee8a85764d311a2ac0bb5829716bf21379b32eec200d\
+                           e9cf265691ca78305d8c3a1f1af5e97f7eed07";
+
+    fn dynamic_text() -> Vec<u8> {
+        let mut text = b"The quick brown fox jumps over the lazy dog. ".repeat(3);
+        text.extend_from_slice(b"Pack my box with five dozen liquor jugs; ");
+        text.extend_from_slice(b"sphinx of black quartz, judge my vow. the the the");
+        text
+    }
+
+    #[test]
+    fn inflates_known_streams() {
+        assert_eq!(decompress(&STORED, 6).unwrap(), b"stored");
+        assert_eq!(decompress(&FIXED, 19).unwrap(), b"hello, hello, hello");
+        let text = dynamic_text();
+        assert_eq!(decompress(&unhex(DYNAMIC), text.len()).unwrap(), text);
+    }
+
+    #[test]
+    fn padding_after_the_stream_is_ignored() {
+        let mut stream = FIXED.to_vec();
+        stream.extend_from_slice(&[0; 4]);
+        assert_eq!(decompress(&stream, 19).unwrap(), b"hello, hello, hello");
+    }
+
+    #[test]
+    fn damaged_streams_are_errors() {
+        let stream = unhex(DYNAMIC);
+        assert!(decompress(&stream[..stream.len() / 2], 225).is_err());
+        assert!(decompress(&[0xff; 8], 100).is_err());
+    }
+
+    #[test]
+    fn stream_len_finds_the_end() {
+        let text = dynamic_text();
+        let stream = unhex(DYNAMIC);
+        assert_eq!(stream_len(&stream, text.len()).unwrap(), stream.len());
+        let mut padded = FIXED.to_vec();
+        padded.extend_from_slice(&[0; 4]);
+        assert_eq!(stream_len(&padded, 19).unwrap(), FIXED.len());
+        assert!(stream_len(&FIXED, 20).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut data = dynamic_text().repeat(20);
+        data.extend(random(3000, 1));
+        data.extend(vec![0; 5000]);
+        for level in 1..=MAX_LEVEL {
+            let stream = compress(&data, level).unwrap();
+            assert!(stream.len() < data.len());
+            assert_eq!(decompress(&stream, data.len()).unwrap(), data, "level {}", level);
+        }
+        let empty = compress(&[], MAX_LEVEL).unwrap();
+        assert_eq!(decompress(&empty, 0).unwrap(), b"");
+    }
+}
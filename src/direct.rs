@@ -0,0 +1,205 @@
+//! Writes outputs around the operating system's file cache, for converting
+//! a whole library onto an external drive without the cache filling up with
+//! data that's never read again.
+//!
+//! Unbuffered files only take whole sectors, written from memory aligned
+//! to them, so everything goes through a sector aligned buffer. The sector
+//! a write stops part way into is written padded and kept in the buffer to
+//! be written again once there's more of it, and the file is cut back to
+//! the true length on every flush.
+
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Read, Seek, Write};
+use std::path::Path;
+
+use crate::error::XcsoError;
+
+/// What writes are aligned to, which suits drives with 512 and 4096 byte
+/// sectors alike.
+pub const SECTOR_ALIGN: usize = 4096;
+
+/// Opens `fp` for unbuffered reading and writing, creating it if needed and
+/// emptying it with `truncate`.
+#[cfg(target_os = "linux")]
+fn open_unbuffered(fp: &Path, truncate: bool) -> Result<File, Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    File::options().read(true).write(true).create(true).truncate(truncate).custom_flags(libc::O_DIRECT).open(fp)
+}
+
+/// Opens `fp` for unbuffered reading and writing, creating it if needed and
+/// emptying it with `truncate`. Writes also go straight through to the drive
+/// rather than waiting in its cache.
+#[cfg(windows)]
+fn open_unbuffered(fp: &Path, truncate: bool) -> Result<File, Error> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_NO_BUFFERING: u32 = 0x20000000;
+    const FILE_FLAG_WRITE_THROUGH: u32 = 0x80000000;
+    File::options().
+        read(true).
+        write(true).
+        create(true).
+        truncate(truncate).
+        custom_flags(FILE_FLAG_NO_BUFFERING | FILE_FLAG_WRITE_THROUGH).
+        open(fp)
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn open_unbuffered(_fp: &Path, _truncate: bool) -> Result<File, Error> {
+    Err(Error::new(ErrorKind::Unsupported, "unbuffered output isn't supported on this system"))
+}
+
+/// A file written without the file cache, which otherwise behaves like a
+/// `BufWriter` around it.
+pub struct DirectWriter {
+    file: File,
+    // Holds `cap` bytes from `start` on, aligned to `SECTOR_ALIGN`
+    mem: Vec<u8>,
+    start: usize,
+    cap: usize,
+    /// Where in the file the buffer starts, always on a sector.
+    buf_pos: u64,
+    /// Bytes of the buffer that hold data.
+    len: usize,
+    /// Where the next write goes, somewhere in the buffer.
+    pos: u64,
+    /// Length of the file, not counting the padding of the last sector.
+    end: u64,
+}
+
+impl DirectWriter {
+    /// Creates `fp`, or empties it if it exists, for writing through a
+    /// buffer of at least `buffer_size` bytes.
+    pub fn create(fp: &Path, buffer_size: usize) -> Result<DirectWriter, Error> {
+        DirectWriter::open(fp, buffer_size, true)
+    }
+
+    /// Opens `fp`, which has to exist already, keeping what's in it.
+    pub fn open_existing(fp: &Path, buffer_size: usize) -> Result<DirectWriter, Error> {
+        DirectWriter::open(fp, buffer_size, false)
+    }
+
+    fn open(fp: &Path, buffer_size: usize, truncate: bool) -> Result<DirectWriter, Error> {
+        let file = open_unbuffered(fp, truncate).map_err(|e| XcsoError::io(fp, e))?;
+        let end = file.metadata()?.len();
+        let cap = buffer_size.max(SECTOR_ALIGN).next_multiple_of(SECTOR_ALIGN);
+        let mem = vec![0; cap + SECTOR_ALIGN];
+        let start = mem.as_ptr().align_offset(SECTOR_ALIGN);
+        let mut writer = DirectWriter { file, mem, start, cap, buf_pos: 0, len: 0, pos: 0, end };
+        writer.load(0)?;
+        Ok(writer)
+    }
+
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// Cuts the file down to (or extends it to) `len` bytes.
+    pub fn set_len(&mut self, len: u64) -> Result<(), Error> {
+        let pos = self.pos;
+        self.write_out()?;
+        self.file.set_len(len)?;
+        self.end = len;
+        self.load(pos.min(len))
+    }
+
+    fn buf(&mut self) -> &mut [u8] {
+        &mut self.mem[self.start..self.start + self.cap]
+    }
+
+    /// Writes the buffer to the file, padding it out to whole sectors.
+    fn write_out(&mut self) -> Result<(), Error> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let (len, padded) = (self.len, self.len.next_multiple_of(SECTOR_ALIGN));
+        self.buf()[len..padded].fill(0);
+        self.file.seek(io::SeekFrom::Start(self.buf_pos))?;
+        let (start, buf_pos) = (self.start, self.buf_pos);
+        self.file.write_all(&self.mem[start..start + padded])?;
+        self.end = self.end.max(buf_pos + len as u64);
+        Ok(())
+    }
+
+    /// Reads the next sector of the file into the buffer, if the buffer
+    /// ends on a sector and the file goes on past it. Returns whether there
+    /// was anything to read.
+    fn read_sector(&mut self) -> Result<bool, Error> {
+        let at = self.buf_pos + self.len as u64;
+        if !self.len.is_multiple_of(SECTOR_ALIGN) || at >= self.end || self.len == self.cap {
+            return Ok(false);
+        }
+        self.file.seek(io::SeekFrom::Start(at))?;
+        let from = self.start + self.len;
+        // Unbuffered reads have to start on a sector too, so this is one
+        // read, which only comes up short at the end of the file
+        let read = self.file.read(&mut self.mem[from..from + SECTOR_ALIGN])?;
+        self.len += read.min((self.end - at) as usize);
+        Ok(read > 0)
+    }
+
+    /// Points the buffer at the sector holding `pos`, reading in what the
+    /// file already has there.
+    fn load(&mut self, pos: u64) -> Result<(), Error> {
+        self.buf_pos = pos - pos % SECTOR_ALIGN as u64;
+        self.len = 0;
+        self.read_sector()?;
+        // Anything from the end of the file up to `pos` reads as zeros
+        let at = (pos - self.buf_pos) as usize;
+        let len = self.len;
+        if at > len {
+            self.buf()[len..at].fill(0);
+        }
+        self.len = self.len.max(at);
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let at = (self.pos - self.buf_pos) as usize;
+        let n = data.len().min(self.cap - at);
+        // Sectors the write only covers part of keep the rest of what's
+        // in the file
+        while self.len < at + n && self.read_sector()? {}
+        self.buf()[at..at + n].copy_from_slice(&data[..n]);
+        self.pos += n as u64;
+        self.len = self.len.max(at + n);
+        // A full buffer is all whole sectors, so nothing has to be kept back
+        if self.len == self.cap && self.pos == self.buf_pos + self.cap as u64 {
+            self.write_out()?;
+            self.buf_pos += self.cap as u64;
+            self.len = 0;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_out()?;
+        // Without the padding of the last sector
+        if self.file.metadata()?.len() > self.end {
+            self.file.set_len(self.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for DirectWriter {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let end = self.end.max(self.buf_pos + self.len as u64);
+        let to = match pos {
+            io::SeekFrom::Start(x) => Some(x),
+            io::SeekFrom::End(x) => end.checked_add_signed(x),
+            io::SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        let to = to.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        // Moving within the data already buffered needs no I/O
+        if to >= self.buf_pos && to <= self.buf_pos + self.len as u64 && to < self.buf_pos + self.cap as u64 {
+            self.pos = to;
+            return Ok(to);
+        }
+        self.write_out()?;
+        self.load(to)?;
+        Ok(to)
+    }
+}
@@ -0,0 +1,154 @@
+//! Expands the inputs given on the command line into the list of files to
+//! convert. Directories are walked recursively and `*`, `?` and `**` globs
+//! are matched here, so neither needs help from the shell.
+//!
+//! Entries are visited in name order, so the same inputs always expand to
+//! the same list.
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use xcso::http::is_url;
+
+fn has_glob(s: &OsStr) -> bool {
+    s.to_string_lossy().contains(['*', '?'])
+}
+
+/// Matches a single path component against a pattern where `*` stands for
+/// any run of characters and `?` for exactly one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was seen, and how much of the name it has taken
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Lists the entries of `dir` sorted by name, with an empty path meaning the
+/// current directory.
+fn sorted_entries(dir: &Path) -> Vec<PathBuf> {
+    let read_dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut entries: Vec<PathBuf> = match fs::read_dir(read_dir) {
+        Ok(entries) => entries.
+            filter_map(|x| x.ok()).
+            map(|x| dir.join(x.file_name())).
+            collect(),
+        Err(_) => return Vec::new(),
+    };
+    entries.sort();
+    entries
+}
+
+/// Adds every file below `dir` that `keep` accepts.
+fn walk_dir(dir: &Path, keep: &dyn Fn(&Path) -> bool, out: &mut Vec<PathBuf>) {
+    for entry in sorted_entries(dir) {
+        // Symlinked directories are not followed, which keeps loops out
+        match fs::symlink_metadata(&entry) {
+            Ok(meta) if meta.is_dir() => walk_dir(&entry, keep, out),
+            Ok(_) if keep(&entry) => out.push(entry),
+            _ => {},
+        }
+    }
+}
+
+/// Matches the remaining pattern components `rest` below `dir`.
+fn walk_glob(dir: &Path, rest: &[OsString], keep: &dyn Fn(&Path) -> bool, out: &mut Vec<PathBuf>) {
+    let Some((first, rest)) = rest.split_first() else {
+        if dir.is_dir() {
+            walk_dir(dir, keep, out);
+        } else if dir.is_file() {
+            out.push(dir.to_owned());
+        }
+        return;
+    };
+
+    if first == "**" {
+        // Zero or more directories
+        walk_glob(dir, rest, keep, out);
+        for entry in sorted_entries(dir) {
+            if fs::symlink_metadata(&entry).is_ok_and(|x| x.is_dir()) {
+                walk_glob(&entry, &[&["**".into()], rest].concat(), keep, out);
+            }
+        }
+    } else if has_glob(first) {
+        for entry in sorted_entries(dir) {
+            let name = entry.file_name().unwrap_or_default().to_string_lossy();
+            if glob_match(&first.to_string_lossy(), &name) {
+                walk_glob(&entry, rest, keep, out);
+            }
+        }
+    } else {
+        walk_glob(&dir.join(first), rest, keep, out);
+    }
+}
+
+/// Expands `inputs` into a list of files. Plain files and URLs are passed
+/// through, directories contribute the files below them that `keep`
+/// accepts, and globs contribute whatever they match (walking any
+/// directories they match the same way). Each file is listed once, in the order it was first found.
+/// Inputs that turn up nothing are returned in `missing`.
+pub fn expand(inputs: &[PathBuf], keep: &dyn Fn(&Path) -> bool) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut files = Vec::new();
+    let mut missing = Vec::new();
+
+    for input in inputs.iter() {
+        let path = input.as_path();
+        let before = files.len();
+
+        if is_url(path) {
+            files.push(input.clone());
+        } else if path.is_dir() {
+            walk_dir(path, keep, &mut files);
+        } else if has_glob(path.as_os_str()) && !path.exists() {
+            // Everything up to the first component with a wildcard in it is
+            // used as is
+            let mut base = PathBuf::new();
+            let mut rest: Vec<OsString> = Vec::new();
+            for component in path.components() {
+                let part = component.as_os_str();
+                if rest.is_empty() && !(matches!(component, Component::Normal(_)) && has_glob(part)) {
+                    base.push(component);
+                } else {
+                    rest.push(part.to_owned());
+                }
+            }
+            walk_glob(&base, &rest, keep, &mut files);
+        } else {
+            files.push(input.clone());
+        }
+
+        if files.len() == before {
+            missing.push(input.clone());
+        }
+    }
+
+    // The same file can be reached through different spellings of its path
+    let mut seen = HashSet::new();
+    files.retain(|x| seen.insert(fs::canonicalize(x).unwrap_or_else(|_| x.clone())));
+    (files, missing)
+}
@@ -0,0 +1,141 @@
+//! The ways the library itself fails, as something a program can match on
+//! rather than a message to pick apart. Functions return `io::Error` as
+//! they always have, so they still compose with `?` on reads and writes,
+//! and an error of their own is carried inside it: `XcsoError::of` looks
+//! at it, and `XcsoError::from` takes it back out. Anything else comes
+//! out as `Io`.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use crate::cso::{max_addressable, Method};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum XcsoError {
+    /// The file isn't what it had to be, as in "not a CSO or ZSO image".
+    UnknownFormat { expected: &'static str },
+    /// The `default.xbe` of an image is damaged or isn't an XBE at all.
+    NotAnXbe { reason: String },
+    /// The index gives a block a size it can't have.
+    BadBlock { block: usize },
+    /// A block, or the file it's in, ends before its data does.
+    ShortRead { block: usize },
+    /// A block doesn't match its checksum in the side index.
+    ChecksumMismatch { block: usize },
+    /// No part the image could be split into fits in what the index can
+    /// point at with `align`.
+    SplitLimit { align: u8 },
+    /// A block couldn't be compressed with `method`.
+    CompressFailed { method: Method },
+    /// The file at `path` changed while it was being read.
+    Changed { path: PathBuf },
+    /// A web server answered with `status` rather than the image.
+    HttpStatus { host: String, status: u32 },
+    /// The certificate `host` gave for a TLS connection isn't trusted, or
+    /// isn't for `host`.
+    Untrusted { host: String, reason: String },
+    /// A TLS connection to `host` couldn't be made or broke down.
+    Tls { host: String, reason: String },
+    /// An FTP server refused `command` with `code`. From 500 up, trying
+    /// again won't go any differently.
+    FtpRefused { command: String, code: u32, reply: String },
+    /// A program that was run, such as `fusermount`, failed.
+    Command { command: String, status: ExitStatus },
+    /// The work was cancelled, or `interrupt` was called.
+    Interrupted,
+    /// Reading or writing failed, at `path` if it's known which file it was.
+    Io { path: Option<PathBuf>, source: io::Error },
+}
+
+impl XcsoError {
+    /// The error carried by `e`, if it's one of the library's own.
+    pub fn of(e: &io::Error) -> Option<&XcsoError> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    /// A failure reading or writing the file at `path`.
+    pub fn io(path: &Path, source: io::Error) -> XcsoError {
+        XcsoError::Io { path: Some(path.to_owned()), source }
+    }
+
+    /// The kind the error has as an `io::Error`.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            XcsoError::UnknownFormat { .. } |
+            XcsoError::NotAnXbe { .. } |
+            XcsoError::BadBlock { .. } |
+            XcsoError::ChecksumMismatch { .. } |
+            XcsoError::Changed { .. } => ErrorKind::InvalidData,
+            XcsoError::ShortRead { .. } => ErrorKind::UnexpectedEof,
+            XcsoError::SplitLimit { .. } => ErrorKind::InvalidInput,
+            XcsoError::HttpStatus { status: 401 | 403, .. } => ErrorKind::PermissionDenied,
+            XcsoError::HttpStatus { status: 404 | 410, .. } => ErrorKind::NotFound,
+            XcsoError::Untrusted { .. } => ErrorKind::PermissionDenied,
+            XcsoError::FtpRefused { code: 500.., .. } => ErrorKind::PermissionDenied,
+            XcsoError::CompressFailed { .. } |
+            XcsoError::HttpStatus { .. } |
+            XcsoError::Tls { .. } |
+            XcsoError::FtpRefused { .. } |
+            XcsoError::Command { .. } => ErrorKind::Other,
+            XcsoError::Interrupted => ErrorKind::Interrupted,
+            XcsoError::Io { source, .. } => source.kind(),
+        }
+    }
+}
+
+impl fmt::Display for XcsoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XcsoError::UnknownFormat { expected } => write!(f, "not {}", expected),
+            XcsoError::NotAnXbe { reason } => write!(f, "invalid XBE: {}", reason),
+            XcsoError::BadBlock { block } => write!(f, "block {} has an invalid size", block),
+            XcsoError::ShortRead { block } => write!(f, "block {} is cut short", block),
+            XcsoError::ChecksumMismatch { block } => write!(f, "block {} doesn't match its checksum", block),
+            XcsoError::SplitLimit { align } => write!(
+                f,
+                "alignment {} can only address {} bytes per file, which is not enough for this image",
+                align,
+                max_addressable(*align),
+            ),
+            XcsoError::CompressFailed { method } => write!(f, "{} compression failed", method.name()),
+            XcsoError::Changed { path } => write!(f, "{} changed while it was being read", path.display()),
+            XcsoError::HttpStatus { host, status } => write!(f, "{} answered with status {}", host, status),
+            XcsoError::Untrusted { host, reason } => write!(f, "the certificate of {} isn't trusted: {}", host, reason),
+            XcsoError::Tls { host, reason } => write!(f, "TLS connection to {} failed: {}", host, reason),
+            XcsoError::FtpRefused { command, code, reply } => {
+                write!(f, "FTP server refused {} ({} {})", command, code, reply)
+            },
+            XcsoError::Command { command, status } => write!(f, "{} failed ({})", command, status),
+            XcsoError::Interrupted => write!(f, "interrupted"),
+            XcsoError::Io { path: Some(path), source } => write!(f, "{}: {}", path.display(), source),
+            XcsoError::Io { path: None, source } => write!(f, "{}", source),
+        }
+    }
+}
+
+impl std::error::Error for XcsoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            XcsoError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<XcsoError> for io::Error {
+    fn from(e: XcsoError) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+impl From<io::Error> for XcsoError {
+    fn from(e: io::Error) -> XcsoError {
+        if XcsoError::of(&e).is_none() {
+            return XcsoError::Io { path: None, source: e };
+        }
+        *e.into_inner().unwrap().downcast().unwrap()
+    }
+}
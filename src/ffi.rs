@@ -0,0 +1,310 @@
+//! C bindings, built with the `ffi` feature, for game managers and other
+//! frontends that would rather link the library than run `make-xcso`. The
+//! declarations are in `include/xcso.h`.
+//!
+//! Every function returns `XCSO_OK` or an error code, and the message of
+//! the last error on the calling thread is kept for `xcso_last_error`. No
+//! panic crosses into C: one is reported as `XCSO_ERROR_PANIC`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io::{Error, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{ptr, thread};
+
+use indicatif::ProgressBar;
+
+use crate::compress::{compress_iso_with_progress, CompressOptions};
+use crate::cso::{Format, Method};
+use crate::decompress::{decompress_cso_with_progress, CsoFile};
+
+// How often progress callbacks are called
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+pub const XCSO_OK: c_int = 0;
+/// Reading or writing a file failed, or an image was damaged.
+pub const XCSO_ERROR_IO: c_int = -1;
+/// An argument was null, not UTF-8 or out of range.
+pub const XCSO_ERROR_INVALID: c_int = -2;
+/// The conversion was stopped, as by a signal.
+pub const XCSO_ERROR_INTERRUPTED: c_int = -3;
+pub const XCSO_ERROR_PANIC: c_int = -4;
+
+pub const XCSO_FORMAT_CSO: u32 = 0;
+pub const XCSO_FORMAT_ZSO: u32 = 1;
+pub const XCSO_METHOD_LZ4: u32 = 0;
+pub const XCSO_METHOD_DEFLATE: u32 = 1;
+
+/// Settings for `xcso_compress`, as filled in by `xcso_default_options`.
+/// `size` is the size of the struct the caller was built against, so that
+/// fields can be added at the end and options from an older header still
+/// told apart.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct XcsoOptions {
+    pub size: usize,
+    pub level: u32,
+    /// `XCSO_FORMAT_CSO` or `XCSO_FORMAT_ZSO`.
+    pub format: u32,
+    /// `XCSO_METHOD_LZ4` or `XCSO_METHOD_DEFLATE`.
+    pub method: u32,
+    pub block_size: u32,
+    pub align: u32,
+    /// Bytes a part may grow to, or 0 to never split.
+    pub split_size: u64,
+    /// Threads compressing blocks, or 0 for one for each core.
+    pub threads: u32,
+}
+
+/// Called with the bytes of the image done so far and how many there are
+/// in all, and the `user` pointer given with it.
+pub type XcsoProgress = Option<unsafe extern "C" fn(done: u64, total: u64, user: *mut c_void)>;
+
+/// What `xcso_info` found out about an image.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct XcsoImageInfo {
+    pub format: u32,
+    pub version: u32,
+    pub block_size: u32,
+    pub align: u32,
+    /// Size of the image uncompressed.
+    pub total_bytes: u64,
+    pub total_blocks: u64,
+    pub parts: u32,
+    /// Size of every part on disk, together.
+    pub stored_bytes: u64,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // A message with a nul in it would be cut short anyway
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|x| *x.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning its error or panic into a code and the last error.
+fn run(f: impl FnOnce() -> Result<(), Error>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => XCSO_OK,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            match e.kind() {
+                ErrorKind::InvalidInput => XCSO_ERROR_INVALID,
+                ErrorKind::Interrupted => XCSO_ERROR_INTERRUPTED,
+                _ => XCSO_ERROR_IO,
+            }
+        },
+        Err(_) => {
+            set_last_error("the conversion panicked".to_owned());
+            XCSO_ERROR_PANIC
+        },
+    }
+}
+
+/// Runs `f` on a thread of its own, calling `progress` on this one with how
+/// far it's got every `PROGRESS_INTERVAL` until it's done.
+fn with_progress(
+    progress: XcsoProgress,
+    user: *mut c_void,
+    f: impl FnOnce(&ProgressBar) -> Result<(), Error> + Send,
+) -> Result<(), Error> {
+    let Some(progress) = progress else {
+        return f(&ProgressBar::hidden());
+    };
+    let pb = ProgressBar::hidden();
+    let report = |pb: &ProgressBar| unsafe { progress(pb.position(), pb.length().unwrap_or(0), user) };
+    let caller = thread::current();
+    thread::scope(|s| {
+        let worker = s.spawn(|| {
+            let result = f(&pb);
+            caller.unpark();
+            result
+        });
+        while !worker.is_finished() {
+            report(&pb);
+            thread::park_timeout(PROGRESS_INTERVAL);
+        }
+        let result = worker.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        if result.is_ok() {
+            report(&pb);
+        }
+        result
+    })
+}
+
+/// # Safety
+///
+/// `path` must be null or point to a nul terminated string.
+unsafe fn path_arg(path: *const c_char, what: &str) -> Result<PathBuf, Error> {
+    if path.is_null() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("no {} given", what)));
+    }
+    match CStr::from_ptr(path).to_str() {
+        Ok(path) => Ok(PathBuf::from(path)),
+        Err(_) => Err(Error::new(ErrorKind::InvalidInput, format!("the {} isn't UTF-8", what))),
+    }
+}
+
+fn compress_options(opts: &XcsoOptions) -> Result<CompressOptions, Error> {
+    let invalid = |what: String| Error::new(ErrorKind::InvalidInput, what);
+    let format = match opts.format {
+        XCSO_FORMAT_CSO => Format::Cso,
+        XCSO_FORMAT_ZSO => Format::Zso,
+        x => return Err(invalid(format!("unknown format {}", x))),
+    };
+    let method = match opts.method {
+        XCSO_METHOD_LZ4 => Method::Lz4,
+        XCSO_METHOD_DEFLATE => Method::Deflate,
+        x => return Err(invalid(format!("unknown method {}", x))),
+    };
+    Ok(CompressOptions {
+        level: opts.level,
+        format,
+        method,
+        block_size: opts.block_size,
+        align: u8::try_from(opts.align).map_err(|_| invalid(format!("unsupported alignment {}", opts.align)))?,
+        split_size: (opts.split_size > 0).then_some(opts.split_size),
+        threads: (opts.threads > 0).then_some(opts.threads as usize),
+        ..CompressOptions::default()
+    })
+}
+
+/// Fills in `opts` with the settings `make-xcso` uses by default.
+///
+/// # Safety
+///
+/// `opts` must point to an `XcsoOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn xcso_default_options(opts: *mut XcsoOptions) -> c_int {
+    if opts.is_null() {
+        set_last_error("no options given".to_owned());
+        return XCSO_ERROR_INVALID;
+    }
+    let defaults = CompressOptions::default();
+    *opts = XcsoOptions {
+        size: size_of::<XcsoOptions>(),
+        level: defaults.level,
+        format: XCSO_FORMAT_CSO,
+        method: XCSO_METHOD_LZ4,
+        block_size: defaults.block_size,
+        align: defaults.align as u32,
+        split_size: defaults.split_size.unwrap_or(0),
+        threads: 0,
+    };
+    XCSO_OK
+}
+
+/// Compresses the ISO at `iso` into `dest`, which is the output's base
+/// name: the parts are written as `dest.1.cso`, `dest.2.cso` and so on.
+/// `opts` can be null for the defaults.
+///
+/// # Safety
+///
+/// `iso` and `dest` must point to nul terminated strings, and `opts` must be
+/// null or point to an `XcsoOptions` of at least the `size` it gives.
+#[no_mangle]
+pub unsafe extern "C" fn xcso_compress(iso: *const c_char, dest: *const c_char, opts: *const XcsoOptions) -> c_int {
+    xcso_compress_with_progress(iso, dest, opts, None, ptr::null_mut())
+}
+
+/// Same as `xcso_compress`, but calls `progress` every so often, on the
+/// calling thread, with how much of the ISO is done.
+///
+/// # Safety
+///
+/// As for `xcso_compress`, and `progress` must be null or safe to call with
+/// `user`.
+#[no_mangle]
+pub unsafe extern "C" fn xcso_compress_with_progress(
+    iso: *const c_char,
+    dest: *const c_char,
+    opts: *const XcsoOptions,
+    progress: XcsoProgress,
+    user: *mut c_void,
+) -> c_int {
+    run(|| {
+        let (iso, dest) = (path_arg(iso, "ISO")?, path_arg(dest, "output")?);
+        let opts = match opts.as_ref() {
+            Some(opts) if opts.size < size_of::<XcsoOptions>() => {
+                return Err(Error::new(ErrorKind::InvalidInput, "the options are from an older xcso.h"));
+            },
+            Some(opts) => compress_options(opts)?,
+            None => CompressOptions::default(),
+        };
+        with_progress(progress, user, |pb| compress_iso_with_progress(&iso, &dest, &opts, pb).map(|_| ()))
+    })
+}
+
+/// Decompresses the CSO or ZSO whose first part is at `cso` into an ISO at
+/// `iso`.
+///
+/// # Safety
+///
+/// `cso` and `iso` must point to nul terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn xcso_decompress(cso: *const c_char, iso: *const c_char) -> c_int {
+    xcso_decompress_with_progress(cso, iso, None, ptr::null_mut())
+}
+
+/// Same as `xcso_decompress`, but calls `progress` every so often, on the
+/// calling thread, with how much of the ISO is written.
+///
+/// # Safety
+///
+/// As for `xcso_decompress`, and `progress` must be null or safe to call
+/// with `user`.
+#[no_mangle]
+pub unsafe extern "C" fn xcso_decompress_with_progress(
+    cso: *const c_char,
+    iso: *const c_char,
+    progress: XcsoProgress,
+    user: *mut c_void,
+) -> c_int {
+    run(|| {
+        let (cso, iso) = (path_arg(cso, "CSO")?, path_arg(iso, "ISO")?);
+        with_progress(progress, user, |pb| decompress_cso_with_progress(&cso, &iso, pb))
+    })
+}
+
+/// Reads the header of the CSO or ZSO whose first part is at `cso` into
+/// `info`.
+///
+/// # Safety
+///
+/// `cso` must point to a nul terminated string and `info` to an
+/// `XcsoImageInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn xcso_info(cso: *const c_char, info: *mut XcsoImageInfo) -> c_int {
+    run(|| {
+        let cso = CsoFile::open(&path_arg(cso, "CSO")?)?;
+        let info = info.as_mut().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "nowhere to put the info"))?;
+        let header = cso.header();
+        *info = XcsoImageInfo {
+            format: match header.format {
+                Format::Cso => XCSO_FORMAT_CSO,
+                Format::Zso => XCSO_FORMAT_ZSO,
+            },
+            version: header.version as u32,
+            block_size: header.block_size,
+            align: header.align as u32,
+            total_bytes: header.total_bytes,
+            total_blocks: header.total_blocks as u64,
+            parts: cso.part_count() as u32,
+            stored_bytes: cso.stored_bytes()?,
+        };
+        Ok(())
+    })
+}
+
+/// The message of the last error on this thread, or null if there hasn't
+/// been one. It stays valid until the next call that fails on the thread.
+#[no_mangle]
+pub extern "C" fn xcso_last_error() -> *const c_char {
+    LAST_ERROR.with(|x| x.borrow().as_ref().map_or(ptr::null(), |x| x.as_ptr()))
+}
@@ -0,0 +1,391 @@
+//! CRC32, MD5 and SHA-1 of a source image, the three hashes Redump lists
+//! for every dump, so they can be worked out while the image is read for
+//! compression, and XXH64 for checksumming each block.
+
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+/// CRC-32 as used by zip and Redump (polynomial 0xedb88320, reflected).
+#[derive(Clone)]
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        Crc32 { table, crc: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.crc;
+        for &b in data {
+            crc = self.table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.crc = crc;
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+const XXH_PRIME_1: u64 = 0x9E3779B185EBCA87;
+const XXH_PRIME_2: u64 = 0xC2B2AE3D27D4EB4F;
+const XXH_PRIME_3: u64 = 0x165667B19E3779F9;
+const XXH_PRIME_4: u64 = 0x85EBCA77C2B2AE63;
+const XXH_PRIME_5: u64 = 0x27D4EB2F165667C5;
+
+fn xxh_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(XXH_PRIME_2)).rotate_left(31).wrapping_mul(XXH_PRIME_1)
+}
+
+fn xxh_merge(acc: u64, v: u64) -> u64 {
+    (acc ^ xxh_round(0, v)).wrapping_mul(XXH_PRIME_1).wrapping_add(XXH_PRIME_4)
+}
+
+/// XXH64 of `data` with a seed of 0, which is quick enough to check every
+/// block as it's read.
+pub fn xxh64(data: &[u8]) -> u64 {
+    let u64_at = |x: &[u8]| u64::from_le_bytes(x[..8].try_into().unwrap());
+    let mut rest = data;
+    let mut h = if data.len() >= 32 {
+        let mut v = [XXH_PRIME_1.wrapping_add(XXH_PRIME_2), XXH_PRIME_2, 0, XXH_PRIME_1.wrapping_neg()];
+        while rest.len() >= 32 {
+            for (n, lane) in v.iter_mut().enumerate() {
+                *lane = xxh_round(*lane, u64_at(&rest[n * 8..]));
+            }
+            rest = &rest[32..];
+        }
+        let h = v[0].rotate_left(1).
+            wrapping_add(v[1].rotate_left(7)).
+            wrapping_add(v[2].rotate_left(12)).
+            wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(h, |h, &lane| xxh_merge(h, lane))
+    } else {
+        XXH_PRIME_5
+    };
+    h = h.wrapping_add(data.len() as u64);
+
+    while rest.len() >= 8 {
+        h ^= xxh_round(0, u64_at(rest));
+        h = h.rotate_left(27).wrapping_mul(XXH_PRIME_1).wrapping_add(XXH_PRIME_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        h ^= (u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64).wrapping_mul(XXH_PRIME_1);
+        h = h.rotate_left(23).wrapping_mul(XXH_PRIME_2).wrapping_add(XXH_PRIME_3);
+        rest = &rest[4..];
+    }
+    for &b in rest {
+        h ^= (b as u64).wrapping_mul(XXH_PRIME_5);
+        h = h.rotate_left(11).wrapping_mul(XXH_PRIME_1);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(XXH_PRIME_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(XXH_PRIME_3);
+    h ^ (h >> 32)
+}
+
+/// Buffers input into the 64 byte blocks MD5 and SHA-1 both work on.
+#[derive(Clone)]
+struct Blocks {
+    buf: [u8; 64],
+    used: usize,
+    len: u64,
+}
+
+impl Blocks {
+    fn new() -> Blocks {
+        Blocks { buf: [0; 64], used: 0, len: 0 }
+    }
+
+    fn update(&mut self, mut data: &[u8], mut block: impl FnMut(&[u8; 64])) {
+        self.len += data.len() as u64;
+        if self.used > 0 {
+            let n = (64 - self.used).min(data.len());
+            self.buf[self.used..self.used + n].copy_from_slice(&data[..n]);
+            self.used += n;
+            data = &data[n..];
+            if self.used < 64 {
+                return;
+            }
+            block(&self.buf);
+            self.used = 0;
+        }
+
+        let mut chunks = data.chunks_exact(64);
+        for chunk in &mut chunks {
+            block(chunk.try_into().unwrap());
+        }
+        let rest = chunks.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.used = rest.len();
+    }
+
+    /// Pads the message out with its length in bits, stored as `length`
+    /// turns it into bytes.
+    fn finish(&mut self, length: impl Fn(u64) -> [u8; 8], mut block: impl FnMut(&[u8; 64])) {
+        let bits = length(self.len * 8);
+        let mut tail = vec![0x80];
+        tail.resize((119 - self.used) % 64 + 1, 0);
+        tail.extend_from_slice(&bits);
+        let len = self.len;
+        self.update(&tail, &mut block);
+        self.len = len;
+    }
+}
+
+#[derive(Clone)]
+pub struct Md5 {
+    state: [u32; 4],
+    blocks: Blocks,
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// The sine table from RFC 1321.
+fn md5_table() -> &'static [u32; 64] {
+    static TABLE: OnceLock<[u32; 64]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32))
+}
+
+fn md5_block(state: &mut [u32; 4], block: &[u8; 64]) {
+    let mut m = [0u32; 16];
+    for (i, chunk) in block.chunks_exact(4).enumerate() {
+        m[i] = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    let table = md5_table();
+    let [mut a, mut b, mut c, mut d] = *state;
+    for (i, &k) in table.iter().enumerate() {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let f = f.wrapping_add(a).wrapping_add(k).wrapping_add(m[g]);
+        (a, d, c) = (d, c, b);
+        b = b.wrapping_add(f.rotate_left(MD5_SHIFTS[(i / 16) * 4 + i % 4]));
+    }
+    for (s, x) in state.iter_mut().zip([a, b, c, d]) {
+        *s = s.wrapping_add(x);
+    }
+}
+
+impl Md5 {
+    pub fn new() -> Md5 {
+        Md5 { state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476], blocks: Blocks::new() }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(data, |x| md5_block(state, x));
+    }
+
+    pub fn finish(&self) -> [u8; 16] {
+        let mut md5 = self.clone();
+        let state = &mut md5.state;
+        md5.blocks.finish(u64::to_le_bytes, |x| md5_block(state, x));
+
+        let mut out = [0; 16];
+        for (chunk, s) in out.chunks_exact_mut(4).zip(md5.state) {
+            chunk.copy_from_slice(&s.to_le_bytes());
+        }
+        out
+    }
+}
+
+impl Default for Md5 {
+    fn default() -> Self {
+        Md5::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    blocks: Blocks,
+}
+
+fn sha1_block(state: &mut [u32; 5], block: &[u8; 64]) {
+    let mut w = [0u32; 80];
+    for (i, chunk) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    for (i, &w) in w.iter().enumerate() {
+        let (f, k) = match i / 20 {
+            0 => ((b & c) | (!b & d), 0x5a827999),
+            1 => (b ^ c ^ d, 0x6ed9eba1),
+            2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w);
+        (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+    }
+    for (s, x) in state.iter_mut().zip([a, b, c, d, e]) {
+        *s = s.wrapping_add(x);
+    }
+}
+
+impl Sha1 {
+    pub fn new() -> Sha1 {
+        Sha1 { state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0], blocks: Blocks::new() }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(data, |x| sha1_block(state, x));
+    }
+
+    pub fn finish(&self) -> [u8; 20] {
+        let mut sha1 = self.clone();
+        let state = &mut sha1.state;
+        sha1.blocks.finish(u64::to_be_bytes, |x| sha1_block(state, x));
+
+        let mut out = [0; 20];
+        for (chunk, s) in out.chunks_exact_mut(4).zip(sha1.state) {
+            chunk.copy_from_slice(&s.to_be_bytes());
+        }
+        out
+    }
+}
+
+impl Default for Sha1 {
+    fn default() -> Self {
+        Sha1::new()
+    }
+}
+
+/// Hashes of a whole file, as listed in a Redump DAT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hashes {
+    pub size: u64,
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+impl Hashes {
+    pub fn crc32_hex(&self) -> String {
+        format!("{:08x}", self.crc32)
+    }
+
+    pub fn md5_hex(&self) -> String {
+        to_hex(&self.md5)
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        to_hex(&self.sha1)
+    }
+}
+
+/// Works out all of `Hashes` in one pass over the data.
+#[derive(Clone, Default)]
+pub struct Hasher {
+    size: u64,
+    crc32: Crc32,
+    md5: Md5,
+    sha1: Sha1,
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        self.crc32.update(data);
+        self.md5.update(data);
+        self.sha1.update(data);
+    }
+
+    pub fn finish(&self) -> Hashes {
+        Hashes { size: self.size, crc32: self.crc32.finish(), md5: self.md5.finish(), sha1: self.sha1.finish() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(data: &[u8]) -> Hashes {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    #[test]
+    fn known_answers() {
+        let empty = hashes(b"");
+        assert_eq!(empty.crc32_hex(), "00000000");
+        assert_eq!(empty.md5_hex(), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(empty.sha1_hex(), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+
+        let check = hashes(b"123456789");
+        assert_eq!(check.crc32, 0xcbf43926);
+
+        let abc = hashes(b"abc");
+        assert_eq!(abc.md5_hex(), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(abc.sha1_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+
+        // Over a block, so the length goes in a block of its own
+        let fox = hashes(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq");
+        assert_eq!(fox.sha1_hex(), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        let fox = hashes(b"The quick brown fox jumps over the lazy dog");
+        assert_eq!(fox.md5_hex(), "9e107d9d372bb6826bd81d3542a419d6");
+    }
+
+    #[test]
+    fn updates_in_pieces() {
+        let data: Vec<u8> = (0..1280).map(|i| i as u8).collect();
+        let mut hasher = Hasher::new();
+        for piece in data.chunks(37) {
+            hasher.update(piece);
+        }
+        let pieces = hasher.finish();
+        assert_eq!(pieces, hashes(&data));
+        assert_eq!(pieces.size, 1280);
+        assert_eq!(pieces.crc32_hex(), "1e7a6d24");
+        assert_eq!(pieces.md5_hex(), "82829f1f3f2bb0f18b25f278e5bba8bd");
+        assert_eq!(pieces.sha1_hex(), "e37a04cb2353309f5cff4ee036cfb91a5e31cefd");
+    }
+
+    #[test]
+    fn xxh64_known_answers() {
+        assert_eq!(xxh64(b""), 0xef46db3751d8e999);
+        assert_eq!(xxh64(b"abc"), 0x44bc2cf5ad770999);
+        let data: Vec<u8> = (0..100).collect();
+        assert_eq!(xxh64(&data), 0x6ac1e58032166597);
+    }
+}
@@ -0,0 +1,327 @@
+//! Seek hints, kept next to an image as `<first part>.csoseek`: which part
+//! each block is in, and where every `interval`th block starts in its part.
+//! With them, a block can be read by loading the stretch of the index
+//! around it, rather than reading the whole index of every part up front,
+//! which is most of the cost of opening an image with millions of blocks.
+//!
+//! The file is, little endian: the magic `CSOSEEK1`, the interval and the
+//! number of parts (`u32` each), the number of blocks (`u64`), the first
+//! block of each part and how many blocks it has (`u64` each), and then the
+//! checkpoints of each part in turn, one for every interval from its first
+//! block (`u64` each).
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::codec::decode_block;
+use crate::cso::{read_cso_info, CsoImage, CISO_HEADER_SIZE};
+use crate::decompress::{entry_pos, nth_part_path, CsoFile};
+use crate::error::XcsoError;
+
+const MAGIC: &[u8; 8] = b"CSOSEEK1";
+const HEADER_SIZE: usize = 24;
+/// Blocks between checkpoints, which is how much of the index is read at
+/// once. 16 KiB of index at a time, and an image of a million blocks has a
+/// thousand checkpoints.
+pub const HINT_INTERVAL: usize = 4096;
+
+/// `game.1.csoseek` for the set whose first part is `game.1.cso`.
+pub fn hint_path(first_part: &Path) -> PathBuf {
+    first_part.with_extension("csoseek")
+}
+
+/// Writes seek hints for the image whose first part is `fp` next to it.
+pub fn write_seek_hints(fp: &Path) -> Result<SeekHints, Error> {
+    let hints = CsoFile::open(fp)?.seek_hints(HINT_INTERVAL)?;
+    hints.write(&hint_path(fp))?;
+    crate::debug!("wrote {} seek hints for {}", hints.checkpoints.iter().map(Vec::len).sum::<usize>(), fp.display());
+    Ok(hints)
+}
+
+/// Removes the seek hints of the image whose first part is `fp`, if it has
+/// any, so the image written over it isn't read by the last one's.
+pub fn remove_seek_hints(fp: &Path) -> Result<(), Error> {
+    match fs::remove_file(hint_path(fp)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeekHints {
+    pub interval: usize,
+    pub total_blocks: usize,
+    /// The first block of each part and how many blocks it has.
+    pub parts: Vec<(usize, usize)>,
+    /// Where every `interval`th block of each part starts in it.
+    pub checkpoints: Vec<Vec<u64>>,
+}
+
+impl SeekHints {
+    /// Reads the hints at `fp`, or `None` when there aren't any.
+    pub fn read(fp: &Path) -> Result<Option<SeekHints>, Error> {
+        let mut raw = Vec::new();
+        match File::open(fp) {
+            Ok(mut file) => file.read_to_end(&mut raw)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("{} isn't a seek hint file", fp.display()));
+        if raw.len() < HEADER_SIZE || &raw[..8] != MAGIC {
+            return Err(invalid());
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap()) as usize;
+        let mut u64s = raw[HEADER_SIZE..].chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap()));
+
+        let (interval, part_count) = (u32_at(8), u32_at(12));
+        let total_blocks = u64::from_le_bytes(raw[16..24].try_into().unwrap()) as usize;
+        if interval == 0 {
+            return Err(invalid());
+        }
+        let mut parts = Vec::with_capacity(part_count.min(1 << 16));
+        for _ in 0..part_count {
+            let mut next = || u64s.next().map(|x| x as usize).ok_or_else(invalid);
+            parts.push((next()?, next()?));
+        }
+        let mut checkpoints = Vec::with_capacity(parts.len());
+        for &(_, blocks) in &parts {
+            let part: Option<Vec<u64>> = (0..blocks.div_ceil(interval)).map(|_| u64s.next()).collect();
+            checkpoints.push(part.ok_or_else(invalid)?);
+        }
+        if u64s.next().is_some() || parts.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Some(SeekHints { interval, total_blocks, parts, checkpoints }))
+    }
+
+    pub fn write(&self, fp: &Path) -> Result<(), Error> {
+        let mut out = BufWriter::new(File::create(fp).map_err(|e| XcsoError::io(fp, e))?);
+        out.write_all(MAGIC)?;
+        out.write_all(&(self.interval as u32).to_le_bytes())?;
+        out.write_all(&(self.parts.len() as u32).to_le_bytes())?;
+        out.write_all(&(self.total_blocks as u64).to_le_bytes())?;
+        for &(first, blocks) in &self.parts {
+            out.write_all(&(first as u64).to_le_bytes())?;
+            out.write_all(&(blocks as u64).to_le_bytes())?;
+        }
+        for checkpoint in self.checkpoints.iter().flatten() {
+            out.write_all(&checkpoint.to_le_bytes())?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+
+    /// The part (counting from 0) block `block` is in.
+    pub fn part_of(&self, block: usize) -> usize {
+        self.parts.partition_point(|&(first, _)| first <= block).saturating_sub(1)
+    }
+}
+
+/// An image read through its seek hints, a stretch of the index at a time.
+/// Every stretch read is checked against its checkpoint, so hints left
+/// over from an image since written again show up as an error rather than
+/// as the wrong data.
+pub struct HintedCso {
+    header: CsoImage,
+    hints: SeekHints,
+    parts: Vec<(File, u8)>,
+    /// The stretch of the index last read: its part and first block in the
+    /// part, and the entries, with the one after them.
+    index: Option<(usize, usize, Vec<u32>)>,
+}
+
+impl HintedCso {
+    /// Opens the image at `fp` by its seek hints, reading no more than the
+    /// header of each part. An image without hints is a `NotFound` error.
+    pub fn open(fp: &Path) -> Result<HintedCso, Error> {
+        let hints = SeekHints::read(&hint_path(fp))?.ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, format!("{} has no seek hints", fp.display()))
+        })?;
+        let stale = || {
+            Error::new(ErrorKind::InvalidData, format!("the seek hints of {} are out of date", fp.display()))
+        };
+
+        let mut parts = Vec::with_capacity(hints.parts.len());
+        let mut header: Option<CsoImage> = None;
+        for (n, &(first, blocks)) in hints.parts.iter().enumerate() {
+            let path = match n {
+                0 => fp.to_path_buf(),
+                _ => nth_part_path(fp, n + 1).ok_or_else(stale)?,
+            };
+            let mut file = File::open(path)?;
+            let part = read_cso_info(&mut file)?;
+            let expected = header.map_or(0, |x| x.total_blocks);
+            if part.total_blocks != blocks || first != expected {
+                return Err(stale());
+            }
+            header = Some(match header {
+                Some(header) => CsoImage {
+                    total_blocks: header.total_blocks + part.total_blocks,
+                    total_bytes: header.total_bytes + part.total_bytes,
+                    ..header
+                },
+                None => part,
+            });
+            parts.push((file, part.align));
+        }
+        let header = header.unwrap();
+        if header.total_blocks != hints.total_blocks {
+            return Err(stale());
+        }
+        crate::debug!("opened {} by its seek hints, {} parts", fp.display(), parts.len());
+        Ok(HintedCso { header, hints, parts, index: None })
+    }
+
+    pub fn header(&self) -> CsoImage {
+        self.header
+    }
+
+    /// Where block `local` of part `part` starts, its index entry, and the
+    /// bytes up to the next block.
+    fn locate(&mut self, part: usize, local: usize) -> Result<(u64, u32, u64), Error> {
+        let interval = self.hints.interval;
+        let first = local - local % interval;
+        if !self.index.as_ref().is_some_and(|x| x.0 == part && x.1 == first) {
+            let blocks = self.hints.parts[part].1;
+            let (file, align) = &mut self.parts[part];
+            let mut raw = vec![0u8; ((blocks - first).min(interval) + 1) * 4];
+            file.seek(io::SeekFrom::Start(CISO_HEADER_SIZE as u64 + first as u64 * 4))?;
+            file.read_exact(&mut raw)?;
+            let entries: Vec<u32> = raw.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect();
+            let checkpoint = self.hints.checkpoints[part][first / interval];
+            if entry_pos(entries[0], *align) != checkpoint {
+                return Err(Error::new(ErrorKind::InvalidData, "the seek hints are out of date"));
+            }
+            self.index = Some((part, first, entries));
+        }
+
+        let (entries, align) = (&self.index.as_ref().unwrap().2, self.parts[part].1);
+        let (entry, next) = (entries[local - first], entries[local - first + 1]);
+        let (pos, next) = (entry_pos(entry, align), entry_pos(next, align));
+        Ok((pos, entry, next.saturating_sub(pos)))
+    }
+
+    /// Reads and decompresses block `block`, returning its raw contents.
+    pub fn read_block(&mut self, block: usize) -> Result<Vec<u8>, Error> {
+        if block >= self.header.total_blocks {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("there's no block {}", block)));
+        }
+        let len = self.header.block_len(block);
+        let part = self.hints.part_of(block);
+        let (pos, entry, span) = self.locate(part, block - self.hints.parts[part].0)?;
+        let method = self.header.block_method(entry, span);
+
+        let file = &mut self.parts[part].0;
+        file.seek(io::SeekFrom::Start(pos))?;
+        let Some(method) = method else {
+            let mut buf = vec![0u8; len];
+            file.read_exact(&mut buf)?;
+            return Ok(buf);
+        };
+        if span == 0 || span > self.header.block_size as u64 * 2 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("block {} has an invalid size", block)));
+        }
+        let mut buf = vec![0u8; span as usize];
+        file.read_exact(&mut buf)?;
+        decode_block(&buf, method, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::compress::{compress_reader, part_path, CompressOptions, LEVEL_FAST};
+    use crate::selftest::random;
+    use crate::testing::{image, Scratch};
+
+    /// Compresses `data` as `game` under `dir` in parts of up to `split_size`
+    /// bytes, writing hints every 16 blocks, and returns the first part.
+    fn compress(dir: &Scratch, data: &[u8], split_size: Option<u64>) -> PathBuf {
+        let opts = CompressOptions { level: LEVEL_FAST, raw: true, split_size, ..Default::default() };
+        compress_reader(Cursor::new(data), &dir.join("game"), &opts).unwrap();
+        let fp = part_path(&dir.join("game"), 1, opts.format);
+        CsoFile::open(&fp).unwrap().seek_hints(16).unwrap().write(&hint_path(&fp)).unwrap();
+        fp
+    }
+
+    #[test]
+    fn hints_agree_with_the_index() {
+        let dir = Scratch::new("hints");
+        let data = image(2000);
+        let fp = compress(&dir, &data, Some(1 << 20));
+        let hints = SeekHints::read(&hint_path(&fp)).unwrap().unwrap();
+        assert_eq!(hints, CsoFile::open(&fp).unwrap().seek_hints(16).unwrap());
+        assert!(hints.parts.len() > 1);
+        assert_eq!(hints.total_blocks, 2000);
+
+        let mut cso = CsoFile::open(&fp).unwrap();
+        let mut hinted = HintedCso::open(&fp).unwrap();
+        assert_eq!(hinted.header().total_blocks, cso.header().total_blocks);
+        assert_eq!(hinted.header().total_bytes, cso.header().total_bytes);
+        for block in 0..2000 {
+            assert_eq!(hinted.read_block(block).unwrap(), cso.read_block(block).unwrap(), "block {}", block);
+        }
+        assert!(hinted.read_block(2000).is_err());
+
+        assert!(write_seek_hints(&fp).unwrap().checkpoints.iter().all(|x| x.len() == 1));
+        remove_seek_hints(&fp).unwrap();
+        remove_seek_hints(&fp).unwrap();
+        assert_eq!(HintedCso::open(&fp).err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn stale_hints_are_not_trusted() {
+        let dir = Scratch::new("hints");
+        let fp = compress(&dir, &image(2000), None);
+        let old = fs::read(hint_path(&fp)).unwrap();
+
+        // The same number of blocks, stored differently
+        let mut data = image(2000);
+        for chunk in data.chunks_mut(20_000).step_by(3) {
+            let n = chunk.len();
+            chunk.copy_from_slice(&random(n, n as u64));
+        }
+        compress(&dir, &data, None);
+        fs::write(hint_path(&fp), &old).unwrap();
+        let mut hinted = HintedCso::open(&fp).unwrap();
+        let mut refused = 0;
+        for block in 0..2000 {
+            match hinted.read_block(block) {
+                Ok(x) => assert_eq!(x, &data[block * 2048..(block * 2048 + 2048).min(data.len())]),
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::InvalidData);
+                    refused += 1;
+                },
+            }
+        }
+        assert!(refused > 0);
+
+        // A different number of blocks is caught on opening
+        compress(&dir, &image(1500), None);
+        fs::write(hint_path(&fp), &old).unwrap();
+        assert_eq!(HintedCso::open(&fp).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        // And so are parts split up differently
+        let fp = compress(&dir, &image(2000), Some(1 << 20));
+        let old = fs::read(hint_path(&fp)).unwrap();
+        compress(&dir, &data, Some(1 << 20));
+        fs::write(hint_path(&fp), &old).unwrap();
+        assert_eq!(HintedCso::open(&fp).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn garbage_is_not_hints() {
+        let dir = Scratch::new("hints");
+        let fp = compress(&dir, &image(500), Some(1 << 20));
+        let good = fs::read(hint_path(&fp)).unwrap();
+        let mut wrong_count = good.clone();
+        wrong_count[12] += 1;
+        for raw in [random(4096, 1), good[..good.len() - 8].to_vec(), [&good[..], &[0; 8]].concat(), wrong_count] {
+            fs::write(hint_path(&fp), raw).unwrap();
+            assert_eq!(SeekHints::read(&hint_path(&fp)).err().unwrap().kind(), ErrorKind::InvalidData);
+            assert_eq!(HintedCso::open(&fp).err().unwrap().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Reads images straight from a web server, so a dump can be compressed
+//! without downloading it first.
+//!
+//! Reads are served from one streamed `GET` for as long as each follows on
+//! from the last, and reading from anywhere else starts a new request for
+//! the range from there, which is all the header probes take. Servers that
+//! don't do range requests are read from the start again, skipping ahead.
+//! `https://` needs a build with the `tls` feature, which has the system's
+//! OpenSSL make the connection.
+
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Seek};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::XcsoError;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+// How many times a download that drops is picked up again before giving up
+const ATTEMPTS: usize = 3;
+
+/// Whether `fp` is a URL rather than a path.
+pub fn is_url(fp: &Path) -> bool {
+    fp.to_str().is_some_and(|x| x.starts_with("http://") || x.starts_with("https://"))
+}
+
+/// The file name at the end of `url`, without any query and with escapes
+/// such as `%20` decoded, for naming outputs after it.
+pub fn file_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    let bytes = name.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).
+            and_then(|x| std::str::from_utf8(x).ok()).
+            and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(c)) => {
+                out.push(c);
+                i += 3;
+            },
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[derive(Clone, Debug)]
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// The path and query, as sent in the request.
+    target: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid URL '{}'", url));
+        let (tls, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => return Err(invalid()),
+        };
+        if tls && !cfg!(feature = "tls") {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "https:// URLs need a build with the tls feature, download the image first or use an http:// URL",
+            ));
+        }
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(at) => (&rest[..at], rest[at..].to_owned()),
+            None => (rest, "/".to_owned()),
+        };
+        let target = match target.starts_with('?') {
+            true => format!("/{}", target),
+            false => target,
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err(invalid());
+        }
+        Ok(Url { tls, host: host.to_owned(), port, target: target.split('#').next().unwrap().to_owned() })
+    }
+
+    /// Where a redirect to `location` goes from here.
+    fn join(&self, location: &str) -> Result<Url, Error> {
+        match location.starts_with('/') {
+            true => Ok(Url { target: location.to_owned(), ..self.clone() }),
+            false => Url::parse(location),
+        }
+    }
+}
+
+/// A connection to a server, over TLS for `https://` URLs.
+enum Connection {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(crate::tls::TlsStream),
+}
+
+impl Connection {
+    fn open(url: &Url, stream: TcpStream) -> Result<Connection, Error> {
+        match url.tls {
+            #[cfg(feature = "tls")]
+            true => Ok(Connection::Tls(crate::tls::TlsStream::connect(stream, &url.host)?)),
+            _ => Ok(Connection::Plain(stream)),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(x) => x.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(x) => x.read(buf),
+        }
+    }
+}
+
+impl io::Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(x) => x.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(x) => x.flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(x) => x.flush(),
+        }
+    }
+}
+
+/// The start of a response, up to its body.
+struct Response {
+    status: u32,
+    headers: Vec<(String, String)>,
+    body: BufReader<Connection>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+fn invalid_response(url: &Url) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid response from {}", url.host))
+}
+
+/// Sends a `GET` for everything from byte `from` on.
+fn request(url: &Url, from: u64) -> Result<Response, Error> {
+    let addr = (url.host.as_str(), url.port).to_socket_addrs()?.
+        next().
+        ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} has no address", url.host)))?;
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut stream = Connection::open(url, stream)?;
+
+    let host = match (url.tls, url.port) {
+        (false, 80) | (true, 443) => url.host.clone(),
+        (_, port) => format!("{}:{}", url.host, port),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={}-\r\nUser-Agent: xcso/{}\r\nConnection: close\r\n\r\n",
+        url.target,
+        host,
+        from,
+        env!("CARGO_PKG_VERSION"),
+    );
+    io::Write::write_all(&mut stream, request.as_bytes())?;
+
+    let mut body = BufReader::new(stream);
+    let mut line = String::new();
+    body.read_line(&mut line)?;
+    let status = line.split(' ').nth(1).and_then(|x| x.parse().ok()).ok_or_else(|| invalid_response(url))?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if body.read_line(&mut line)? == 0 {
+            return Err(invalid_response(url));
+        }
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_owned(), value.trim().to_owned())),
+            None if line.trim_end().is_empty() => break,
+            None => return Err(invalid_response(url)),
+        }
+        if headers.len() > 256 {
+            return Err(invalid_response(url));
+        }
+    }
+    Ok(Response { status, headers, body })
+}
+
+/// How long the whole file is, from a response to a request starting at
+/// byte 0.
+fn total_len(response: &Response) -> Option<u64> {
+    match response.status {
+        // bytes 0-1234/1235
+        206 => response.header("Content-Range")?.rsplit('/').next()?.parse().ok(),
+        _ => response.header("Content-Length")?.parse().ok(),
+    }
+}
+
+/// An image on a web server, read as a stream.
+pub struct HttpReader {
+    url: Url,
+    size: u64,
+    pos: u64,
+    /// The response being read from and where it has got to.
+    body: Option<(BufReader<Connection>, u64)>,
+}
+
+impl HttpReader {
+    pub fn open(url: &str) -> Result<HttpReader, Error> {
+        let mut url = Url::parse(url)?;
+        let mut response = request(&url, 0)?;
+        for _ in 0..MAX_REDIRECTS {
+            match (response.status, response.header("Location")) {
+                (301 | 302 | 303 | 307 | 308, Some(location)) => {
+                    url = url.join(location)?;
+                    crate::debug!("redirected to {}{}", url.host, url.target);
+                    response = request(&url, 0)?;
+                },
+                _ => break,
+            }
+        }
+        if !matches!(response.status, 200 | 206) {
+            return Err(XcsoError::HttpStatus { host: url.host, status: response.status }.into());
+        }
+        if response.header("Transfer-Encoding").is_some_and(|x| x.eq_ignore_ascii_case("chunked")) {
+            return Err(Error::new(ErrorKind::Unsupported, format!("{} doesn't say how big the image is", url.host)));
+        }
+        let size = total_len(&response).ok_or_else(|| invalid_response(&url))?;
+        crate::debug!("{} is {} bytes, range requests {}", url.target, size, response.status == 206);
+        Ok(HttpReader { url, size, pos: 0, body: Some((response.body, 0)) })
+    }
+
+    /// The size of the file on the server.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Starts reading from `pos`.
+    fn reopen(&mut self) -> Result<(), Error> {
+        crate::trace!("requesting {} from byte {}", self.url.target, self.pos);
+        let response = request(&self.url, self.pos)?;
+        let mut body = response.body;
+        match response.status {
+            206 => {},
+            // The server sent the whole file, so skip to where the read is
+            200 => {
+                let skipped = io::copy(&mut body.by_ref().take(self.pos), &mut io::sink())?;
+                if skipped != self.pos {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "download cut short"));
+                }
+            },
+            status => return Err(XcsoError::HttpStatus { host: self.url.host.clone(), status }.into()),
+        }
+        self.body = Some((body, self.pos));
+        Ok(())
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min(self.size.saturating_sub(self.pos) as usize);
+        if want == 0 {
+            return Ok(0);
+        }
+        let mut attempt = 1;
+        loop {
+            if self.body.as_ref().is_none_or(|(_, at)| *at != self.pos) {
+                self.reopen()?;
+            }
+            let (body, at) = self.body.as_mut().unwrap();
+            let e = match body.read(&mut buf[..want]) {
+                Ok(0) => Error::new(ErrorKind::UnexpectedEof, "download cut short"),
+                Ok(n) => {
+                    *at += n as u64;
+                    self.pos += n as u64;
+                    return Ok(n);
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            // Pick the download up again from where it got to
+            self.body = None;
+            if attempt == ATTEMPTS {
+                return Err(e);
+            }
+            crate::warn!("download of {} dropped at byte {} ({}), trying again", self.url.target, self.pos, e);
+            attempt += 1;
+        }
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let to = match pos {
+            io::SeekFrom::Start(x) => Some(x),
+            io::SeekFrom::End(x) => self.size.checked_add_signed(x),
+            io::SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        self.pos = to.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "seek before the start of the image"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let url = Url::parse("http://nas.local/dumps/halo%202.iso?x=1#top").unwrap();
+        assert_eq!((url.tls, url.host.as_str(), url.port), (false, "nas.local", 80));
+        assert_eq!(url.target, "/dumps/halo%202.iso?x=1");
+        assert_eq!(Url::parse("http://10.0.0.2:8080?x").unwrap().target, "/?x");
+        for bad in ["ftp://nas.local/halo.iso", "http://", "http://user@nas.local/", "http://nas.local:port/"] {
+            assert_eq!(Url::parse(bad).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", bad);
+        }
+
+        let url = Url::parse("https://example.com/halo.iso");
+        match cfg!(feature = "tls") {
+            true => assert_eq!(url.unwrap().port, 443),
+            false => assert_eq!(url.unwrap_err().kind(), ErrorKind::Unsupported),
+        }
+        assert_eq!(Url::parse("http://nas.local/a/b.iso").unwrap().join("/c.iso").unwrap().target, "/c.iso");
+        assert_eq!(file_name("http://nas.local/dumps/halo%202.iso?x=1"), "halo 2.iso");
+    }
+}
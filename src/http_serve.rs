@@ -0,0 +1,251 @@
+//! `http-serve`: the image inside a CSO, decompressed, over HTTP with range
+//! requests, so emulators and network loaders can stream a game straight
+//! from compressed storage. Each connection reads the image through a
+//! `CsoReader` of its own, so a range only decompresses the blocks it
+//! covers, and connections are kept open from one request to the next.
+
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+
+use indicatif::BinaryBytes;
+use xcso::open_uncompressed;
+use xcso::xdvdfs::ReadSeek;
+
+use crate::cli::{HttpServeArgs, Listen, Verbosity};
+use crate::serve::{bind, read_request, Request, Stream};
+
+// Bytes of the image read and sent at a time
+const CHUNK_SIZE: usize = 64 << 10;
+
+struct Server {
+    image: PathBuf,
+    /// The file name the image is served under, besides `/`.
+    name: String,
+    verbose: bool,
+}
+
+/// The part of the image a request asks for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Wanted {
+    All,
+    /// From the first byte to the second, inclusive.
+    Range(u64, u64),
+    /// A range that starts past the end of the image.
+    Unsatisfiable,
+}
+
+/// What `range`, the value of a `Range` header, asks for out of `len`
+/// bytes. Anything but a single byte range, which an HTTP server is free to
+/// ignore, gets the whole image.
+fn wanted(range: &str, len: u64) -> Wanted {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return Wanted::All;
+    };
+    let Some((first, last)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return Wanted::All;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    // `bytes=-N` is the last N bytes
+    if first.is_empty() {
+        return match last.parse::<u64>() {
+            Ok(0) => Wanted::Unsatisfiable,
+            Ok(_) if len == 0 => Wanted::Unsatisfiable,
+            Ok(n) => Wanted::Range(len.saturating_sub(n), len - 1),
+            Err(_) => Wanted::All,
+        };
+    }
+    let Ok(first) = first.parse::<u64>() else {
+        return Wanted::All;
+    };
+    let last = match last {
+        "" => u64::MAX,
+        _ => match last.parse::<u64>() {
+            Ok(last) if last >= first => last,
+            _ => return Wanted::All,
+        },
+    };
+    match first < len {
+        true => Wanted::Range(first, last.min(len - 1)),
+        false => Wanted::Unsatisfiable,
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn write_head(out: &mut dyn Stream, code: u16, headers: &[(&str, String)], length: u64) -> Result<(), Error> {
+    let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", code, reason(code), length);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    out.write_all(head.as_bytes())
+}
+
+/// Answers with `message` as the body, for anything but the image.
+fn write_error(out: &mut dyn Stream, code: u16, headers: &[(&str, String)], message: &str) -> Result<u16, Error> {
+    let body = format!("{}\n", message);
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Type", "text/plain".to_owned()));
+    write_head(out, code, &headers, body.len() as u64)?;
+    out.write_all(body.as_bytes())?;
+    out.flush()?;
+    Ok(code)
+}
+
+/// Answers `request`, opening the image into `image` the first time it's
+/// asked for. Returns the status sent, or why the connection should end.
+fn respond(
+    out: &mut dyn Stream,
+    request: &Request,
+    server: &Server,
+    image: &mut Option<(Box<dyn ReadSeek + Send>, u64)>,
+) -> Result<u16, Error> {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let name = xcso::http::file_name(path);
+    if path.trim_start_matches('/').contains('/') || !(name.is_empty() || name == server.name) {
+        return write_error(out, 404, &[], &format!("nothing at {}, the image is at /{}", path, server.name));
+    }
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            let message = format!("{} isn't supported here", request.method);
+            return write_error(out, 405, &[("Allow", "GET, HEAD".to_owned())], &message);
+        },
+    };
+
+    if image.is_none() {
+        let opened = open_uncompressed(&server.image).and_then(|mut x| Ok((x.seek(SeekFrom::End(0))?, x)));
+        match opened {
+            Ok((len, reader)) => *image = Some((reader, len)),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", server.image.display(), e);
+                return write_error(out, 500, &[], &e.to_string());
+            },
+        }
+    }
+    let (reader, len) = image.as_mut().unwrap();
+    let len = *len;
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes".to_owned()),
+        ("Content-Type", "application/octet-stream".to_owned()),
+    ];
+    let (code, start, count) = match request.header("range").map_or(Wanted::All, |x| wanted(x, len)) {
+        Wanted::All => (200, 0, len),
+        Wanted::Range(first, last) => {
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", first, last, len)));
+            (206, first, last - first + 1)
+        },
+        Wanted::Unsatisfiable => {
+            let range = [("Content-Range", format!("bytes */{}", len))];
+            return write_error(out, 416, &range, &format!("the image is only {} bytes", len));
+        },
+    };
+    write_head(out, code, &headers, count)?;
+    if head_only {
+        out.flush()?;
+        return Ok(code);
+    }
+
+    reader.seek(SeekFrom::Start(start))?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut left = count;
+    while left > 0 {
+        let chunk = &mut buf[..left.min(CHUNK_SIZE as u64) as usize];
+        if let Err(e) = reader.read_exact(chunk) {
+            // The response is already under way, so all that's left is to
+            // cut it short
+            eprintln!("Error reading {}: {}", server.image.display(), e);
+            return Err(e);
+        }
+        out.write_all(chunk)?;
+        left -= chunk.len() as u64;
+    }
+    out.flush()?;
+    Ok(code)
+}
+
+/// Answers requests on a connection until the client closes it or asks
+/// for it to be closed.
+fn handle(stream: Box<dyn Stream>, server: &Server) -> Result<(), Error> {
+    let mut stream = BufReader::new(stream);
+    let mut image = None;
+    loop {
+        let request = match read_request(&mut stream) {
+            Ok(request) => request,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => {
+                write_error(stream.get_mut().as_mut(), 400, &[], &e.to_string())?;
+                return Err(e);
+            },
+        };
+        let code = respond(stream.get_mut().as_mut(), &request, server, &mut image)?;
+        if server.verbose {
+            match request.header("range") {
+                Some(range) => println!("{} {} {} -> {}", request.method, request.path, range, code),
+                None => println!("{} {} -> {}", request.method, request.path, code),
+            }
+        }
+        if request.header("connection").is_some_and(|x| x.eq_ignore_ascii_case("close")) {
+            return Ok(());
+        }
+    }
+}
+
+pub fn run(args: HttpServeArgs) -> ExitCode {
+    // Fail straight away on an image that won't open, rather than on every
+    // request
+    let len = match open_uncompressed(&args.image).and_then(|mut x| x.seek(SeekFrom::End(0))) {
+        Ok(len) => len,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.image.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let name = crate::decompressed_name(&args.image).file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+    let mut accept = match bind(&args.listen) {
+        Ok((accept, public)) => {
+            if public {
+                eprintln!("warning: anyone who can reach {} can download {}", args.listen, args.image.display());
+            }
+            accept
+        },
+        Err(e) => {
+            eprintln!("Error listening on {}: {}", args.listen, e);
+            return ExitCode::FAILURE;
+        },
+    };
+    if args.verbosity != Verbosity::Quiet {
+        let (image, size) = (args.image.display(), BinaryBytes(len));
+        match args.listen {
+            Listen::Tcp(ref addr) => println!("Serving {} ({}) at http://{}/{}", image, size, addr, name),
+            Listen::Unix(ref path) => println!("Serving {} ({}) on {}", image, size, path.display()),
+        }
+    }
+
+    let server = Arc::new(Server { image: args.image, name, verbose: args.verbosity == Verbosity::Verbose });
+    loop {
+        match accept() {
+            Ok(stream) => {
+                let server = server.clone();
+                // A client going away part way through is its own business
+                thread::spawn(move || handle(stream, &server).ok());
+            },
+            Err(e) => eprintln!("Error accepting a connection: {}", e),
+        }
+    }
+}
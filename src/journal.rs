@@ -0,0 +1,199 @@
+//! A record kept next to each part as it's written, of which blocks made it
+//! to disk, so a conversion cut short by a crash or a power cut can be
+//! resumed without trusting blocks that never reached the disk.
+//!
+//! The block index in the part is only saved now and then, and after a
+//! power cut it can point at data that's still zeros, or be zeros itself.
+//! So at every checkpoint a record of the new index entries is appended to
+//! `<part>.journal`, along with a checksum of the bytes written since the
+//! last one. Resuming checks each record against what's actually in the
+//! part and carries on after the last one that matches.
+//!
+//! Each record is, little endian: the number of the first entry in it and
+//! how many it has (`u32` each), where the data it covers starts and ends
+//! (`u64` each), the checksum of that data (`u64`), the entries, and a CRC32
+//! of everything before it.
+
+use std::fs::File;
+use std::io::{self, Error, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hash::Crc32;
+
+// Every field of a record ahead of its entries
+const RECORD_HEADER: usize = 32;
+
+/// `<name>.1.cso.journal` for the part written as `<name>.1.cso.part`.
+pub fn journal_path(part: &Path) -> PathBuf {
+    part.with_extension("journal")
+}
+
+/// A Fletcher style checksum of a run of bytes, which is quick enough to
+/// keep up with writing and the same whichever way the bytes are split up.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Checksum {
+    a: u64,
+    b: u64,
+}
+
+impl Checksum {
+    pub fn update(&mut self, data: &[u8]) {
+        let (mut a, mut b) = (self.a, self.b);
+        for &x in data {
+            a = a.wrapping_add(x as u64);
+            b = b.wrapping_add(a);
+        }
+        (self.a, self.b) = (a, b);
+    }
+
+    /// Adds `len` zero bytes.
+    pub fn update_zeros(&mut self, len: u64) {
+        self.b = self.b.wrapping_add(self.a.wrapping_mul(len));
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.a ^ self.b.rotate_left(32)
+    }
+}
+
+/// The journal of a part being written.
+pub struct Journal {
+    file: File,
+    /// Index entries recorded so far.
+    recorded: usize,
+    /// Where the data the next record covers starts.
+    start: u64,
+    checksum: Checksum,
+    sync: bool,
+}
+
+impl Journal {
+    /// Starts the journal at `fp` of a part whose first block goes at
+    /// `start`. With `sync` every record is flushed to disk as it's added.
+    pub fn create(fp: &Path, start: u64, sync: bool) -> Result<Journal, Error> {
+        let file = File::create(fp)?;
+        Ok(Journal { file, recorded: 0, start, checksum: Checksum::default(), sync })
+    }
+
+    /// Reads the journal at `fp` and checks its records against `part`,
+    /// whose first block went at `start`. Returns the index entries of the
+    /// blocks that are intact, where the next block goes, and the journal
+    /// cut back to the records that could be trusted, to carry on with.
+    pub fn recover(
+        fp: &Path,
+        part: &mut File,
+        start: u64,
+        sync: bool,
+    ) -> Result<(Vec<u32>, u64, Journal), Error> {
+        let mut file = File::options().read(true).write(true).open(fp)?;
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+
+        let (mut entries, mut end, mut good) = (Vec::new(), start, 0);
+        let mut buf = vec![0; 1 << 20];
+        while let Some(record) = Record::parse(&raw[good..]) {
+            if record.first != entries.len() || record.start != end || record.end < record.start {
+                break;
+            }
+            // The data has to have made it to disk as well
+            let mut checksum = Checksum::default();
+            part.seek(io::SeekFrom::Start(record.start))?;
+            let mut left = record.end - record.start;
+            while left > 0 {
+                let n = left.min(buf.len() as u64) as usize;
+                let read = part.read(&mut buf[..n])?;
+                if read == 0 {
+                    break;
+                }
+                checksum.update(&buf[..read]);
+                left -= read as u64;
+            }
+            if left > 0 || checksum.finish() != record.checksum {
+                crate::debug!("journal of {} is good up to block {}", fp.display(), entries.len());
+                break;
+            }
+            entries.extend_from_slice(&record.entries);
+            end = record.end;
+            good += record.len;
+        }
+
+        file.set_len(good as u64)?;
+        file.seek(io::SeekFrom::End(0))?;
+        let recorded = entries.len();
+        Ok((entries, end, Journal { file, recorded, start: end, checksum: Checksum::default(), sync }))
+    }
+
+    /// Counts bytes written to the part.
+    pub fn update(&mut self, data: &[u8]) {
+        self.checksum.update(data);
+    }
+
+    pub fn update_zeros(&mut self, len: u64) {
+        self.checksum.update_zeros(len);
+    }
+
+    /// Records the entries of `index` added since the last record, for the
+    /// blocks written up to `end`.
+    pub fn record(&mut self, index: &[u32], end: u64) -> Result<(), Error> {
+        let entries = &index[self.recorded..];
+        let mut raw = Vec::with_capacity(RECORD_HEADER + entries.len() * 4 + 4);
+        raw.extend_from_slice(&(self.recorded as u32).to_le_bytes());
+        raw.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        raw.extend_from_slice(&self.start.to_le_bytes());
+        raw.extend_from_slice(&end.to_le_bytes());
+        raw.extend_from_slice(&self.checksum.finish().to_le_bytes());
+        for entry in entries {
+            raw.extend_from_slice(&entry.to_le_bytes());
+        }
+        let mut crc = Crc32::new();
+        crc.update(&raw);
+        raw.extend_from_slice(&crc.finish().to_le_bytes());
+
+        self.file.write_all(&raw)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        (self.recorded, self.start, self.checksum) = (index.len(), end, Checksum::default());
+        Ok(())
+    }
+}
+
+struct Record {
+    first: usize,
+    start: u64,
+    end: u64,
+    checksum: u64,
+    entries: Vec<u32>,
+    /// Bytes it takes up in the journal.
+    len: usize,
+}
+
+impl Record {
+    /// Reads the record at the start of `raw`, if there's a whole one there
+    /// and it hasn't been damaged.
+    fn parse(raw: &[u8]) -> Option<Record> {
+        let u32_at = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(raw[at..at + 8].try_into().unwrap());
+        if raw.len() < RECORD_HEADER {
+            return None;
+        }
+        let count = u32_at(4) as usize;
+        let body = RECORD_HEADER + count.checked_mul(4)?;
+        if raw.len() < body + 4 {
+            return None;
+        }
+        let mut crc = Crc32::new();
+        crc.update(&raw[..body]);
+        if crc.finish() != u32_at(body) {
+            return None;
+        }
+        Some(Record {
+            first: u32_at(0) as usize,
+            start: u64_at(8),
+            end: u64_at(16),
+            checksum: u64_at(24),
+            entries: (RECORD_HEADER..body).step_by(4).map(u32_at).collect(),
+            len: body + 4,
+        })
+    }
+}
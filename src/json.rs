@@ -0,0 +1,323 @@
+//! Just enough JSON to print machine-readable results, and to read the
+//! requests `serve` takes.
+
+use std::fmt;
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(u64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Looks up `key` in an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// Builds an object from `(key, value)` pairs, keeping their order.
+pub fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            // JSON has no NaN or infinity
+            Value::Float(n) if !n.is_finite() => f.write_str("null"),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Str(s) => write_str(f, s),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            },
+            Value::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            },
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+/// Paths that aren't valid UTF-8 can't be represented exactly, so they are
+/// converted lossily.
+impl From<&Path> for Value {
+    fn from(p: &Path) -> Self {
+        Value::Str(p.to_string_lossy().into_owned())
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Float(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Self {
+        Value::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+// Deep enough for any request, shallow enough that a hostile one can't
+// overflow the stack
+const MAX_DEPTH: usize = 32;
+
+struct Reader<'a> {
+    s: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn skip_space(&mut self) {
+        while self.s.get(self.at).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.at += 1;
+        }
+    }
+
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{} at byte {}", what, self.at))
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        match self.s[self.at..].starts_with(word.as_bytes()) {
+            true => {
+                self.at += word.len();
+                Ok(value)
+            },
+            false => self.error("invalid value"),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return self.error("too deeply nested");
+        }
+        self.skip_space();
+        match self.s.get(self.at) {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => Ok(Value::Str(self.string()?)),
+            Some(b'[') => {
+                self.at += 1;
+                let mut items = Vec::new();
+                self.skip_space();
+                if self.s.get(self.at) == Some(&b']') {
+                    self.at += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    self.skip_space();
+                    match self.s.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b']') => {
+                            self.at += 1;
+                            return Ok(Value::Array(items));
+                        },
+                        _ => return self.error("expected ',' or ']'"),
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.at += 1;
+                let mut fields = Vec::new();
+                self.skip_space();
+                if self.s.get(self.at) == Some(&b'}') {
+                    self.at += 1;
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    if self.s.get(self.at) != Some(&b'"') {
+                        return self.error("expected a key");
+                    }
+                    let key = self.string()?;
+                    self.skip_space();
+                    if self.s.get(self.at) != Some(&b':') {
+                        return self.error("expected ':'");
+                    }
+                    self.at += 1;
+                    fields.push((key, self.value(depth + 1)?));
+                    self.skip_space();
+                    match self.s.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b'}') => {
+                            self.at += 1;
+                            return Ok(Value::Object(fields));
+                        },
+                        _ => return self.error("expected ',' or '}'"),
+                    }
+                }
+            },
+            Some(c) if *c == b'-' || c.is_ascii_digit() => self.number(),
+            _ => self.error("expected a value"),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.at;
+        while self.s.get(self.at).is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c)) {
+            self.at += 1;
+        }
+        let text = std::str::from_utf8(&self.s[start..self.at]).unwrap();
+        match (text.parse::<u64>(), text.parse::<f64>()) {
+            (Ok(n), _) => Ok(Value::Int(n)),
+            (_, Ok(n)) => Ok(Value::Float(n)),
+            _ => self.error("invalid number"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.s.get(self.at..self.at + 4).and_then(|x| std::str::from_utf8(x).ok());
+        match digits.and_then(|x| u32::from_str_radix(x, 16).ok()) {
+            Some(n) => {
+                self.at += 4;
+                Ok(n)
+            },
+            None => self.error("invalid \\u escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.at += 1;
+        let mut out = Vec::new();
+        loop {
+            let Some(&c) = self.s.get(self.at) else {
+                return self.error("unterminated string");
+            };
+            self.at += 1;
+            match c {
+                b'"' => return String::from_utf8(out).or_else(|_| self.error("invalid UTF-8")),
+                b'\\' => {
+                    let Some(&escape) = self.s.get(self.at) else {
+                        return self.error("unterminated string");
+                    };
+                    self.at += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut n = self.hex4()?;
+                            // Characters outside the BMP come as a pair of
+                            // surrogates
+                            if (0xd800..0xdc00).contains(&n) && self.s[self.at..].starts_with(b"\\u") {
+                                self.at += 2;
+                                let low = self.hex4()?;
+                                n = 0x10000 + ((n - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            char::from_u32(n).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        },
+                        _ => return self.error("invalid escape"),
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                },
+                _ => out.push(c),
+            }
+        }
+    }
+}
+
+/// Parses a JSON document.
+pub fn parse(s: &str) -> Result<Value, String> {
+    let mut reader = Reader { s: s.as_bytes(), at: 0 };
+    let value = reader.value(0)?;
+    reader.skip_space();
+    match reader.at == s.len() {
+        true => Ok(value),
+        false => reader.error("unexpected data after the value"),
+    }
+}
@@ -0,0 +1,65 @@
+//! Library for creating compressed CSO images of Xbox ISOs for Project Stellar.
+
+pub mod checksums;
+pub mod codec;
+pub mod compat;
+pub mod compress;
+pub mod cso;
+pub mod decompress;
+pub mod deflate;
+pub mod direct;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod hash;
+pub mod hints;
+pub mod http;
+pub mod journal;
+pub mod log;
+pub mod lz4;
+pub mod merge;
+pub mod pack;
+pub mod progress;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod repair;
+pub mod sample;
+pub mod selftest;
+#[cfg(test)]
+mod testing;
+pub mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod trailer;
+pub mod verify;
+pub mod xbe;
+pub mod xdvdfs;
+
+pub use codec::{codec, decode_image, decode_into, encode_from, encode_image, BlockCodec, CsoWriter};
+pub use compress::{
+    compress_block_v2, compress_iso, compress_iso_hashed, compress_iso_with_progress, compress_iso_with_sink,
+    compress_reader, compress_reader_hashed, compress_reader_with_progress, compress_reader_with_sink, compress_stream,
+    compress_stream_hashed, compress_stream_with_progress, compress_stream_with_sink, interrupt, interrupted, part_path,
+    paused, physical_cores, toggle_pause, CancelPolicy, CancelToken, CompressOptions, SplitNaming, SyncPolicy,
+    WriteOptions, BATCH_BLOCKS, DEFAULT_WRITE_BUFFER, FATX_MAX_SIZE, LEVEL_FAST, LEVEL_HC, LEVEL_MAX, PROGRESS_TEMPLATE,
+};
+pub use cso::{
+    get_cso_info, get_image_offset, get_raw_cso_info, identify_image, max_addressable, read_cso_info,
+    read_cso_info_with, strip_extension, write_cso_info, CsoImage, Format, ImageKind, Method, Parsing,
+    CISO_BLOCK_SIZE, MAX_ALIGN, SUPPORTED_BLOCK_SIZES,
+};
+pub use decompress::{
+    decompress_block_v2, decompress_cso, decompress_cso_with_cancel, decompress_cso_with_progress, decompress_to,
+    open_uncompressed, CsoFile, CsoReader, DEFAULT_CACHE_BLOCKS,
+};
+pub use error::XcsoError;
+pub use hash::Hashes;
+pub use hints::{HintedCso, SeekHints};
+pub use merge::{merge_cso, merge_cso_with_progress};
+pub use progress::{Phase, Progress, ProgressSink};
+pub use repair::{repair_cso, repair_cso_with_progress, Repaired};
+pub use selftest::{selftest, RoundTrip};
+pub use verify::{
+    check_cso, check_cso_with_progress, compare_cso, compare_cso_with_progress, verify_cso, verify_cso_with_progress,
+    Checked, Comparison, VerifyOutcome,
+};
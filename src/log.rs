@@ -0,0 +1,204 @@
+//! A small structured logger, so a failure deep in a large batch can be
+//! traced back to the file and phase it happened in.
+//!
+//! Nothing is logged unless the `XCSO_LOG` environment variable asks for it
+//! with comma separated directives, each either a level or `target=level`:
+//! `debug`, or `info,xcso::compress=trace`. Targets are module paths, and the
+//! longest one matching an event wins. Events go to stderr, prefixed with
+//! the time since start and the spans they happened in.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Environment variable the filter is read from.
+pub const ENV_VAR: &str = "XCSO_LOG";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Parses a level name, with `Some(None)` meaning `off`.
+fn parse_level(s: &str) -> Option<Option<Level>> {
+    match s.to_ascii_lowercase().as_str() {
+        "off" => Some(None),
+        "error" => Some(Some(Level::Error)),
+        "warn" => Some(Some(Level::Warn)),
+        "info" => Some(Some(Level::Info)),
+        "debug" => Some(Some(Level::Debug)),
+        "trace" => Some(Some(Level::Trace)),
+        _ => None,
+    }
+}
+
+struct Filter {
+    default: Option<Level>,
+    targets: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    fn parse(directives: &str) -> Result<Filter, String> {
+        let mut filter = Filter { default: None, targets: Vec::new() };
+        for directive in directives.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let invalid = || format!("invalid {} directive '{}'", ENV_VAR, directive);
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = parse_level(level).ok_or_else(invalid)?;
+                    filter.targets.push((target.to_owned(), level));
+                },
+                None => filter.default = parse_level(directive).ok_or_else(invalid)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    fn max_level(&self, target: &str) -> Option<Level> {
+        self.targets.iter().
+            filter(|(t, _)| target == t || target.strip_prefix(t.as_str()).is_some_and(|x| x.starts_with("::"))).
+            max_by_key(|(t, _)| t.len()).
+            map_or(self.default, |(_, level)| *level)
+    }
+
+    fn is_off(&self) -> bool {
+        self.default.is_none() && self.targets.iter().all(|(_, level)| level.is_none())
+    }
+}
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+static START: OnceLock<Instant> = OnceLock::new();
+
+thread_local! {
+    static SPANS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Turns logging on as described by `directives`. Only the first call has
+/// any effect.
+pub fn init(directives: &str) -> Result<(), String> {
+    let filter = Filter::parse(directives)?;
+    START.get_or_init(Instant::now);
+    _ = FILTER.set(filter);
+    Ok(())
+}
+
+/// Turns logging on as described by `XCSO_LOG`, if it is set.
+pub fn init_from_env() -> Result<(), String> {
+    match std::env::var(ENV_VAR) {
+        Ok(directives) => init(&directives),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Whether anything at all will be logged.
+pub fn is_active() -> bool {
+    FILTER.get().is_some_and(|x| !x.is_off())
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    FILTER.get().and_then(|x| x.max_level(target)).is_some_and(|max| level <= max)
+}
+
+/// Logs a single event. The macros at the crate root fill in the target.
+pub fn event(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+
+    let elapsed = START.get().map_or(0.0, |x| x.elapsed().as_secs_f64());
+    let spans = SPANS.with(|x| x.borrow().iter().map(|s| format!("{}:", s)).collect::<String>());
+    let spans = if spans.is_empty() { spans } else { spans + " " };
+    let line = format!("{:>10.3}s {:>5} {}{}: {}\n", elapsed, level.name(), spans, target, args);
+    _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// A span that stays entered on the current thread until it's dropped.
+/// Leaving it logs how long it lasted at debug level.
+pub struct Span {
+    target: &'static str,
+    started: Option<Instant>,
+}
+
+/// Enters a span called `name`, described by `fields` (`file=x.iso`, say).
+/// Spans are per thread, so work handed to other threads is logged outside
+/// of them.
+pub fn span(level: Level, target: &'static str, name: &str, fields: fmt::Arguments) -> Span {
+    if !enabled(level, target) {
+        return Span { target, started: None };
+    }
+
+    let fields = fields.to_string();
+    let span = if fields.is_empty() { name.to_owned() } else { format!("{}{{{}}}", name, fields) };
+    SPANS.with(|x| x.borrow_mut().push(span));
+    Span { target, started: Some(Instant::now()) }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(started) = self.started {
+            event(Level::Debug, self.target, format_args!("done in {:.3}s", started.elapsed().as_secs_f64()));
+            SPANS.with(|x| x.borrow_mut().pop());
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! event {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::event($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::event!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::event!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::event!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::event!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::event!($crate::log::Level::Trace, $($arg)+) };
+}
+
+/// `span!(Level::Info, "convert", "file={}", path.display())` enters a span
+/// until the returned guard is dropped.
+#[macro_export]
+macro_rules! span {
+    ($level:expr, $name:expr) => {
+        $crate::log::span($level, module_path!(), $name, format_args!(""))
+    };
+    ($level:expr, $name:expr, $($arg:tt)+) => {
+        $crate::log::span($level, module_path!(), $name, format_args!($($arg)+))
+    };
+}
@@ -0,0 +1,236 @@
+//! Raw LZ4 block API used to compress and decompress CSO data blocks.
+//!
+//! These bind directly to the liblz4 that minilz4 builds and links.
+
+use std::ffi::{c_char, c_int, c_void};
+use std::io::{Error, ErrorKind};
+
+use crate::cso::Method;
+use crate::error::XcsoError;
+
+// Nothing else references minilz4, so make sure its copy of liblz4 is linked
+extern crate minilz4;
+
+extern "C" {
+    fn LZ4_compressBound(input_size: c_int) -> c_int;
+    fn LZ4_sizeofState() -> c_int;
+    fn LZ4_compress_fast_extState(
+        state: *mut c_void,
+        src: *const c_char,
+        dst: *mut c_char,
+        src_size: c_int,
+        dst_capacity: c_int,
+        acceleration: c_int,
+    ) -> c_int;
+    fn LZ4_sizeofStateHC() -> c_int;
+    fn LZ4_compress_HC_extStateHC(
+        state: *mut c_void,
+        src: *const c_char,
+        dst: *mut c_char,
+        src_size: c_int,
+        dst_capacity: c_int,
+        compression_level: c_int,
+    ) -> c_int;
+    fn LZ4_decompress_safe(src: *const c_char, dst: *mut c_char, src_size: c_int, dst_capacity: c_int) -> c_int;
+    fn LZ4_decompress_safe_partial(
+        src: *const c_char,
+        dst: *mut c_char,
+        src_size: c_int,
+        target_output_size: c_int,
+        dst_capacity: c_int,
+    ) -> c_int;
+}
+
+// Levels below this use the fast compressor, matching liblz4's frame API
+const HC_MIN_LEVEL: u32 = 3;
+
+/// Compresses `src` into a raw LZ4 block.
+pub fn compress(src: &[u8], level: u32) -> Result<Vec<u8>, Error> {
+    let mut dst = Vec::new();
+    Encoder::new(level).compress_into(src, &mut dst)?;
+    Ok(dst)
+}
+
+/// Compresses blocks one after another with the same state, which liblz4
+/// would otherwise set up (and for HC, allocate) for every block.
+pub struct Encoder {
+    level: u32,
+    // liblz4 wants its state aligned for pointers
+    state: Vec<u64>,
+}
+
+impl Encoder {
+    pub fn new(level: u32) -> Encoder {
+        let size = unsafe {
+            match level < HC_MIN_LEVEL {
+                true => LZ4_sizeofState(),
+                false => LZ4_sizeofStateHC(),
+            }
+        };
+        Encoder { level, state: vec![0; (size as usize).div_ceil(8)] }
+    }
+
+    /// Compresses `src` into a raw LZ4 block added to the end of `dst`,
+    /// which only allocates when `dst` hasn't room for `bound(src.len())`
+    /// more bytes.
+    pub fn compress_into(&mut self, src: &[u8], dst: &mut Vec<u8>) -> Result<(), Error> {
+        let capacity = unsafe { LZ4_compressBound(src.len() as c_int) };
+        if capacity <= 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "block too large for lz4"));
+        }
+
+        let start = dst.len();
+        dst.resize(start + capacity as usize, 0);
+        let out = dst[start..].as_mut_ptr() as *mut c_char;
+        let state = self.state.as_mut_ptr() as *mut c_void;
+        let written = unsafe {
+            if self.level < HC_MIN_LEVEL {
+                LZ4_compress_fast_extState(
+                    state,
+                    src.as_ptr() as *const c_char,
+                    out,
+                    src.len() as c_int,
+                    capacity,
+                    1,
+                )
+            } else {
+                LZ4_compress_HC_extStateHC(
+                    state,
+                    src.as_ptr() as *const c_char,
+                    out,
+                    src.len() as c_int,
+                    capacity,
+                    self.level as c_int,
+                )
+            }
+        };
+
+        if written <= 0 {
+            return Err(XcsoError::CompressFailed { method: Method::Lz4 }.into());
+        }
+
+        dst.truncate(start + written as usize);
+        Ok(())
+    }
+}
+
+/// The most a block of `len` bytes can take up once compressed.
+pub fn bound(len: usize) -> usize {
+    unsafe { LZ4_compressBound(len as c_int) }.max(0) as usize
+}
+
+/// Decompresses a raw LZ4 block holding `size` bytes. Trailing bytes after
+/// the block (such as alignment padding) are ignored, but a block that
+/// holds fewer bytes, as a damaged or cut short one can, is an error.
+pub fn decompress(src: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+    let mut dst = vec![0u8; size];
+    let read = unsafe {
+        LZ4_decompress_safe_partial(
+            src.as_ptr() as *const c_char,
+            dst.as_mut_ptr() as *mut c_char,
+            src.len() as c_int,
+            size as c_int,
+            size as c_int,
+        )
+    };
+
+    if read < 0 || read as usize != size {
+        return Err(Error::new(ErrorKind::InvalidData, "corrupt lz4 block"));
+    }
+    Ok(dst)
+}
+
+/// Decompresses `src`, which is exactly one raw LZ4 block, holding at most
+/// `max` bytes.
+pub fn decompress_up_to(src: &[u8], max: usize) -> Result<Vec<u8>, Error> {
+    let mut dst = vec![0u8; max];
+    let read = unsafe {
+        LZ4_decompress_safe(
+            src.as_ptr() as *const c_char,
+            dst.as_mut_ptr() as *mut c_char,
+            src.len() as c_int,
+            max as c_int,
+        )
+    };
+
+    if read < 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "corrupt lz4 block"));
+    }
+    dst.truncate(read as usize);
+    Ok(dst)
+}
+
+/// How many bytes at the start of `src` make up an LZ4 block holding `size`
+/// bytes, found by walking its sequences without decompressing them. `None`
+/// if `src` doesn't start with such a block.
+pub fn block_len(src: &[u8], size: usize) -> Option<usize> {
+    // Lengths of 15 go on in the bytes after, for as long as they're 255
+    let extend = |at: &mut usize, len: &mut usize| -> Option<()> {
+        loop {
+            let byte = *src.get(*at)?;
+            *at += 1;
+            *len += byte as usize;
+            if byte != 255 {
+                return Some(());
+            }
+        }
+    };
+    let (mut at, mut out) = (0, 0);
+    loop {
+        let token = *src.get(at)?;
+        at += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            extend(&mut at, &mut literals)?;
+        }
+        at += literals;
+        out += literals;
+        // The block always ends on literals
+        if at > src.len() || out > size {
+            return None;
+        }
+        if out == size {
+            return Some(at);
+        }
+
+        let offset = u16::from_le_bytes([*src.get(at)?, *src.get(at + 1)?]) as usize;
+        at += 2;
+        let mut len = (token & 15) as usize;
+        if len == 15 {
+            extend(&mut at, &mut len)?;
+        }
+        out += len + 4;
+        if offset == 0 || offset > out - len - 4 || out >= size {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::patterned;
+
+    #[test]
+    fn round_trip_with_padding() {
+        let data = patterned(2048);
+        for level in [1, 12] {
+            let mut block = compress(&data, level).unwrap();
+            assert_eq!(block_len(&block, data.len()), Some(block.len()));
+            block.extend_from_slice(&[0; 3]);
+            assert_eq!(decompress(&block, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn short_blocks_are_errors() {
+        let data = patterned(2048);
+        let block = compress(&data[..1000], 1).unwrap();
+        assert_eq!(decompress(&block, 2048).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(decompress_up_to(&block, 2048).unwrap(), data[..1000]);
+
+        let block = compress(&data, 1).unwrap();
+        assert!(decompress(&block[..block.len() / 2], 2048).is_err());
+        assert!(decompress_up_to(&block[..block.len() / 2], 2048).is_err());
+    }
+}
@@ -1,285 +1,1712 @@
-use std::io::{Error, ErrorKind, Write};
-use std::fs::File;
 use std::env;
-use std::io;
 use std::ffi::OsString;
-use std::io::{Read, Seek};
-use std::path::Path;
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use console::{style, Emoji};
-use indicatif::ProgressBar;
-use minilz4::EncoderBuilder;
+use console::{style, user_attended, Emoji};
+use indicatif::{BinaryBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use xcso::log::Level;
+use xcso::xbe::{Certificate, DEFAULT_XBE};
+use xcso::pack::{pack_dir_with_progress, PackOptions};
+use xcso::sample::{Sample, DEFAULT_SAMPLES};
+use xcso::decompress::nth_part_path;
+use xcso::{deflate, xdvdfs};
+use xcso::{
+    check_cso_with_progress, compare_cso_with_progress, compress_iso_hashed, compress_iso_with_progress,
+    compress_stream_hashed, compress_stream_with_progress, decompress_cso_with_progress, get_image_offset,
+    identify_image, merge_cso_with_progress, open_uncompressed, part_path, physical_cores, repair_cso_with_progress,
+    selftest, strip_extension, verify_cso_with_progress, CancelPolicy, CancelToken, CompressOptions, CsoFile, Format,
+    Hashes, ImageKind, Method, Parsing, VerifyOutcome, WriteOptions, XcsoError, CISO_BLOCK_SIZE, FATX_MAX_SIZE,
+    LEVEL_FAST, LEVEL_MAX, PROGRESS_TEMPLATE,
+};
 
-static CISO_MAGIC: u32 = 0x4F534943; // CISO
-static CISO_HEADER_SIZE: u32 = 0x18; // 24
-static CISO_BLOCK_SIZE: usize = 0x800; // 2048
-static XBOX_MEDIA_HEADER_REDUMP_OFFSET: io::SeekFrom = io::SeekFrom::Start(0x18310000);
-static XBOX_MEDIA_HEADER_XDVDFS_OFFSET: io::SeekFrom = io::SeekFrom::Start(0x10000);
-static FATX_MAX_SIZE: u64 = 4290732032;
+mod cli;
+mod dat;
+mod discover;
+mod ftp;
+mod http_serve;
+mod json;
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+mod mount;
+mod naming;
+mod nbd;
+mod pause;
+mod priority;
+mod serve;
+mod signal;
+mod summary;
+
+use cli::{
+    BenchArgs, CatArgs, CheckArgs, Command, CompressArgs, DecompressArgs, ExtractArgs, InfoArgs, Layout, LsArgs,
+    MergeArgs, MountArgs, PackArgs, RepairArgs, TranscodeArgs, ValidateArgs, Verbosity, VerifyArgs,
+};
+use dat::{Dat, DatMatch};
+use ftp::Uploader;
+use summary::{Outcome, Summary};
 
 static CLIP: Emoji<'_, '_> = Emoji("🔗  ", "");
 
-#[derive(Copy, Clone)]
-struct CsoImage {
-    version: u8,
-    align: u8,
-    total_bytes: u64,
-    total_blocks: usize,
-}
-
-fn get_filename_from_path(fp: &String) -> String {
-    let path = Path::new(fp);
-    return String::from(
-        path.file_name()
-            .unwrap_or(&OsString::from(""))
-            .to_str()
-            .unwrap_or(""),
-    );
+/// The emoji in front of status messages, left out when stdout isn't a
+/// terminal so logs stay plain. Colours are turned off the same way.
+fn clip() -> String {
+    if user_attended() {
+        CLIP.to_string()
+    } else {
+        String::new()
+    }
 }
 
-fn is_iso(fp: &String) -> bool {
-    let path = Path::new(fp);
-    let ext = String::from(
-        path.extension()
-            .unwrap_or(&OsString::from(""))
-            .to_str()
-            .unwrap_or(""),
-    );
+/// Progress is only drawn for someone watching: not with `--quiet`, not
+/// when stdout goes to a pipe or a file, and not underneath `XCSO_LOG`
+/// output.
+fn shows_progress(verbosity: Verbosity) -> bool {
+    verbosity != Verbosity::Quiet && user_attended() && !xcso::log::is_active()
+}
 
-    match ext.as_str() {
-        "xiso"|"iso" => true,
-        _ => false,
-    }
+fn progress_bar(verbosity: Verbosity) -> ProgressBar {
+    let pb = match shows_progress(verbosity) {
+        true => ProgressBar::new(0),
+        false => ProgressBar::hidden(),
+    };
+    pb.set_style(ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap());
+    pb
 }
 
-fn get_image_offset(f: &mut File) -> Result<u32, io::Error> {
-    let mut buf: Vec<u8> = vec![0; 20];
-    let xbox_media_header: Vec<u8> = b"MICROSOFT*XBOX*MEDIA".to_vec();
+/// Prints what might get past `e`, for the failures of a damaged image.
+fn print_hint(e: &io::Error) {
+    let hint = match XcsoError::of(e) {
+        Some(XcsoError::BadBlock { .. } | XcsoError::ShortRead { .. }) =>
+            "`make-xcso validate` lists what else is damaged, and `make-xcso repair` can rebuild a broken index",
+        Some(XcsoError::ChecksumMismatch { .. }) =>
+            "the image changed after it was written; `make-xcso verify` without the ISO lists every bad block",
+        _ => return,
+    };
+    eprintln!("hint: {}", hint);
+}
 
-    // Check for redump
-    _ = f.seek(XBOX_MEDIA_HEADER_REDUMP_OFFSET);
-    _ = f.read_exact(&mut buf);
+fn image_kind(fp: &Path) -> io::Result<ImageKind> {
+    identify_image(&mut fs::File::open(fp)?)
+}
 
-    if xbox_media_header == buf {
-        return Ok(0x18300000);
+/// Whether a file found in a directory should be compressed: it needs one
+/// of the extensions asked for, and to hold an Xbox image (or with `--raw`,
+/// which can't go by the contents, anything but a CSO). With `--recompress`
+/// CSOs are taken too, reading split sets from their first part.
+fn is_image(fp: &Path, args: &CompressArgs) -> bool {
+    let ext = fp.extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    let compressed = args.recompress && (ext == Format::Cso.extension() || ext == Format::Zso.extension());
+    if !compressed && !args.extensions.contains(&ext) {
+        return false;
     }
+    match image_kind(fp) {
+        Ok(ImageKind::Iso) => !compressed,
+        Ok(ImageKind::Unknown) => args.raw && !compressed,
+        Ok(ImageKind::Compressed(_)) => compressed && !naming::is_later_part(fp),
+        Err(_) => false,
+    }
+}
 
-    // Check for XDVDFS
-    _ = f.seek(XBOX_MEDIA_HEADER_XDVDFS_OFFSET);
-    _ = f.read_exact(&mut buf);
-    if xbox_media_header == buf {
-        return Ok(0x0);
+/// Fails if the output would replace the CSO it's compressed from, which is
+/// still being read while the output is written.
+fn check_not_source(fname: &Path, dest: &Path, args: &CompressArgs) -> io::Result<()> {
+    let fp = args.split_naming.part_path(dest, 1, args.format);
+    let same = fs::canonicalize(fname).ok().is_some_and(|x| fs::canonicalize(&fp).is_ok_and(|fp| fp == x));
+    match same {
+        false => Ok(()),
+        true => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} would replace the image it's made from, choose another output", fp.display()),
+        )),
     }
+}
 
-    return Err(Error::new(ErrorKind::Other, "could not get image offset"));
+/// Fails if converting to `dest` would replace an earlier output, unless
+/// that was asked for.
+fn check_existing(dest: &Path, args: &CompressArgs) -> io::Result<()> {
+    let fp = args.split_naming.part_path(dest, 1, args.format);
+    if args.force || args.resume || !fp.exists() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists (use --force to replace it)", fp.display()),
+    ))
 }
 
-fn pad_file(f: &mut File) -> Result<(), io::Error> {
-    let end = f.seek(io::SeekFrom::End(0))?;
-    let pad_size = end & 0x3FF;
+/// Writes the attacher `xbe` to the game folder of `dest` as default.xbe,
+/// patched to show the title of the game where it's known.
+fn write_attach_xbe(dest: &Path, xbe: &[u8], cert: Option<&Certificate>) -> io::Result<()> {
+    let mut xbe = xbe.to_vec();
+    if let Some(cert) = cert {
+        cert.patch(&mut xbe)?;
+    }
+    fs::write(dest.with_file_name(DEFAULT_XBE), xbe)
+}
 
-    let buf: Vec<u8> = vec![0; 0x400-pad_size as usize];
+/// Prints a record of how far the conversion of an image has got every
+/// second, for `--json-progress`, until dropped.
+struct ProgressRecords {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
 
-    _ = f.write(&buf)?;
-    return Ok(());
+impl ProgressRecords {
+    fn start(fname: &Path, pb: &ProgressBar) -> ProgressRecords {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (fname, pb, stopped) = (fname.to_owned(), pb.clone(), stop.clone());
+        let thread = thread::spawn(move || {
+            let mut ticks = 0;
+            while !stopped.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+                ticks += 1;
+                if ticks % 10 == 0 {
+                    let record = json::object(vec![
+                        ("source", fname.as_path().into()),
+                        ("status", "converting".into()),
+                        ("bytes_done", pb.position().into()),
+                        ("bytes_total", pb.length().into()),
+                    ]);
+                    println!("{}", record);
+                }
+            }
+        });
+        ProgressRecords { stop, thread: Some(thread) }
+    }
 }
 
-fn get_cso_info(f: &mut File) -> Result<CsoImage, io::Error> {
-    let image_offset = get_image_offset(f)?;
-    let fmetadata = f.metadata()?;
+impl Drop for ProgressRecords {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
 
-    let byte_len: u64 = fmetadata.len() - image_offset as u64;
-    let blocks: usize = (byte_len as usize/ CISO_BLOCK_SIZE) as usize;
+/// Lists what goes to the console for the outputs at `dest`, each with
+/// its path below the upload folder, which keeps the game folder and the
+/// folders of split parts.
+fn uploads(dest: &Path, args: &CompressArgs) -> Vec<(PathBuf, String)> {
+    let mut files = outputs(dest, args);
+    if args.attach_xbe.is_some() {
+        files.push(dest.with_file_name(DEFAULT_XBE));
+    }
+    let root = match args.layout {
+        Layout::Flat => dest.parent(),
+        Layout::Folder => dest.parent().and_then(|x| x.parent()),
+    };
+    files.into_iter().
+        map(|fp| {
+            let relative = root.and_then(|x| fp.strip_prefix(x).ok()).unwrap_or(&fp);
+            let names: Vec<String> = relative.iter().map(|x| x.to_string_lossy().into_owned()).collect();
+            let name = names.join("/");
+            (fp, name)
+        }).
+        collect()
+}
 
-    f.seek(io::SeekFrom::Start(image_offset as u64))?;
+/// Lists the parts written to `dest`.
+fn outputs(dest: &Path, args: &CompressArgs) -> Vec<PathBuf> {
+    (1..).
+        map(|n| args.split_naming.part_path(dest, n, args.format)).
+        take_while(|x| x.exists()).
+        collect()
+}
 
-    return Ok(CsoImage {
-        version: 2,
-        align: 2,
-        total_bytes: byte_len,
-        total_blocks: blocks,
-    });
+fn total_size(paths: &[PathBuf]) -> u64 {
+    paths.iter().
+        filter_map(|x| fs::metadata(x).ok()).
+        map(|x| x.len()).
+        sum()
 }
 
-fn write_cso_info(f: &mut File, img_data: CsoImage) -> Result<(), Error> {
-    let mut buf: Vec<u8> = Vec::new();
-    buf.write(&CISO_MAGIC.to_le_bytes())?;
-    buf.write(&CISO_HEADER_SIZE.to_le_bytes())?;
-    buf.write(&img_data.total_bytes.to_le_bytes())?;
+/// Removes the source `fname` once its outputs at `dest`, starting with
+/// `fp`, are flushed to disk and decompress back to exactly the source.
+fn delete_source(fname: &Path, dest: &Path, fp: &Path, args: &CompressArgs, pb: &ProgressBar) -> io::Result<()> {
+    for part in outputs(dest, args) {
+        fs::File::options().write(true).open(part)?.sync_all()?;
+    }
+
+    match verify_cso_with_progress(fname, fp, pb)? {
+        VerifyOutcome::Match => fs::remove_file(fname),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not match its source, so the source was kept", fp.display()),
+        )),
+    }
+}
 
-    let block_size = CISO_BLOCK_SIZE as u32;
-    buf.write(&block_size.to_le_bytes())?;
+/// Where the hashes of the source of `dest` are written with `--hash`.
+fn hashes_path(dest: &Path) -> PathBuf {
+    let mut fp = dest.as_os_str().to_owned();
+    fp.push(".hashes");
+    PathBuf::from(fp)
+}
 
-    buf.write(&img_data.version.to_le_bytes())?;
-    buf.write(&img_data.align.to_le_bytes())?;
+fn write_hashes(fname: &Path, dest: &Path, hashes: &Hashes) -> io::Result<()> {
+    let name = fname.file_name().unwrap_or_default().to_string_lossy();
+    let text = format!(
+        "file:  {}\nsize:  {}\ncrc32: {}\nmd5:   {}\nsha1:  {}\n",
+        name,
+        hashes.size,
+        hashes.crc32_hex(),
+        hashes.md5_hex(),
+        hashes.sha1_hex(),
+    );
+    fs::write(hashes_path(dest), text)
+}
 
-    let pad: u16 = 0;
-    buf.write(&pad.to_le_bytes())?;
+fn is_skipped(e: &io::Error, args: &CompressArgs) -> bool {
+    args.skip_existing && e.kind() == io::ErrorKind::AlreadyExists
+}
 
-    assert_eq!(CISO_HEADER_SIZE, buf.len() as u32);
-    f.write_all(&buf)
+/// What became of converting one image, and what was learnt about it.
+struct Conversion<'a> {
+    result: &'a io::Result<(PathBuf, Option<Hashes>)>,
+    input_bytes: Option<u64>,
+    /// Whether the source is a full redump, with a video partition.
+    redump: bool,
+    elapsed: Duration,
+    cert: Option<&'a Certificate>,
+    checked: Option<&'a DatMatch<'a>>,
 }
 
-fn write_block_index(f: &mut File, blocks: &Vec<u32>) -> Result<u64, Error> {
-    for block in blocks.iter() {
-        f.write(&block.to_le_bytes())?;
-    }
+/// Describes the outcome of converting `fname` to the parts at `dest`.
+fn conversion_record(fname: &Path, dest: &Path, args: &CompressArgs, conversion: &Conversion) -> json::Value {
+    let Conversion { result, input_bytes, redump, elapsed, cert, checked } = *conversion;
+    let (status, error) = match result {
+        Ok(_) => ("ok", None),
+        Err(e) if is_skipped(e, args) => ("skipped", None),
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => ("interrupted", Some(e.to_string())),
+        Err(e) => ("error", Some(e.to_string())),
+    };
 
-    // Get the current position
-    return f.seek(io::SeekFrom::Current(0));
+    let outputs = match error {
+        None => outputs(dest, args),
+        Some(_) => Vec::new(),
+    };
+    let output_bytes = error.is_none().then(|| total_size(&outputs));
+    let ratio = match (input_bytes, output_bytes) {
+        (Some(input), Some(output)) if input > 0 => Some(output as f64 / input as f64),
+        _ => None,
+    };
+    let hashes = result.as_ref().ok().and_then(|(_, x)| x.as_ref());
+
+    json::object(vec![
+        ("source", fname.into()),
+        ("outputs", outputs.iter().map(|x| x.as_path().into()).collect::<Vec<json::Value>>().into()),
+        ("input_bytes", input_bytes.into()),
+        ("output_bytes", output_bytes.into()),
+        ("ratio", ratio.into()),
+        ("duration_secs", elapsed.as_secs_f64().into()),
+        ("video_partition", redump.then_some(if args.keep_video_partition { "kept" } else { "skipped" }).into()),
+        ("title", cert.map(|x| x.title_name.clone()).into()),
+        ("title_id", cert.map(|x| x.title_id_hex()).into()),
+        ("region", cert.map(|x| x.region_name()).into()),
+        ("crc32", hashes.map(|x| x.crc32_hex()).into()),
+        ("md5", hashes.map(|x| x.md5_hex()).into()),
+        ("sha1", hashes.map(|x| x.sha1_hex()).into()),
+        ("dat", checked.map(|x| x.name()).into()),
+        ("dat_game", checked.and_then(|x| x.game()).into()),
+        ("source_deleted", (args.delete_source && result.is_ok()).into()),
+        ("status", status.into()),
+        ("error", error.into()),
+    ])
 }
 
+fn run_compress(args: CompressArgs) -> ExitCode {
+    // Before any threads are started, so they all run at the lower priority
+    if args.nice {
+        if let Err(e) = priority::lower() {
+            xcso::warn!("could not lower the priority: {}", e);
+        }
+    }
+    let opts = CompressOptions {
+        // Picked for each image with --level auto
+        level: args.level.unwrap_or(LEVEL_MAX),
+        format: args.format,
+        method: args.method,
+        version: args.cso_version,
+        block_size: args.block_size,
+        align: args.align,
+        resume: args.resume,
+        store: args.store,
+        batched: args.batched,
+        // Shared out between the jobs once it's known how many there are
+        threads: None,
+        max_memory: None,
+        limit_rate: None,
+        trim: args.trim,
+        strip_system_update: args.strip_system_update,
+        keep_video_partition: args.keep_video_partition,
+        raw: args.raw,
+        split_size: args.split_size,
+        split_naming: args.split_naming,
+        write: WriteOptions {
+            buffer_size: args.write_buffer,
+            sync: args.fsync,
+            preallocate: args.preallocate,
+            direct: args.direct,
+            check_space: args.check_space,
+        },
+        checksums: args.checksums,
+        trailer: args.trailer,
+        seek_hints: args.seek_hints,
+        cancel: CancelToken::new(),
+        on_cancel: match args.keep_partial {
+            true => CancelPolicy::Keep,
+            false => CancelPolicy::Remove,
+        },
+    };
+    let chatty = args.verbosity != Verbosity::Quiet;
+    let verbose = args.verbosity == Verbosity::Verbose;
 
-fn compress_block_v2(block: Vec<u8>) -> Result<Vec<u8>, Error> {
-    let mut encoder = EncoderBuilder::new().
-        auto_flush(true).
-        checksum(minilz4::ContentChecksum::NoChecksum).
-        block_mode(minilz4::BlockMode::Independent).
-        block_size(minilz4::BlockSize::Max64KB).
-        level(16).
-        build(Vec::new())?;
-    {
-        std::io::Write::write_all(&mut encoder, &block)?;
+    let summary = Mutex::new(Summary::default());
+    let (files, missing) = discover::expand(&args.inputs, &|fp| is_image(fp, &args));
+    for input in missing {
+        if chatty {
+            eprintln!("Skipping {}: no Xbox ISO images found", input.display());
+        }
+        summary.lock().unwrap().add(None, input, Outcome::Skipped("no Xbox ISO images found".to_owned()));
     }
+    if args.output.is_some() && files.len() > 1 {
+        eprintln!("error: --output can only be used with a single input\n\nFor more information, try '--help'.");
+        return ExitCode::from(2);
+    }
+
+    // Files that can't be read are passed on, so the conversion reports why
+    let inputs: Vec<&PathBuf> = files.iter().
+        filter(|x| {
+            if cli::is_stdin(x) {
+                return true;
+            }
+            let reason = match image_kind(x) {
+                Ok(ImageKind::Iso) | Err(_) => return true,
+                Ok(ImageKind::Unknown) if args.raw => return true,
+                Ok(ImageKind::Compressed(_)) if args.recompress => return true,
+                Ok(ImageKind::Compressed(format)) => format!("already a {} image", format.extension().to_uppercase()),
+                Ok(ImageKind::Unknown) => "not an Xbox ISO image".to_owned(),
+            };
+            if chatty {
+                eprintln!("Skipping {}: {}", x.display(), reason);
+            }
+            summary.lock().unwrap().add(None, x.to_path_buf(), Outcome::Skipped(reason));
+            false
+        }).
+        collect();
 
-    let result = encoder.finish()?;
-    // Trim the header and some of the footer off
+    if args.estimate {
+        return run_estimate(&inputs, &opts, &args);
+    }
 
-    // TODO: This is a gigantic hack but it saves a lot of time as there's no low-level lz4 libraries
-    // and we'd have to modify
-    return Ok(result[7..result.len()-4].to_vec());
-}
+    if let Some(ref dir) = args.output_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Error creating output directory {}: {}", dir.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
 
-fn compress_iso(fp: &String) -> Result<String, io::Error> {
-    let fd_result = File::open(fp);
-    let mut iso_file = match fd_result {
-        Ok(file) => file,
-        Err(e) => return Err(e),
+    let dat = match args.dat {
+        Some(ref fp) => match Dat::open(fp) {
+            Ok(dat) => Some(dat),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", fp.display(), e);
+                return ExitCode::FAILURE;
+            },
+        },
+        None => None,
+    };
+    if let (Some(dat), true) = (&dat, verbose && !args.json) {
+        println!("Checking images against {} known dumps", dat.len());
+    }
+    let attach_xbe = match args.attach_xbe {
+        Some(ref fp) => match fs::read(fp).and_then(|x| Certificate::parse(&x).map(|_| x)) {
+            Ok(xbe) => Some(xbe),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", fp.display(), e);
+                return ExitCode::FAILURE;
+            },
+        },
+        None => None,
     };
+    let dat_results: Mutex<Vec<(PathBuf, DatMatch)>> = Mutex::new(Vec::new());
 
-    let image_details = get_cso_info(&mut iso_file)?;
-
-    // TODO: Split files
-    let dest_fp = fp.to_owned() + ".1.cso";
-    let mut dest_f1: File = File::create(dest_fp.clone())?;
-    let mut dest_f2: Option<File> = None;
-
-    // Write the CSO header
-    write_cso_info(&mut dest_f1, image_details)?;
-    
-    // Followed by a placeholder block index
-    let block_size = image_details.total_blocks;
-    let mut block_index = vec![0; block_size+1];
-    let mut write_pos = write_block_index(&mut dest_f1, &block_index)?;
-
-    let align_b = 1 << image_details.align;
-    let align_m = align_b - 1;
-    let alignment_buffer: Vec<u8> = vec![0; 64];
-
-    // Holds the block size
-    let mut blockbuf = vec![0; CISO_BLOCK_SIZE];
-    let pb = ProgressBar::new(image_details.total_blocks as u64);
-
-    for block in 0..image_details.total_blocks {
-        // Check if we need to split the ISO (due to FATX limitations)
-        if write_pos > FATX_MAX_SIZE {
-            let dest_fp = fp.to_owned() + ".2.cso";
-            let cso2 = File::create(dest_fp)?;
-
-            dest_f2 = Some(cso2);
-            write_pos = 0;
+    signal::install();
+    // Keys can't be read from a terminal that's being compressed
+    let _keys = pause::install(!inputs.iter().any(|x| cli::is_stdin(x)));
+
+    if let Some(target) = args.target {
+        for problem in target.check_options(&opts) {
+            eprintln!("warning: {} won't load these images, as {}", target.display_name(), problem);
         }
+    }
+    if verbose && !args.json {
+        let method = match args.store {
+            true => "stored".to_owned(),
+            false => match args.level {
+                Some(level) => format!("{} level {}", args.method.name(), level),
+                None => format!("{} level picked for each image", args.method.name()),
+            },
+        };
+        println!(
+            "Writing {} images with {} byte blocks ({}), alignment {}",
+            args.format.extension().to_uppercase(), args.block_size, method, args.align,
+        );
+    }
 
-        let mut align: usize = write_pos as usize & align_m as usize;
-        if align > 0 {
-            align = align_b - align;
-            match dest_f2 {
-                Some(ref mut fh) => fh.write_all(&alignment_buffer[..align])?,
-                None => dest_f1.write_all(&alignment_buffer[..align])?,
-            }
+    // Each job takes the next image off the list until it runs dry
+    let jobs = args.jobs.clamp(1, inputs.len().max(1));
+    // with the threads, memory and bandwidth shared out between them
+    let threads = args.threads.unwrap_or_else(physical_cores);
+    let opts = CompressOptions {
+        threads: Some((threads / jobs).max(1)),
+        max_memory: args.max_memory.map(|x| x / jobs as u64),
+        limit_rate: args.limit_rate.map(|x| (x / jobs as u64).max(1)),
+        ..opts
+    };
+    let mp = match shows_progress(args.verbosity) {
+        true => MultiProgress::new(),
+        false => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+    };
+    let next = AtomicUsize::new(0);
+    let interrupted = AtomicBool::new(false);
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= inputs.len() || interrupted.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                let fname = inputs[i];
+                if failed.load(Ordering::SeqCst) {
+                    summary.lock().unwrap().add(Some(i), fname.clone(), Outcome::NotStarted);
+                    continue;
+                }
+                let _span = xcso::span!(Level::Info, "convert", "file={}", fname.display());
+                let fancy_file: String = format!("[{}/{}]", i+1, inputs.len());
+                let template = args.name_template.as_ref();
+                let wants_cert = template.is_some_and(|x| x.uses_xbe()) || args.attach_xbe.is_some();
+                // Nothing can be read ahead of the conversion from a stream
+                let stdin = cli::is_stdin(fname);
+                let cert = match (wants_cert || args.json || verbose) && !stdin {
+                    true => Some(naming::read_certificate(fname).map_err(|e| e.to_string())),
+                    false => None,
+                };
+                let cert = cert.map(|x| x.inspect_err(|e| xcso::debug!("no certificate: {}", e)));
+                let name = match template.map(|x| x.expand(fname, cert.as_ref().and_then(|x| x.as_ref().ok()))) {
+                    Some(Ok(name)) => Some(name),
+                    Some(Err(e)) => {
+                        // Say why default.xbe couldn't be read, if that's the reason
+                        let reason = match cert {
+                            Some(Err(ref e)) => e,
+                            _ => &e,
+                        };
+                        if chatty {
+                            mp.suspend(|| eprintln!("Keeping the name of {}: {}", fname.display(), reason));
+                        }
+                        None
+                    },
+                    None => None,
+                };
+                let cert = cert.and_then(Result::ok);
+                let mut dest = naming::output_base(fname, name.as_deref(), &args);
+                if args.fatx_safe {
+                    let (safe, truncated) = naming::fatx_safe_base(&dest, &args);
+                    if truncated && chatty {
+                        let name = args.split_naming.part_path(&safe, 1, args.format);
+                        mp.suspend(|| {
+                            eprintln!("Shortened the output of {} to {} to fit FATX", fname.display(), name.display())
+                        });
+                    }
+                    dest = safe;
+                }
+                let existing = check_existing(&dest, &args).and_then(|_| check_not_source(fname, &dest, &args));
+                let redump = !args.raw && !stdin && open_uncompressed(fname).
+                    and_then(|mut x| get_image_offset(&mut x)).
+                    is_ok_and(|x| x > 0);
+                if chatty && !args.json && existing.is_ok() {
+                    mp.suspend(|| println!(
+                        "{} {}Converting image {}...",
+                        style(fancy_file.clone()).bold().dim(),
+                        clip(),
+                        fname.display(),
+                    ));
+                    if redump {
+                        let mode = match args.keep_video_partition {
+                            true => "keeping its video partition",
+                            false => "compressing the game partition only",
+                        };
+                        mp.suspend(|| println!("{} Redump image, {}", style(&fancy_file).bold().dim(), mode));
+                    }
+                }
+
+                let new_bar = || {
+                    let pb = mp.add(ProgressBar::new(0));
+                    if stdin {
+                        // which has no end to show progress towards
+                        let template = "{spinner} {binary_bytes} ({binary_bytes_per_sec}) {msg}";
+                        pb.set_style(ProgressStyle::with_template(template).unwrap());
+                    } else if jobs > 1 {
+                        let template = format!("{{prefix}} {}", PROGRESS_TEMPLATE);
+                        pb.set_style(ProgressStyle::with_template(&template).unwrap());
+                        pb.set_prefix(fname.display().to_string());
+                    } else {
+                        pb.set_style(ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap());
+                    }
+                    pb
+                };
+                let pb = new_bar();
+                let records = args.json_progress.then(|| ProgressRecords::start(fname, &pb));
+
+                let opts = match args.level {
+                    Some(_) => opts.clone(),
+                    None => {
+                        let level = auto_level(fname, &opts);
+                        if verbose && !args.json {
+                            mp.suspend(|| println!("{} Picked level {}", style(&fancy_file).bold().dim(), level));
+                        }
+                        CompressOptions { level, ..opts.clone() }
+                    },
+                };
+
+                // Taken up front, as --delete-source removes the input
+                let input_bytes = match stdin {
+                    true => None,
+                    false => open_uncompressed(fname).and_then(|mut x| x.seek(io::SeekFrom::End(0))).ok(),
+                };
+                let started = Instant::now();
+                let result = existing.
+                    and_then(|_| match args.layout {
+                        Layout::Folder => fs::create_dir_all(dest.parent().unwrap()),
+                        Layout::Flat => Ok(()),
+                    }).
+                    and_then(|_| match args.hash || dat.is_some() {
+                        true => {
+                            let (fp, hashes) = match stdin {
+                                true => compress_stream_hashed(io::stdin(), &dest, &opts, &pb)?,
+                                false => compress_iso_hashed(fname, &dest, &opts, &pb)?,
+                            };
+                            if args.hash {
+                                write_hashes(fname, &dest, &hashes)?;
+                            }
+                            Ok((fp, Some(hashes)))
+                        },
+                        false if stdin => Ok((compress_stream_with_progress(io::stdin(), &dest, &opts, &pb)?, None)),
+                        false => Ok((compress_iso_with_progress(fname, &dest, &opts, &pb)?, None)),
+                    }).
+                    and_then(|(fp, hashes)| {
+                        if let Some(target) = args.target {
+                            for problem in target.check_image(&CsoFile::open(&fp)?) {
+                                let name = target.display_name();
+                                let warning = format!("warning: {} won't load {}, as {}", name, fp.display(), problem);
+                                if chatty {
+                                    mp.suspend(|| eprintln!("{}", warning));
+                                }
+                            }
+                        }
+                        Ok((fp, hashes))
+                    }).
+                    and_then(|(fp, hashes)| {
+                        if let Some(ref xbe) = attach_xbe {
+                            if cert.is_none() && chatty {
+                                mp.suspend(|| eprintln!("Copying the attacher for {} as is", fname.display()));
+                            }
+                            write_attach_xbe(&dest, xbe, cert.as_ref())?;
+                        }
+                        Ok((fp, hashes))
+                    }).
+                    and_then(|(fp, hashes)| {
+                        if let Some(ref url) = args.ftp {
+                            let mut uploader = Uploader::new(url);
+                            for (local, name) in uploads(&dest, &args) {
+                                if chatty && !args.json {
+                                    let tag = style(&fancy_file).bold().dim();
+                                    mp.suspend(|| println!("{} Uploading {} to {}", tag, name, url));
+                                }
+                                let pb = new_bar();
+                                let sent = uploader.upload(&local, &name, &pb);
+                                mp.remove(&pb);
+                                sent?;
+                            }
+                            uploader.finish();
+                        }
+                        Ok((fp, hashes))
+                    }).
+                    and_then(|(fp, hashes)| {
+                        if args.delete_source {
+                            let pb = new_bar();
+                            let deleted = delete_source(fname, &dest, &fp, &args, &pb);
+                            mp.remove(&pb);
+                            deleted?;
+                        }
+                        Ok((fp, hashes))
+                    });
+                drop(records);
+                let checked = match (&dat, &result) {
+                    (Some(dat), Ok((_, Some(hashes)))) => {
+                        let name = fname.file_name().unwrap_or_default().to_string_lossy();
+                        Some(dat.check(&name, hashes))
+                    },
+                    _ => None,
+                };
+                if args.json {
+                    let conversion = Conversion {
+                        result: &result,
+                        input_bytes,
+                        redump,
+                        elapsed: started.elapsed(),
+                        cert: cert.as_ref(),
+                        checked: checked.as_ref(),
+                    };
+                    let record = conversion_record(fname, &dest, &args, &conversion);
+                    mp.suspend(|| println!("{}", record));
+                }
 
-            write_pos += align as u64;
+                let outcome = match &result {
+                    Ok((fp, _)) => {
+                        xcso::info!("wrote {}", fp.display());
+                        let output_bytes = total_size(&outputs(&dest, &args));
+                        let ratio = input_bytes.filter(|&x| x > 0).map(|x| output_bytes as f64 / x as f64);
+                        Outcome::Ok(fp.clone(), ratio)
+                    },
+                    Err(e) if is_skipped(e, &args) => Outcome::Skipped("output already exists".to_owned()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => Outcome::Interrupted,
+                    Err(e) => {
+                        xcso::error!("{}", e);
+                        Outcome::Failed(e.to_string())
+                    },
+                };
+                summary.lock().unwrap().add(Some(i), fname.clone(), outcome);
+                match result {
+                    Ok((fp, hashes)) if chatty && !args.json => mp.suspend(|| {
+                        let tag = style(&fancy_file).bold().dim();
+                        println!("{} {}Converted image {}!", tag, clip(), fp.display());
+                        if verbose {
+                            let output_bytes = total_size(&outputs(&dest, &args));
+                            let input_bytes = input_bytes.unwrap_or(0);
+                            println!(
+                                "{} {} -> {} ({:.1}%) in {:.1}s",
+                                tag,
+                                BinaryBytes(input_bytes),
+                                BinaryBytes(output_bytes),
+                                output_bytes as f64 / input_bytes.max(1) as f64 * 100.0,
+                                started.elapsed().as_secs_f64(),
+                            );
+                            if let Some(ref cert) = cert {
+                                println!(
+                                    "{} {} ({}, {})",
+                                    tag,
+                                    cert.title_name,
+                                    cert.title_id_hex(),
+                                    cert.region_name(),
+                                );
+                            }
+                            if let Some(hashes) = hashes {
+                                println!(
+                                    "{} crc32 {}, md5 {}, sha1 {}",
+                                    tag,
+                                    hashes.crc32_hex(),
+                                    hashes.md5_hex(),
+                                    hashes.sha1_hex(),
+                                );
+                            }
+                        }
+                        if args.delete_source {
+                            println!("{} {}Verified and removed {}", tag, clip(), fname.display());
+                        }
+                    }),
+                    Ok(_) => {},
+                    Err(e) if is_skipped(&e, &args) => {
+                        if chatty && !args.json {
+                            mp.suspend(|| println!("Skipping {}: output already exists", fname.display()));
+                        }
+                    },
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                        let done = if args.keep_partial { "kept" } else { "removed" };
+                        mp.suspend(|| eprintln!("Interrupted, {} partial output for {}", done, fname.display()));
+                        interrupted.store(true, Ordering::SeqCst);
+                    },
+                    Err(e) => mp.suspend(|| {
+                        eprintln!("Error converting {}: {}", fname.display(), e);
+                        if args.fail_fast && !failed.swap(true, Ordering::SeqCst) && inputs.len() > i + 1 {
+                            eprintln!("Stopping the batch after the first failure (--fail-fast)");
+                        }
+                    }),
+                };
+                if let Some(checked) = checked {
+                    if chatty && !args.json {
+                        mp.suspend(|| print_dat_match(&fancy_file, fname, &checked));
+                    }
+                    dat_results.lock().unwrap().push((fname.clone(), checked));
+                }
+                mp.remove(&pb);
+            });
         }
+    });
 
-        block_index[block] = write_pos as u32 >> image_details.align as u32;
-        let read = iso_file.read(&mut blockbuf[..])?;
-        let compressed = compress_block_v2(blockbuf[..read].to_vec())?;
+    let mut summary = summary.into_inner().unwrap();
+    if chatty && !args.json && summary.is_batch() {
+        summary.print_table();
+    }
+    if dat.is_some() && !args.json {
+        print_dat_summary(&dat_results.into_inner().unwrap(), args.verbosity);
+    }
 
-        // If the compressed size is greater than the original, prefer the original
-        if compressed.len() + 12 >= read {
-            write_pos += read as u64;
-            match dest_f2 {
-                Some(ref mut fh) => fh.write_all(&blockbuf[..read])?,
-                None => dest_f1.write_all(&blockbuf[..read])?,
-            }
-        } else {
-            block_index[block] |= 0x80000000;
-            write_pos += compressed.len() as u64;
-            match dest_f2 {
-                Some(ref mut fh) => fh.write_all(&compressed)?,
-                None => dest_f1.write_all(&compressed)?,
-            }   
+    summary.exit_code()
+}
+
+fn print_dat_match(tag: &str, fname: &Path, checked: &DatMatch) {
+    let tag = style(tag).bold().dim();
+    match checked {
+        DatMatch::Match(rom) => println!("{} {}Matches Redump dump {}", tag, clip(), rom.game),
+        DatMatch::Mismatch(rom) => {
+            eprintln!("{} {} does not match Redump dump {} (bad or modified dump?)", tag, fname.display(), rom.game);
+        },
+        DatMatch::Unknown => println!("{} {} is not in the DAT", tag, fname.display()),
+    }
+}
+
+/// Sums up how a batch compared to the DAT, listing the images that didn't
+/// match so they stand out at the end of a long run.
+fn print_dat_summary(results: &[(PathBuf, DatMatch)], verbosity: Verbosity) {
+    let count = |name| results.iter().filter(|(_, x)| x.name() == name).count();
+    let mismatched: Vec<&(PathBuf, DatMatch)> = results.iter().
+        filter(|(_, x)| matches!(x, DatMatch::Mismatch(_))).
+        collect();
+
+    if verbosity != Verbosity::Quiet {
+        println!(
+            "Redump check: {} matched, {} mismatched, {} not in the DAT",
+            count("match"),
+            mismatched.len(),
+            count("unknown"),
+        );
+    }
+    let indent = if verbosity == Verbosity::Quiet { "" } else { "  " };
+    for (fname, checked) in mismatched {
+        eprintln!("{}mismatch: {} (expected {})", indent, fname.display(), checked.game().unwrap_or_default());
+    }
+}
+
+fn decompressed_name(fp: &Path) -> PathBuf {
+    let base = ["cso", "zso"].iter().
+        find_map(|ext| strip_extension(fp, ext)).
+        map(|x| strip_extension(&x, "1").unwrap_or(x)).
+        unwrap_or_else(|| fp.to_owned());
+    let stem = strip_extension(&base, "iso").
+        or_else(|| strip_extension(&base, "xiso")).
+        unwrap_or(base);
+
+    let mut name = stem.into_os_string();
+    name.push(".xiso");
+    PathBuf::from(name)
+}
+
+fn run_decompress(args: DecompressArgs) -> ExitCode {
+    signal::install();
+    let mut summary = Summary::default();
+    for (i, fname) in args.inputs.iter().enumerate() {
+        let _span = xcso::span!(Level::Info, "decompress", "file={}", fname.display());
+        let fancy_file: String = format!("[{}/{}]", i+1, args.inputs.len());
+        let dest = args.output.clone().unwrap_or_else(|| decompressed_name(fname));
+        let tag = style(fancy_file).bold().dim();
+        if args.verbosity != Verbosity::Quiet {
+            println!("{} {}Decompressing image {}...", tag, clip(), fname.display());
         }
 
-        pb.inc(1);
+        let started = Instant::now();
+        match decompress_cso_with_progress(fname, &dest, &progress_bar(args.verbosity)) {
+            Ok(()) => {
+                if args.verbosity != Verbosity::Quiet {
+                    println!("{} {}Decompressed image {}!", tag, clip(), dest.display());
+                }
+                if args.verbosity == Verbosity::Verbose {
+                    let size = fs::metadata(&dest).map_or(0, |x| x.len());
+                    println!("{} {} in {:.1}s", tag, BinaryBytes(size), started.elapsed().as_secs_f64());
+                }
+                summary.add(Some(i), fname.clone(), Outcome::Ok(dest, None));
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                eprintln!("Interrupted, removed partial output for {}", fname.display());
+                summary.add(Some(i), fname.clone(), Outcome::Interrupted);
+                break;
+            },
+            Err(e) => {
+                xcso::error!("{}", e);
+                eprintln!("Error decompressing {}: {}", fname.display(), e);
+                print_hint(&e);
+                summary.add(Some(i), fname.clone(), Outcome::Failed(e.to_string()));
+            },
+        }
     }
 
-    // end for block
-    // last position (total size)
-    // NOTE: We don't actually need this, but we're keeping it for legacy reasons.
-    let last = block_index.len()-1;
-    block_index[last] = write_pos as u32 >> image_details.align as u32;
+    if args.verbosity != Verbosity::Quiet && summary.is_batch() {
+        summary.print_table();
+    }
+    summary.exit_code()
+}
 
-    // Seek back to the beginning, past the header to re-write the block index
-    dest_f1.seek(io::SeekFrom::Start(CISO_HEADER_SIZE as u64))?;
-    write_block_index(&mut dest_f1, &block_index)?;
+fn run_info(args: InfoArgs) -> ExitCode {
+    let mut status = ExitCode::SUCCESS;
+    let parsing = if args.strict { Parsing::Strict } else { Parsing::Lenient };
+    for fname in args.inputs.iter() {
+        let cso = match CsoFile::open_with(fname, parsing) {
+            Ok(cso) => cso,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", fname.display(), e);
+                status = ExitCode::FAILURE;
+                continue;
+            },
+        };
 
-    pad_file(&mut dest_f1)?;
+        let header = cso.header();
+        let magic = header.format.magic().to_le_bytes();
+        let stored = cso.stored_bytes().unwrap_or(0);
+        let ratio = if header.total_bytes > 0 {
+            stored as f64 / header.total_bytes as f64 * 100.0
+        } else {
+            0.0
+        };
+        let sane = match cso.index_looks_sane() {
+            Ok(true) => "yes",
+            Ok(false) => "no",
+            Err(_) => "unknown",
+        };
+        if sane != "yes" {
+            status = ExitCode::FAILURE;
+        }
 
-    if dest_f2.is_some() {
-        pad_file(&mut dest_f2.unwrap())?;
+        println!("{}", style(fname.display()).bold());
+        println!("  magic:              {}", String::from_utf8_lossy(&magic));
+        println!("  version:            {}", header.version);
+        let methods: Vec<&str> = cso.methods().iter().map(|x| x.name()).collect();
+        println!("  methods:            {}", if methods.is_empty() { "none".to_owned() } else { methods.join(", ") });
+        println!("  block size:         {}", header.block_size);
+        println!("  alignment:          {}", header.align);
+        println!("  uncompressed bytes: {}", header.total_bytes);
+        println!("  stored bytes:       {}", stored);
+        println!("  ratio:              {:.1}%", ratio);
+        println!("  total blocks:       {}", header.total_blocks);
+        println!("  compressed blocks:  {}", cso.compressed_blocks());
+        println!("  parts:              {}", cso.part_count());
+        println!("  index looks sane:   {}", sane);
+        for quirk in cso.quirks() {
+            println!("  off the spec:       {}", quirk);
+        }
+        if let Some(trailer) = cso.trailer() {
+            println!("  written by:         {}", trailer.tool);
+            println!("  ISO size:           {}", trailer.source.size);
+            println!("  ISO CRC32:          {}", trailer.source.crc32_hex());
+            println!("  ISO MD5:            {}", trailer.source.md5_hex());
+            println!("  ISO SHA-1:          {}", trailer.source.sha1_hex());
+        }
     }
 
-    pb.finish_and_clear();
+    status
+}
 
-    return Ok(dest_fp);
+fn run_verify(args: VerifyArgs) -> ExitCode {
+    let _span = xcso::span!(Level::Info, "verify", "file={}", args.cso.display());
+    let started = Instant::now();
+    match verify_cso_with_progress(&args.iso, &args.cso, &progress_bar(args.verbosity)) {
+        Ok(VerifyOutcome::Match) => {
+            if args.verbosity != Verbosity::Quiet {
+                println!("{} {}matches {}", args.cso.display(), clip(), args.iso.display());
+            }
+            if args.verbosity == Verbosity::Verbose {
+                let size = CsoFile::open(&args.cso).map_or(0, |x| x.header().total_bytes);
+                println!("Compared {} in {:.1}s", BinaryBytes(size), started.elapsed().as_secs_f64());
+            }
+            ExitCode::SUCCESS
+        },
+        Ok(VerifyOutcome::SizeMismatch { expected, actual }) => {
+            eprintln!(
+                "{} does not match {}: expected {} bytes of image data, found {}",
+                args.cso.display(), args.iso.display(), expected, actual,
+            );
+            ExitCode::FAILURE
+        },
+        Ok(VerifyOutcome::BlockMismatch { block, offset }) => {
+            eprintln!(
+                "{} does not match {}: first mismatch in block {} (ISO offset {:#x})",
+                args.cso.display(), args.iso.display(), block, offset,
+            );
+            ExitCode::FAILURE
+        },
+        Err(e) => {
+            eprintln!("Error verifying {}: {}", args.cso.display(), e);
+            print_hint(&e);
+            ExitCode::FAILURE
+        },
+    }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        println!("{} usage: <isos to convert>", get_filename_from_path(&args[0]));
-        return;
+// Bad blocks listed unless --verbose asks for all of them
+const SHOWN_BAD_BLOCKS: usize = 10;
+
+fn run_verify_checksums(args: CheckArgs) -> ExitCode {
+    let _span = xcso::span!(Level::Info, "verify", "file={}", args.cso.display());
+    let started = Instant::now();
+    let checked = match check_cso_with_progress(&args.cso, &progress_bar(args.verbosity)) {
+        Ok(checked) => checked,
+        Err(e) => {
+            eprintln!("Error verifying {}: {}", args.cso.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let block_size = CsoFile::open(&args.cso).map_or(CISO_BLOCK_SIZE as u64, |x| x.header().block_size as u64);
+    if checked.is_good() {
+        let against = match (&checked.bad_parts, &checked.bad_blocks) {
+            (Some(_), Some(_)) => "its trailer and checksums",
+            (Some(_), None) => "its trailer",
+            _ => "its checksums",
+        };
+        if args.verbosity != Verbosity::Quiet {
+            println!("{} {}matches {}", args.cso.display(), clip(), against);
+        }
+        if args.verbosity == Verbosity::Verbose {
+            let size = checked.blocks as u64 * block_size;
+            println!("Checked {} in {:.1}s", BinaryBytes(size), started.elapsed().as_secs_f64());
+        }
+        return ExitCode::SUCCESS;
     }
 
-    let iter = args.iter().
-        skip(1).
-        filter(|x| is_iso(x)).
-        enumerate();
+    for &part in checked.bad_parts.iter().flatten() {
+        eprintln!("Part {} of {} doesn't match its trailer", part, args.cso.display());
+    }
+    let bad_blocks = checked.bad_blocks.unwrap_or_default();
+    if bad_blocks.is_empty() {
+        return ExitCode::FAILURE;
+    }
+    eprintln!(
+        "{} has {} bad block{} of {}:",
+        args.cso.display(),
+        bad_blocks.len(),
+        if bad_blocks.len() == 1 { "" } else { "s" },
+        checked.blocks,
+    );
+    let shown = match args.verbosity {
+        Verbosity::Verbose => bad_blocks.len(),
+        _ => SHOWN_BAD_BLOCKS,
+    };
+    for &block in bad_blocks.iter().take(shown) {
+        eprintln!("  block {} (image offset {:#x})", block, block as u64 * block_size);
+    }
+    if bad_blocks.len() > shown {
+        eprintln!("  and {} more (--verbose lists them all)", bad_blocks.len() - shown);
+    }
+    ExitCode::FAILURE
+}
 
-    for (i, fname) in iter {        
-        let fancy_file: String = format!("[{}/{}]", i+1, args.len()-1);
-        println!(
-            "{} {}Converting image {}...",
-            style(fancy_file.clone()).bold().dim(),
-            CLIP,
-            fname,
+fn run_compare(args: VerifyArgs) -> ExitCode {
+    let _span = xcso::span!(Level::Info, "compare", "file={}", args.cso.display());
+    let started = Instant::now();
+    let result = match compare_cso_with_progress(&args.iso, &args.cso, &progress_bar(args.verbosity)) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Error comparing {}: {}", args.cso.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    if result.matches() {
+        if args.verbosity != Verbosity::Quiet {
+            println!("{} {}matches {}", args.cso.display(), clip(), args.iso.display());
+        }
+        if args.verbosity == Verbosity::Verbose {
+            let compared = result.cso_bytes.min(result.iso_bytes);
+            println!("Compared {} in {:.1}s", BinaryBytes(compared), started.elapsed().as_secs_f64());
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if !result.trimmed && result.iso_bytes != result.cso_bytes {
+        eprintln!(
+            "{} holds {} bytes of image data, but {} has {}",
+            args.cso.display(), result.cso_bytes, args.iso.display(), result.iso_bytes,
         );
+    }
+    match result.first_mismatch {
+        Some((block, offset)) => eprintln!(
+            "{} differs from {} in {} of {} blocks, first in block {} (ISO offset {:#x})",
+            args.cso.display(), args.iso.display(), result.mismatched_blocks, result.blocks, block, offset,
+        ),
+        None => eprintln!("The {} blocks both hold match", result.blocks),
+    }
+    ExitCode::FAILURE
+}
+
+// Problems printed for each image unless --verbose asks for all of them
+const SHOWN_PROBLEMS: usize = 10;
 
-        match compress_iso(fname) {
-            Ok(fp) => {
-                println!(
-                    "{} {}Converted image {}!",
-                    style(fancy_file).bold().dim(),
-                    CLIP,
-                    fp,
-                );
+fn run_validate(args: ValidateArgs) -> ExitCode {
+    let mut status = ExitCode::SUCCESS;
+    for fname in args.inputs.iter() {
+        let problems = match CsoFile::open(fname).and_then(|x| x.validate().map(|p| (x, p))) {
+            Ok((cso, problems)) => {
+                if args.verbosity == Verbosity::Verbose {
+                    for quirk in cso.quirks() {
+                        println!("{} is off the spec: {}", fname.display(), quirk);
+                    }
+                }
+                problems
+            },
+            Err(e) => {
+                eprintln!("Error reading {}: {}", fname.display(), e);
+                status = ExitCode::FAILURE;
                 continue;
             },
+        };
+        if problems.is_empty() {
+            if args.verbosity != Verbosity::Quiet {
+                println!("{} {}is valid", fname.display(), clip());
+            }
+            continue;
+        }
+
+        status = ExitCode::FAILURE;
+        eprintln!("{} has {} problem{}:", fname.display(), problems.len(), if problems.len() == 1 { "" } else { "s" });
+        let shown = match args.verbosity {
+            Verbosity::Verbose => problems.len(),
+            _ => SHOWN_PROBLEMS,
+        };
+        for problem in problems.iter().take(shown) {
+            eprintln!("  {}", problem);
+        }
+        if problems.len() > shown {
+            eprintln!("  and {} more (--verbose lists them all)", problems.len() - shown);
+        }
+    }
+    status
+}
+
+fn run_repair(args: RepairArgs) -> ExitCode {
+    signal::install();
+    let mut failed = false;
+    for fp in &args.images {
+        let _span = xcso::span!(Level::Info, "repair", "file={}", fp.display());
+        if let Some(dest) = args.output.as_ref().filter(|x| x.exists() && !args.force) {
+            eprintln!(
+                "Error repairing {}: {} already exists (use --force to replace it)",
+                fp.display(), dest.display(),
+            );
+            return ExitCode::FAILURE;
+        }
+        if args.verbosity != Verbosity::Quiet {
+            println!("{}Repairing image {}...", clip(), fp.display());
+        }
+        let started = Instant::now();
+        match repair_cso_with_progress(fp, args.output.as_deref(), &progress_bar(args.verbosity)) {
+            Ok(repaired) => {
+                let dest = args.output.as_deref().unwrap_or(fp);
+                if args.verbosity != Verbosity::Quiet {
+                    match repaired.changed {
+                        0 if args.output.is_none() => println!("{}{} already has a good index", clip(), fp.display()),
+                        _ => println!("{}Rebuilt the index of {}!", clip(), dest.display()),
+                    }
+                }
+                if args.verbosity == Verbosity::Verbose {
+                    println!(
+                        "{} of {} entries changed in {:.1}s",
+                        repaired.changed, repaired.blocks + 1, started.elapsed().as_secs_f64(),
+                    );
+                }
+            },
+            Err(e) => {
+                eprintln!("Error repairing {}: {}", fp.display(), e);
+                if e.kind() == io::ErrorKind::Interrupted {
+                    return ExitCode::from(summary::EXIT_INTERRUPTED);
+                }
+                failed = true;
+            },
+        }
+    }
+    match failed {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+/// Picks a level for compressing `fname` from a sample of it, keeping the
+/// level in `opts` if the image can't be sampled.
+fn auto_level(fname: &Path, opts: &CompressOptions) -> u32 {
+    match Sample::read(fname, opts, DEFAULT_SAMPLES).and_then(|x| x.pick_level(opts)) {
+        Ok(level) => level,
+        Err(e) => {
+            xcso::warn!("could not sample {}, using level {}: {}", fname.display(), opts.level, e);
+            opts.level
+        },
+    }
+}
+
+/// Prints what converting each of `inputs` would give, without writing
+/// anything.
+fn run_estimate(inputs: &[&PathBuf], opts: &CompressOptions, args: &CompressArgs) -> ExitCode {
+    let mut failed = false;
+    for fname in inputs {
+        let opts = CompressOptions { threads: args.threads, ..opts.clone() };
+        let estimate = Sample::read(fname, &opts, DEFAULT_SAMPLES).and_then(|sample| {
+            let level = match args.level {
+                Some(level) => level,
+                None => sample.pick_level(&opts)?,
+            };
+            Ok((level, sample.estimate(&CompressOptions { level, ..opts.clone() })?))
+        });
+        let (level, estimate) = match estimate {
+            Ok(x) => x,
             Err(e) => {
-                eprintln!("Error converting {}: {}", fname, e);
+                eprintln!("Error sampling {}: {}", fname.display(), e);
+                failed = true;
                 continue;
             },
         };
+        if args.json {
+            println!("{}", json::object(vec![
+                ("source", fname.as_path().into()),
+                ("status", "estimated".into()),
+                ("level", (level as u64).into()),
+                ("estimated_bytes", estimate.total_bytes().into()),
+                ("estimated_parts", estimate.parts.iter().map(|&x| x.into()).collect::<Vec<json::Value>>().into()),
+                ("estimated_secs", estimate.duration.as_secs_f64().into()),
+            ]));
+            continue;
+        }
+        let parts = match estimate.parts.len() {
+            1 => "in one file".to_owned(),
+            n => {
+                let sizes: Vec<String> = estimate.parts.iter().map(|&x| BinaryBytes(x).to_string()).collect();
+                let why = match opts.split_size {
+                    Some(FATX_MAX_SIZE) => "to fit FATX",
+                    _ => "at the split size",
+                };
+                format!("split {} into {} parts of {}", why, n, sizes.join(", "))
+            },
+        };
+        println!(
+            "{}: about {} ({:.1}%) at level {}, {}, taking {}",
+            fname.display(),
+            BinaryBytes(estimate.total_bytes()),
+            estimate.ratio * 100.0,
+            level,
+            parts,
+            HumanDuration(estimate.duration),
+        );
+    }
+    match failed {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+fn run_bench(args: BenchArgs) -> ExitCode {
+    let opts = CompressOptions {
+        block_size: args.block_size,
+        align: args.align,
+        raw: args.raw,
+        keep_video_partition: args.keep_video_partition,
+        ..CompressOptions::default()
+    };
+    let sample = match Sample::read(&args.input, &opts, args.samples) {
+        Ok(sample) => sample,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.input.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let threads = physical_cores();
+    println!(
+        "Sampled {} of {} blocks of {} ({}), speeds are for one thread and times for the whole image on every core",
+        sample.len(), sample.image.total_blocks, args.input.display(), BinaryBytes(sample.image.total_bytes),
+    );
+    println!("{:<9}{:<7}{:<9}{:<14}Time", "Method", "Level", "Ratio", "Speed");
+
+    let lz4 = (LEVEL_FAST..=LEVEL_MAX).map(|x| (Method::Lz4, x));
+    let deflate = (1..=deflate::MAX_LEVEL).map(|x| (Method::Deflate, x));
+    for (method, level) in lz4.chain(deflate) {
+        let trial = match sample.trial(&CompressOptions { method, level, ..opts.clone() }) {
+            Ok(trial) => trial,
+            Err(e) => {
+                eprintln!("Error compressing with {} level {}: {}", method.name(), level, e);
+                return ExitCode::FAILURE;
+            },
+        };
+        let whole = sample.image.total_bytes as f64 / (trial.bytes_per_sec() * threads as f64);
+        println!(
+            "{:<9}{:<7}{:<9}{:<14}{}",
+            method.name(),
+            level,
+            format!("{:.1}%", trial.ratio() * 100.0),
+            format!("{}/s", BinaryBytes(trial.bytes_per_sec() as u64)),
+            HumanDuration(Duration::from_secs_f64(whole)),
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn run_selftest(verbosity: Verbosity) -> ExitCode {
+    let started = Instant::now();
+    let results = selftest(|x| match x.result {
+        Ok(stored) if verbosity == Verbosity::Verbose => {
+            println!("ok      {} as {}, {} to {} bytes", x.image, x.settings, x.bytes, stored);
+        },
+        Ok(_) => {},
+        Err(ref e) => eprintln!("FAILED  {} as {}: {}", x.image, x.settings, e),
+    });
+    let failed = results.iter().filter(|x| x.result.is_err()).count();
+    if failed > 0 {
+        eprintln!("{} of {} round trips failed", failed, results.len());
+        return ExitCode::FAILURE;
+    }
+    if verbosity != Verbosity::Quiet {
+        println!("All {} round trips passed in {}", results.len(), HumanDuration(started.elapsed()));
+    }
+    ExitCode::SUCCESS
+}
+
+fn run_ls(args: LsArgs) -> ExitCode {
+    let listing = xdvdfs::open_image(&args.image).and_then(|mut volume| {
+        let root = volume.root().clone();
+        volume.walk(&root)
+    });
+    let listing = match listing {
+        Ok(listing) => listing,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.image.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let (mut files, mut bytes) = (0, 0);
+    for (path, entry) in listing.iter() {
+        match entry.is_dir() {
+            true => println!("{:>10}  {}/", "", style(path).bold()),
+            false => {
+                println!("{:>10}  {}", BinaryBytes(entry.size as u64).to_string(), path);
+                files += 1;
+                bytes += entry.size as u64;
+            },
+        }
+    }
+    println!("{} files, {}", files, BinaryBytes(bytes));
+    ExitCode::SUCCESS
+}
+
+fn run_extract(args: ExtractArgs) -> ExitCode {
+    let started = Instant::now();
+    let pb = progress_bar(args.verbosity);
+    let extracted = xdvdfs::open_image(&args.image).and_then(|mut volume| {
+        let entry = match args.path {
+            Some(ref path) => volume.find(path)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{} isn't in the image", path))
+            })?,
+            None => volume.root().clone(),
+        };
+        let files = xdvdfs::extract_with_progress(&mut volume, &entry, &args.output, args.force, &pb)?;
+        Ok((files, pb.length().unwrap_or(0)))
+    });
+
+    match extracted {
+        Ok((files, bytes)) => {
+            if args.verbosity != Verbosity::Quiet {
+                let noun = if files == 1 { "file" } else { "files" };
+                println!("{}Extracted {} {} to {}!", clip(), files, noun, args.output.display());
+            }
+            if args.verbosity == Verbosity::Verbose {
+                println!("{} in {:.1}s", BinaryBytes(bytes), started.elapsed().as_secs_f64());
+            }
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            pb.finish_and_clear();
+            eprintln!("Error extracting {}: {}", args.image.display(), e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn run_cat(args: CatArgs) -> ExitCode {
+    let copied = xdvdfs::open_image(&args.image).and_then(|mut volume| {
+        let entry = volume.find(&args.path)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} isn't in the image", args.path))
+        })?;
+        if entry.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a folder", args.path)));
+        }
+        let mut out = io::stdout().lock();
+        volume.copy_file(&entry, &mut out)?;
+        out.flush()
+    });
+
+    match copied {
+        Ok(()) => ExitCode::SUCCESS,
+        // Whatever it was piped into has all it wanted, as with `| head`
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.image.display(), e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+fn run_mount(args: MountArgs) -> ExitCode {
+    mount::run(args)
+}
+
+#[cfg(not(all(feature = "fuse", target_os = "linux")))]
+fn run_mount(args: MountArgs) -> ExitCode {
+    eprintln!("Error mounting {} on {}: this build can't mount images", args.image.display(), args.dir.display());
+    if args.verbosity != Verbosity::Quiet && cfg!(target_os = "linux") {
+        eprintln!("hint: mounting takes a build made with `cargo build --release --features fuse`");
+    }
+    ExitCode::FAILURE
+}
+
+/// Packs `args.dir` into `iso`, then compresses it into `base` when asked
+/// to, returning the final output.
+fn pack(args: &PackArgs, iso: &Path, base: &Path, pb: &ProgressBar) -> io::Result<PathBuf> {
+    let opts = PackOptions { strip_system_update: args.strip_system_update };
+    pack_dir_with_progress(&args.dir, iso, &opts, pb)?;
+    if !args.compress {
+        return Ok(iso.to_path_buf());
+    }
+
+    let result = compress_iso_with_progress(iso, base, &CompressOptions::default(), pb);
+    if let Err(e) = fs::remove_file(iso) {
+        eprintln!("Error removing {}: {}", iso.display(), e);
+    }
+    result
+}
+
+fn run_pack(args: PackArgs) -> ExitCode {
+    let name = args.dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    // The image is staged next to the CSO, and only kept without --compress
+    let base = match args.output {
+        Some(ref output) if args.compress => strip_extension(output, Format::Cso.extension()).
+            map(|x| strip_extension(&x, "1").unwrap_or(x)).
+            unwrap_or_else(|| output.clone()),
+        _ => args.dir.with_file_name(&name),
+    };
+    let (iso, dest) = match (&args.output, args.compress) {
+        (_, true) => (base.with_file_name(format!("{}.iso.part", name)), part_path(&base, 1, Format::Cso)),
+        (Some(output), false) => (output.clone(), output.clone()),
+        (None, false) => {
+            let iso = args.dir.with_file_name(format!("{}.iso", name));
+            (iso.clone(), iso)
+        },
+    };
+    if dest.exists() && !args.force {
+        eprintln!(
+            "Error packing {}: {} already exists (use --force to replace it)",
+            args.dir.display(),
+            dest.display(),
+        );
+        return ExitCode::FAILURE;
+    }
+
+    signal::install();
+    let started = Instant::now();
+    let pb = progress_bar(args.verbosity);
+    match pack(&args, &iso, &base, &pb) {
+        Ok(fp) => {
+            if args.verbosity != Verbosity::Quiet {
+                println!("{}Packed {} into {}!", clip(), args.dir.display(), fp.display());
+            }
+            if args.verbosity == Verbosity::Verbose {
+                let size = fs::metadata(&fp).map_or(0, |x| x.len());
+                println!("{} in {:.1}s", BinaryBytes(size), started.elapsed().as_secs_f64());
+            }
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            pb.finish_and_clear();
+            eprintln!("Error packing {}: {}", args.dir.display(), e);
+            match e.kind() {
+                io::ErrorKind::Interrupted => ExitCode::from(summary::EXIT_INTERRUPTED),
+                _ => ExitCode::FAILURE,
+            }
+        },
+    }
+}
+
+/// Names the single image a split set is merged into, `game.cso` for the
+/// set starting with `game.1.cso`.
+fn merged_name(fp: &Path) -> Option<PathBuf> {
+    [Format::Cso, Format::Zso].iter().find_map(|format| {
+        let base = strip_extension(&strip_extension(fp, format.extension())?, "1")?;
+        let mut name = base.into_os_string();
+        name.push(format!(".{}", format.extension()));
+        Some(PathBuf::from(name))
+    })
+}
+
+fn run_merge(args: MergeArgs) -> ExitCode {
+    let first = &args.parts[0];
+    let _span = xcso::span!(Level::Info, "merge", "file={}", first.display());
+    // Later parts are found from the first, so any others given have to be
+    // the ones it would find
+    for (n, fp) in args.parts.iter().enumerate().skip(1) {
+        let expected = nth_part_path(first, n + 1);
+        if expected.as_deref().and_then(|x| fs::canonicalize(x).ok()) != fs::canonicalize(fp).ok() {
+            eprintln!("Error merging {}: {} is not part {} of the set", first.display(), fp.display(), n + 1);
+            return ExitCode::FAILURE;
+        }
+    }
+    let Some(dest) = args.output.clone().or_else(|| merged_name(first)) else {
+        eprintln!("Error merging {}: not the first part of a split set, such as game.1.cso", first.display());
+        return ExitCode::FAILURE;
+    };
+    // The later parts would be read as part of the merged image
+    if fs::canonicalize(&dest).is_ok_and(|x| fs::canonicalize(first).is_ok_and(|first| first == x)) {
+        eprintln!("Error merging {}: the output would replace the first part, choose another", first.display());
+        return ExitCode::FAILURE;
+    }
+    if dest.exists() && !args.force {
+        eprintln!("Error merging {}: {} already exists (use --force to replace it)", first.display(), dest.display());
+        return ExitCode::FAILURE;
+    }
+    if args.verbosity != Verbosity::Quiet {
+        println!("{}Merging image {}...", clip(), first.display());
+    }
+
+    signal::install();
+    let started = Instant::now();
+    match merge_cso_with_progress(first, &dest, &progress_bar(args.verbosity)) {
+        Ok(()) => {
+            if args.verbosity != Verbosity::Quiet {
+                println!("{}Merged image {}!", clip(), dest.display());
+            }
+            if args.verbosity == Verbosity::Verbose {
+                let size = fs::metadata(&dest).map_or(0, |x| x.len());
+                println!("{} in {:.1}s", BinaryBytes(size), started.elapsed().as_secs_f64());
+            }
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            eprintln!("Error merging {}: {}", first.display(), e);
+            match e.kind() {
+                io::ErrorKind::Interrupted => ExitCode::from(summary::EXIT_INTERRUPTED),
+                _ => ExitCode::FAILURE,
+            }
+        },
+    }
+}
+
+/// Writes the image in `args.input` out in the format asked for, returning
+/// the path of the (first) output.
+/// Fails if `dest` is `input`, which is still being read while it's written,
+/// or is there already and `force` wasn't given.
+fn check_transcode_dest(input: &Path, dest: &Path, force: bool) -> io::Result<()> {
+    if fs::canonicalize(dest).is_ok_and(|x| fs::canonicalize(input).is_ok_and(|input| input == x)) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the output would replace the input"));
+    }
+    if dest.exists() && !force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists (use --force to replace it)", dest.display()),
+        ));
+    }
+    Ok(())
+}
+
+fn transcode(args: &TranscodeArgs, pb: &ProgressBar) -> io::Result<PathBuf> {
+    let Some(format) = args.format else {
+        if !matches!(image_kind(&args.input)?, ImageKind::Compressed(_)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the input is already an ISO"));
+        }
+        check_transcode_dest(&args.input, &args.output, args.force)?;
+        if args.force {
+            _ = fs::remove_file(&args.output);
+        }
+        // What's there now is this run's, unless something took the name first
+        let result = decompress_cso_with_progress(&args.input, &args.output, pb);
+        if result.as_ref().is_err_and(|e| e.kind() != io::ErrorKind::AlreadyExists) {
+            _ = fs::remove_file(&args.output);
+        }
+        return result.map(|_| args.output.clone());
+    };
+
+    // Blocks are laid out like the input's unless asked otherwise
+    let header = match image_kind(&args.input)? {
+        ImageKind::Compressed(_) => Some(CsoFile::open(&args.input)?.header()),
+        _ => None,
+    };
+    let opts = CompressOptions {
+        level: args.level,
+        format,
+        method: args.method,
+        version: args.cso_version,
+        block_size: args.block_size.or(header.map(|x| x.block_size)).unwrap_or(CISO_BLOCK_SIZE as u32),
+        align: args.align.or(header.map(|x| x.align)).unwrap_or(2),
+        // Whatever the image holds is kept as it is
+        raw: true,
+        split_size: args.split_size,
+        split_naming: args.split_naming,
+        ..CompressOptions::default()
+    };
+    let base = strip_extension(&args.output, format.extension()).
+        map(|x| strip_extension(&x, "1").unwrap_or(x)).
+        unwrap_or_else(|| args.output.clone());
+    check_transcode_dest(&args.input, &args.split_naming.part_path(&base, 1, format), args.force)?;
+    compress_iso_with_progress(&args.input, &base, &opts, pb)
+}
+
+fn run_transcode(args: TranscodeArgs) -> ExitCode {
+    let _span = xcso::span!(Level::Info, "transcode", "file={}", args.input.display());
+    if args.verbosity != Verbosity::Quiet {
+        println!("{}Transcoding image {}...", clip(), args.input.display());
+    }
+
+    signal::install();
+    let started = Instant::now();
+    let pb = progress_bar(args.verbosity);
+    match transcode(&args, &pb) {
+        Ok(fp) => {
+            if args.verbosity != Verbosity::Quiet {
+                println!("{}Transcoded image {}!", clip(), fp.display());
+            }
+            if args.verbosity == Verbosity::Verbose {
+                let size = fs::metadata(&fp).map_or(0, |x| x.len());
+                println!("{} in {:.1}s", BinaryBytes(size), started.elapsed().as_secs_f64());
+            }
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            pb.finish_and_clear();
+            eprintln!("Error transcoding {}: {}", args.input.display(), e);
+            match e.kind() {
+                io::ErrorKind::Interrupted => ExitCode::from(summary::EXIT_INTERRUPTED),
+                _ => ExitCode::FAILURE,
+            }
+        },
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<OsString> = env::args_os().skip(1).collect();
+    if let Err(e) = xcso::log::init_from_env() {
+        eprintln!("warning: {}", e);
+    }
+
+    let command = match cli::parse(args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}\n\nFor more information, try '--help'.", e);
+            return ExitCode::from(2);
+        },
+    };
+
+    match command {
+        Command::Compress(args) => run_compress(*args),
+        Command::Decompress(args) => run_decompress(args),
+        Command::Info(args) => run_info(args),
+        Command::Ls(args) => run_ls(args),
+        Command::Cat(args) => run_cat(args),
+        Command::Extract(args) => run_extract(args),
+        Command::Mount(args) => run_mount(args),
+        Command::Pack(args) => run_pack(args),
+        Command::Merge(args) => run_merge(args),
+        Command::Transcode(args) => run_transcode(args),
+        Command::Verify(args) => run_verify(args),
+        Command::VerifyChecksums(args) => run_verify_checksums(args),
+        Command::Compare(args) => run_compare(args),
+        Command::Validate(args) => run_validate(args),
+        Command::Repair(args) => run_repair(args),
+        Command::Bench(args) => run_bench(args),
+        Command::SelfTest(verbosity) => run_selftest(verbosity),
+        Command::Serve(args) => serve::run(args),
+        Command::HttpServe(args) => http_serve::run(args),
+        Command::NbdServe(args) => nbd::run(args),
+        Command::Help(command) => {
+            println!("{}", cli::usage(command.as_deref()));
+            ExitCode::SUCCESS
+        },
+        Command::Version => {
+            println!("make-xcso {}", cli::VERSION);
+            ExitCode::SUCCESS
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use xcso::{encode_image, SplitNaming};
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("xcso-transcode-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_cso(fp: &Path, data: &[u8]) {
+        let mut out = Cursor::new(Vec::new());
+        encode_image(data, &mut out, &CompressOptions::default()).unwrap();
+        fs::write(fp, out.into_inner()).unwrap();
+    }
+
+    fn to_iso(input: PathBuf, output: PathBuf, force: bool) -> TranscodeArgs {
+        TranscodeArgs {
+            input,
+            output,
+            format: None,
+            level: LEVEL_MAX,
+            method: Method::Lz4,
+            cso_version: None,
+            block_size: None,
+            align: None,
+            split_size: None,
+            split_naming: SplitNaming::Numbered,
+            force,
+            verbosity: Verbosity::Quiet,
+        }
+    }
+
+    #[test]
+    fn transcoding_to_an_iso_keeps_what_was_there() {
+        let dir = scratch("keep");
+        let data = vec![7; 10_000];
+        write_cso(&dir.join("game.1.cso"), &data);
+        fs::write(dir.join("keep.iso"), b"keep").unwrap();
+
+        let args = to_iso(dir.join("game.1.cso"), dir.join("keep.iso"), false);
+        let e = transcode(&args, &ProgressBar::hidden()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(dir.join("keep.iso")).unwrap(), b"keep");
+
+        let args = to_iso(dir.join("game.1.cso"), dir.join("keep.iso"), true);
+        transcode(&args, &ProgressBar::hidden()).unwrap();
+        assert_eq!(fs::read(dir.join("keep.iso")).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transcoding_never_replaces_the_input() {
+        let dir = scratch("input");
+        write_cso(&dir.join("victim.iso"), &[7; 10_000]);
+        let image = fs::read(dir.join("victim.iso")).unwrap();
+
+        let args = to_iso(dir.join("victim.iso"), dir.join("victim.iso"), true);
+        let e = transcode(&args, &ProgressBar::hidden()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(dir.join("victim.iso")).unwrap(), image);
+
+        fs::rename(dir.join("victim.iso"), dir.join("victim.1.cso")).unwrap();
+        let cso = to_iso(dir.join("victim.1.cso"), dir.join("victim.1.cso"), true);
+        let e = transcode(&TranscodeArgs { format: Some(Format::Cso), ..cso }, &ProgressBar::hidden()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(dir.join("victim.1.cso")).unwrap(), image);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
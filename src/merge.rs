@@ -0,0 +1,194 @@
+//! Joins a split CSO set back into a single file, for drives that don't
+//! have the FATX file size limit.
+//!
+//! Blocks are copied as they were stored, so nothing is compressed again;
+//! only the header and block index are rebuilt to cover the whole image.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::codec::Block;
+use crate::compress::{interrupted, temp_path, PartWriter, WriteOptions, PROGRESS_TEMPLATE};
+use crate::cso::{max_addressable, CsoImage, CISO_HEADER_SIZE};
+use crate::decompress::CsoFile;
+use crate::error::XcsoError;
+
+/// Merges the split set whose first part is `fp` into the single image
+/// `dest`.
+pub fn merge_cso(fp: &Path, dest: &Path) -> Result<(), Error> {
+    let pb = ProgressBar::new(0);
+    pb.set_style(ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap());
+    merge_cso_with_progress(fp, dest, &pb)
+}
+
+/// Same as `merge_cso`, but reports progress on `pb` (counting bytes of the
+/// image).
+pub fn merge_cso_with_progress(fp: &Path, dest: &Path, pb: &ProgressBar) -> Result<(), Error> {
+    let mut cso = CsoFile::open(fp)?;
+    if cso.part_count() < 2 {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a split image", fp.display())));
+    }
+
+    // Blocks of v2 images from earlier versions of this tool come out the way
+    // the format is meant to have them, so the header can stay as it is
+    let image = cso.header();
+    let index_bytes = (image.total_blocks as u64 + 1) * 4;
+    if CISO_HEADER_SIZE as u64 + index_bytes + cso.image_bytes() >= max_addressable(image.align) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("alignment {} can't address the whole image in one file, transcode it instead", image.align),
+        ));
+    }
+
+    let temp = temp_path(dest);
+    let result = write_merged(&mut cso, image, &temp, pb).and_then(|_| fs::rename(&temp, dest));
+    pb.finish_and_clear();
+    if result.is_err() {
+        _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_merged(cso: &mut CsoFile, image: CsoImage, temp: &Path, pb: &ProgressBar) -> Result<(), Error> {
+    let alignment_buffer = vec![0; 1 << image.align];
+    // Compressed blocks bring along the padding that followed them, which in
+    // CSO v2 could make them long enough to pass for raw ones
+    let max_compressed = image.block_size as usize - (1 << image.align);
+
+    let mut part = PartWriter::create(temp, image, 0, Some(image.total_blocks), WriteOptions::default())?;
+    pb.set_length(image.total_bytes);
+    pb.set_position(0);
+    for seq in 0..image.total_blocks {
+        if interrupted() {
+            return Err(XcsoError::Interrupted.into());
+        }
+        let (mut data, mut method) = cso.read_stored(seq)?;
+        if method.is_some() && image.sizes_raw_blocks() && data.len() > max_compressed {
+            (data, method) = (cso.read_block(seq)?, None);
+        }
+        part.write_block(&Block { seq, data, method }, &alignment_buffer)?;
+        pb.inc(image.block_len(seq) as u64);
+    }
+    part.finish(image.total_bytes, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::compress::{compress_block_v2, compress_reader, part_path, CompressOptions, LEVEL_FAST};
+    use crate::cso::{write_cso_info, Format, Method};
+    use crate::decompress::decompress_cso;
+    use crate::testing::{image, Scratch};
+
+    fn decoded(dir: &Scratch, fp: &Path) -> Vec<u8> {
+        let out = dir.join("out.iso");
+        _ = fs::remove_file(&out);
+        decompress_cso(fp, &out).unwrap();
+        fs::read(out).unwrap()
+    }
+
+    /// Writes `data` the way earlier versions of this tool did, as a CSO v2
+    /// split set of `per_part` blocks a part, with LZ4 blocks flagged and
+    /// prefixed with their size, and one index in the first part whose
+    /// positions start again from zero in each of the others.
+    fn legacy_set(dir: &Scratch, data: &[u8], per_part: usize) -> PathBuf {
+        let image = CsoImage {
+            format: Format::Cso,
+            version: 2,
+            align: 2,
+            block_size: 2048,
+            total_bytes: data.len() as u64,
+            total_blocks: CsoImage::block_count(data.len() as u64, 2048),
+        };
+        let index_end = CISO_HEADER_SIZE as usize + (image.total_blocks + 1) * 4;
+        let mut index = Vec::new();
+        let mut parts = vec![Vec::new()];
+        for (seq, block) in data.chunks(2048).enumerate() {
+            if seq > 0 && seq % per_part == 0 {
+                parts.push(Vec::new());
+            }
+            let start = if parts.len() == 1 { index_end } else { 0 };
+            let part = parts.last_mut().unwrap();
+            let pos = ((start + part.len()) >> image.align) as u32;
+            let compressed = compress_block_v2(block, LEVEL_FAST).unwrap();
+            match compressed.len() < block.len() {
+                true => {
+                    index.push(image.index_entry(pos, Some(Method::Lz4)));
+                    part.extend(compressed);
+                },
+                false => {
+                    index.push(image.index_entry(pos, None));
+                    part.extend(block);
+                },
+            }
+            part.resize(part.len().next_multiple_of(1 << image.align), 0);
+        }
+        index.push((parts.last().unwrap().len() >> image.align) as u32);
+
+        let mut first = Vec::new();
+        write_cso_info(&mut first, image).unwrap();
+        first.extend(index.iter().flat_map(|x| x.to_le_bytes()));
+        parts[0].splice(0..0, first);
+        for (n, part) in parts.iter().enumerate() {
+            fs::write(part_path(&dir.join("legacy"), n + 1, Format::Cso), part).unwrap();
+        }
+        part_path(&dir.join("legacy"), 1, Format::Cso)
+    }
+
+    #[test]
+    fn merges_split_sets() {
+        let dir = Scratch::new("merge");
+        let data = image(3000);
+        let methods = [(Format::Cso, Method::Lz4), (Format::Cso, Method::Deflate), (Format::Zso, Method::Lz4)];
+        for (format, method) in methods {
+            let opts = CompressOptions {
+                format,
+                method,
+                level: LEVEL_FAST,
+                raw: true,
+                split_size: Some(1 << 20),
+                ..CompressOptions::default()
+            };
+            let fp = compress_reader(Cursor::new(&data), &dir.join("game"), &opts).unwrap();
+            assert!(CsoFile::open(&fp).unwrap().part_count() > 1);
+
+            let merged = dir.join("merged.cso");
+            merge_cso(&fp, &merged).unwrap();
+            let cso = CsoFile::open(&merged).unwrap();
+            assert_eq!(cso.part_count(), 1);
+            assert_eq!(cso.validate().unwrap(), Vec::<String>::new());
+            assert_eq!((cso.header().format, cso.methods()), (format, vec![method]));
+            assert_eq!(decoded(&dir, &merged), data, "{:?} {:?}", format, method);
+
+            // There's nothing to merge in an image that's already one file
+            let e = merge_cso(&merged, &dir.join("again.cso")).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+            assert!(!dir.join("again.cso").exists());
+        }
+    }
+
+    #[test]
+    fn merges_legacy_v2_sets() {
+        let dir = Scratch::new("merge");
+        let data = image(1500);
+        let fp = legacy_set(&dir, &data, 400);
+        let cso = CsoFile::open(&fp).unwrap();
+        assert!(cso.is_legacy_v2() && cso.has_shared_index());
+        assert_eq!(cso.part_count(), 4);
+        assert_eq!(decoded(&dir, &fp), data);
+
+        let merged = dir.join("merged.cso");
+        merge_cso(&fp, &merged).unwrap();
+        let cso = CsoFile::open(&merged).unwrap();
+        assert!(!cso.is_legacy_v2() && !cso.has_shared_index());
+        assert_eq!((cso.part_count(), cso.header().version), (1, 2));
+        assert_eq!(cso.validate().unwrap(), Vec::<String>::new());
+        assert_eq!(decoded(&dir, &merged), data);
+    }
+}
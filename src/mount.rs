@@ -0,0 +1,490 @@
+//! `mount`: the files inside an image as a read-only folder, served through
+//! the kernel's FUSE device. The directory tables are read once up front,
+//! and file data is read through a `CsoReader` as the kernel asks for it,
+//! so only the blocks behind what's read get decompressed.
+//!
+//! This speaks the FUSE protocol itself rather than linking libfuse, and
+//! only answers the handful of requests a read-only file system needs. Run
+//! as root it mounts the device directly; anyone else goes through
+//! `fusermount3`, which mounts it and hands the device back over a socket.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, ExitCode};
+use std::thread;
+use std::time::Duration;
+
+use xcso::xdvdfs::{self, DirEntry, ReadSeek, Volume, SECTOR_SIZE};
+use xcso::XcsoError;
+
+use crate::cli::{MountArgs, Verbosity};
+
+// The requests answered, from linux/fuse.h
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+// The protocol version answered with, which the kernel talks down to its
+// own if it's older
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+const FUSE_ROOT_ID: u64 = 1;
+// Keep what was read of a file in the page cache from one open to the next
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+// Most the kernel asks for in one read, and so how big a request can get
+const MAX_READ: usize = 128 << 10;
+// The image doesn't change under the mount, so the kernel can hang on to
+// names and attributes for as long as it likes
+const TTL: u64 = 60 * 60;
+// How often Ctrl+C is checked for while serving
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A file or directory of the image, numbered by its place in `nodes` plus
+/// one, as the root is inode 1.
+struct Node {
+    entry: DirEntry,
+    /// Its path from the root, for printing.
+    path: String,
+    parent: u64,
+    /// Inodes of what a directory holds, in the order of its table.
+    children: Vec<u64>,
+}
+
+struct Filesystem {
+    volume: Volume<Box<dyn ReadSeek>>,
+    nodes: Vec<Node>,
+    /// Owner and modification time of the image, which everything in it
+    /// takes on.
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    verbose: bool,
+}
+
+fn corrupt_name() -> Error {
+    Error::new(ErrorKind::InvalidData, "corrupt XDVDFS file name")
+}
+
+impl Filesystem {
+    fn open(image: &Path, verbose: bool) -> Result<Filesystem, Error> {
+        let meta = fs::metadata(image)?;
+        let mut volume = xdvdfs::open_image(image)?;
+        let root = volume.root().clone();
+        let mut nodes = vec![Node { entry: root.clone(), path: String::new(), parent: FUSE_ROOT_ID, children: vec![] }];
+        let mut dirs = HashMap::from([(String::new(), FUSE_ROOT_ID)]);
+        for (path, entry) in volume.walk(&root)? {
+            // A name with a slash in it would be looked up as two
+            if matches!(entry.name.as_str(), "" | "." | "..") || entry.name.contains(['/', '\0']) {
+                return Err(corrupt_name());
+            }
+            let parent_path = path.rsplit_once('/').map_or("", |(x, _)| x);
+            let parent = *dirs.get(parent_path).ok_or_else(corrupt_name)?;
+            let ino = nodes.len() as u64 + 1;
+            if entry.is_dir() {
+                dirs.insert(path.clone(), ino);
+            }
+            nodes[parent as usize - 1].children.push(ino);
+            nodes.push(Node { entry, path, parent, children: vec![] });
+        }
+        Ok(Filesystem { volume, nodes, uid: meta.uid(), gid: meta.gid(), mtime: meta.mtime() as u64, verbose })
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node, i32> {
+        ino.checked_sub(1).and_then(|x| self.nodes.get(x as usize)).ok_or(libc::ENOENT)
+    }
+
+    /// A `fuse_attr` for inode `ino`.
+    fn attr(&self, ino: u64, node: &Node, out: &mut Vec<u8>) {
+        let entry = &node.entry;
+        let (mode, nlink) = match entry.is_dir() {
+            true => (libc::S_IFDIR | 0o555, 2),
+            false => (libc::S_IFREG | 0o444, 1),
+        };
+        let size = if entry.is_dir() { 0 } else { entry.size as u64 };
+        for x in [ino, size, size.div_ceil(512), self.mtime, self.mtime, self.mtime] {
+            push64(out, x);
+        }
+        for x in [0, 0, 0, mode, nlink, self.uid, self.gid, 0, SECTOR_SIZE as u32, 0] {
+            push32(out, x);
+        }
+    }
+
+    /// A `fuse_entry_out` for inode `ino`.
+    fn entry_out(&self, ino: u64, node: &Node) -> Vec<u8> {
+        let mut out = Vec::with_capacity(128);
+        for x in [ino, 0, TTL, TTL] {
+            push64(&mut out, x);
+        }
+        push64(&mut out, 0);
+        self.attr(ino, node, &mut out);
+        out
+    }
+
+    /// Answers a request, with what goes after the reply header or an
+    /// errno, or `None` for a request that takes no reply.
+    fn handle(&mut self, opcode: u32, ino: u64, body: &[u8]) -> Option<Result<Vec<u8>, i32>> {
+        let reply = match opcode {
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+            FUSE_INIT => init(body),
+            FUSE_LOOKUP => self.lookup(ino, body),
+            FUSE_GETATTR => self.node(ino).map(|node| {
+                let mut out = Vec::with_capacity(104);
+                push64(&mut out, TTL);
+                push64(&mut out, 0);
+                self.attr(ino, node, &mut out);
+                out
+            }),
+            FUSE_OPEN => self.open_file(ino, body),
+            FUSE_READ => self.read(ino, body),
+            FUSE_OPENDIR => self.node(ino).and_then(|node| match node.entry.is_dir() {
+                true => Ok(vec![0; 16]),
+                false => Err(libc::ENOTDIR),
+            }),
+            FUSE_READDIR => self.read_dir(ino, body),
+            FUSE_STATFS => Ok(self.statfs()),
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_DESTROY => Ok(Vec::new()),
+            FUSE_ACCESS => match field32(body, 0) & libc::W_OK as u32 {
+                0 => self.node(ino).map(|_| Vec::new()),
+                _ => Err(libc::EROFS),
+            },
+            _ => Err(libc::ENOSYS),
+        };
+        Some(reply)
+    }
+
+    fn lookup(&self, ino: u64, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let dir = self.node(ino)?;
+        if !dir.entry.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        let name = body.split(|&c| c == 0).next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name);
+        let named = |exact: bool| dir.children.iter().copied().find(|&x| {
+            let child = &self.nodes[x as usize - 1].entry.name;
+            match exact {
+                true => *child == name,
+                false => child.eq_ignore_ascii_case(&name),
+            }
+        });
+        // Names are matched case-insensitively like the Xbox does, as long
+        // as no other entry has that exact name
+        let found = named(true).or_else(|| named(false)).ok_or(libc::ENOENT)?;
+        Ok(self.entry_out(found, &self.nodes[found as usize - 1]))
+    }
+
+    fn open_file(&self, ino: u64, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let node = self.node(ino)?;
+        if node.entry.is_dir() {
+            return Err(libc::EISDIR);
+        }
+        if field32(body, 0) as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+        if self.verbose {
+            println!("Opening {}", node.path);
+        }
+        let mut out = Vec::with_capacity(16);
+        push64(&mut out, 0);
+        push32(&mut out, FOPEN_KEEP_CACHE);
+        push32(&mut out, 0);
+        Ok(out)
+    }
+
+    fn read(&mut self, ino: u64, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let node = self.node(ino)?;
+        if node.entry.is_dir() {
+            return Err(libc::EISDIR);
+        }
+        let (entry, path) = (node.entry.clone(), node.path.clone());
+        let (offset, size) = (field64(body, 8), field32(body, 16) as u64);
+        let len = (entry.size as u64).saturating_sub(offset).min(size);
+        self.volume.read_at(entry.offset() + offset, len as usize).map_err(|e| {
+            eprintln!("Error reading {}: {}", path, e);
+            libc::EIO
+        })
+    }
+
+    /// Lists a directory from the entry `fuse_read_in` asks for, as many as
+    /// fit, with `.` and `..` first. Each entry's offset says where the next
+    /// listing should start.
+    fn read_dir(&self, ino: u64, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let dir = self.node(ino)?;
+        if !dir.entry.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        let (start, size) = (field64(body, 8) as usize, field32(body, 16) as usize);
+        let listing = [(ino, "."), (dir.parent, "..")].into_iter().
+            chain(dir.children.iter().map(|&x| (x, self.nodes[x as usize - 1].entry.name.as_str())));
+
+        let mut out = Vec::new();
+        for (i, (child, name)) in listing.enumerate().skip(start) {
+            let len = (24 + name.len()).next_multiple_of(8);
+            if out.len() + len > size {
+                break;
+            }
+            let kind = match self.nodes[child as usize - 1].entry.is_dir() {
+                true => libc::DT_DIR,
+                false => libc::DT_REG,
+            };
+            push64(&mut out, child);
+            push64(&mut out, i as u64 + 1);
+            push32(&mut out, name.len() as u32);
+            push32(&mut out, kind as u32);
+            out.extend_from_slice(name.as_bytes());
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        Ok(out)
+    }
+
+    /// A `fuse_kstatfs`, of a file system that's full.
+    fn statfs(&self) -> Vec<u8> {
+        let sectors: u64 = self.nodes.iter().map(|x| (x.entry.size as u64).div_ceil(SECTOR_SIZE)).sum();
+        let mut out = Vec::with_capacity(80);
+        for x in [sectors, 0, 0, self.nodes.len() as u64, 0] {
+            push64(&mut out, x);
+        }
+        for x in [SECTOR_SIZE as u32, 255, SECTOR_SIZE as u32, 0, 0, 0, 0, 0, 0, 0] {
+            push32(&mut out, x);
+        }
+        out
+    }
+}
+
+fn push32(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_ne_bytes());
+}
+
+fn push64(out: &mut Vec<u8>, x: u64) {
+    out.extend_from_slice(&x.to_ne_bytes());
+}
+
+/// The `u32` at `at` in a request, or 0 past its end.
+fn field32(body: &[u8], at: usize) -> u32 {
+    body.get(at..at + 4).map_or(0, |x| u32::from_ne_bytes(x.try_into().unwrap()))
+}
+
+fn field64(body: &[u8], at: usize) -> u64 {
+    body.get(at..at + 8).map_or(0, |x| u64::from_ne_bytes(x.try_into().unwrap()))
+}
+
+/// Answers `FUSE_INIT` with a `fuse_init_out`, asking for nothing beyond
+/// the basics.
+fn init(body: &[u8]) -> Result<Vec<u8>, i32> {
+    let (major, max_readahead) = (field32(body, 0), field32(body, 8));
+    if major < FUSE_KERNEL_VERSION {
+        return Err(libc::EPROTO);
+    }
+    let mut out = Vec::with_capacity(64);
+    for x in [FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION, max_readahead, 0] {
+        push32(&mut out, x);
+    }
+    // Background requests and the congestion threshold, as libfuse has them
+    out.extend_from_slice(&12u16.to_ne_bytes());
+    out.extend_from_slice(&9u16.to_ne_bytes());
+    // The most written at once, though nothing is, and timestamps to the second
+    push32(&mut out, 4096);
+    push32(&mut out, 1_000_000_000);
+    out.resize(64, 0);
+    Ok(out)
+}
+
+/// Answers requests on `fuse` until the file system is unmounted.
+fn serve(fuse: &mut File, fs: &mut Filesystem) -> Result<(), Error> {
+    let mut buf = vec![0u8; MAX_READ + 4096];
+    loop {
+        let n = match fuse.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+            // The request was interrupted before it could be read
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) || e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n < IN_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "short FUSE request"));
+        }
+        let (opcode, unique, ino) = (field32(&buf, 4), field64(&buf, 8), field64(&buf, 16));
+        let Some(reply) = fs.handle(opcode, ino, &buf[IN_HEADER_SIZE..n]) else {
+            continue;
+        };
+        let (error, data) = match reply {
+            Ok(data) => (0, data),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut out = Vec::with_capacity(OUT_HEADER_SIZE + data.len());
+        push32(&mut out, (OUT_HEADER_SIZE + data.len()) as u32);
+        push32(&mut out, error as u32);
+        push64(&mut out, unique);
+        out.extend_from_slice(&data);
+        match fuse.write(&out) {
+            // The request was interrupted, and no longer wants a reply
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {},
+            Err(e) => return Err(e),
+            Ok(_) => {},
+        }
+        if opcode == FUSE_DESTROY {
+            return Ok(());
+        }
+    }
+}
+
+/// How the image was mounted, and so how it's unmounted.
+#[derive(Copy, Clone)]
+enum Mounted {
+    Direct,
+    Fusermount(&'static str),
+}
+
+fn c_path(path: &Path) -> Result<CString, Error> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// Mounts the FUSE device on `dir` with `mount(2)`, which takes root.
+fn mount_direct(dir: &Path) -> Result<File, Error> {
+    let fuse = File::options().read(true).write(true).open("/dev/fuse")?;
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let opts = format!("fd={},rootmode=40000,user_id={},group_id={}", fuse.as_raw_fd(), uid, gid);
+    let opts = CString::new(opts).unwrap();
+    let flags = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV;
+    let ret = unsafe {
+        libc::mount(c"xcso".as_ptr(), c_path(dir)?.as_ptr(), c"fuse.xcso".as_ptr(), flags, opts.as_ptr().cast())
+    };
+    match ret {
+        0 => Ok(fuse),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Has `fusermount` mount `dir`, and receives the FUSE device it opened
+/// over the socket named in `_FUSE_COMMFD`.
+fn mount_with(fusermount: &str, dir: &Path) -> Result<File, Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let (ours, theirs) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    unsafe {
+        libc::fcntl(ours.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    let mut child = Command::new(fusermount).
+        args(["-o", "ro,nosuid,nodev,fsname=xcso,subtype=xcso", "--"]).
+        arg(dir).
+        env("_FUSE_COMMFD", theirs.as_raw_fd().to_string()).
+        spawn()?;
+    drop(theirs);
+
+    // One byte comes with the descriptor, a u64 to keep the buffer aligned
+    let mut byte = [0u8; 1];
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let received = unsafe { libc::recvmsg(ours.as_raw_fd(), &mut msg, 0) };
+    let status = child.wait()?;
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if received <= 0 || cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(XcsoError::Command { command: fusermount.to_owned(), status }.into());
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn mount(dir: &Path) -> Result<(File, Mounted), Error> {
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok((mount_direct(dir)?, Mounted::Direct));
+    }
+    // Older systems only have the fusermount of FUSE 2, which works the same
+    let fusermount = match mount_with("fusermount3", dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => "fusermount",
+        fuse => return Ok((fuse?, Mounted::Fusermount("fusermount3"))),
+    };
+    Ok((mount_with(fusermount, dir)?, Mounted::Fusermount(fusermount)))
+}
+
+/// Detaches the mount, which ends `serve` once nothing has files open in
+/// it any more.
+fn unmount(dir: &Path, mounted: Mounted) -> Result<(), Error> {
+    match mounted {
+        Mounted::Direct => match unsafe { libc::umount2(c_path(dir)?.as_ptr(), libc::MNT_DETACH) } {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        },
+        Mounted::Fusermount(fusermount) => {
+            let status = Command::new(fusermount).args(["-u", "-z", "--"]).arg(dir).status()?;
+            match status.success() {
+                true => Ok(()),
+                false => Err(XcsoError::Command { command: format!("{} -u", fusermount), status }.into()),
+            }
+        },
+    }
+}
+
+pub fn run(args: MountArgs) -> ExitCode {
+    let mut fs = match Filesystem::open(&args.image, args.verbosity == Verbosity::Verbose) {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.image.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let (mut fuse, mounted) = match mount(&args.dir) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error mounting {} on {}: {}", args.image.display(), args.dir.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    if args.verbosity != Verbosity::Quiet {
+        println!("Mounted {} on {}, until it's unmounted or Ctrl+C", args.image.display(), args.dir.display());
+    }
+
+    crate::signal::install();
+    let dir = args.dir.clone();
+    thread::spawn(move || {
+        while !xcso::interrupted() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        if let Err(e) = unmount(&dir, mounted) {
+            eprintln!("Error unmounting {}: {}", dir.display(), e);
+        }
+    });
+
+    let served = serve(&mut fuse, &mut fs);
+    if let Err(e) = served {
+        eprintln!("Error serving {}: {}", args.dir.display(), e);
+        // Leave nothing behind that would say "Transport endpoint is not
+        // connected" to whoever looks next
+        let _ = unmount(&args.dir, mounted);
+        return ExitCode::FAILURE;
+    }
+    if args.verbosity != Verbosity::Quiet {
+        println!("Unmounted {}", args.dir.display());
+    }
+    ExitCode::SUCCESS
+}
+
@@ -0,0 +1,213 @@
+//! Works out what outputs are called: after the ISO by default, or after
+//! the game in its `default.xbe` with a name template.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use xcso::http;
+use xcso::xbe::Certificate;
+use xcso::xdvdfs::open_image;
+use xcso::{strip_extension, Format};
+
+use crate::cli::{CompressArgs, Layout};
+
+/// Fields a name template can use, and what they stand for.
+pub const FIELDS: &[(&str, &str)] = &[
+    ("name", "file name of the ISO, without its extension"),
+    ("title", "game title from default.xbe"),
+    ("titleid", "title ID from default.xbe, as 8 hex digits"),
+    ("region", "regions the game is for (NTSC-U, NTSC-J, PAL)"),
+    ("version", "version of the game from default.xbe"),
+];
+
+/// The longest file or folder name FATX, the Xbox hard drive's file system,
+/// can hold.
+pub const FATX_MAX_NAME: usize = 42;
+
+/// What outputs are called inside their game folder with `--layout folder`,
+/// as dashboards look for the game under the name of its executable.
+pub const FOLDER_BASE: &str = "default";
+
+// Punctuation FATX allows in names, besides letters, digits and spaces
+const FATX_PUNCTUATION: &str = "!#$%&'()-.@[]^_`{}~";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Field(String),
+}
+
+/// A template such as `{title} ({titleid})`, where each `{field}` is
+/// replaced by details of the image and `{{` and `}}` stand for braces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, String> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let field: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    if !FIELDS.iter().any(|(name, _)| *name == field) {
+                        let known: Vec<String> = FIELDS.iter().map(|(name, _)| format!("{{{}}}", name)).collect();
+                        return Err(format!("unknown field '{{{}}}', expected one of {}", field, known.join(", ")));
+                    }
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    pieces.push(Piece::Field(field));
+                },
+                '}' => return Err("unmatched '}' (write '}}' for a brace)".to_owned()),
+                c => text.push(c),
+            }
+        }
+        pieces.push(Piece::Text(text));
+        Ok(Template { pieces })
+    }
+
+    /// Whether the template needs anything from default.xbe.
+    pub fn uses_xbe(&self) -> bool {
+        self.pieces.iter().any(|x| matches!(x, Piece::Field(name) if name != "name"))
+    }
+
+    /// Fills in the template for the ISO at `fname`.
+    pub fn expand(&self, fname: &Path, cert: Option<&Certificate>) -> Result<String, String> {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Field(name) if name == "name" => {
+                    let stem = source_path(fname);
+                    let stem = stem.file_stem().unwrap_or_default();
+                    out.push_str(&stem.to_string_lossy());
+                },
+                Piece::Field(name) => {
+                    let cert = cert.ok_or_else(|| format!("{{{}}} needs default.xbe", name))?;
+                    match name.as_str() {
+                        "title" if cert.title_name.is_empty() => return Err("default.xbe has no title".to_owned()),
+                        "title" => out.push_str(&cert.title_name),
+                        "titleid" => out.push_str(&cert.title_id_hex()),
+                        "region" => out.push_str(&cert.region_name()),
+                        "version" => out.push_str(&cert.version.to_string()),
+                        _ => unreachable!("fields are checked when parsing"),
+                    }
+                },
+            }
+        }
+
+        match safe_file_name(&out) {
+            name if name.is_empty() => Err(format!("the name template gives an empty name for {}", fname.display())),
+            name => Ok(name),
+        }
+    }
+}
+
+/// Turns a game title into something every file system will take as a
+/// file name, Windows and FATX included.
+pub fn safe_file_name(title: &str) -> String {
+    let name: String = title.chars().
+        map(|c| if c.is_control() || "<>:\"/\\|?*".contains(c) { ' ' } else { c }).
+        collect();
+    let name = name.split_whitespace().collect::<Vec<&str>>().join(" ");
+    name.trim_end_matches(['.', ' ']).to_owned()
+}
+
+/// Turns `name` into a name FATX accepts, replacing anything but ASCII
+/// letters, digits, spaces and the punctuation it allows with `_`, and
+/// cutting it down to leave room for `reserved` more characters. Returns
+/// the name and whether it had to be cut.
+pub fn fatx_safe_name(name: &str, reserved: usize) -> (String, bool) {
+    let name: String = name.chars().
+        map(|c| if c.is_ascii_alphanumeric() || c == ' ' || FATX_PUNCTUATION.contains(c) { c } else { '_' }).
+        collect();
+    let max = FATX_MAX_NAME.saturating_sub(reserved);
+    match name.len() > max {
+        true => (name[..max].trim_end_matches(['.', ' ']).to_owned(), true),
+        false => (name, false),
+    }
+}
+
+/// Renames the output base `dest` so every part written to it, and the game
+/// folder it goes in with `--layout folder`, has a name FATX accepts.
+/// Returns the new base and whether a name had to be cut.
+pub fn fatx_safe_base(dest: &Path, args: &CompressArgs) -> (PathBuf, bool) {
+    // Room for the longest suffix a part is likely to get, whatever the name
+    let part = args.split_naming.part_path(Path::new("x"), 9, args.format);
+    let suffix = part.file_name().unwrap_or_default().len() - 1;
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let (name, truncated) = fatx_safe_name(&name, suffix);
+
+    let folder = dest.parent().filter(|_| args.layout == Layout::Folder);
+    let Some(folder) = folder.filter(|x| x.file_name().is_some()) else {
+        return (dest.with_file_name(name), truncated);
+    };
+    let (folder_name, folder_truncated) = fatx_safe_name(&folder.file_name().unwrap().to_string_lossy(), 0);
+    (folder.with_file_name(folder_name).join(name), truncated || folder_truncated)
+}
+
+/// Takes the `.cso` or `.zso` extension and part number off a compressed
+/// input, so outputs of `halo.1.cso` are named after `halo` like those of
+/// `halo.iso`. A URL gives the name of the file it points to, so its outputs
+/// go in the current directory.
+fn source_path(fname: &Path) -> PathBuf {
+    if http::is_url(fname) {
+        return PathBuf::from(http::file_name(&fname.to_string_lossy()));
+    }
+    let stem = [Format::Cso, Format::Zso].iter().find_map(|x| strip_extension(fname, x.extension()));
+    match stem {
+        Some(stem) => strip_extension(&stem, "1").unwrap_or(stem),
+        None => fname.to_owned(),
+    }
+}
+
+/// Whether `fname` is the second or a later part of a split CSO, which is
+/// read along with the first.
+pub fn is_later_part(fname: &Path) -> bool {
+    let stem = [Format::Cso, Format::Zso].iter().find_map(|x| strip_extension(fname, x.extension()));
+    stem.and_then(|x| x.extension()?.to_str()?.parse::<usize>().ok()).is_some_and(|n| n > 1)
+}
+
+/// Reads the certificate of the game in `fname` from its default.xbe.
+pub fn read_certificate(fname: &Path) -> io::Result<Certificate> {
+    Certificate::from_volume(&mut open_image(fname)?)
+}
+
+/// Works out the base path outputs for `fname` are written to, which gets
+/// the `.1.cso` style part suffix appended. With a `name` from a template
+/// the output is named after that rather than the ISO, or with `--layout
+/// folder` goes in a folder of that name.
+pub fn output_base(fname: &Path, name: Option<&str>, args: &CompressArgs) -> PathBuf {
+    if let Some(ref output) = args.output {
+        let ext = args.format.extension();
+        return strip_extension(output, ext).
+            map(|x| strip_extension(&x, "1").unwrap_or(x)).
+            unwrap_or_else(|| output.clone());
+    }
+
+    let fname = &source_path(fname);
+    let name = match (name, args.layout) {
+        (Some(name), _) => OsString::from(name),
+        (None, Layout::Flat) => fname.file_name().unwrap_or_default().to_owned(),
+        (None, Layout::Folder) => fname.file_stem().unwrap_or_default().to_owned(),
+    };
+    let dest = match args.output_dir {
+        Some(ref dir) => dir.join(name),
+        None => fname.with_file_name(name),
+    };
+    match args.layout {
+        Layout::Flat => dest,
+        Layout::Folder => dest.join(FOLDER_BASE),
+    }
+}
@@ -0,0 +1,335 @@
+//! `nbd-serve`: the image inside a CSO, decompressed, as a read-only
+//! network block device, for loop mounting with `nbd-client` and for
+//! virtual machines and emulators that only take block devices.
+//!
+//! It speaks the fixed newstyle handshake of the NBD protocol, with a single
+//! export named after the image, and answers reads with simple replies.
+//! Each client reads through a `CsoReader` of its own, so a read only
+//! decompresses the blocks it covers. Everything on the wire is big endian.
+
+use std::io::{self, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+
+use indicatif::BinaryBytes;
+use xcso::open_uncompressed;
+use xcso::xdvdfs::SECTOR_SIZE;
+
+use crate::cli::{Listen, NbdServeArgs, Verbosity};
+use crate::serve::{bind, Stream};
+
+// Magic numbers, from the protocol's proto.md
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// The export has flags, is read-only, and looks the same to any number of
+// connections
+const TRANSMISSION_FLAGS: u16 = 1 << 0 | 1 << 1 | 1 << 8;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+
+// The errors replies carry, as Linux numbers them
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+// An option is only ever a name and a few info requests
+const MAX_OPTION_SIZE: u32 = 64 << 10;
+// Most one read can ask for, as the reference server has it
+const MAX_READ: u32 = 32 << 20;
+
+struct Server {
+    image: PathBuf,
+    /// The name of the export, which clients asking for the default one
+    /// get too.
+    name: String,
+    len: u64,
+    verbose: bool,
+}
+
+impl Server {
+    fn exports(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.name.as_bytes()
+    }
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn protocol_error(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, what.to_owned())
+}
+
+/// Answers option `option` with a reply of type `kind`.
+fn option_reply(out: &mut dyn Stream, option: u32, kind: u32, data: &[u8]) -> io::Result<()> {
+    let mut reply = Vec::with_capacity(20 + data.len());
+    reply.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+    reply.extend_from_slice(&option.to_be_bytes());
+    reply.extend_from_slice(&kind.to_be_bytes());
+    reply.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    reply.extend_from_slice(data);
+    out.write_all(&reply)?;
+    out.flush()
+}
+
+/// The name and info requests of `NBD_OPT_INFO` and `NBD_OPT_GO`, or
+/// `None` if the lengths in them don't add up.
+fn parse_go(data: &[u8]) -> Option<(&[u8], Vec<u16>)> {
+    let name_len = u32::from_be_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+    let name = data.get(4..4 + name_len)?;
+    let rest = &data[4 + name_len..];
+    let count = u16::from_be_bytes(rest.get(..2)?.try_into().unwrap()) as usize;
+    let requests = rest.get(2..)?;
+    if requests.len() != count * 2 {
+        return None;
+    }
+    Some((name, requests.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect()))
+}
+
+/// Goes through the options a client sends until it picks the export,
+/// returning whether it did rather than giving up.
+fn negotiate(stream: &mut BufReader<Box<dyn Stream>>, server: &Server, no_zeroes: bool) -> io::Result<bool> {
+    loop {
+        if read_u64(stream)? != IHAVEOPT {
+            return Err(protocol_error("not an NBD client"));
+        }
+        let (option, len) = (read_u32(stream)?, read_u32(stream)?);
+        if len > MAX_OPTION_SIZE {
+            return Err(protocol_error("NBD option too long"));
+        }
+        let mut data = vec![0; len as usize];
+        stream.read_exact(&mut data)?;
+        let out = stream.get_mut().as_mut();
+
+        match option {
+            // The old way of picking an export, which has no way to say no
+            // but hanging up
+            OPT_EXPORT_NAME => {
+                if !server.exports(&data) {
+                    return Ok(false);
+                }
+                let mut reply = server.len.to_be_bytes().to_vec();
+                reply.extend_from_slice(&TRANSMISSION_FLAGS.to_be_bytes());
+                if !no_zeroes {
+                    reply.resize(reply.len() + 124, 0);
+                }
+                out.write_all(&reply)?;
+                out.flush()?;
+                return Ok(true);
+            },
+            OPT_ABORT => {
+                option_reply(out, option, REP_ACK, &[])?;
+                return Ok(false);
+            },
+            OPT_LIST if data.is_empty() => {
+                let mut export = (server.name.len() as u32).to_be_bytes().to_vec();
+                export.extend_from_slice(server.name.as_bytes());
+                option_reply(out, option, REP_SERVER, &export)?;
+                option_reply(out, option, REP_ACK, &[])?;
+            },
+            OPT_INFO | OPT_GO => {
+                let Some((name, requests)) = parse_go(&data) else {
+                    option_reply(out, option, REP_ERR_INVALID, b"malformed option")?;
+                    continue;
+                };
+                if !server.exports(name) {
+                    let message = format!("the only export is {}", server.name);
+                    option_reply(out, option, REP_ERR_UNKNOWN, message.as_bytes())?;
+                    continue;
+                }
+                let mut export = INFO_EXPORT.to_be_bytes().to_vec();
+                export.extend_from_slice(&server.len.to_be_bytes());
+                export.extend_from_slice(&TRANSMISSION_FLAGS.to_be_bytes());
+                option_reply(out, option, REP_INFO, &export)?;
+                // Any size of read works, though whole sectors suit it best
+                if requests.contains(&INFO_BLOCK_SIZE) {
+                    let mut sizes = INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                    for x in [1, SECTOR_SIZE as u32, MAX_READ] {
+                        sizes.extend_from_slice(&x.to_be_bytes());
+                    }
+                    option_reply(out, option, REP_INFO, &sizes)?;
+                }
+                option_reply(out, option, REP_ACK, &[])?;
+                if option == OPT_GO {
+                    return Ok(true);
+                }
+            },
+            OPT_LIST => option_reply(out, option, REP_ERR_INVALID, b"list takes no data")?,
+            _ => option_reply(out, option, REP_ERR_UNSUP, b"")?,
+        }
+    }
+}
+
+fn simple_reply(out: &mut dyn Stream, error: u32, cookie: u64, data: &[u8]) -> io::Result<()> {
+    let mut reply = Vec::with_capacity(16 + data.len());
+    reply.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+    reply.extend_from_slice(&error.to_be_bytes());
+    reply.extend_from_slice(&cookie.to_be_bytes());
+    reply.extend_from_slice(data);
+    out.write_all(&reply)?;
+    out.flush()
+}
+
+/// Answers requests until the client disconnects, returning how many bytes
+/// it read.
+fn transmit(stream: &mut BufReader<Box<dyn Stream>>, server: &Server) -> io::Result<u64> {
+    let mut image = open_uncompressed(&server.image)?;
+    let mut sent = 0;
+    loop {
+        let magic = match read_u32(stream) {
+            Ok(magic) => magic,
+            // Hanging up without NBD_CMD_DISC is rude, but common
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(sent),
+            Err(e) => return Err(e),
+        };
+        if magic != REQUEST_MAGIC {
+            return Err(protocol_error("invalid NBD request"));
+        }
+        let (_flags, kind) = (read_u16(stream)?, read_u16(stream)?);
+        let (cookie, offset, len) = (read_u64(stream)?, read_u64(stream)?, read_u32(stream)?);
+
+        match kind {
+            CMD_READ if len > MAX_READ || offset.checked_add(len as u64).is_none_or(|x| x > server.len) => {
+                simple_reply(stream.get_mut().as_mut(), EINVAL, cookie, &[])?;
+            },
+            CMD_READ => {
+                let mut data = vec![0; len as usize];
+                let read = image.seek(SeekFrom::Start(offset)).and_then(|_| image.read_exact(&mut data));
+                match read {
+                    Ok(()) => {
+                        simple_reply(stream.get_mut().as_mut(), 0, cookie, &data)?;
+                        sent += len as u64;
+                    },
+                    Err(e) => {
+                        eprintln!("Error reading {}: {}", server.image.display(), e);
+                        simple_reply(stream.get_mut().as_mut(), EIO, cookie, &[])?;
+                    },
+                }
+            },
+            // The export is read-only, which a client should know better than
+            // to write to, but the data still has to be got out of the way
+            CMD_WRITE => {
+                io::copy(&mut stream.by_ref().take(len as u64), &mut io::sink())?;
+                simple_reply(stream.get_mut().as_mut(), EPERM, cookie, &[])?;
+            },
+            CMD_DISC => return Ok(sent),
+            _ => simple_reply(stream.get_mut().as_mut(), EINVAL, cookie, &[])?,
+        }
+    }
+}
+
+/// Takes a client through the handshake and then its requests.
+fn handle(stream: Box<dyn Stream>, server: &Server) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut hello = NBDMAGIC.to_be_bytes().to_vec();
+    hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    hello.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+    let out = stream.get_mut().as_mut();
+    out.write_all(&hello)?;
+    out.flush()?;
+
+    let no_zeroes = read_u32(&mut stream)? & FLAG_C_NO_ZEROES != 0;
+    if !negotiate(&mut stream, server, no_zeroes)? {
+        return Ok(());
+    }
+    if server.verbose {
+        println!("A client attached {}", server.name);
+    }
+    let sent = transmit(&mut stream, server)?;
+    if server.verbose {
+        println!("A client detached after reading {}", BinaryBytes(sent));
+    }
+    Ok(())
+}
+
+pub fn run(args: NbdServeArgs) -> ExitCode {
+    // Fail straight away on an image that won't open, rather than on every
+    // client
+    let len = match open_uncompressed(&args.image).and_then(|mut x| x.seek(SeekFrom::End(0))) {
+        Ok(len) => len,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args.image.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let name = crate::decompressed_name(&args.image).file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+    let mut accept = match bind(&args.listen) {
+        Ok((accept, public)) => {
+            if public {
+                eprintln!("warning: anyone who can reach {} can read {}", args.listen, args.image.display());
+            }
+            accept
+        },
+        Err(e) => {
+            eprintln!("Error listening on {}: {}", args.listen, e);
+            return ExitCode::FAILURE;
+        },
+    };
+    if args.verbosity != Verbosity::Quiet {
+        let (image, size) = (args.image.display(), BinaryBytes(len));
+        match args.listen {
+            Listen::Tcp(ref addr) => println!("Exporting {} ({}) at nbd://{}/{}", image, size, addr, name),
+            Listen::Unix(ref path) => println!("Exporting {} ({}) as {} on {}", image, size, name, path.display()),
+        }
+    }
+
+    let server = Arc::new(Server { image: args.image, name, len, verbose: args.verbosity == Verbosity::Verbose });
+    loop {
+        match accept() {
+            Ok(stream) => {
+                let server = server.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, &server) {
+                        if server.verbose {
+                            println!("A client was dropped: {}", e);
+                        }
+                    }
+                });
+            },
+            Err(e) => eprintln!("Error accepting a connection: {}", e),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Builds an XDVDFS image from a directory, such as a game extracted with
+//! `extract` and then patched.
+//!
+//! The image is laid out like the ones the Xbox tools make: the volume
+//! descriptor 32 sectors in, the root directory table right after it, the
+//! other directory tables next and then the contents of each file, every
+//! one of them starting on a sector of its own.
+//!
+//! Folders can also be taken out of an existing image while it's being
+//! compressed, without unpacking it, with a `Removal`.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::compress::{interrupted, DEFAULT_WRITE_BUFFER, PROGRESS_TEMPLATE};
+use crate::cso::{XBOX_MEDIA_HEADER, XBOX_MEDIA_HEADER_XDVDFS_OFFSET};
+use crate::error::XcsoError;
+use crate::xdvdfs::{DirEntry, Volume, ATTR_DIRECTORY, SECTOR_SIZE};
+
+/// Attribute given to every file, as the Xbox tools do.
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+// Directory entries hold the dword offsets of their subtrees in 16 bits
+const MAX_TABLE_SIZE: usize = 0xffff * 4;
+// Where the second copy of the magic goes in the volume descriptor
+const VOLUME_MAGIC_TAIL: usize = 0x7ec;
+// Seconds between 1601, where Windows file times start, and 1970
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// A file or directory to be written to the image.
+struct Node {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    /// Sorted the way the Xbox searches them, for directories.
+    children: Option<Vec<Node>>,
+    sector: u32,
+    /// Size of the directory table, or of the file.
+    stored_size: u32,
+}
+
+impl Node {
+    fn dir_entry(&self) -> DirEntry {
+        DirEntry {
+            name: self.name.clone(),
+            sector: self.sector,
+            size: self.stored_size,
+            attributes: if self.children.is_some() { ATTR_DIRECTORY } else { ATTR_ARCHIVE },
+        }
+    }
+
+    fn dir_table(&self) -> Result<Vec<u8>, Error> {
+        let children = self.children.as_deref().unwrap_or_default();
+        dir_table(&children.iter().map(Node::dir_entry).collect::<Vec<DirEntry>>())
+    }
+
+    /// Combined size of the files at or below this node.
+    fn file_bytes(&self) -> u64 {
+        self.children.as_ref().map_or(self.size, |x| x.iter().map(Node::file_bytes).sum())
+    }
+}
+
+fn sectors(size: u64) -> u64 {
+    size.div_ceil(SECTOR_SIZE)
+}
+
+fn too_big(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, what.to_owned())
+}
+
+/// Settings used when packing a folder.
+#[derive(Clone, Debug, Default)]
+pub struct PackOptions {
+    /// Leave out the `$SystemUpdate` folder of dashboard updates.
+    pub strip_system_update: bool,
+}
+
+/// The folder holding the dashboard update some games carry, which the
+/// Xbox installs before starting the game.
+pub const SYSTEM_UPDATE_DIR: &str = "$SystemUpdate";
+
+/// Reads the tree at `path` into nodes, checking every name can be stored.
+fn scan(path: &Path, name: String) -> Result<Node, Error> {
+    let meta = fs::metadata(path)?;
+    if !meta.is_dir() {
+        if meta.len() > u32::MAX as u64 {
+            return Err(too_big(&format!("{} is too big for XDVDFS (4 GiB at most)", path.display())));
+        }
+        return Ok(Node { name, path: path.to_path_buf(), size: meta.len(), children: None, sector: 0, stored_size: 0 });
+    }
+
+    let mut children = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let child_name = entry.file_name().into_string().ok().filter(|x| x.is_ascii() && (1..=255).contains(&x.len()));
+        let child_name = child_name.ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, format!("{} has a name XDVDFS can't hold", entry.path().display()))
+        })?;
+        children.push(scan(&entry.path(), child_name)?);
+    }
+
+    // Directory tables are searched comparing names in upper case
+    children.sort_by_key(|x| x.name.to_ascii_uppercase());
+    if let Some(x) = children.windows(2).find(|x| x[0].name.eq_ignore_ascii_case(&x[1].name)) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} and {} only differ in case in {}", x[0].name, x[1].name, path.display()),
+        ));
+    }
+    Ok(Node { name, path: path.to_path_buf(), size: 0, children: Some(children), sector: 0, stored_size: 0 })
+}
+
+/// Writes the entries `lo..hi` of `children` to `table` as a balanced search
+/// tree, returning the dword offset of its root.
+fn write_tree(table: &mut Vec<u8>, children: &[DirEntry], lo: usize, hi: usize) -> u16 {
+    let mid = lo + (hi - lo) / 2;
+    let child = &children[mid];
+    let len = (14 + child.name.len()).next_multiple_of(4);
+
+    // Entries never straddle a sector, padding to the next one instead
+    let used = table.len() % SECTOR_SIZE as usize;
+    if used + len > SECTOR_SIZE as usize {
+        table.resize(table.len() + SECTOR_SIZE as usize - used, 0xff);
+    }
+    let at = table.len();
+    table.resize(at + len, 0);
+
+    let left = if mid > lo { write_tree(table, children, lo, mid) } else { 0 };
+    let right = if mid + 1 < hi { write_tree(table, children, mid + 1, hi) } else { 0 };
+
+    let entry = &mut table[at..at + len];
+    entry[0..2].copy_from_slice(&left.to_le_bytes());
+    entry[2..4].copy_from_slice(&right.to_le_bytes());
+    entry[4..8].copy_from_slice(&child.sector.to_le_bytes());
+    entry[8..12].copy_from_slice(&child.size.to_le_bytes());
+    entry[12] = child.attributes;
+    entry[13] = child.name.len() as u8;
+    entry[14..14 + child.name.len()].copy_from_slice(child.name.as_bytes());
+    (at / 4) as u16
+}
+
+/// Builds the directory table for `children`, which have to be sorted by
+/// their names in upper case, padded out to whole sectors. An empty
+/// directory has no table at all.
+fn dir_table(children: &[DirEntry]) -> Result<Vec<u8>, Error> {
+    let mut table = Vec::new();
+    if !children.is_empty() {
+        write_tree(&mut table, children, 0, children.len());
+    }
+    if table.len() > MAX_TABLE_SIZE {
+        return Err(too_big("directory has too many entries for XDVDFS"));
+    }
+    table.resize(table.len().next_multiple_of(SECTOR_SIZE as usize), 0xff);
+    Ok(table)
+}
+
+/// Gives every directory table a place after `next`, then every file,
+/// returning the first sector left free.
+fn place(root: &mut Node, mut next: u64) -> Result<u64, Error> {
+    // Tables first, breadth first so the root comes right after the volume
+    // descriptor, then the files in the order they're listed
+    let mut level: Vec<&mut Node> = vec![root];
+    let mut files: Vec<&mut Node> = Vec::new();
+    while !level.is_empty() {
+        let mut below = Vec::new();
+        for node in level {
+            let size = node.dir_table()?.len() as u64;
+            node.stored_size = size as u32;
+            node.sector = if size > 0 { next as u32 } else { 0 };
+            next += sectors(size);
+            for child in node.children.as_mut().unwrap().iter_mut() {
+                match child.children.is_some() {
+                    true => below.push(child),
+                    false => files.push(child),
+                }
+            }
+        }
+        level = below;
+    }
+
+    for file in files {
+        file.stored_size = file.size as u32;
+        file.sector = next as u32;
+        next += sectors(file.size);
+    }
+    match next <= u32::MAX as u64 {
+        true => Ok(next),
+        false => Err(too_big("image is too big for XDVDFS")),
+    }
+}
+
+/// Writes the tables and files below `node` into the image `out`.
+fn write_node<W: Write + Seek>(out: &mut W, node: &Node, pb: &ProgressBar) -> Result<(), Error> {
+    let children = match node.children {
+        Some(ref children) => children,
+        None => {
+            if interrupted() {
+                return Err(XcsoError::Interrupted.into());
+            }
+            out.seek(io::SeekFrom::Start(node.sector as u64 * SECTOR_SIZE))?;
+            let copied = io::copy(&mut File::open(&node.path)?, &mut pb.wrap_write(&mut *out))?;
+            if copied != node.size {
+                return Err(XcsoError::Changed { path: node.path.clone() }.into());
+            }
+            return Ok(());
+        },
+    };
+
+    if node.stored_size > 0 {
+        out.seek(io::SeekFrom::Start(node.sector as u64 * SECTOR_SIZE))?;
+        out.write_all(&node.dir_table()?)?;
+    }
+    for child in children {
+        write_node(out, child, pb)?;
+    }
+    Ok(())
+}
+
+fn volume_descriptor(root: &Node) -> Vec<u8> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs());
+    let filetime = (secs + FILETIME_UNIX_OFFSET) * 10_000_000;
+
+    let mut sector = vec![0u8; SECTOR_SIZE as usize];
+    sector[..20].copy_from_slice(XBOX_MEDIA_HEADER);
+    sector[20..24].copy_from_slice(&root.sector.to_le_bytes());
+    sector[24..28].copy_from_slice(&root.stored_size.to_le_bytes());
+    sector[28..36].copy_from_slice(&filetime.to_le_bytes());
+    sector[VOLUME_MAGIC_TAIL..VOLUME_MAGIC_TAIL + 20].copy_from_slice(XBOX_MEDIA_HEADER);
+    sector
+}
+
+/// Builds an XDVDFS image of the directory `src` at `dest`, returning its
+/// size. `dest` is replaced if it exists, and removed again if packing
+/// fails part way.
+pub fn pack_dir(src: &Path, dest: &Path, opts: &PackOptions) -> Result<u64, Error> {
+    let pb = ProgressBar::new(0);
+    pb.set_style(ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap());
+    pack_dir_with_progress(src, dest, opts, &pb)
+}
+
+/// Same as `pack_dir`, but reports progress on `pb` (counting bytes of the
+/// files packed).
+pub fn pack_dir_with_progress(src: &Path, dest: &Path, opts: &PackOptions, pb: &ProgressBar) -> Result<u64, Error> {
+    let mut root = scan(src, String::new())?;
+    let Some(ref mut children) = root.children else {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a directory", src.display())));
+    };
+    if opts.strip_system_update {
+        children.retain(|x| !x.name.eq_ignore_ascii_case(SYSTEM_UPDATE_DIR));
+    }
+    let descriptor_sector = XBOX_MEDIA_HEADER_XDVDFS_OFFSET / SECTOR_SIZE;
+    let end = place(&mut root, descriptor_sector + 1)? * SECTOR_SIZE;
+    crate::debug!("packing {} into {} sectors", src.display(), end / SECTOR_SIZE);
+
+    pb.set_length(root.file_bytes());
+    pb.set_position(0);
+
+    let mut out = BufWriter::with_capacity(DEFAULT_WRITE_BUFFER, File::create(dest)?);
+    let written = out.seek(io::SeekFrom::Start(XBOX_MEDIA_HEADER_XDVDFS_OFFSET)).
+        and_then(|_| out.write_all(&volume_descriptor(&root))).
+        and_then(|_| write_node(&mut out, &root, pb)).
+        and_then(|_| out.flush()).
+        // Covers the padding after the last file
+        and_then(|_| out.get_ref().set_len(end)).
+        and_then(|_| out.get_ref().sync_all());
+    if let Err(e) = written {
+        drop(out);
+        _ = fs::remove_file(dest);
+        return Err(e);
+    }
+
+    pb.finish_and_clear();
+    Ok(end)
+}
+
+/// Changes that take a folder out of the root of an existing image as it's
+/// read: the root directory table without it, and the sectors it used
+/// blanked out.
+#[derive(Clone, Debug)]
+pub struct Removal {
+    root_offset: u64,
+    root_table: Vec<u8>,
+    /// Byte ranges of the partition to replace with zeros.
+    zeroed: Vec<(u64, u64)>,
+    /// Where what's left of the file system ends.
+    pub used_bytes: u64,
+}
+
+impl Removal {
+    /// Plans the removal of `name` from the root of `volume`, or returns
+    /// `None` when there's nothing by that name.
+    pub fn plan<R: io::Read + Seek>(volume: &mut Volume<R>, name: &str) -> Result<Option<Removal>, Error> {
+        let root = volume.root().clone();
+        let (removed, kept): (Vec<DirEntry>, Vec<DirEntry>) = volume.read_dir(&root)?.
+            into_iter().
+            partition(|x| x.name.eq_ignore_ascii_case(name));
+        let Some(removed) = removed.into_iter().next() else {
+            return Ok(None);
+        };
+
+        let extent = |x: &DirEntry| (x.offset(), x.offset() + (x.size as u64).next_multiple_of(SECTOR_SIZE));
+        let mut zeroed = vec![extent(&removed)];
+        if removed.is_dir() {
+            zeroed.extend(volume.walk(&removed)?.iter().map(|(_, x)| extent(x)));
+        }
+
+        // Entries come out of the table already sorted, and the smaller table
+        // fills the rest of the old one with padding
+        let mut root_table = dir_table(&kept)?;
+        root_table.resize(root_table.len().max(root.size as usize), 0xff);
+        let mut used_bytes = extent(&root).1.max(XBOX_MEDIA_HEADER_XDVDFS_OFFSET + SECTOR_SIZE);
+        for entry in kept.iter() {
+            used_bytes = used_bytes.max(extent(entry).1);
+            if entry.is_dir() {
+                used_bytes = volume.walk(entry)?.iter().map(|(_, x)| extent(x).1).fold(used_bytes, u64::max);
+            }
+        }
+        crate::debug!("removing {} from the image frees {} ranges", removed.name, zeroed.len());
+        Ok(Some(Removal { root_offset: root.offset(), root_table, zeroed, used_bytes }))
+    }
+
+    /// Applies the changes to `buf`, read from `offset` bytes into the
+    /// partition.
+    pub fn apply(&self, offset: u64, buf: &mut [u8]) {
+        let end = offset + buf.len() as u64;
+        let overlap = |start: u64, stop: u64| (start.max(offset), stop.min(end));
+        for &(start, stop) in self.zeroed.iter() {
+            let (start, stop) = overlap(start, stop);
+            if start < stop {
+                buf[(start - offset) as usize..(stop - offset) as usize].fill(0);
+            }
+        }
+
+        let table_end = self.root_offset + self.root_table.len() as u64;
+        let (start, stop) = overlap(self.root_offset, table_end);
+        if start < stop {
+            let from = (start - self.root_offset) as usize..(stop - self.root_offset) as usize;
+            buf[(start - offset) as usize..(stop - offset) as usize].copy_from_slice(&self.root_table[from]);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Pausing a batch part way, with SIGUSR1 or by pressing p in the terminal
+//! it's running in, and letting it carry on the same way.
+
+/// Puts the terminal back the way it was when dropped.
+pub struct Keys(());
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+#[cfg(unix)]
+static SAVED: std::sync::OnceLock<libc::termios> = std::sync::OnceLock::new();
+
+#[cfg(unix)]
+extern "C" fn on_signal(_signum: libc::c_int) {
+    xcso::toggle_pause();
+}
+
+/// Routes SIGUSR1, and with `keys` the p key, to `xcso::toggle_pause`. Keys
+/// are only read in the foreground of a terminal, a key at a time without
+/// echoing them, which leaves Ctrl+C and output as they were.
+#[cfg(unix)]
+pub fn install(keys: bool) -> Keys {
+    use std::io::Read;
+
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler);
+    }
+    // Changing the terminal from the background would stop the process
+    let foreground = unsafe { libc::isatty(0) == 1 && libc::tcgetpgrp(0) == libc::getpgrp() };
+    if !keys || !foreground {
+        return Keys(());
+    }
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(0, &mut termios) } != 0 {
+        return Keys(());
+    }
+    let mut keyed = termios;
+    keyed.c_lflag &= !(libc::ICANON | libc::ECHO);
+    keyed.c_cc[libc::VMIN] = 1;
+    keyed.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(0, libc::TCSANOW, &keyed) } != 0 {
+        return Keys(());
+    }
+    _ = SAVED.set(termios);
+
+    std::thread::spawn(|| {
+        let mut key = [0];
+        while let Ok(1) = std::io::stdin().read(&mut key) {
+            if key[0].eq_ignore_ascii_case(&b'p') {
+                xcso::toggle_pause();
+            }
+        }
+    });
+    Keys(())
+}
+
+/// Puts the terminal back the way it was before `install`. Only makes one
+/// call, so it is safe to call from a signal handler.
+#[cfg(unix)]
+pub fn restore() {
+    if let Some(termios) = SAVED.get() {
+        unsafe {
+            libc::tcsetattr(0, libc::TCSANOW, termios);
+        }
+    }
+}
+
+/// Routes the p key to `xcso::toggle_pause` with `keys`, when running in a
+/// console. Windows has no SIGUSR1.
+#[cfg(windows)]
+pub fn install(keys: bool) -> Keys {
+    if keys && console::user_attended() {
+        std::thread::spawn(|| {
+            let term = console::Term::stdout();
+            while let Ok(key) = term.read_key() {
+                if matches!(key, console::Key::Char('p' | 'P')) {
+                    xcso::toggle_pause();
+                }
+            }
+        });
+    }
+    Keys(())
+}
+
+#[cfg(windows)]
+pub fn restore() {}
+
+#[cfg(not(any(unix, windows)))]
+pub fn install(_keys: bool) -> Keys {
+    Keys(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn restore() {}
@@ -0,0 +1,76 @@
+//! Running at low priority, so a library can be converted in the background
+//! without the desktop becoming sluggish. The priority is set for the whole
+//! process before any threads are started, which inherit it.
+
+use std::io::Error;
+
+// The nice value `nice` itself uses by default
+#[cfg(unix)]
+const NICE: libc::c_int = 10;
+
+/// Lowers the CPU priority, and on Linux the I/O priority to the idle class,
+/// which only gets the disk when nothing else wants it.
+#[cfg(target_os = "linux")]
+pub fn lower() -> Result<(), Error> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    lower_cpu()?;
+    let prio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Puts the process in the background band, which lowers its CPU, I/O and
+/// network priority together.
+#[cfg(target_os = "macos")]
+pub fn lower() -> Result<(), Error> {
+    lower_cpu()?;
+    match unsafe { libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Lowers the CPU priority, which is all other systems offer.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+pub fn lower() -> Result<(), Error> {
+    lower_cpu()
+}
+
+#[cfg(unix)]
+fn lower_cpu() -> Result<(), Error> {
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICE) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(windows)]
+mod win {
+    pub type Handle = isize;
+    pub const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x00100000;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn GetCurrentProcess() -> Handle;
+        pub fn SetPriorityClass(process: Handle, class: u32) -> i32;
+    }
+}
+
+/// Puts the process in background mode, which lowers its CPU, I/O and
+/// memory priority together.
+#[cfg(windows)]
+pub fn lower() -> Result<(), Error> {
+    match unsafe { win::SetPriorityClass(win::GetCurrentProcess(), win::PROCESS_MODE_BACKGROUND_BEGIN) } {
+        0 => Err(Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower() -> Result<(), Error> {
+    Err(Error::new(std::io::ErrorKind::Unsupported, "priorities can't be changed on this system"))
+}
@@ -0,0 +1,119 @@
+//! Progress of a conversion for whoever is running it, as plain numbers
+//! rather than a terminal progress bar, so a GUI or a game manager can show
+//! it its own way. `compress_iso_with_sink` and friends call a
+//! `ProgressSink` as each block is written; the `_with_progress` functions
+//! are the same thing drawing an `indicatif` bar.
+
+use std::time::{Duration, Instant};
+
+use indicatif::{BinaryBytes, ProgressBar};
+
+/// What a conversion is busy with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Blocks are being compressed and written.
+    Compressing,
+    /// Held by `toggle_pause`, with nothing being written.
+    Paused,
+    /// Every block is written, and the parts are being finished off: their
+    /// indexes, trailers and side files.
+    Finishing,
+    /// The parts are all in place.
+    Done,
+}
+
+/// How far a conversion has got.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Blocks of the image done, including any an earlier run did when
+    /// resuming.
+    pub blocks_done: usize,
+    /// Blocks in the image, which isn't known for a stream.
+    pub total_blocks: Option<usize>,
+    /// Bytes of the image done, counted like `blocks_done`.
+    pub bytes_in: u64,
+    pub total_bytes: Option<u64>,
+    /// Bytes of compressed blocks written by this run.
+    pub bytes_out: u64,
+    /// Parts started so far.
+    pub parts: usize,
+}
+
+/// Told how a conversion is going: after every block it writes, when it
+/// pauses and carries on, and as it finishes. It's called on the thread the
+/// conversion was started on, between blocks, so it should be quick about
+/// it.
+pub trait ProgressSink {
+    fn update(&mut self, progress: &Progress);
+}
+
+impl<F: FnMut(&Progress)> ProgressSink for F {
+    fn update(&mut self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+// How often the write rate and ratio on a bar are brought up to date
+const MESSAGE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Shows progress on a bar counting bytes of the image, with how fast
+/// output is being written and the compression ratio so far as its message.
+/// The bar is cleared once the blocks are done, or the conversion fails.
+pub(crate) struct BarProgress<'a> {
+    pb: &'a ProgressBar,
+    /// Bytes in and out at the first update, which a resumed run didn't do.
+    start: Option<(u64, u64)>,
+    last_update: Instant,
+    last_written: u64,
+    /// The message from before pausing, while paused.
+    paused: Option<String>,
+}
+
+impl BarProgress<'_> {
+    pub fn new(pb: &ProgressBar) -> BarProgress<'_> {
+        BarProgress { pb, start: None, last_update: Instant::now(), last_written: 0, paused: None }
+    }
+}
+
+impl ProgressSink for BarProgress<'_> {
+    fn update(&mut self, progress: &Progress) {
+        let pb = self.pb;
+        match progress.phase {
+            Phase::Compressing => {
+                let &mut (start_in, start_out) = self.start.get_or_insert_with(|| {
+                    if let Some(total) = progress.total_bytes {
+                        pb.set_length(total);
+                    }
+                    (progress.bytes_in, progress.bytes_out)
+                });
+                pb.set_position(progress.bytes_in);
+                if let Some(message) = self.paused.take() {
+                    pb.set_message(message);
+                    // The write rate only counts time spent writing
+                    (self.last_update, self.last_written) = (Instant::now(), progress.bytes_out);
+                }
+                let elapsed = self.last_update.elapsed();
+                if elapsed >= MESSAGE_INTERVAL {
+                    let rate = ((progress.bytes_out - self.last_written) as f64 / elapsed.as_secs_f64()) as u64;
+                    let ratio = (progress.bytes_out - start_out) as f64 / (progress.bytes_in - start_in) as f64 * 100.0;
+                    pb.set_message(format!("writing {}/s, ratio {:.1}%", BinaryBytes(rate), ratio));
+                    (self.last_update, self.last_written) = (Instant::now(), progress.bytes_out);
+                }
+            },
+            Phase::Paused => {
+                if self.paused.is_none() {
+                    self.paused = Some(pb.message());
+                    pb.set_message("paused");
+                }
+            },
+            Phase::Finishing | Phase::Done => pb.finish_and_clear(),
+        }
+    }
+}
+
+impl Drop for BarProgress<'_> {
+    fn drop(&mut self) {
+        self.pb.finish_and_clear();
+    }
+}
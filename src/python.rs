@@ -0,0 +1,530 @@
+//! A Python extension module, built with the `python` feature, with the
+//! same `compress`, `decompress` and `info` as `python/xcso.py`, but with
+//! nothing to load at run time and no C library or ctypes in between. It
+//! only uses CPython's stable ABI, so one build imports into any Python
+//! from 3.6 on.
+//!
+//! Conversions run on a thread of their own with the GIL released, so the
+//! rest of the program carries on, while the calling thread calls the
+//! progress callback and checks for Ctrl+C every `PROGRESS_INTERVAL`. An
+//! exception from either stops the conversion, and is raised once it has.
+
+use std::ffi::{c_char, c_int, c_ulonglong, c_void, CStr, CString};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr::{self, addr_of, addr_of_mut};
+use std::thread;
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+
+use crate::compress::{compress_iso_with_progress, CancelPolicy, CancelToken, CompressOptions, FATX_MAX_SIZE};
+use crate::cso::{Format, Method};
+use crate::decompress::{decompress_cso_with_cancel, CsoFile};
+
+// How often progress callbacks are called
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A Python object, only ever handled through a pointer.
+#[repr(C)]
+pub struct PyObject {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PyThreadState {
+    _private: [u8; 0],
+}
+
+type PyCFunctionWithKeywords =
+    unsafe extern "C" fn(module: *mut PyObject, args: *mut PyObject, kwargs: *mut PyObject) -> *mut PyObject;
+
+#[repr(C)]
+struct PyMethodDef {
+    name: *const c_char,
+    meth: Option<PyCFunctionWithKeywords>,
+    flags: c_int,
+    doc: *const c_char,
+}
+
+#[repr(C)]
+struct PyModuleDef {
+    // PyModuleDef_HEAD_INIT: the object header, m_init, m_index and m_copy
+    refcnt: isize,
+    ob_type: *mut c_void,
+    init: *mut c_void,
+    index: isize,
+    copy: *mut PyObject,
+    name: *const c_char,
+    doc: *const c_char,
+    size: isize,
+    methods: *mut PyMethodDef,
+    slots: *mut c_void,
+    traverse: *mut c_void,
+    clear: *mut c_void,
+    free: *mut c_void,
+}
+
+const METH_VARARGS: c_int = 0x1;
+const METH_KEYWORDS: c_int = 0x2;
+// What `PyModule_Create` passes for modules built for the stable ABI
+const PYTHON_ABI_VERSION: c_int = 3;
+
+// Extension modules leave these to be found in the interpreter that loads
+// them, except on Windows, where they come from python3.dll
+#[cfg_attr(windows, link(name = "python3"))]
+extern "C" {
+    #[link_name = "PyExc_Exception"]
+    static EXCEPTION: *mut PyObject;
+    #[link_name = "PyExc_OSError"]
+    static OS_ERROR: *mut PyObject;
+    #[link_name = "PyExc_ValueError"]
+    static VALUE_ERROR: *mut PyObject;
+    #[link_name = "PyExc_InterruptedError"]
+    static INTERRUPTED_ERROR: *mut PyObject;
+    #[link_name = "_Py_NoneStruct"]
+    static NONE: PyObject;
+
+    fn PyModule_Create2(def: *mut PyModuleDef, api_version: c_int) -> *mut PyObject;
+    fn PyModule_AddObject(module: *mut PyObject, name: *const c_char, value: *mut PyObject) -> c_int;
+    fn PyModule_AddStringConstant(module: *mut PyObject, name: *const c_char, value: *const c_char) -> c_int;
+    fn PyErr_NewException(name: *const c_char, base: *mut PyObject, dict: *mut PyObject) -> *mut PyObject;
+    fn PyArg_ParseTupleAndKeywords(
+        args: *mut PyObject,
+        kwargs: *mut PyObject,
+        format: *const c_char,
+        keywords: *const *const c_char,
+        ...
+    ) -> c_int;
+    fn PyUnicode_FSConverter(obj: *mut PyObject, result: *mut c_void) -> c_int;
+    fn PyUnicode_DecodeFSDefaultAndSize(s: *const c_char, size: isize) -> *mut PyObject;
+    fn PyUnicode_FromString(s: *const c_char) -> *mut PyObject;
+    fn PyBytes_AsString(obj: *mut PyObject) -> *mut c_char;
+    fn PyBytes_Size(obj: *mut PyObject) -> isize;
+    fn PyLong_AsUnsignedLongLong(obj: *mut PyObject) -> c_ulonglong;
+    fn PyLong_FromUnsignedLongLong(value: c_ulonglong) -> *mut PyObject;
+    fn PyDict_New() -> *mut PyObject;
+    fn PyDict_SetItemString(dict: *mut PyObject, key: *const c_char, value: *mut PyObject) -> c_int;
+    fn PyObject_CallFunction(callable: *mut PyObject, format: *const c_char, ...) -> *mut PyObject;
+    fn PyErr_Occurred() -> *mut PyObject;
+    fn PyErr_SetString(exception: *mut PyObject, message: *const c_char);
+    fn PyErr_CheckSignals() -> c_int;
+    fn PyEval_SaveThread() -> *mut PyThreadState;
+    fn PyEval_RestoreThread(state: *mut PyThreadState);
+    fn Py_IncRef(obj: *mut PyObject);
+    fn Py_DecRef(obj: *mut PyObject);
+}
+
+// `xcso.XcsoError`, set up when the module is
+static mut XCSO_ERROR: *mut PyObject = ptr::null_mut();
+
+/// Why a call failed: with an error still to be raised, or with the
+/// exception Python has been given already.
+enum Failure {
+    Io(Error),
+    Raised,
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        Failure::Io(e)
+    }
+}
+
+fn invalid(message: String) -> Failure {
+    Failure::Io(Error::new(ErrorKind::InvalidInput, message))
+}
+
+/// A reference to a Python object, given up when it's dropped.
+struct Owned(*mut PyObject);
+
+impl Owned {
+    /// Takes the new reference `obj` a call returned, which is null when it
+    /// raised.
+    fn new(obj: *mut PyObject) -> Result<Owned, Failure> {
+        match obj.is_null() {
+            true => Err(Failure::Raised),
+            false => Ok(Owned(obj)),
+        }
+    }
+
+    fn into_raw(self) -> *mut PyObject {
+        let obj = self.0;
+        mem::forget(self);
+        obj
+    }
+}
+
+impl Drop for Owned {
+    fn drop(&mut self) {
+        unsafe { Py_DecRef(self.0) }
+    }
+}
+
+unsafe fn is_none(obj: *mut PyObject) -> bool {
+    ptr::eq(obj, addr_of!(NONE))
+}
+
+unsafe fn none() -> *mut PyObject {
+    let none = addr_of!(NONE).cast_mut();
+    Py_IncRef(none);
+    none
+}
+
+unsafe fn raise(exception: *mut PyObject, message: &str) {
+    // A message with a nul in it would be cut short anyway
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    PyErr_SetString(exception, message.as_ptr());
+}
+
+/// Runs the body of a module function, raising what went wrong, whether an
+/// error or a panic, as the exception `python/xcso.py` would.
+unsafe fn call(f: impl FnOnce() -> Result<*mut PyObject, Failure>) -> *mut PyObject {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(obj)) => return obj,
+        Ok(Err(Failure::Raised)) => (),
+        Ok(Err(Failure::Io(e))) => {
+            let exception = match e.kind() {
+                ErrorKind::InvalidInput => VALUE_ERROR,
+                ErrorKind::Interrupted => INTERRUPTED_ERROR,
+                _ => OS_ERROR,
+            };
+            raise(exception, &e.to_string());
+        },
+        Err(_) => raise(XCSO_ERROR, "the conversion panicked"),
+    }
+    ptr::null_mut()
+}
+
+/// A path from a `str`, `bytes` or `os.PathLike` argument.
+unsafe fn path_arg(obj: *mut PyObject) -> Result<PathBuf, Failure> {
+    let mut bytes: *mut PyObject = ptr::null_mut();
+    if PyUnicode_FSConverter(obj, addr_of_mut!(bytes).cast()) == 0 {
+        return Err(Failure::Raised);
+    }
+    let bytes = Owned(bytes);
+    let raw = std::slice::from_raw_parts(PyBytes_AsString(bytes.0).cast::<u8>(), PyBytes_Size(bytes.0) as usize);
+    Ok(path_from_bytes(raw))
+}
+
+#[cfg(unix)]
+fn path_from_bytes(raw: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(raw).into()
+}
+
+// Python encodes paths as UTF-8 everywhere else
+#[cfg(not(unix))]
+fn path_from_bytes(raw: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(raw).into_owned().into()
+}
+
+/// `fp` as a `str`, decoded the way Python decodes paths from the system.
+unsafe fn path_object(fp: &Path) -> Result<*mut PyObject, Failure> {
+    #[cfg(unix)]
+    let raw = std::os::unix::ffi::OsStrExt::as_bytes(fp.as_os_str()).to_vec();
+    #[cfg(not(unix))]
+    let raw = fp.to_string_lossy().into_owned().into_bytes();
+    Owned::new(PyUnicode_DecodeFSDefaultAndSize(raw.as_ptr().cast(), raw.len() as isize)).map(Owned::into_raw)
+}
+
+/// A whole number argument, or `None` when it's None or wasn't given.
+unsafe fn uint_arg(obj: *mut PyObject) -> Result<Option<u64>, Failure> {
+    if obj.is_null() || is_none(obj) {
+        return Ok(None);
+    }
+    let value = PyLong_AsUnsignedLongLong(obj);
+    if value == c_ulonglong::MAX && !PyErr_Occurred().is_null() {
+        return Err(Failure::Raised);
+    }
+    Ok(Some(value))
+}
+
+unsafe fn u32_arg(obj: *mut PyObject, what: &str, default: u32) -> Result<u32, Failure> {
+    match uint_arg(obj)? {
+        Some(x) => u32::try_from(x).map_err(|_| invalid(format!("{} {} is out of range", what, x))),
+        None => Ok(default),
+    }
+}
+
+/// Runs `f` on a thread of its own with the GIL released, calling
+/// `progress` with the bytes done and the total every so often unless it's
+/// null or None. If it raises, or Ctrl+C is pressed, `cancel` is cancelled
+/// and the exception is raised once `f` has stopped.
+unsafe fn with_progress<T: Send>(
+    progress: *mut PyObject,
+    cancel: &CancelToken,
+    f: impl FnOnce(&ProgressBar) -> Result<T, Error> + Send,
+) -> Result<T, Failure> {
+    let progress = (!progress.is_null() && !is_none(progress)).then_some(progress);
+    // Whether the callback returned rather than raised
+    let report = |pb: &ProgressBar| {
+        let Some(progress) = progress else {
+            return true;
+        };
+        let (done, total) = (pb.position() as c_ulonglong, pb.length().unwrap_or(0) as c_ulonglong);
+        Owned::new(PyObject_CallFunction(progress, c"KK".as_ptr(), done, total)).is_ok()
+    };
+
+    let pb = ProgressBar::hidden();
+    let caller = thread::current();
+    thread::scope(|s| {
+        let worker = s.spawn(|| {
+            let result = f(&pb);
+            caller.unpark();
+            result
+        });
+        let mut raised = false;
+        while !worker.is_finished() {
+            if !raised && (PyErr_CheckSignals() != 0 || !report(&pb)) {
+                raised = true;
+                cancel.cancel();
+            }
+            let state = PyEval_SaveThread();
+            thread::park_timeout(PROGRESS_INTERVAL);
+            PyEval_RestoreThread(state);
+        }
+        let result = worker.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        if raised || (result.is_ok() && !report(&pb)) {
+            return Err(Failure::Raised);
+        }
+        result.map_err(Failure::Io)
+    })
+}
+
+unsafe extern "C" fn compress(_module: *mut PyObject, args: *mut PyObject, kwargs: *mut PyObject) -> *mut PyObject {
+    call(|| {
+        let keywords = [
+            c"path".as_ptr(),
+            c"dest".as_ptr(),
+            c"level".as_ptr(),
+            c"format".as_ptr(),
+            c"method".as_ptr(),
+            c"block_size".as_ptr(),
+            c"align".as_ptr(),
+            c"split_size".as_ptr(),
+            c"threads".as_ptr(),
+            c"progress".as_ptr(),
+            ptr::null(),
+        ];
+        let [mut path, mut dest, mut level, mut block_size, mut align, mut split_size, mut threads, mut progress] =
+            [ptr::null_mut::<PyObject>(); 8];
+        let (mut format, mut method) = (c"cso".as_ptr(), c"lz4".as_ptr());
+        let parsed = PyArg_ParseTupleAndKeywords(
+            args,
+            kwargs,
+            c"O|O$OssOOOOO:compress".as_ptr(),
+            keywords.as_ptr(),
+            &mut path as *mut *mut PyObject,
+            &mut dest as *mut *mut PyObject,
+            &mut level as *mut *mut PyObject,
+            &mut format as *mut *const c_char,
+            &mut method as *mut *const c_char,
+            &mut block_size as *mut *mut PyObject,
+            &mut align as *mut *mut PyObject,
+            &mut split_size as *mut *mut PyObject,
+            &mut threads as *mut *mut PyObject,
+            &mut progress as *mut *mut PyObject,
+        );
+        if parsed == 0 {
+            return Err(Failure::Raised);
+        }
+
+        let iso = path_arg(path)?;
+        // Named after the ISO by default, as `make-xcso` does
+        let dest = match dest.is_null() || is_none(dest) {
+            true => iso.with_extension(""),
+            false => path_arg(dest)?,
+        };
+        let defaults = CompressOptions::default();
+        let opts = CompressOptions {
+            level: u32_arg(level, "level", defaults.level)?,
+            format: match CStr::from_ptr(format).to_bytes() {
+                b"cso" => Format::Cso,
+                b"zso" => Format::Zso,
+                x => {
+                    let x = String::from_utf8_lossy(x);
+                    return Err(invalid(format!("invalid format '{}', expected cso or zso", x)));
+                },
+            },
+            method: match CStr::from_ptr(method).to_bytes() {
+                b"lz4" => Method::Lz4,
+                b"deflate" => Method::Deflate,
+                x => {
+                    let x = String::from_utf8_lossy(x);
+                    return Err(invalid(format!("invalid method '{}', expected lz4 or deflate", x)));
+                },
+            },
+            block_size: u32_arg(block_size, "block size", defaults.block_size)?,
+            align: u8::try_from(u32_arg(align, "alignment", defaults.align as u32)?).
+                map_err(|_| invalid("the alignment is out of range".to_owned()))?,
+            // None or 0 never splits
+            split_size: match split_size.is_null() {
+                true => defaults.split_size,
+                false => uint_arg(split_size)?.filter(|&x| x > 0),
+            },
+            threads: uint_arg(threads)?.filter(|&x| x > 0).map(|x| x as usize),
+            ..defaults
+        };
+        let first = with_progress(progress, &opts.cancel, |pb| compress_iso_with_progress(&iso, &dest, &opts, pb))?;
+        path_object(&first)
+    })
+}
+
+unsafe extern "C" fn decompress(_module: *mut PyObject, args: *mut PyObject, kwargs: *mut PyObject) -> *mut PyObject {
+    call(|| {
+        let keywords = [c"path".as_ptr(), c"dest".as_ptr(), c"progress".as_ptr(), ptr::null()];
+        let [mut path, mut dest, mut progress] = [ptr::null_mut::<PyObject>(); 3];
+        let parsed = PyArg_ParseTupleAndKeywords(
+            args,
+            kwargs,
+            c"OO|$O:decompress".as_ptr(),
+            keywords.as_ptr(),
+            &mut path as *mut *mut PyObject,
+            &mut dest as *mut *mut PyObject,
+            &mut progress as *mut *mut PyObject,
+        );
+        if parsed == 0 {
+            return Err(Failure::Raised);
+        }
+
+        let (cso, iso) = (path_arg(path)?, path_arg(dest)?);
+        let cancel = CancelToken::new();
+        let remove = CancelPolicy::Remove;
+        with_progress(progress, &cancel, |pb| decompress_cso_with_cancel(&cso, &iso, pb, &cancel, remove))?;
+        Ok(none())
+    })
+}
+
+/// Sets `key` of `dict` to `value`, a new reference that's given up.
+unsafe fn set_item(dict: &Owned, key: &CStr, value: *mut PyObject) -> Result<(), Failure> {
+    let value = Owned::new(value)?;
+    match PyDict_SetItemString(dict.0, key.as_ptr(), value.0) {
+        0 => Ok(()),
+        _ => Err(Failure::Raised),
+    }
+}
+
+unsafe extern "C" fn info(_module: *mut PyObject, args: *mut PyObject, kwargs: *mut PyObject) -> *mut PyObject {
+    call(|| {
+        let keywords = [c"path".as_ptr(), ptr::null()];
+        let mut path = ptr::null_mut::<PyObject>();
+        let parsed = PyArg_ParseTupleAndKeywords(
+            args,
+            kwargs,
+            c"O:info".as_ptr(),
+            keywords.as_ptr(),
+            &mut path as *mut *mut PyObject,
+        );
+        if parsed == 0 {
+            return Err(Failure::Raised);
+        }
+
+        let cso = CsoFile::open(&path_arg(path)?)?;
+        let header = cso.header();
+        let dict = Owned::new(PyDict_New())?;
+        let format = match header.format {
+            Format::Cso => c"cso",
+            Format::Zso => c"zso",
+        };
+        set_item(&dict, c"format", PyUnicode_FromString(format.as_ptr()))?;
+        let numbers = [
+            (c"version", header.version as u64),
+            (c"block_size", header.block_size as u64),
+            (c"align", header.align as u64),
+            (c"total_bytes", header.total_bytes),
+            (c"total_blocks", header.total_blocks as u64),
+            (c"parts", cso.part_count() as u64),
+            (c"stored_bytes", cso.stored_bytes()?),
+        ];
+        for (key, value) in numbers {
+            set_item(&dict, key, PyLong_FromUnsignedLongLong(value))?;
+        }
+        Ok(dict.into_raw())
+    })
+}
+
+// The first lines of each are the signature `help` and `inspect` show
+static mut METHODS: [PyMethodDef; 4] = [
+    PyMethodDef {
+        name: c"compress".as_ptr(),
+        meth: Some(compress),
+        flags: METH_VARARGS | METH_KEYWORDS,
+        doc: c"compress(path, dest=None, *, level=None, format='cso', method='lz4', block_size=2048, align=2, \
+            split_size=FATX_MAX_SIZE, threads=None, progress=None)\n--\n\n\
+            Compresses the ISO at path, returning the path of the first part. dest is the output's base name,\n\
+            which defaults to the ISO's without .iso: the parts are written as dest.1.cso, dest.2.cso and so\n\
+            on. split_size=None never splits, and progress is called with the bytes done and the total every\n\
+            so often."
+            .as_ptr(),
+    },
+    PyMethodDef {
+        name: c"decompress".as_ptr(),
+        meth: Some(decompress),
+        flags: METH_VARARGS | METH_KEYWORDS,
+        doc: c"decompress(path, dest, *, progress=None)\n--\n\n\
+            Decompresses the CSO or ZSO whose first part is at path into an ISO at dest, which mustn't exist\n\
+            yet."
+            .as_ptr(),
+    },
+    PyMethodDef {
+        name: c"info".as_ptr(),
+        meth: Some(info),
+        flags: METH_VARARGS | METH_KEYWORDS,
+        doc: c"info(path)\n--\n\n\
+            The header details of the CSO or ZSO whose first part is at path, as a dict."
+            .as_ptr(),
+    },
+    PyMethodDef { name: ptr::null(), meth: None, flags: 0, doc: ptr::null() },
+];
+
+static mut MODULE: PyModuleDef = PyModuleDef {
+    refcnt: 1,
+    ob_type: ptr::null_mut(),
+    init: ptr::null_mut(),
+    index: 0,
+    copy: ptr::null_mut(),
+    name: c"xcso".as_ptr(),
+    doc: c"Compresses Xbox ISOs into CSO and ZSO images, and back again.".as_ptr(),
+    size: -1,
+    methods: addr_of_mut!(METHODS).cast(),
+    slots: ptr::null_mut(),
+    traverse: ptr::null_mut(),
+    clear: ptr::null_mut(),
+    free: ptr::null_mut(),
+};
+
+/// Creates the module, which Python does when it's first imported.
+#[export_name = "PyInit_xcso"]
+pub extern "C" fn init_module() -> *mut PyObject {
+    unsafe {
+        let Ok(module) = Owned::new(PyModule_Create2(addr_of_mut!(MODULE), PYTHON_ABI_VERSION)) else {
+            return ptr::null_mut();
+        };
+        let Ok(error) = Owned::new(PyErr_NewException(c"xcso.XcsoError".as_ptr(), EXCEPTION, ptr::null_mut())) else {
+            return ptr::null_mut();
+        };
+        // The module takes a reference of its own, and this one is kept
+        Py_IncRef(error.0);
+        if PyModule_AddObject(module.0, c"XcsoError".as_ptr(), error.0) < 0 {
+            Py_DecRef(error.0);
+            return ptr::null_mut();
+        }
+        XCSO_ERROR = error.into_raw();
+
+        let Ok(fatx) = Owned::new(PyLong_FromUnsignedLongLong(FATX_MAX_SIZE)) else {
+            return ptr::null_mut();
+        };
+        if PyModule_AddObject(module.0, c"FATX_MAX_SIZE".as_ptr(), fatx.0) < 0 {
+            return ptr::null_mut();
+        }
+        mem::forget(fatx);
+        let version = concat!(env!("CARGO_PKG_VERSION"), "\0");
+        if PyModule_AddStringConstant(module.0, c"__version__".as_ptr(), version.as_ptr().cast()) < 0 {
+            return ptr::null_mut();
+        }
+        module.into_raw()
+    }
+}
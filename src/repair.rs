@@ -0,0 +1,371 @@
+//! Rebuilds the block index of a CSO or ZSO whose index was zeroed or
+//! damaged, from the blocks themselves.
+//!
+//! Compressed blocks don't record their size, but walking an LZ4 block's
+//! sequences or inflating a DEFLATE stream shows where it ends, and every
+//! block starts on the alignment after the one before. So the data is read
+//! from the front, taking each block as compressed when it parses as one
+//! and as stored otherwise. The rare stored block that happens to parse
+//! leaves the last block ending in the wrong place, in which case the scan
+//! backs up and tries the other reading of the blocks before it.
+
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read, Seek};
+use std::path::Path;
+
+use indicatif::ProgressBar;
+
+use crate::compress::interrupted;
+use crate::cso::{read_cso_info, write_block_index, CsoImage, Format, Method, CISO_HEADER_SIZE};
+use crate::error::XcsoError;
+use crate::trailer::read_trailer;
+use crate::{deflate, lz4};
+
+// How much of the part is read at once
+const WINDOW: usize = 4 << 20;
+// Readings of earlier blocks tried, over every place the first block might
+// start, before giving up on an image
+const MAX_BACKTRACKS: usize = 1 << 16;
+// pad_file leaves up to this much after the last block
+const MAX_PADDING: u64 = 0x400;
+
+/// What rebuilding an index came to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Repaired {
+    pub blocks: usize,
+    /// Index entries that were different before.
+    pub changed: usize,
+}
+
+/// Rebuilds the block index of the CSO or ZSO at `fp`: in place, or in a
+/// copy written to `dest`. Each part of a split set has an index of its
+/// own and is repaired by itself.
+pub fn repair_cso(fp: &Path, dest: Option<&Path>) -> Result<Repaired, Error> {
+    repair_cso_with_progress(fp, dest, &ProgressBar::new(0))
+}
+
+/// Same as `repair_cso`, but reports progress on `pb`, counting the bytes
+/// of the file scanned.
+pub fn repair_cso_with_progress(fp: &Path, dest: Option<&Path>, pb: &ProgressBar) -> Result<Repaired, Error> {
+    let mut file = File::open(fp).map_err(|e| XcsoError::io(fp, e))?;
+    let image = read_cso_info(&mut file)?;
+    let blocks = image.total_blocks;
+    let mut old = vec![0u8; (blocks + 1) * 4];
+    file.read_exact(&mut old)?;
+    let old: Vec<u32> = old.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect();
+
+    // A trailer isn't part of the image
+    let len = match read_trailer(&mut file)? {
+        Some(trailer) => trailer.data_len,
+        None => file.metadata()?.len(),
+    };
+    pb.set_length(len);
+    let mut scan = Scan { data: Window::new(file, len), image, pb, backtracks: 0 };
+    let index = scan.run()?;
+    pb.finish_and_clear();
+    let changed = index.iter().zip(&old).filter(|(a, b)| a != b).count();
+    crate::debug!("rebuilt {} index entries, {} of them changed", index.len(), changed);
+
+    // Copying a file onto itself would empty it
+    let dest = dest.filter(|x| fs::canonicalize(x).ok() != fs::canonicalize(fp).ok());
+    let out = match dest {
+        Some(dest) => {
+            fs::copy(fp, dest)?;
+            dest
+        },
+        None => fp,
+    };
+    if changed > 0 || dest.is_some() {
+        let mut file = File::options().write(true).open(out).map_err(|e| XcsoError::io(out, e))?;
+        file.seek(io::SeekFrom::Start(CISO_HEADER_SIZE as u64))?;
+        write_block_index(&mut file, &index)?;
+        file.sync_all()?;
+    }
+    Ok(Repaired { blocks, changed })
+}
+
+/// Reads a part a window at a time, as blocks are looked at from the front
+/// and only occasionally from a little way back.
+struct Window {
+    file: File,
+    len: u64,
+    start: u64,
+    buf: Vec<u8>,
+}
+
+impl Window {
+    fn new(file: File, len: u64) -> Window {
+        Window { file, len, start: 0, buf: Vec::new() }
+    }
+
+    /// Up to `want` bytes from `at`, fewer at the end of the file.
+    fn get(&mut self, at: u64, want: usize) -> Result<&[u8], Error> {
+        let end = (at + want as u64).min(self.len);
+        if at < self.start || end > self.start + self.buf.len() as u64 {
+            // Going back is usually to try a block a little way back again
+            let from = match at < self.start {
+                true => at.saturating_sub((WINDOW / 2) as u64),
+                false => at,
+            };
+            self.buf.resize(WINDOW.max(want + (at - from) as usize), 0);
+            self.file.seek(io::SeekFrom::Start(from))?;
+            let mut read = 0;
+            while read < self.buf.len() {
+                match self.file.read(&mut self.buf[read..])? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            self.buf.truncate(read);
+            self.start = from;
+        }
+        let from = (at - self.start) as usize;
+        Ok(&self.buf[from..(end - self.start) as usize])
+    }
+}
+
+struct Scan<'a> {
+    data: Window,
+    image: CsoImage,
+    pb: &'a ProgressBar,
+    backtracks: usize,
+}
+
+/// A block as it was read, with the other readings still to try.
+struct Reading {
+    start: u64,
+    /// Where the block ends and how it was stored, for each way of reading
+    /// it, best first.
+    ends: Vec<(u64, Option<Method>)>,
+    taken: usize,
+}
+
+impl Scan<'_> {
+    /// The index of the blocks. The first part of a split set leaves room
+    /// for an index of every block still to come, so the first block might
+    /// not follow this part's index. It's in the zeros after the index or
+    /// right before the first byte that isn't zero, as a block that's all
+    /// zeros is always compressed.
+    fn run(&mut self) -> Result<Vec<u32>, Error> {
+        let align_m = (1u64 << self.image.align) - 1;
+        let index_end = CISO_HEADER_SIZE as u64 + (self.image.total_blocks as u64 + 1) * 4;
+        let mut at = index_end;
+        loop {
+            let data = self.data.get(at, WINDOW)?;
+            match data.iter().position(|&x| x != 0) {
+                Some(n) => break at += n as u64,
+                None if data.is_empty() => break,
+                None => at += data.len() as u64,
+            }
+        }
+
+        let earliest = at.saturating_sub(self.image.block_size as u64).max(index_end);
+        let mut start = at & !align_m;
+        while start + align_m >= earliest {
+            if let Some(index) = self.scan_from(start.max(index_end))? {
+                return Ok(index);
+            }
+            if self.backtracks > MAX_BACKTRACKS {
+                break;
+            }
+            match start.checked_sub(align_m + 1) {
+                Some(x) => start = x,
+                None => break,
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "the blocks can't be made sense of, so the index can't be rebuilt"))
+    }
+
+    /// The index of the blocks if the first starts at `data_start`, or
+    /// `None` if no reading of them fills the part.
+    fn scan_from(&mut self, data_start: u64) -> Result<Option<Vec<u32>>, Error> {
+        let align_m = (1u64 << self.image.align) - 1;
+        let blocks = self.image.total_blocks;
+        let mut readings: Vec<Reading> = Vec::with_capacity(blocks);
+        let mut end = data_start;
+        loop {
+            if interrupted() {
+                return Err(XcsoError::Interrupted.into());
+            }
+            let done = readings.len() == blocks;
+            if done && end <= self.data.len && self.data.len - end <= MAX_PADDING {
+                break;
+            }
+            let start = (end + align_m) & !align_m;
+            let ends = match done {
+                true => Vec::new(),
+                false => self.readings(start, readings.len())?,
+            };
+            if !ends.is_empty() {
+                end = ends[0].0;
+                readings.push(Reading { start, ends, taken: 0 });
+                continue;
+            }
+
+            // Nothing fits here, so read an earlier block another way
+            while readings.last().is_some_and(|x| x.taken + 1 == x.ends.len()) {
+                readings.pop();
+            }
+            self.backtracks += 1;
+            let Some(last) = readings.last_mut().filter(|_| self.backtracks <= MAX_BACKTRACKS) else {
+                crate::debug!("no reading of the blocks from {:#x} fills the part", data_start);
+                return Ok(None);
+            };
+            last.taken += 1;
+            end = last.ends[last.taken].0;
+            crate::trace!("trying block {} another way", readings.len() - 1);
+        }
+
+        let mut index: Vec<u32> = readings.
+            iter().
+            map(|x| self.image.index_entry((x.start >> self.image.align) as u32, x.ends[x.taken].1)).
+            collect();
+        index.push(((end + align_m) >> self.image.align) as u32);
+        Ok(Some(index))
+    }
+
+    /// The ways block `block` starting at `start` could have been stored.
+    fn readings(&mut self, start: u64, block: usize) -> Result<Vec<(u64, Option<Method>)>, Error> {
+        let image = self.image;
+        let block_size = image.block_size as usize;
+        let len = image.block_len(block);
+        if block.is_multiple_of(4096) {
+            self.pb.set_position(start);
+        }
+        let methods: &[Method] = match (image.format, image.version) {
+            (Format::Zso, _) => &[Method::Lz4],
+            (Format::Cso, 0 | 1) => &[Method::Deflate],
+            (Format::Cso, _) => &[Method::Lz4, Method::Deflate],
+        };
+        let data = self.data.get(start, block_size * 2)?;
+        let mut ends = Vec::new();
+        for &method in methods {
+            let stored = match method {
+                Method::Lz4 => lz4::block_len(data, len),
+                Method::Deflate => deflate::stream_len(data, len).ok(),
+            };
+            // CSO v2 tells compressed blocks apart by their being smaller
+            let fits = |x: &usize| !image.sizes_raw_blocks() || *x < block_size;
+            if let Some(stored) = stored.filter(fits) {
+                ends.push((start + stored as u64, Some(method)));
+            }
+        }
+        let raw = match image.sizes_raw_blocks() {
+            true => block_size,
+            false => len,
+        };
+        if data.len() >= raw {
+            ends.push((start + raw as u64, None));
+        }
+        Ok(ends)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::compress::{compress_reader, part_path, CompressOptions, LEVEL_FAST};
+    use crate::selftest::{patterned, random};
+    use crate::testing::{image, Scratch};
+
+    /// Compresses `data` under `dir` and returns its parts.
+    fn parts(dir: &Scratch, data: &[u8], opts: &CompressOptions) -> Vec<PathBuf> {
+        let opts = CompressOptions { raw: true, ..opts.clone() };
+        compress_reader(Cursor::new(data), &dir.join("game"), &opts).unwrap();
+        (1..).map(|n| part_path(&dir.join("game"), n, opts.format)).take_while(|x| x.exists()).collect()
+    }
+
+    /// Zeroes the index of the image at `fp`, returning it as it was.
+    fn zero_index(fp: &Path) -> Vec<u8> {
+        let mut cso = fs::read(fp).unwrap();
+        let image = read_cso_info(&mut Cursor::new(&cso)).unwrap();
+        let index = CISO_HEADER_SIZE as usize..CISO_HEADER_SIZE as usize + (image.total_blocks + 1) * 4;
+        let before = cso.clone();
+        cso[index].fill(0);
+        fs::write(fp, cso).unwrap();
+        before
+    }
+
+    /// Where block `block` of the image `cso` starts, going by its index.
+    fn entry_pos(cso: &[u8], block: usize) -> usize {
+        let image = read_cso_info(&mut Cursor::new(cso)).unwrap();
+        let at = CISO_HEADER_SIZE as usize + block * 4;
+        let entry = u32::from_le_bytes(cso[at..at + 4].try_into().unwrap());
+        crate::decompress::entry_pos(entry, image.align) as usize
+    }
+
+    /// Zeroes the index of each part of an image compressed with `opts`,
+    /// checks it's rebuilt as it was, and returns how many parts there were.
+    fn check_repairs(opts: CompressOptions) -> usize {
+        let dir = Scratch::new("repair");
+        let fps = parts(&dir, &image(2000), &CompressOptions { level: LEVEL_FAST, ..opts });
+        for fp in &fps {
+            let before = zero_index(fp);
+            let blocks = read_cso_info(&mut Cursor::new(&before)).unwrap().total_blocks;
+            assert_eq!(repair_cso(fp, None).unwrap(), Repaired { blocks, changed: blocks + 1 }, "{:?}", fp);
+            assert_eq!(fs::read(fp).unwrap(), before, "{:?}", fp);
+            assert_eq!(repair_cso(fp, None).unwrap(), Repaired { blocks, changed: 0 }, "{:?}", fp);
+        }
+        fps.len()
+    }
+
+    #[test]
+    fn rebuilds_an_lz4_index() {
+        check_repairs(CompressOptions::default());
+        check_repairs(CompressOptions { format: Format::Zso, ..CompressOptions::default() });
+    }
+
+    #[test]
+    fn rebuilds_a_deflate_index() {
+        check_repairs(CompressOptions { method: Method::Deflate, ..CompressOptions::default() });
+        check_repairs(CompressOptions { method: Method::Deflate, version: Some(2), ..CompressOptions::default() });
+    }
+
+    #[test]
+    fn rebuilds_the_index_of_each_part() {
+        assert!(check_repairs(CompressOptions { split_size: Some(1 << 20), ..CompressOptions::default() }) > 1);
+    }
+
+    #[test]
+    fn backs_up_over_a_stored_block_that_parses() {
+        // A block that doesn't compress but starts with one that does
+        let mut data = image(200);
+        let block = lz4::compress(&patterned(2048), LEVEL_FAST).unwrap();
+        let mut stored = block.clone();
+        stored.extend(random(2048 - block.len(), 9));
+        data[100 * 2048..101 * 2048].copy_from_slice(&stored);
+
+        let dir = Scratch::new("repair-backtrack");
+        let fp = parts(&dir, &data, &CompressOptions::default()).remove(0);
+        let before = zero_index(&fp);
+        assert_eq!(lz4::block_len(&before[entry_pos(&before, 100)..], 2048), Some(block.len()));
+        repair_cso(&fp, None).unwrap();
+        assert_eq!(fs::read(&fp).unwrap(), before);
+    }
+
+    #[test]
+    fn repairs_into_a_copy() {
+        let dir = Scratch::new("repair-copy");
+        let fp = parts(&dir, &image(300), &CompressOptions::default()).remove(0);
+        let before = zero_index(&fp);
+        let zeroed = fs::read(&fp).unwrap();
+        let copy = dir.join("copy.cso");
+        assert_eq!(repair_cso(&fp, Some(&copy)).unwrap().changed, 301);
+        assert_eq!((fs::read(&fp).unwrap(), fs::read(&copy).unwrap()), (zeroed, before));
+    }
+
+    #[test]
+    fn what_isnt_blocks_cant_be_repaired() {
+        let dir = Scratch::new("repair-junk");
+        let fp = parts(&dir, &image(300), &CompressOptions::default()).remove(0);
+        zero_index(&fp);
+        let mut cso = fs::read(&fp).unwrap();
+        let at = cso.len() / 2;
+        cso.truncate(at);
+        fs::write(&fp, cso).unwrap();
+        assert!(repair_cso(&fp, None).is_err());
+    }
+}
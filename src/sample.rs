@@ -0,0 +1,207 @@
+//! Compresses a sample of an image's blocks to see how it would turn out
+//! with different settings, without converting all of it.
+//!
+//! Blocks are picked evenly from across the image, so the sample takes in
+//! the padding and the video partition of a redump in proportion as well as
+//! the game's files.
+
+use std::io::{self, Error, Read, Seek};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::codec::{codec, compress_worker, encode_block, stored_len, Block};
+use crate::compress::{header_version, physical_cores, CompressOptions, LEVEL_FAST, LEVEL_HC, LEVEL_MAX};
+use crate::cso::{get_cso_info, get_raw_cso_info, CsoImage, Method, CISO_HEADER_SIZE};
+use crate::decompress::open_uncompressed;
+use crate::deflate;
+use crate::xdvdfs::Volume;
+
+// Levels `pick_level` tries for each method, fastest first
+const LZ4_CANDIDATES: [u32; 4] = [LEVEL_FAST, 4, LEVEL_HC, LEVEL_MAX];
+const DEFLATE_CANDIDATES: [u32; 4] = [1, 4, 6, deflate::MAX_LEVEL];
+
+// How much more of the image a slower level has to save to be picked
+const MIN_SAVING: f64 = 0.005;
+
+/// Blocks sampled unless asked otherwise, enough to settle on a ratio
+/// within a percent or so for most images.
+pub const DEFAULT_SAMPLES: usize = 512;
+
+/// Blocks read from an image, up front so that trials only time the
+/// compression.
+pub struct Sample {
+    /// The image the blocks are from, as it would be compressed.
+    pub image: CsoImage,
+    blocks: Vec<Vec<u8>>,
+}
+
+impl Sample {
+    /// Reads `count` blocks spread over the image at `fp`, or every block of
+    /// an image with fewer. The blocks and how much of the image they stand
+    /// for follow `opts`, as for compressing it.
+    pub fn read(fp: &Path, opts: &CompressOptions, count: usize) -> Result<Sample, Error> {
+        let mut file = open_uncompressed(fp)?;
+        let file_len = file.seek(io::SeekFrom::End(0))?;
+        let mut image = match opts.raw {
+            true => get_raw_cso_info(&mut file, opts.format, opts.block_size)?,
+            false => get_cso_info(&mut file, opts.format, opts.block_size)?,
+        };
+        let partition_offset = file_len - image.total_bytes;
+        if opts.trim {
+            let used = Volume::open(&mut file, partition_offset)?.used_bytes()?;
+            image.total_bytes = image.total_bytes.min(used);
+        }
+        let offset = match opts.keep_video_partition {
+            true => 0,
+            false => partition_offset,
+        };
+        image.total_bytes += partition_offset - offset;
+        image.total_blocks = CsoImage::block_count(image.total_bytes, opts.block_size);
+        image.version = header_version(opts)?;
+        image.align = opts.align;
+
+        let count = count.min(image.total_blocks);
+        let mut blocks = Vec::with_capacity(count);
+        for i in 0..count {
+            let block = (i as u64 * image.total_blocks as u64 / count as u64) as usize;
+            file.seek(io::SeekFrom::Start(offset + block as u64 * opts.block_size as u64))?;
+            let mut data = vec![0; image.block_len(block)];
+            file.read_exact(&mut data)?;
+            blocks.push(data);
+        }
+        Ok(Sample { image, blocks })
+    }
+
+    /// How many blocks were read.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Compresses the sample with the method, level and store setting of
+    /// `opts` on the calling thread.
+    pub fn trial(&self, opts: &CompressOptions) -> Result<Trial, Error> {
+        if !self.image.supports(opts.method) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} version {} images cannot hold {} blocks",
+                    self.image.format.extension().to_uppercase(),
+                    self.image.version,
+                    opts.method.name(),
+                ),
+            ));
+        }
+        let block_size = self.image.block_size as usize;
+        let mut codec = codec(opts.method, opts.level);
+        let mut scratch = Vec::new();
+        let mut zero_block = Block { seq: 0, data: vec![0; block_size], method: None };
+        compress_worker(&mut zero_block, &mut *codec, &mut scratch, &self.image)?;
+        let mut blocks: Vec<Block> = self.blocks.iter().
+            map(|x| Block { seq: 0, data: x.clone(), method: None }).
+            collect();
+
+        let started = Instant::now();
+        for block in &mut blocks {
+            encode_block(block, &mut *codec, &mut scratch, &zero_block, opts.store, &self.image)?;
+        }
+        let elapsed = started.elapsed();
+
+        let align = 1u64 << self.image.align;
+        Ok(Trial {
+            raw_bytes: self.blocks.iter().map(|x| x.len() as u64).sum(),
+            stored_bytes: blocks.iter().map(|x| stored_len(x, &self.image).next_multiple_of(align)).sum(),
+            elapsed,
+        })
+    }
+
+    /// Projects what compressing the whole image with `opts` would give, from
+    /// how the sample compresses. The time only counts compressing, on
+    /// `opts.threads`, so a slow source or destination makes it longer.
+    pub fn estimate(&self, opts: &CompressOptions) -> Result<Estimate, Error> {
+        let trial = self.trial(opts)?;
+        let total_blocks = self.image.total_blocks as u64;
+        let per_block = trial.stored_bytes as f64 / self.blocks.len().max(1) as f64;
+        let part_len = |blocks: u64| {
+            let len = CISO_HEADER_SIZE as u64 + (blocks + 1) * 4 + (blocks as f64 * per_block) as u64;
+            (len & !0x3ff) + 0x400
+        };
+        // Each part fills up to the split size with its own index
+        let per_part = match opts.split_size {
+            Some(split) => ((split - CISO_HEADER_SIZE as u64) as f64 / (per_block + 4.0)).max(1.0) as u64,
+            None => total_blocks,
+        };
+        let mut parts = Vec::new();
+        let mut left = total_blocks;
+        while left > 0 || parts.is_empty() {
+            let blocks = left.min(per_part.max(1));
+            parts.push(part_len(blocks));
+            left -= blocks;
+        }
+
+        let threads = opts.threads.unwrap_or_else(physical_cores).max(1);
+        let secs = self.image.total_bytes as f64 / (trial.bytes_per_sec() * threads as f64);
+        Ok(Estimate { parts, ratio: trial.ratio(), duration: Duration::from_secs_f64(secs) })
+    }
+
+    /// Picks a level of `opts.method` for compressing the image: the
+    /// fastest that comes within half a percent of the smallest output.
+    /// The slower levels barely shrink video or assets that are compressed
+    /// already, so they're only used when they make a difference.
+    pub fn pick_level(&self, opts: &CompressOptions) -> Result<u32, Error> {
+        let candidates = match opts.method {
+            Method::Lz4 => LZ4_CANDIDATES,
+            Method::Deflate => DEFLATE_CANDIDATES,
+        };
+        let mut ratios = Vec::with_capacity(candidates.len());
+        for level in candidates {
+            let trial = self.trial(&CompressOptions { level, ..opts.clone() })?;
+            let (ratio, speed) = (trial.ratio() * 100.0, trial.bytes_per_sec());
+            crate::debug!("level {} gives {:.2}% at {:.0} bytes/s", level, ratio, speed);
+            ratios.push((level, trial.ratio()));
+        }
+        let best = ratios.iter().map(|(_, x)| *x).fold(f64::INFINITY, f64::min);
+        Ok(ratios.iter().find(|(_, x)| x - best < MIN_SAVING).map_or(opts.level, |(level, _)| *level))
+    }
+}
+
+/// What compressing a whole image is expected to give.
+#[derive(Clone, Debug)]
+pub struct Estimate {
+    /// Size of each part.
+    pub parts: Vec<u64>,
+    /// Compressed size as a fraction of the original.
+    pub ratio: f64,
+    /// How long compressing it takes.
+    pub duration: Duration,
+}
+
+impl Estimate {
+    pub fn total_bytes(&self) -> u64 {
+        self.parts.iter().sum()
+    }
+}
+
+/// How a sample compressed.
+#[derive(Copy, Clone, Debug)]
+pub struct Trial {
+    pub raw_bytes: u64,
+    /// What the blocks took up once compressed, with their alignment.
+    pub stored_bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Trial {
+    /// Compressed size as a fraction of the original.
+    pub fn ratio(&self) -> f64 {
+        self.stored_bytes as f64 / self.raw_bytes.max(1) as f64
+    }
+
+    /// Bytes of the image compressed a second on one thread.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.raw_bytes as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
@@ -0,0 +1,177 @@
+//! A round trip through the codecs and the image layout, on images made up
+//! on the spot: each is compressed with a spread of settings and read back,
+//! straight through and with the `CsoFile` and `CsoReader` the commands
+//! read with, and has to come back byte for byte. It's a quick check that a build
+//! works, for users and for packagers, and needs no ISO to run. Everything
+//! happens in memory, so nothing is left behind.
+
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::codec::{decode_image, encode_image};
+use crate::compress::{header_version, CompressOptions, LEVEL_FAST, LEVEL_HC, LEVEL_MAX};
+use crate::cso::{Format, Method};
+use crate::decompress::{CsoFile, CsoReader};
+use crate::deflate;
+
+/// What one image compressed one way came to.
+#[derive(Debug)]
+pub struct RoundTrip {
+    pub image: &'static str,
+    pub settings: String,
+    pub bytes: usize,
+    /// Size of the compressed image, or why it didn't come back.
+    pub result: Result<usize, Error>,
+}
+
+/// Runs every image through every setting, calling `done` with each round
+/// trip as it finishes.
+pub fn selftest(mut done: impl FnMut(&RoundTrip)) -> Vec<RoundTrip> {
+    let mut results = Vec::new();
+    for (image, data) in images() {
+        for opts in settings() {
+            let round_trip = RoundTrip {
+                image,
+                settings: describe(&opts),
+                bytes: data.len(),
+                result: round_trip(&data, &opts),
+            };
+            done(&round_trip);
+            results.push(round_trip);
+        }
+    }
+    results
+}
+
+/// The images to try: blocks of every kind, and sizes that end part way
+/// through a block.
+fn images() -> Vec<(&'static str, Vec<u8>)> {
+    let mut mixed = vec![0; 64 << 10];
+    mixed.extend(random(64 << 10, 1));
+    mixed.extend(patterned(64 << 10));
+    mixed.extend(vec![0; 64 << 10]);
+    let mut uneven = patterned(60 << 10);
+    uneven.extend(random(40_003, 2));
+    vec![
+        ("zeros", vec![0; 256 << 10]),
+        ("random", random(256 << 10, 3)),
+        ("patterned", patterned(256 << 10)),
+        ("mixed", mixed),
+        ("uneven", uneven),
+        ("short", random(1_000, 4)),
+        ("one block", patterned(1_500)),
+    ]
+}
+
+/// Data that doesn't compress, the same for the same `seed`.
+pub(crate) fn random(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..len).
+        map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        }).
+        collect()
+}
+
+/// Data with repeats near and far, as in file tables and game assets.
+pub(crate) fn patterned(len: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(len + 64);
+    let mut n = 0u32;
+    while data.len() < len {
+        data.extend_from_slice(format!("MEDIA_{:06} ", n / 3).as_bytes());
+        data.extend((0..n % 29).map(|x| (x * n) as u8));
+        n += 1;
+    }
+    data.truncate(len);
+    data
+}
+
+pub(crate) fn settings() -> Vec<CompressOptions> {
+    let opts = |format, method, version, level, block_size, align, store| CompressOptions {
+        format,
+        method,
+        version,
+        level,
+        block_size,
+        align,
+        store,
+        ..CompressOptions::default()
+    };
+    vec![
+        opts(Format::Cso, Method::Lz4, None, LEVEL_MAX, 2048, 2, false),
+        opts(Format::Cso, Method::Lz4, None, LEVEL_FAST, 2048, 0, false),
+        opts(Format::Cso, Method::Deflate, Some(2), 6, 4096, 4, false),
+        opts(Format::Cso, Method::Deflate, None, deflate::MAX_LEVEL, 2048, 0, false),
+        opts(Format::Zso, Method::Lz4, None, LEVEL_HC, 16384, 6, false),
+        opts(Format::Cso, Method::Lz4, None, LEVEL_MAX, 2048, 2, true),
+    ]
+}
+
+fn describe(opts: &CompressOptions) -> String {
+    let version = header_version(opts).unwrap_or(0);
+    let method = match opts.store {
+        true => "stored".to_owned(),
+        false => format!("{} level {}", opts.method.name(), opts.level),
+    };
+    format!(
+        "{} v{}, {}, {} byte blocks, align {}",
+        opts.format.extension().to_uppercase(), version, method, opts.block_size, opts.align,
+    )
+}
+
+/// Checks that `back`, which is `data` read back `how`, is the same.
+fn compare(back: &[u8], data: &[u8], how: &str) -> Result<(), Error> {
+    if back.len() != data.len() {
+        let msg = format!("{}, it came back as {} bytes rather than {}", how, back.len(), data.len());
+        return Err(Error::new(ErrorKind::InvalidData, msg));
+    }
+    if let Some(at) = back.iter().zip(data).position(|(a, b)| a != b) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{}, it came back different from byte {}", how, at)));
+    }
+    Ok(())
+}
+
+/// Compresses `data` with `opts` and reads it back every way the commands
+/// do, returning the size of the image.
+pub(crate) fn round_trip(data: &[u8], opts: &CompressOptions) -> Result<usize, Error> {
+    let mut out = Cursor::new(Vec::new());
+    encode_image(data, &mut out, opts)?;
+    let image = out.into_inner();
+    let len = image.len();
+    compare(&decode_image(&mut Cursor::new(&image[..]))?, data, "decoded")?;
+
+    let mut cso = CsoFile::from_reader(Cursor::new(image))?;
+    let mut blocks = Vec::with_capacity(data.len());
+    for block in 0..cso.header().total_blocks {
+        blocks.extend(cso.read_block(block)?);
+    }
+    compare(&blocks, data, "read block by block")?;
+
+    let mut reader = CsoReader::new(cso);
+    let mut read = Vec::with_capacity(data.len());
+    reader.read_to_end(&mut read)?;
+    compare(&read, data, "read as a stream")?;
+    // Part way into a block, and on across the next few
+    let start = data.len() / 3 + 1;
+    let mut read = vec![0; (data.len() - start).min(10_000)];
+    reader.seek(SeekFrom::Start(start as u64))?;
+    reader.read_exact(&mut read)?;
+    compare(&read, &data[start..start + read.len()], "read from the middle")?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn everything_comes_back() {
+        let results = selftest(|_| {});
+        assert_eq!(results.len(), images().len() * settings().len());
+        for x in results {
+            assert!(x.result.is_ok(), "{} with {}: {:?}", x.image, x.settings, x.result);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! `serve`: a queue of conversions driven through a small HTTP API on
+//! localhost or a Unix socket, so GUIs and scripts can hand off long
+//! batches without keeping a process of their own around.
+//!
+//! Each job runs as `make-xcso compress --json --json-progress` in a child
+//! process, whose records are collected as it goes. A job that's cancelled
+//! (or crashes) takes nothing else down with it, and a cancelled one gets to
+//! remove its partial outputs like a conversion stopped with Ctrl+C.
+
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::process::{Child, ChildStdout, Command, ExitCode, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cli::{is_stdin, parse_compress_args, Listen, ServeArgs, Verbosity};
+use crate::json::{self, Value};
+use crate::summary::EXIT_INTERRUPTED;
+
+// Generous for a request line, a few headers and a list of arguments
+const MAX_HEAD_SIZE: usize = 64 << 10;
+const MAX_BODY_SIZE: usize = 1 << 20;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Status {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Done => "done",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+        }
+    }
+}
+
+struct Job {
+    id: u64,
+    args: Vec<String>,
+    status: Status,
+    /// Set when a running job should be stopped.
+    cancel: bool,
+    /// The image being converted and how far it has got.
+    source: Option<String>,
+    bytes_done: u64,
+    bytes_total: u64,
+    /// Records of the images finished so far, as `compress --json` prints them.
+    images: Vec<Value>,
+    error: Option<String>,
+}
+
+impl Job {
+    fn record(&self) -> Value {
+        json::object(vec![
+            ("id", self.id.into()),
+            ("status", self.status.name().into()),
+            ("args", self.args.clone().into()),
+            ("source", self.source.clone().into()),
+            ("bytes_done", self.bytes_done.into()),
+            ("bytes_total", self.bytes_total.into()),
+            ("images", Value::Array(self.images.clone())),
+            ("error", self.error.clone().into()),
+        ])
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: Mutex<Vec<Job>>,
+    /// Signalled when a job is queued.
+    queued: Condvar,
+    last_id: Mutex<u64>,
+}
+
+impl Queue {
+    fn with_job<T>(&self, id: u64, f: impl FnOnce(&mut Job) -> T) -> Option<T> {
+        self.jobs.lock().unwrap().iter_mut().find(|x| x.id == id).map(f)
+    }
+}
+
+/// Something requests come in on, a TCP or Unix socket connection.
+pub(crate) trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Takes the next connection.
+pub(crate) type Accept = Box<dyn FnMut() -> io::Result<Box<dyn Stream>>>;
+
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    /// Names and values, trimmed, in the order they came.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of header `name`, whatever its case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(x, _)| x.eq_ignore_ascii_case(name)).map(|(_, x)| x.as_str())
+    }
+}
+
+struct Response {
+    code: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response { code: 200, body }
+    }
+
+    fn error(code: u16, message: &str) -> Response {
+        Response { code, body: json::object(vec![("error", message.into())]) }
+    }
+}
+
+fn bad_request(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, what.to_owned())
+}
+
+/// Reads a request, with its body if it has a `Content-Length`. A
+/// connection closed before a request starts is `UnexpectedEof`.
+pub(crate) fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, Error> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        let read = reader.by_ref().take(MAX_HEAD_SIZE as u64).read_until(b'\n', &mut head)?;
+        if read == 0 && head.is_empty() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        if read == 0 {
+            return Err(bad_request("request cut short"));
+        }
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(bad_request("request headers too long"));
+        }
+    }
+
+    let head = String::from_utf8(head).map_err(|_| bad_request("request headers aren't UTF-8"))?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err(bad_request("invalid request line")),
+    };
+    let headers: Vec<(String, String)> = lines.
+        filter_map(|x| x.split_once(':')).
+        map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned())).
+        collect();
+    let length = headers.iter().
+        find(|(name, _)| name.eq_ignore_ascii_case("content-length")).
+        map_or(Ok(0), |(_, value)| value.parse().map_err(|_| bad_request("invalid Content-Length")))?;
+    if length > MAX_BODY_SIZE {
+        return Err(bad_request("request body too large"));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, headers, body })
+}
+
+fn write_response(stream: &mut dyn Stream, response: &Response) -> Result<(), Error> {
+    let reason = match response.code {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Error",
+    };
+    let body = format!("{}\n", response.body);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.code,
+        reason,
+        body.len(),
+        body,
+    )?;
+    stream.flush()
+}
+
+/// Queues a job from a body such as `{"args": ["halo.iso", "-d", "out"]}`,
+/// checking the arguments the same way `compress` would.
+fn queue_job(queue: &Queue, body: &[u8]) -> Response {
+    let Ok(body) = std::str::from_utf8(body) else {
+        return Response::error(400, "the body isn't UTF-8");
+    };
+    let request = match json::parse(body) {
+        Ok(request) => request,
+        Err(e) => return Response::error(400, &format!("invalid JSON: {}", e)),
+    };
+    let args: Option<Vec<String>> = match request.get("args") {
+        Some(Value::Array(items)) => items.iter().map(|x| x.as_str().map(str::to_owned)).collect(),
+        _ => None,
+    };
+    let Some(args) = args else {
+        return Response::error(400, "expected an object with \"args\", a list of strings");
+    };
+    let parsed = match parse_compress_args(args.iter().map(OsString::from).collect()) {
+        Ok(parsed) => parsed,
+        Err(e) => return Response::error(400, &e.to_string()),
+    };
+    // Jobs run with nothing on their stdin
+    if parsed.inputs.iter().any(|x| is_stdin(x)) {
+        return Response::error(400, "jobs can't read from stdin");
+    }
+
+    let id = {
+        let mut last_id = queue.last_id.lock().unwrap();
+        *last_id += 1;
+        *last_id
+    };
+    let job = Job {
+        id,
+        args,
+        status: Status::Queued,
+        cancel: false,
+        source: None,
+        bytes_done: 0,
+        bytes_total: 0,
+        images: Vec::new(),
+        error: None,
+    };
+    let record = job.record();
+    queue.jobs.lock().unwrap().push(job);
+    queue.queued.notify_one();
+    Response { code: 201, body: record }
+}
+
+/// Cancels a job: one still queued never starts, and a running one is
+/// stopped.
+fn cancel_job(queue: &Queue, id: u64) -> Response {
+    let cancelled = queue.with_job(id, |job| {
+        match job.status {
+            Status::Queued => job.status = Status::Cancelled,
+            Status::Running => job.cancel = true,
+            _ => return Err(format!("job {} has already {}", id, job.status.name())),
+        }
+        Ok(job.record())
+    });
+    match cancelled {
+        Some(Ok(record)) => Response::ok(record),
+        Some(Err(e)) => Response::error(409, &e),
+        None => Response::error(404, &format!("no job {}", id)),
+    }
+}
+
+fn route(queue: &Queue, request: &Request) -> Response {
+    let path = request.path.split('?').next().unwrap_or_default().trim_end_matches('/');
+    let id = match path.strip_prefix("/jobs/") {
+        Some(id) => match id.parse::<u64>() {
+            Ok(id) => Some(id),
+            Err(_) => return Response::error(404, &format!("no job {}", id)),
+        },
+        None if path == "/jobs" => None,
+        None => return Response::error(404, &format!("nothing at {}", request.path)),
+    };
+
+    match (request.method.as_str(), id) {
+        ("GET", None) => {
+            let jobs = queue.jobs.lock().unwrap().iter().map(Job::record).collect();
+            Response::ok(Value::Array(jobs))
+        },
+        ("POST", None) => queue_job(queue, &request.body),
+        ("GET", Some(id)) => match queue.with_job(id, |job| job.record()) {
+            Some(record) => Response::ok(record),
+            None => Response::error(404, &format!("no job {}", id)),
+        },
+        ("DELETE", Some(id)) => cancel_job(queue, id),
+        _ => Response::error(405, &format!("{} isn't supported here", request.method)),
+    }
+}
+
+fn handle(mut stream: Box<dyn Stream>, queue: &Queue, verbose: bool) {
+    let response = match read_request(&mut BufReader::new(stream.as_mut())) {
+        Ok(request) => {
+            let response = route(queue, &request);
+            if verbose {
+                println!("{} {} -> {}", request.method, request.path, response.code);
+            }
+            response
+        },
+        Err(e) => Response::error(400, &e.to_string()),
+    };
+    // The client may well have gone, which is its own business
+    write_response(stream.as_mut(), &response).ok();
+}
+
+/// Follows the records a job prints as it goes.
+fn follow(queue: &Queue, id: u64, stdout: ChildStdout) {
+    for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+        let Ok(record) = json::parse(&line) else {
+            continue;
+        };
+        queue.with_job(id, |job| match record.get("status").and_then(Value::as_str) {
+            Some("converting") => {
+                job.source = record.get("source").and_then(Value::as_str).map(str::to_owned);
+                job.bytes_done = record.get("bytes_done").and_then(Value::as_u64).unwrap_or(0);
+                job.bytes_total = record.get("bytes_total").and_then(Value::as_u64).unwrap_or(0);
+            },
+            _ => {
+                job.source = None;
+                job.images.push(record);
+            },
+        });
+    }
+}
+
+/// Asks a running job to stop, which removes its partial outputs.
+fn stop(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    #[cfg(not(unix))]
+    child.kill().ok();
+}
+
+/// Runs job `id`, returning how it went and why it failed.
+fn run_job(queue: &Queue, id: u64, args: &[String]) -> Result<(Status, Option<String>), Error> {
+    let mut child = Command::new(env::current_exe()?).
+        args(["compress", "--json", "--json-progress"]).
+        args(args).
+        stdin(Stdio::null()).
+        stdout(Stdio::piped()).
+        stderr(Stdio::piped()).
+        spawn()?;
+
+    let stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let (status, errors) = thread::scope(|s| {
+        s.spawn(|| follow(queue, id, stdout));
+        let errors = s.spawn(move || {
+            let mut errors = String::new();
+            stderr.read_to_string(&mut errors).map(|_| errors)
+        });
+
+        let mut stopping = false;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if !stopping && queue.with_job(id, |job| job.cancel).unwrap_or(false) {
+                stop(&mut child);
+                stopping = true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        Ok::<_, Error>((status, errors.join().unwrap()?))
+    })?;
+
+    let cancelled = queue.with_job(id, |job| job.cancel).unwrap_or(false);
+    let error = errors.lines().rfind(|x| !x.trim().is_empty()).map(str::to_owned);
+    Ok(match status.code() {
+        Some(0) => (Status::Done, None),
+        _ if cancelled => (Status::Cancelled, None),
+        Some(code) if code == EXIT_INTERRUPTED as i32 => (Status::Cancelled, error),
+        Some(code) => (Status::Failed, error.or_else(|| Some(format!("exited with status {}", code)))),
+        None => (Status::Failed, error.or_else(|| Some("stopped by a signal".to_owned()))),
+    })
+}
+
+/// Takes queued jobs one at a time and runs them.
+fn worker(queue: &Queue, chatty: bool) {
+    loop {
+        let (id, args) = {
+            let mut jobs = queue.jobs.lock().unwrap();
+            loop {
+                if let Some(job) = jobs.iter_mut().find(|x| x.status == Status::Queued) {
+                    job.status = Status::Running;
+                    break (job.id, job.args.clone());
+                }
+                jobs = queue.queued.wait(jobs).unwrap();
+            }
+        };
+        if chatty {
+            println!("Starting job {}: {}", id, args.join(" "));
+        }
+
+        let (status, error) = run_job(queue, id, &args).unwrap_or_else(|e| (Status::Failed, Some(e.to_string())));
+        queue.with_job(id, |job| {
+            job.status = status;
+            job.source = None;
+            job.error = error.clone();
+        });
+        match error {
+            Some(ref e) if status == Status::Failed => eprintln!("Job {} failed: {}", id, e),
+            _ if chatty => println!("Job {} {}", id, status.name()),
+            _ => {},
+        }
+    }
+}
+
+/// Accepts connections forever, handing each to a thread of its own.
+fn accept_loop(accept: &mut Accept, queue: &Arc<Queue>, verbose: bool) -> ! {
+    loop {
+        match accept() {
+            Ok(stream) => {
+                let queue = queue.clone();
+                thread::spawn(move || handle(stream, &queue, verbose));
+            },
+            Err(e) => eprintln!("Error accepting a connection: {}", e),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket left behind by a server that's no longer running
+    if std::fs::symlink_metadata(path).is_ok_and(|x| x.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    std::os::unix::net::UnixListener::bind(path)
+}
+
+/// Listens on `listen`, returning how to take connections and whether
+/// they can come from other machines.
+pub(crate) fn bind(listen: &Listen) -> io::Result<(Accept, bool)> {
+    match listen {
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)?;
+            let public = listener.local_addr().is_ok_and(|x| !x.ip().is_loopback());
+            Ok((Box::new(move || listener.accept().map(|(x, _)| Box::new(x) as Box<dyn Stream>)), public))
+        },
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            let listener = bind_unix(path)?;
+            Ok((Box::new(move || listener.accept().map(|(x, _)| Box::new(x) as Box<dyn Stream>)), false))
+        },
+        #[cfg(not(unix))]
+        Listen::Unix(_) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unix sockets aren't available on this system, listen on a TCP address instead",
+        )),
+    }
+}
+
+pub fn run(args: ServeArgs) -> ExitCode {
+    let chatty = args.verbosity != Verbosity::Quiet;
+    let verbose = args.verbosity == Verbosity::Verbose;
+    let queue = Arc::new(Queue::default());
+
+    let mut accept = match bind(&args.listen) {
+        Ok((accept, public)) => {
+            if public {
+                eprintln!("warning: anyone who can reach {} can queue conversions on this machine", args.listen);
+            }
+            accept
+        },
+        Err(e) => {
+            eprintln!("Error listening on {}: {}", args.listen, e);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    if chatty {
+        match args.listen {
+            Listen::Tcp(ref addr) => println!("Listening on http://{}/jobs", addr),
+            Listen::Unix(ref path) => println!("Listening on {}", path.display()),
+        }
+    }
+    for _ in 0..args.jobs {
+        let queue = queue.clone();
+        thread::spawn(move || worker(&queue, chatty));
+    }
+    accept_loop(&mut accept, &queue, verbose)
+}
@@ -0,0 +1,53 @@
+//! Ctrl+C handling, so an interrupted conversion gets a chance to remove its
+//! partial outputs. A second Ctrl+C falls back to the default behaviour.
+
+#[cfg(unix)]
+extern "C" fn on_signal(signum: libc::c_int) {
+    xcso::interrupt();
+    // There's no more pausing, and the default behaviour exits without
+    // putting the terminal back
+    crate::pause::restore();
+    unsafe {
+        libc::signal(signum, libc::SIG_DFL);
+    }
+}
+
+/// Routes Ctrl+C (and SIGTERM) to `xcso::interrupt`.
+#[cfg(unix)]
+pub fn install() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(windows)]
+mod win {
+    pub type Bool = i32;
+    pub type HandlerRoutine = unsafe extern "system" fn(ctrl_type: u32) -> Bool;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: Bool) -> Bool;
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn on_ctrl(_ctrl_type: u32) -> win::Bool {
+    xcso::interrupt();
+    // Let the next Ctrl+C terminate the process as usual
+    win::SetConsoleCtrlHandler(Some(on_ctrl), 0);
+    1
+}
+
+/// Routes Ctrl+C and console close events to `xcso::interrupt`.
+#[cfg(windows)]
+pub fn install() {
+    unsafe {
+        win::SetConsoleCtrlHandler(Some(on_ctrl), 1);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn install() {}
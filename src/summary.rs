@@ -0,0 +1,119 @@
+//! The table printed at the end of a batch, and the exit status that goes
+//! with it.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use console::style;
+
+/// Some images were converted, but at least one failed.
+pub const EXIT_PARTIAL: u8 = 3;
+/// None of the inputs were images that could be converted.
+pub const EXIT_NO_IMAGES: u8 = 4;
+/// Ctrl+C stopped the batch.
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    /// Converted, with the path of the first output and its size as a
+    /// share of the input.
+    Ok(PathBuf, Option<f64>),
+    Skipped(String),
+    Failed(String),
+    /// Left alone because an earlier image failed with `--fail-fast`.
+    NotStarted,
+    Interrupted,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Ok(..) => "OK",
+            Outcome::Skipped(_) | Outcome::NotStarted => "SKIPPED",
+            Outcome::Failed(_) => "FAILED",
+            Outcome::Interrupted => "STOPPED",
+        }
+    }
+}
+
+/// What happened to each input of a batch, in the order they were given.
+#[derive(Default)]
+pub struct Summary {
+    results: Vec<(Option<usize>, PathBuf, Outcome)>,
+}
+
+impl Summary {
+    /// Records the outcome for `fname`. Inputs that were never queued, like
+    /// files that aren't images, go under `None` and are listed first.
+    pub fn add(&mut self, seq: Option<usize>, fname: PathBuf, outcome: Outcome) {
+        self.results.push((seq, fname, outcome));
+    }
+
+    /// Whether there was more than a single input, which is when the table
+    /// is worth printing.
+    pub fn is_batch(&self) -> bool {
+        self.results.len() > 1
+    }
+
+    fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.results.iter().filter(|(_, _, x)| f(x)).count()
+    }
+
+    /// Counts the queued images with a matching outcome.
+    fn count_queued(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.results.iter().filter(|(seq, _, x)| seq.is_some() && f(x)).count()
+    }
+
+    pub fn print_table(&mut self) {
+        self.results.sort_by_key(|(seq, _, _)| seq.map_or(0, |x| x + 1));
+        let width = self.results.iter().
+            map(|(_, fname, _)| fname.display().to_string().chars().count()).
+            max().
+            unwrap_or(0).
+            min(60);
+
+        println!();
+        for (_, fname, outcome) in &self.results {
+            let label = format!("{:<7}", outcome.label());
+            let label = match outcome {
+                Outcome::Ok(..) => style(label).green(),
+                Outcome::Skipped(_) | Outcome::NotStarted => style(label).dim(),
+                Outcome::Failed(_) | Outcome::Interrupted => style(label).red(),
+            };
+            let detail = match outcome {
+                Outcome::Ok(fp, Some(ratio)) => format!("{} ({:.1}%)", fp.display(), ratio * 100.0),
+                Outcome::Ok(fp, None) => fp.display().to_string(),
+                Outcome::Skipped(reason) | Outcome::Failed(reason) => reason.clone(),
+                Outcome::NotStarted => "not started after an earlier failure".to_owned(),
+                Outcome::Interrupted => "interrupted".to_owned(),
+            };
+            println!("  {}  {:<width$}  {}", label, fname.display(), detail, width = width);
+        }
+
+        println!(
+            "{} converted, {} skipped, {} failed",
+            self.count(|x| matches!(x, Outcome::Ok(..))),
+            self.count(|x| matches!(x, Outcome::Skipped(_) | Outcome::NotStarted)),
+            self.count(|x| matches!(x, Outcome::Failed(_) | Outcome::Interrupted)),
+        );
+    }
+
+    /// Success unless something failed: `EXIT_PARTIAL` if some images made
+    /// it, plain failure if none did, and `EXIT_NO_IMAGES` if there was
+    /// nothing to convert in the first place.
+    pub fn exit_code(&self) -> ExitCode {
+        let ok = self.count_queued(|x| matches!(x, Outcome::Ok(..) | Outcome::Skipped(_)));
+        let failed = self.count_queued(|x| matches!(x, Outcome::Failed(_)));
+        if self.count(|x| matches!(x, Outcome::Interrupted)) > 0 {
+            ExitCode::from(EXIT_INTERRUPTED)
+        } else if ok + failed == 0 {
+            ExitCode::from(EXIT_NO_IMAGES)
+        } else if failed == 0 {
+            ExitCode::SUCCESS
+        } else if ok > 0 {
+            ExitCode::from(EXIT_PARTIAL)
+        } else {
+            ExitCode::FAILURE
+        }
+    }
+}
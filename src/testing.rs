@@ -0,0 +1,50 @@
+//! What the unit tests share, on top of the images `selftest` makes up.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::selftest::{patterned, random};
+
+static SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory of a test's own to write files in, removed with everything
+/// in it once the test is done with it.
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn new(name: &str) -> Scratch {
+        let n = SCRATCH.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("xcso-test-{}-{}-{}", name, std::process::id(), n));
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An image of `blocks` 2048 byte blocks that mostly compress, with some
+/// that don't and some that are blank, ending part way through the last.
+pub fn image(blocks: usize) -> Vec<u8> {
+    let len = blocks * 2048 - 1000;
+    let mut data = patterned(len);
+    for (i, chunk) in data.chunks_mut(64 << 10).enumerate() {
+        match i % 4 {
+            1 => {
+                let n = chunk.len().min(20_000);
+                chunk[..n].copy_from_slice(&random(n, i as u64));
+            },
+            3 => chunk.fill(0),
+            _ => (),
+        }
+    }
+    data
+}
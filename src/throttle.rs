@@ -0,0 +1,92 @@
+//! Caps how fast images are read and written, so a conversion running
+//! against a NAS or a shared disk leaves some of it for everyone else.
+//!
+//! Each direction has a token bucket that fills at the rate and holds up to
+//! a second of it. Transfers take their size out of the bucket, going into
+//! debt for ones bigger than what's there, and wait for the debt to be paid
+//! off before the next one.
+
+use std::io::{self, Read, Seek, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longest a wait sleeps before checking for Ctrl+C
+const WAIT_SLICE: Duration = Duration::from_millis(100);
+
+/// A token bucket of `rate` bytes a second.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    /// Starts with a full bucket, so the first second of transfers go
+    /// through straight away.
+    pub fn new(bytes_per_sec: u64) -> RateLimit {
+        let rate = bytes_per_sec.max(1) as f64;
+        RateLimit { rate, tokens: rate, last: Instant::now() }
+    }
+
+    /// Takes `n` bytes out of the bucket, waiting for as long as that would
+    /// take if it doesn't have them.
+    pub fn take(&mut self, n: u64) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate) - n as f64;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            return;
+        }
+        let until = now + Duration::from_secs_f64(-self.tokens / self.rate);
+        while !crate::interrupted() {
+            match until.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => thread::sleep(left.min(WAIT_SLICE)),
+                _ => break,
+            }
+        }
+    }
+}
+
+/// A reader or writer that goes no faster than its `RateLimit`.
+pub struct Throttled<T> {
+    inner: T,
+    limit: RateLimit,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, bytes_per_sec: u64) -> Throttled<T> {
+        Throttled { inner, limit: RateLimit::new(bytes_per_sec) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.limit.take(n as u64);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.limit.take(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Throttled<T> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
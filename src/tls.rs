@@ -0,0 +1,213 @@
+//! TLS for `https://` URLs, built with the `tls` feature, through the
+//! system's OpenSSL 3. Servers are checked against the system's trusted
+//! certificates (or `SSL_CERT_FILE` and `SSL_CERT_DIR`, which OpenSSL
+//! looks at first) and the host name in the URL, as a browser would.
+
+use std::ffi::{c_char, c_int, c_long, c_ulong, c_void, CStr, CString};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+use crate::error::XcsoError;
+
+#[repr(C)]
+struct SslMethod {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct SslCtx {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Ssl {
+    _private: [u8; 0],
+}
+
+const SSL_VERIFY_PEER: c_int = 0x01;
+const SSL_CTRL_SET_TLSEXT_HOSTNAME: c_int = 55;
+const SSL_CTRL_SET_MIN_PROTO_VERSION: c_int = 123;
+const TLSEXT_NAMETYPE_HOST_NAME: c_long = 0;
+const TLS1_2_VERSION: c_long = 0x0303;
+// Servers often close the connection without saying so first, which only
+// matters when less came than the headers said, and that's caught anyway
+const SSL_OP_IGNORE_UNEXPECTED_EOF: u64 = 1 << 7;
+const X509_V_OK: c_long = 0;
+
+const SSL_ERROR_WANT_READ: c_int = 2;
+const SSL_ERROR_WANT_WRITE: c_int = 3;
+const SSL_ERROR_SYSCALL: c_int = 5;
+const SSL_ERROR_ZERO_RETURN: c_int = 6;
+
+#[link(name = "ssl")]
+#[link(name = "crypto")]
+extern "C" {
+    fn TLS_client_method() -> *const SslMethod;
+    fn SSL_CTX_new(method: *const SslMethod) -> *mut SslCtx;
+    fn SSL_CTX_free(ctx: *mut SslCtx);
+    fn SSL_CTX_set_default_verify_paths(ctx: *mut SslCtx) -> c_int;
+    fn SSL_CTX_set_verify(ctx: *mut SslCtx, mode: c_int, callback: *const c_void);
+    fn SSL_CTX_set_options(ctx: *mut SslCtx, options: u64) -> u64;
+    fn SSL_CTX_ctrl(ctx: *mut SslCtx, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_new(ctx: *mut SslCtx) -> *mut Ssl;
+    fn SSL_free(ssl: *mut Ssl);
+    fn SSL_ctrl(ssl: *mut Ssl, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_set1_host(ssl: *mut Ssl, host: *const c_char) -> c_int;
+    fn SSL_set_fd(ssl: *mut Ssl, fd: c_int) -> c_int;
+    fn SSL_connect(ssl: *mut Ssl) -> c_int;
+    fn SSL_read(ssl: *mut Ssl, buf: *mut c_void, len: c_int) -> c_int;
+    fn SSL_write(ssl: *mut Ssl, buf: *const c_void, len: c_int) -> c_int;
+    fn SSL_get_error(ssl: *const Ssl, ret: c_int) -> c_int;
+    fn SSL_get_verify_result(ssl: *const Ssl) -> c_long;
+    fn X509_verify_cert_error_string(code: c_long) -> *const c_char;
+    fn ERR_get_error() -> c_ulong;
+    fn ERR_clear_error();
+    fn ERR_error_string_n(code: c_ulong, buf: *mut c_char, len: usize);
+}
+
+/// What OpenSSL last said went wrong while `what`, with a connection to
+/// `host`, emptying its queue of errors.
+fn openssl_error(host: &str, what: &str) -> Error {
+    let mut detail = None;
+    loop {
+        let code = unsafe { ERR_get_error() };
+        if code == 0 {
+            break;
+        }
+        let mut buf = [0 as c_char; 256];
+        unsafe { ERR_error_string_n(code, buf.as_mut_ptr(), buf.len()) };
+        // The first is where it started
+        detail.get_or_insert_with(|| unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned());
+    }
+    let reason = detail.map_or_else(|| what.to_owned(), |x| format!("{} ({})", what, x));
+    XcsoError::Tls { host: host.to_owned(), reason }.into()
+}
+
+#[cfg(unix)]
+fn socket_fd(stream: &TcpStream) -> c_int {
+    use std::os::unix::io::AsRawFd;
+    stream.as_raw_fd()
+}
+
+#[cfg(windows)]
+fn socket_fd(stream: &TcpStream) -> c_int {
+    use std::os::windows::io::AsRawSocket;
+    stream.as_raw_socket() as c_int
+}
+
+/// A TLS connection over a `TcpStream`, which keeps its timeouts.
+pub struct TlsStream {
+    ctx: *mut SslCtx,
+    ssl: *mut Ssl,
+    host: String,
+    // Kept open for as long as `ssl` reads and writes through it
+    _stream: TcpStream,
+}
+
+// OpenSSL connections can move between threads, as long as only one uses
+// them at a time
+unsafe impl Send for TlsStream {}
+
+impl TlsStream {
+    /// Makes a TLS connection to `host` over `stream`, checking the server's
+    /// certificate is trusted and is for `host`.
+    pub fn connect(stream: TcpStream, host: &str) -> Result<TlsStream, Error> {
+        let name = CString::new(host).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid host name"))?;
+        unsafe {
+            ERR_clear_error();
+            let ctx = SSL_CTX_new(TLS_client_method());
+            if ctx.is_null() {
+                return Err(openssl_error(host, "setting up"));
+            }
+            // From here on, dropping it frees what's been made
+            let mut tls = TlsStream { ctx, ssl: std::ptr::null_mut(), host: host.to_owned(), _stream: stream };
+            if SSL_CTX_set_default_verify_paths(ctx) != 1 {
+                return Err(openssl_error(host, "loading the trusted certificates"));
+            }
+            SSL_CTX_set_verify(ctx, SSL_VERIFY_PEER, std::ptr::null());
+            SSL_CTX_set_options(ctx, SSL_OP_IGNORE_UNEXPECTED_EOF);
+            SSL_CTX_ctrl(ctx, SSL_CTRL_SET_MIN_PROTO_VERSION, TLS1_2_VERSION, std::ptr::null_mut());
+
+            tls.ssl = SSL_new(ctx);
+            if tls.ssl.is_null() || SSL_set_fd(tls.ssl, socket_fd(&tls._stream)) != 1 {
+                return Err(openssl_error(host, "setting up"));
+            }
+            // Server names are only sent for names, not addresses
+            if host.parse::<IpAddr>().is_err() {
+                let parg = name.as_ptr().cast_mut().cast();
+                SSL_ctrl(tls.ssl, SSL_CTRL_SET_TLSEXT_HOSTNAME, TLSEXT_NAMETYPE_HOST_NAME, parg);
+            }
+            if SSL_set1_host(tls.ssl, name.as_ptr()) != 1 {
+                return Err(openssl_error(host, "setting up"));
+            }
+            if SSL_connect(tls.ssl) != 1 {
+                let verified = SSL_get_verify_result(tls.ssl);
+                if verified != X509_V_OK {
+                    ERR_clear_error();
+                    let reason = CStr::from_ptr(X509_verify_cert_error_string(verified));
+                    let reason = reason.to_string_lossy().into_owned();
+                    return Err(XcsoError::Untrusted { host: host.to_owned(), reason }.into());
+                }
+                return Err(openssl_error(host, "in the handshake"));
+            }
+            Ok(tls)
+        }
+    }
+
+    /// The error for a read or write that returned `ret`.
+    fn error(&self, ret: c_int) -> Error {
+        match unsafe { SSL_get_error(self.ssl, ret) } {
+            // Blocking sockets only want more when they've timed out
+            SSL_ERROR_WANT_READ | SSL_ERROR_WANT_WRITE => Error::new(ErrorKind::TimedOut, "timed out"),
+            SSL_ERROR_SYSCALL => {
+                let e = Error::last_os_error();
+                unsafe { ERR_clear_error() };
+                match e.raw_os_error() {
+                    Some(0) | None => Error::new(ErrorKind::UnexpectedEof, "the connection was closed"),
+                    _ => e,
+                }
+            },
+            _ => openssl_error(&self.host, "part way through"),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        let ret = unsafe { SSL_read(self.ssl, buf.as_mut_ptr().cast(), len) };
+        if ret > 0 {
+            return Ok(ret as usize);
+        }
+        match unsafe { SSL_get_error(self.ssl, ret) } {
+            SSL_ERROR_ZERO_RETURN => Ok(0),
+            _ => Err(self.error(ret)),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        let ret = unsafe { SSL_write(self.ssl, buf.as_ptr().cast(), len) };
+        match ret > 0 {
+            true => Ok(ret as usize),
+            false => Err(self.error(ret)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ssl.is_null() {
+                SSL_free(self.ssl);
+            }
+            SSL_CTX_free(self.ctx);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! A trailer after the padding at the end of each part, recording the hashes
+//! of the ISO the image was made from, a hash of the part itself and the
+//! tool that wrote it, so an image can be checked long after the ISO is
+//! gone. Loaders only go by the block index, and never look past the end
+//! of the last block, so the trailer doesn't get in the way of any of them.
+//!
+//! The trailer is 128 bytes, little endian: the size (`u64`), CRC32 (`u32`),
+//! MD5 and SHA-1 of the ISO, how much of the part comes before the trailer
+//! (`u64`) and its SHA-1, the name and version of the tool (36 bytes padded
+//! with zeros), a CRC32 of everything so far, and then the size of the
+//! trailer (`u32`) and the magic `XCSOTRL1`, which is how it's found.
+
+use std::fs::File;
+use std::io::{self, Error, Read, Seek, Write};
+use std::path::Path;
+
+use crate::error::XcsoError;
+use crate::hash::{Crc32, Hashes, Sha1};
+
+const MAGIC: &[u8; 8] = b"XCSOTRL1";
+pub const TRAILER_SIZE: u64 = 128;
+// Bytes the tool name is padded out to
+const TOOL_LEN: usize = 36;
+// Where the CRC32 of the fields goes
+const CRC_AT: usize = 112;
+
+/// Whatever wrote the trailers of new images.
+pub const TOOL: &str = concat!("xcso ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trailer {
+    /// Hashes of the whole ISO, video partition and all.
+    pub source: Hashes,
+    /// Bytes of the part ahead of the trailer.
+    pub data_len: u64,
+    pub data_sha1: [u8; 20],
+    pub tool: String,
+}
+
+impl Trailer {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(TRAILER_SIZE as usize);
+        raw.extend_from_slice(&self.source.size.to_le_bytes());
+        raw.extend_from_slice(&self.source.crc32.to_le_bytes());
+        raw.extend_from_slice(&self.source.md5);
+        raw.extend_from_slice(&self.source.sha1);
+        raw.extend_from_slice(&self.data_len.to_le_bytes());
+        raw.extend_from_slice(&self.data_sha1);
+        let mut tool = self.tool.as_bytes().to_vec();
+        tool.resize(TOOL_LEN, 0);
+        raw.extend_from_slice(&tool);
+        let mut crc = Crc32::new();
+        crc.update(&raw);
+        raw.extend_from_slice(&crc.finish().to_le_bytes());
+        raw.extend_from_slice(&(TRAILER_SIZE as u32).to_le_bytes());
+        raw.extend_from_slice(MAGIC);
+        raw
+    }
+
+    fn parse(raw: &[u8]) -> Option<Trailer> {
+        let u64_at = |at: usize| u64::from_le_bytes(raw[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+        let mut crc = Crc32::new();
+        crc.update(&raw[..CRC_AT]);
+        if &raw[120..] != MAGIC || u32_at(116) as u64 != TRAILER_SIZE || crc.finish() != u32_at(CRC_AT) {
+            return None;
+        }
+        let tool = &raw[76..CRC_AT];
+        let tool = &tool[..tool.iter().position(|&x| x == 0).unwrap_or(TOOL_LEN)];
+        Some(Trailer {
+            source: Hashes {
+                size: u64_at(0),
+                crc32: u32_at(8),
+                md5: raw[12..28].try_into().unwrap(),
+                sha1: raw[28..48].try_into().unwrap(),
+            },
+            data_len: u64_at(48),
+            data_sha1: raw[56..76].try_into().unwrap(),
+            tool: String::from_utf8_lossy(tool).into_owned(),
+        })
+    }
+
+    /// Whether the part in `file` still hashes to what it did when the
+    /// trailer was written.
+    pub fn check<R: Read + Seek>(&self, file: &mut R) -> Result<bool, Error> {
+        Ok(hash_data(file, self.data_len)? == self.data_sha1)
+    }
+}
+
+/// The trailer at the end of `file`, if it has one.
+pub fn read_trailer<R: Read + Seek>(file: &mut R) -> Result<Option<Trailer>, Error> {
+    let len = file.seek(io::SeekFrom::End(0))?;
+    if len < TRAILER_SIZE {
+        return Ok(None);
+    }
+    let mut raw = [0; TRAILER_SIZE as usize];
+    file.seek(io::SeekFrom::Start(len - TRAILER_SIZE))?;
+    file.read_exact(&mut raw)?;
+    Ok(Trailer::parse(&raw).filter(|x| x.data_len == len - TRAILER_SIZE))
+}
+
+/// Adds a trailer for an image of the ISO hashed as `source` to the part at
+/// `fp`, replacing the one it has already.
+pub fn write_trailer(fp: &Path, source: &Hashes) -> Result<Trailer, Error> {
+    let mut file = File::options().read(true).write(true).open(fp).map_err(|e| XcsoError::io(fp, e))?;
+    let data_len = match read_trailer(&mut file)? {
+        Some(old) => old.data_len,
+        None => file.metadata()?.len(),
+    };
+    let trailer = Trailer {
+        source: source.clone(),
+        data_len,
+        data_sha1: hash_data(&mut file, data_len)?,
+        tool: TOOL.to_owned(),
+    };
+    file.set_len(data_len)?;
+    file.seek(io::SeekFrom::Start(data_len))?;
+    file.write_all(&trailer.to_bytes())?;
+    file.sync_all()?;
+    crate::debug!("wrote the trailer of {}", fp.display());
+    Ok(trailer)
+}
+
+/// SHA-1 of the first `len` bytes of `file`.
+fn hash_data<R: Read + Seek>(file: &mut R, len: u64) -> Result<[u8; 20], Error> {
+    file.seek(io::SeekFrom::Start(0))?;
+    let mut sha1 = Sha1::new();
+    let mut buf = vec![0; 1 << 20];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(buf.len() as u64) as usize;
+        file.read_exact(&mut buf[..n])?;
+        sha1.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(sha1.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::compress::{compress_reader, part_path, CompressOptions, LEVEL_FAST};
+    use crate::decompress::{decompress_cso, CsoFile};
+    use crate::hash::Hasher;
+    use crate::merge::merge_cso;
+    use crate::repair::{repair_cso, Repaired};
+    use crate::testing::{image, Scratch};
+
+    /// Compresses `data` as `name` under `dir`, split into parts of up to
+    /// `split_size` bytes, and returns the parts.
+    fn parts(dir: &Scratch, name: &str, data: &[u8], trailer: bool, split_size: Option<u64>) -> Vec<PathBuf> {
+        let opts = CompressOptions { level: LEVEL_FAST, raw: true, trailer, split_size, ..CompressOptions::default() };
+        compress_reader(Cursor::new(data), &dir.join(name), &opts).unwrap();
+        (1..).map(|n| part_path(&dir.join(name), n, opts.format)).take_while(|x| x.exists()).collect()
+    }
+
+    fn hashes(data: &[u8]) -> Hashes {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    fn decoded(dir: &Scratch, fp: &Path) -> Vec<u8> {
+        let out = dir.join("out.iso");
+        _ = fs::remove_file(&out);
+        decompress_cso(fp, &out).unwrap();
+        fs::read(out).unwrap()
+    }
+
+    #[test]
+    fn trailers_come_back() {
+        let dir = Scratch::new("trailer");
+        let data = image(1000);
+        let fp = &parts(&dir, "game", &data, true, None)[0];
+        let len = fs::metadata(fp).unwrap().len();
+        let trailer = read_trailer(&mut File::open(fp).unwrap()).unwrap().unwrap();
+        assert_eq!(trailer.source, hashes(&data));
+        assert_eq!(trailer.data_len, len - TRAILER_SIZE);
+        assert_eq!(trailer.tool, TOOL);
+        assert!(trailer.check(&mut File::open(fp).unwrap()).unwrap());
+
+        // Writing one again replaces it rather than adding another
+        let other = hashes(b"something else");
+        let written = write_trailer(fp, &other).unwrap();
+        assert_eq!(fs::metadata(fp).unwrap().len(), len);
+        assert_eq!(read_trailer(&mut File::open(fp).unwrap()).unwrap(), Some(written.clone()));
+        assert_eq!(written, Trailer { source: other, ..trailer });
+
+        // Changing the part, or the trailer itself, is noticed
+        let mut raw = fs::read(fp).unwrap();
+        raw[100] ^= 1;
+        assert!(!written.check(&mut Cursor::new(&raw)).unwrap());
+        let end = raw.len() - 20;
+        raw[end] ^= 1;
+        assert_eq!(read_trailer(&mut Cursor::new(&raw)).unwrap(), None);
+        assert_eq!(read_trailer(&mut Cursor::new(&raw[..50])).unwrap(), None);
+    }
+
+    #[test]
+    fn trailers_are_not_part_of_the_image() {
+        let dir = Scratch::new("trailer");
+        let data = image(3000);
+        let plain = parts(&dir, "plain", &data, false, Some(2 << 20));
+        let fps = parts(&dir, "game", &data, true, Some(2 << 20));
+        assert!(fps.len() > 1);
+
+        let cso = CsoFile::open(&fps[0]).unwrap();
+        assert!(cso.trailer().is_some());
+        assert_eq!(cso.validate().unwrap(), Vec::<String>::new());
+        assert_eq!(cso.image_bytes(), cso.stored_bytes().unwrap() - fps.len() as u64 * TRAILER_SIZE);
+        assert_eq!(decoded(&dir, &fps[0]), data);
+
+        // Repairs rebuild the index without touching the trailer
+        for fp in &fps {
+            let before = fs::read(fp).unwrap();
+            let Repaired { blocks, changed } = repair_cso(fp, None).unwrap();
+            assert_eq!(changed, 0, "{:?}", fp);
+            let mut zeroed = before.clone();
+            zeroed[24..32].fill(0);
+            fs::write(fp, zeroed).unwrap();
+            assert!(repair_cso(fp, None).unwrap().changed > 0, "{:?}", fp);
+            assert_eq!(fs::read(fp).unwrap(), before, "{:?}", fp);
+            assert_eq!(repair_cso(fp, None).unwrap(), Repaired { blocks, changed: 0 }, "{:?}", fp);
+        }
+
+        // Merging sizes the image by its blocks, so comes out the same with
+        // trailers or without
+        merge_cso(&fps[0], &dir.join("merged.cso")).unwrap();
+        merge_cso(&plain[0], &dir.join("plain.cso")).unwrap();
+        assert_eq!(fs::read(dir.join("merged.cso")).unwrap(), fs::read(dir.join("plain.cso")).unwrap());
+        assert_eq!(decoded(&dir, &dir.join("merged.cso")), data);
+    }
+}
@@ -0,0 +1,257 @@
+use std::fs::File;
+use std::io::{self, Error, Read, Seek};
+use std::path::Path;
+
+use indicatif::ProgressBar;
+
+use crate::checksums::checksum_path;
+use crate::compress::interrupted;
+use crate::cso::{get_image_offset, CsoImage};
+use crate::decompress::CsoFile;
+use crate::error::XcsoError;
+use crate::xdvdfs::Volume;
+
+/// Result of comparing a CSO against its source ISO.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    Match,
+    /// The CSO header describes a different amount of data than the ISO's
+    /// game partition holds.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// Block `block`, found at byte `offset` of the ISO, decompressed to
+    /// something else.
+    BlockMismatch { block: usize, offset: u64 },
+}
+
+/// Decompresses the CSO (or split set) at `cso_fp` block by block and
+/// compares it against the game partition of the ISO at `iso_fp`. A CSO
+/// that was trimmed only has to match up to where it ends, as long as that
+/// is past the end of the file system.
+pub fn verify_cso(iso_fp: &Path, cso_fp: &Path) -> Result<VerifyOutcome, Error> {
+    verify_cso_with_progress(iso_fp, cso_fp, &ProgressBar::new(0))
+}
+
+/// Same as `verify_cso`, but reports progress on `pb` (counting bytes of the
+/// image).
+pub fn verify_cso_with_progress(iso_fp: &Path, cso_fp: &Path, pb: &ProgressBar) -> Result<VerifyOutcome, Error> {
+    let mut pair = Pair::open(iso_fp, cso_fp)?;
+    let header = pair.cso.header();
+    if !pair.trimmed && pair.iso_bytes != header.total_bytes {
+        crate::warn!("image is {} bytes, but the CSO holds {}", pair.iso_bytes, header.total_bytes);
+        return Ok(VerifyOutcome::SizeMismatch { expected: pair.iso_bytes, actual: header.total_bytes });
+    }
+
+    let mut blockbuf = vec![0; header.block_size as usize];
+    pb.set_length(header.total_bytes);
+    pb.set_position(0);
+
+    for block in 0..header.total_blocks {
+        if interrupted() {
+            pb.finish_and_clear();
+            return Err(XcsoError::Interrupted.into());
+        }
+        let len = header.block_len(block);
+        pair.iso.read_exact(&mut blockbuf[..len])?;
+        if pair.cso.read_block(block)? != blockbuf[..len] {
+            pb.finish_and_clear();
+            let offset = pair.image_offset + block as u64 * header.block_size as u64;
+            crate::warn!("block {} at {:#x} does not match", block, offset);
+            return Ok(VerifyOutcome::BlockMismatch { block, offset });
+        }
+        pb.inc(len as u64);
+    }
+
+    pb.finish_and_clear();
+    crate::debug!("all {} blocks match", header.total_blocks);
+    Ok(VerifyOutcome::Match)
+}
+
+/// What comparing every block of a CSO against an ISO found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comparison {
+    /// Bytes of image data in the ISO, from where the CSO starts.
+    pub iso_bytes: u64,
+    /// Bytes of image data the CSO holds.
+    pub cso_bytes: u64,
+    /// Whether the CSO was trimmed, so it only holds the start of the ISO.
+    pub trimmed: bool,
+    /// Blocks both of them hold, which are the ones compared.
+    pub blocks: usize,
+    pub mismatched_blocks: usize,
+    /// The first block that differs, and the ISO offset of its first byte
+    /// that does.
+    pub first_mismatch: Option<(usize, u64)>,
+}
+
+impl Comparison {
+    pub fn matches(&self) -> bool {
+        self.first_mismatch.is_none() && (self.trimmed || self.iso_bytes == self.cso_bytes)
+    }
+}
+
+/// Decompresses the CSO at `cso_fp` and compares all of it against the ISO
+/// at `iso_fp`, like `verify_cso` but carrying on past differences to count
+/// them, which suits checking images converted long ago or by other tools.
+/// Where the sizes differ, the blocks both hold are still compared.
+pub fn compare_cso(iso_fp: &Path, cso_fp: &Path) -> Result<Comparison, Error> {
+    compare_cso_with_progress(iso_fp, cso_fp, &ProgressBar::new(0))
+}
+
+/// Same as `compare_cso`, but reports progress on `pb` (counting bytes of
+/// the image).
+pub fn compare_cso_with_progress(iso_fp: &Path, cso_fp: &Path, pb: &ProgressBar) -> Result<Comparison, Error> {
+    let mut pair = Pair::open(iso_fp, cso_fp)?;
+    let header = pair.cso.header();
+    let compared = header.total_bytes.min(pair.iso_bytes);
+    let blocks = CsoImage::block_count(compared, header.block_size);
+    let mut result = Comparison {
+        iso_bytes: pair.iso_bytes,
+        cso_bytes: header.total_bytes,
+        trimmed: pair.trimmed,
+        blocks,
+        mismatched_blocks: 0,
+        first_mismatch: None,
+    };
+
+    let mut blockbuf = vec![0; header.block_size as usize];
+    pb.set_length(compared);
+    pb.set_position(0);
+    for block in 0..blocks {
+        if interrupted() {
+            pb.finish_and_clear();
+            return Err(XcsoError::Interrupted.into());
+        }
+        let at = block as u64 * header.block_size as u64;
+        let len = (compared - at).min(header.block_size as u64) as usize;
+        pair.iso.read_exact(&mut blockbuf[..len])?;
+        // A block that can't be read, or fails its checksum, differs from
+        // its first byte
+        let data = match pair.cso.read_block(block) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Vec::new(),
+            x => x?,
+        };
+        let differs = blockbuf[..len].iter().zip(&data).position(|(a, b)| a != b);
+        if let Some(byte) = differs.or((data.len() < len).then_some(data.len())) {
+            let offset = pair.image_offset + at + byte as u64;
+            crate::debug!("block {} differs from {:#x}", block, offset);
+            result.mismatched_blocks += 1;
+            result.first_mismatch.get_or_insert((block, offset));
+        }
+        pb.inc(len as u64);
+    }
+
+    pb.finish_and_clear();
+    crate::debug!("{} of {} blocks differ", result.mismatched_blocks, blocks);
+    Ok(result)
+}
+
+/// What checking a CSO against its block checksums and trailers found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checked {
+    pub blocks: usize,
+    /// Blocks that didn't match their checksum or couldn't be decompressed,
+    /// or `None` without block checksums to check them against.
+    pub bad_blocks: Option<Vec<usize>>,
+    /// Parts (counting from 1) that don't match their trailer, or `None`
+    /// when the image has no trailers.
+    pub bad_parts: Option<Vec<usize>>,
+}
+
+impl Checked {
+    pub fn is_good(&self) -> bool {
+        self.bad_blocks.as_ref().is_none_or(|x| x.is_empty()) && self.bad_parts.as_ref().is_none_or(|x| x.is_empty())
+    }
+}
+
+/// Checks the CSO at `fp` without the ISO it was made from: every part
+/// against its trailer, and every block, decompressed, against the
+/// checksums written next to it by `CompressOptions::checksums`. Either is
+/// enough to find out what went bad.
+pub fn check_cso(fp: &Path) -> Result<Checked, Error> {
+    check_cso_with_progress(fp, &ProgressBar::new(0))
+}
+
+/// Same as `check_cso`, but reports progress on `pb`, counting the bytes
+/// of each part hashed and of the image decompressed.
+pub fn check_cso_with_progress(fp: &Path, pb: &ProgressBar) -> Result<Checked, Error> {
+    let mut cso = CsoFile::open(fp)?;
+    if !cso.has_checksums() && cso.trailer().is_none() {
+        return Err(Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no trailer, nor block checksums in {}", fp.display(), checksum_path(fp).display()),
+        ));
+    }
+    let header = cso.header();
+    let mut result = Checked { blocks: header.total_blocks, bad_blocks: None, bad_parts: None };
+    let hashed = if cso.trailer().is_some() { cso.stored_bytes()? } else { 0 };
+    let decompressed = if cso.has_checksums() { header.total_bytes } else { 0 };
+    pb.set_length(hashed + decompressed);
+    pb.set_position(0);
+
+    if cso.trailer().is_some() {
+        let bad_parts = cso.check_trailers()?;
+        crate::debug!("{} of {} parts don't match their trailers", bad_parts.len(), cso.part_count());
+        result.bad_parts = Some(bad_parts);
+        pb.inc(hashed);
+    }
+
+    if cso.has_checksums() {
+        let mut bad_blocks = Vec::new();
+        for block in 0..header.total_blocks {
+            if interrupted() {
+                pb.finish_and_clear();
+                return Err(XcsoError::Interrupted.into());
+            }
+            match cso.read_block(block) {
+                Ok(_) => {},
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    crate::debug!("block {} is bad: {}", block, e);
+                    bad_blocks.push(block);
+                },
+                Err(e) => return Err(e),
+            }
+            pb.inc(header.block_len(block) as u64);
+        }
+        crate::debug!("{} of {} blocks are bad", bad_blocks.len(), header.total_blocks);
+        result.bad_blocks = Some(bad_blocks);
+    }
+
+    pb.finish_and_clear();
+    Ok(result)
+}
+
+/// A CSO and the ISO it's compared against, lined up so the ISO is at
+/// the first byte of image data the CSO holds.
+struct Pair {
+    cso: CsoFile,
+    iso: File,
+    image_offset: u64,
+    /// Bytes of the ISO from `image_offset` on.
+    iso_bytes: u64,
+    trimmed: bool,
+}
+
+impl Pair {
+    fn open(iso_fp: &Path, cso_fp: &Path) -> Result<Pair, Error> {
+        let mut cso = CsoFile::open(cso_fp)?;
+        let header = cso.header();
+
+        let mut iso = File::open(iso_fp).map_err(|e| XcsoError::io(iso_fp, e))?;
+        let partition_offset = get_image_offset(&mut iso)? as u64;
+        // A CSO that kept the video partition of a redump holds the whole file
+        let image_offset = match partition_offset > 0 && cso.game_partition_offset()? > 0 {
+            true => 0,
+            false => partition_offset,
+        };
+        let iso_bytes = iso.metadata()?.len() - image_offset;
+        let trimmed = header.total_bytes < iso_bytes && Volume::open(&mut iso, partition_offset).
+            and_then(|mut x| x.used_bytes()).
+            is_ok_and(|x| partition_offset - image_offset + x <= header.total_bytes);
+        if trimmed {
+            crate::debug!("CSO is trimmed to {} of {} bytes", header.total_bytes, iso_bytes);
+        }
+
+        iso.seek(io::SeekFrom::Start(image_offset))?;
+        Ok(Pair { cso, iso, image_offset, iso_bytes, trimmed })
+    }
+}
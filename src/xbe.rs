@@ -0,0 +1,145 @@
+//! Reads the certificate of an XBE, the Xbox executable format, which names
+//! the game and holds its title ID and region.
+
+use std::io::{Error, ErrorKind, Read, Seek};
+
+use crate::error::XcsoError;
+use crate::xdvdfs::Volume;
+
+pub const XBE_MAGIC: &[u8; 4] = b"XBEH";
+
+/// Where the game's executable lives in its image.
+pub const DEFAULT_XBE: &str = "default.xbe";
+
+// Offsets into the image header
+const BASE_ADDRESS: usize = 0x104;
+const HEADERS_SIZE: usize = 0x108;
+const CERTIFICATE_ADDRESS: usize = 0x118;
+
+// Offsets into the certificate
+const TITLE_ID: usize = 0x08;
+const TITLE_NAME: usize = 0x0c;
+const TITLE_NAME_LEN: usize = 40;
+const GAME_REGION: usize = 0xa0;
+const VERSION: usize = 0xac;
+
+// The headers are a few kilobytes; anything this big isn't an XBE
+const MAX_HEADERS_SIZE: u32 = 1 << 20;
+
+/// Bits of the game region field.
+pub const REGION_NORTH_AMERICA: u32 = 0x1;
+pub const REGION_JAPAN: u32 = 0x2;
+pub const REGION_REST_OF_WORLD: u32 = 0x4;
+pub const REGION_MANUFACTURING: u32 = 0x80000000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Certificate {
+    pub title_id: u32,
+    pub title_name: String,
+    pub region: u32,
+    pub version: u32,
+}
+
+fn invalid(what: &str) -> Error {
+    XcsoError::NotAnXbe { reason: what.to_owned() }.into()
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, Error> {
+    data.get(at..at + 4).
+        map(|x| u32::from_le_bytes(x.try_into().unwrap())).
+        ok_or_else(|| invalid("truncated header"))
+}
+
+/// Finds the certificate in the image headers of an XBE.
+fn certificate_offset(headers: &[u8]) -> Result<usize, Error> {
+    if headers.get(..4) != Some(XBE_MAGIC) {
+        return Err(invalid("bad magic"));
+    }
+
+    // The certificate is given as an address in the loaded image, which
+    // maps the headers to the base address
+    let base = u32_at(headers, BASE_ADDRESS)?;
+    let at = u32_at(headers, CERTIFICATE_ADDRESS)?.checked_sub(base).ok_or_else(|| invalid("bad certificate"))?;
+    match headers.len() >= at as usize + VERSION + 4 {
+        true => Ok(at as usize),
+        false => Err(invalid("bad certificate")),
+    }
+}
+
+impl Certificate {
+    /// Reads the certificate from the image headers at the start of an XBE,
+    /// which need to be at least as long as the XBE says they are.
+    pub fn parse(headers: &[u8]) -> Result<Certificate, Error> {
+        let cert = &headers[certificate_offset(headers)?..];
+
+        let name: Vec<u16> = cert[TITLE_NAME..TITLE_NAME + TITLE_NAME_LEN * 2].chunks_exact(2).
+            map(|x| u16::from_le_bytes([x[0], x[1]])).
+            take_while(|&c| c != 0).
+            collect();
+
+        Ok(Certificate {
+            title_id: u32_at(cert, TITLE_ID)?,
+            title_name: String::from_utf16_lossy(&name).trim().to_owned(),
+            region: u32_at(cert, GAME_REGION)?,
+            version: u32_at(cert, VERSION)?,
+        })
+    }
+
+    /// Writes the title ID and name of this certificate over those of the
+    /// XBE in `xbe`, so an attacher shows up as the game it launches. Names
+    /// too long for the certificate are cut short.
+    pub fn patch(&self, xbe: &mut [u8]) -> Result<(), Error> {
+        let at = certificate_offset(xbe)?;
+        let cert = &mut xbe[at..];
+        cert[TITLE_ID..TITLE_ID + 4].copy_from_slice(&self.title_id.to_le_bytes());
+
+        let name = &mut cert[TITLE_NAME..TITLE_NAME + TITLE_NAME_LEN * 2];
+        name.fill(0);
+        for (c, out) in self.title_name.encode_utf16().take(TITLE_NAME_LEN).zip(name.chunks_exact_mut(2)) {
+            out.copy_from_slice(&c.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// Reads the certificate of `default.xbe` in `volume`.
+    pub fn from_volume<R: Read + Seek>(volume: &mut Volume<R>) -> Result<Certificate, Error> {
+        let entry = volume.find(DEFAULT_XBE)?.
+            filter(|x| !x.is_dir()).
+            ok_or_else(|| Error::new(ErrorKind::NotFound, format!("image has no {}", DEFAULT_XBE)))?;
+
+        let start = volume.read_at(entry.offset(), (HEADERS_SIZE + 4).min(entry.size as usize))?;
+        let size = u32_at(&start, HEADERS_SIZE)?;
+        if size > MAX_HEADERS_SIZE || size > entry.size {
+            return Err(invalid("bad header size"));
+        }
+        Certificate::parse(&volume.read_at(entry.offset(), size as usize)?)
+    }
+
+    /// The title ID as frontends list it, `4D530004` for Halo.
+    pub fn title_id_hex(&self) -> String {
+        format!("{:08X}", self.title_id)
+    }
+
+    /// The regions the game runs in, by their video standard: `NTSC-U`,
+    /// `NTSC-J`, `PAL`, or several of them such as `NTSC-U, PAL`. Games for
+    /// all three are `Region Free`.
+    pub fn region_name(&self) -> String {
+        let all = REGION_NORTH_AMERICA | REGION_JAPAN | REGION_REST_OF_WORLD;
+        if self.region & all == all {
+            return "Region Free".to_owned();
+        }
+
+        let regions = [(REGION_NORTH_AMERICA, "NTSC-U"), (REGION_JAPAN, "NTSC-J"), (REGION_REST_OF_WORLD, "PAL")];
+        let mut names: Vec<&str> = regions.iter().
+            filter(|(bit, _)| self.region & bit != 0).
+            map(|(_, name)| *name).
+            collect();
+        if self.region & REGION_MANUFACTURING != 0 {
+            names.push("Debug");
+        }
+        match names.is_empty() {
+            true => "Unknown".to_owned(),
+            false => names.join(", "),
+        }
+    }
+}
@@ -0,0 +1,404 @@
+//! Reads the XDVDFS file system of an Xbox game partition.
+//!
+//! The volume descriptor sits 32 sectors into the partition and points at
+//! the root directory. Each directory is a table of entries forming a binary
+//! search tree, where every entry holds the dword offsets of its left and
+//! right subtrees within the table, followed by the sector and size of the
+//! file (or subdirectory table) it names.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Seek, Write};
+use std::path::Path;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::compress::{DEFAULT_WRITE_BUFFER, PROGRESS_TEMPLATE};
+
+use crate::cso::{get_image_offset, identify_image, ImageKind, XBOX_MEDIA_HEADER, XBOX_MEDIA_HEADER_XDVDFS_OFFSET};
+use crate::decompress::{open_uncompressed, CsoFile, CsoReader};
+use crate::error::XcsoError;
+use crate::http::is_url;
+
+pub const SECTOR_SIZE: u64 = 2048;
+
+/// Attribute bit marking an entry as a directory.
+pub const ATTR_DIRECTORY: u8 = 0x10;
+
+// Directory tables are padded with this where entries would otherwise
+// straddle a sector
+const PADDING: u16 = 0xffff;
+// Far more than the thousand or so files the biggest games have
+const MAX_DIR_SIZE: u32 = 16 << 20;
+
+/// A file or directory in the image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub sector: u32,
+    pub size: u32,
+    pub attributes: u8,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Where the entry's data starts, relative to the game partition.
+    pub fn offset(&self) -> u64 {
+        self.sector as u64 * SECTOR_SIZE
+    }
+}
+
+/// The file system of a game partition starting `image_offset` bytes into
+/// `R`.
+pub struct Volume<R> {
+    inner: R,
+    image_offset: u64,
+    root: DirEntry,
+}
+
+/// Anything an image can be read from, such as a file or a CSO.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Opens the file system of the image at `fp`, which can be an ISO, a full
+/// redump image or a CSO, or an ISO at an `http://` or `https://` URL.
+pub fn open_image(fp: &Path) -> Result<Volume<Box<dyn ReadSeek>>, Error> {
+    if is_url(fp) {
+        let mut image = open_uncompressed(fp)?;
+        let image_offset = get_image_offset(&mut image)? as u64;
+        return Volume::open(image, image_offset);
+    }
+    let mut file = File::open(fp)?;
+    match identify_image(&mut file)? {
+        ImageKind::Iso => {
+            let image_offset = get_image_offset(&mut file)? as u64;
+            Volume::open(Box::new(file), image_offset)
+        },
+        ImageKind::Compressed(_) => {
+            let mut cso = CsoFile::open(fp)?;
+            let image_offset = cso.game_partition_offset()?;
+            Volume::open(Box::new(CsoReader::new(cso)), image_offset)
+        },
+        ImageKind::Unknown => Err(XcsoError::UnknownFormat { expected: "an Xbox image" }.into()),
+    }
+}
+
+fn corrupt(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("corrupt XDVDFS {}", what))
+}
+
+impl<R: Read + Seek> Volume<R> {
+    pub fn open(mut inner: R, image_offset: u64) -> Result<Volume<R>, Error> {
+        let mut buf = [0u8; 28];
+        inner.seek(io::SeekFrom::Start(image_offset + XBOX_MEDIA_HEADER_XDVDFS_OFFSET))?;
+        inner.read_exact(&mut buf)?;
+        if &buf[..20] != XBOX_MEDIA_HEADER {
+            return Err(XcsoError::UnknownFormat { expected: "an XDVDFS image" }.into());
+        }
+
+        let sector = u32::from_le_bytes(buf[20..24].try_into().unwrap());
+        let size = u32::from_le_bytes(buf[24..28].try_into().unwrap());
+        let root = DirEntry { name: String::new(), sector, size, attributes: ATTR_DIRECTORY };
+        Ok(Volume { inner, image_offset, root })
+    }
+
+    pub fn root(&self) -> &DirEntry {
+        &self.root
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads `len` bytes at `offset` into the partition.
+    pub fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; len];
+        self.inner.seek(io::SeekFrom::Start(self.image_offset + offset))?;
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads the whole of a file.
+    pub fn read_file(&mut self, entry: &DirEntry) -> Result<Vec<u8>, Error> {
+        self.read_at(entry.offset(), entry.size as usize)
+    }
+
+    /// Copies the contents of a file to `out`, returning how many bytes were
+    /// copied.
+    pub fn copy_file<W: Write>(&mut self, entry: &DirEntry, out: &mut W) -> Result<u64, Error> {
+        self.inner.seek(io::SeekFrom::Start(self.image_offset + entry.offset()))?;
+        let copied = io::copy(&mut self.inner.by_ref().take(entry.size as u64), out)?;
+        match copied == entry.size as u64 {
+            true => Ok(copied),
+            false => Err(Error::new(ErrorKind::UnexpectedEof, format!("{} is cut short", entry.name))),
+        }
+    }
+
+    /// Lists a directory, in the order of its search tree (which sorts names
+    /// case-insensitively).
+    pub fn read_dir(&mut self, dir: &DirEntry) -> Result<Vec<DirEntry>, Error> {
+        if !dir.is_dir() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a directory", dir.name)));
+        }
+        // An empty directory has no table at all
+        if dir.size == 0 {
+            return Ok(Vec::new());
+        }
+        if dir.size > MAX_DIR_SIZE {
+            return Err(corrupt("directory size"));
+        }
+        let table = self.read_at(dir.offset(), dir.size as usize)?;
+
+        let mut entries = Vec::new();
+        // Subtrees still to visit, and the right subtree to visit after each
+        // entry, so the tree is walked in order without recursing
+        let mut stack: Vec<(usize, bool)> = vec![(0, false)];
+        let mut steps = 0;
+        while let Some((offset, after_left)) = stack.pop() {
+            // Every entry is stepped on twice, so any more means the tree
+            // loops back on itself
+            steps += 1;
+            if steps > 2 * (table.len() / 14 + 1) {
+                return Err(corrupt("directory tree"));
+            }
+            if offset + 14 > table.len() {
+                return Err(corrupt("directory entry"));
+            }
+            let field = |at: usize| u16::from_le_bytes([table[offset + at], table[offset + at + 1]]);
+            let (left, right) = (field(0), field(2));
+            if left == PADDING && right == PADDING {
+                return Err(corrupt("directory tree"));
+            }
+
+            if !after_left {
+                stack.push((offset, true));
+                if left != 0 {
+                    stack.push((left as usize * 4, false));
+                }
+                continue;
+            }
+
+            let name_len = table[offset + 13] as usize;
+            let name = table.get(offset + 14..offset + 14 + name_len).ok_or_else(|| corrupt("file name"))?;
+            entries.push(DirEntry {
+                name: name.iter().map(|&c| c as char).collect(),
+                sector: u32::from_le_bytes(table[offset + 4..offset + 8].try_into().unwrap()),
+                size: u32::from_le_bytes(table[offset + 8..offset + 12].try_into().unwrap()),
+                attributes: table[offset + 12],
+            });
+            if right != 0 {
+                stack.push((right as usize * 4, false));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Lists everything below `dir`, each with its path from `dir` joined by
+    /// `/`. Directories come just before what they hold.
+    pub fn walk(&mut self, dir: &DirEntry) -> Result<Vec<(String, DirEntry)>, Error> {
+        let mut found = Vec::new();
+        // Entries still to visit, stacked in reverse, and the directory
+        // tables already read so an image that links a directory back to
+        // its parent doesn't go round forever
+        let mut pending = vec![(String::new(), dir.clone())];
+        let mut seen = HashSet::new();
+        while let Some((path, entry)) = pending.pop() {
+            if entry.is_dir() && entry.size > 0 {
+                if !seen.insert(entry.sector) {
+                    return Err(corrupt("directory tree"));
+                }
+                let prefix = if path.is_empty() { path.clone() } else { format!("{}/", path) };
+                for child in self.read_dir(&entry)?.into_iter().rev() {
+                    pending.push((format!("{}{}", prefix, child.name), child));
+                }
+            }
+            if !path.is_empty() {
+                found.push((path, entry));
+            }
+        }
+        Ok(found)
+    }
+
+    /// How much of the partition the file system uses, up to the end of the
+    /// last sector holding a file or directory table. Anything after it is
+    /// padding.
+    pub fn used_bytes(&mut self) -> Result<u64, Error> {
+        let descriptor_end = XBOX_MEDIA_HEADER_XDVDFS_OFFSET + SECTOR_SIZE;
+        let root = self.root.clone();
+        let end = self.walk(&root)?.iter().
+            map(|(_, x)| x).
+            chain([&root]).
+            map(|x| x.offset() + (x.size as u64).next_multiple_of(SECTOR_SIZE)).
+            fold(descriptor_end, u64::max);
+        Ok(end)
+    }
+
+    /// Looks up `path`, a `/` or `\` separated path from the root, matching
+    /// names case-insensitively like the Xbox does.
+    pub fn find(&mut self, path: &str) -> Result<Option<DirEntry>, Error> {
+        let mut entry = self.root.clone();
+        for name in path.split(['/', '\\']).filter(|x| !x.is_empty()) {
+            if !entry.is_dir() {
+                return Ok(None);
+            }
+            match self.read_dir(&entry)?.into_iter().find(|x| x.name.eq_ignore_ascii_case(name)) {
+                Some(found) => entry = found,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+/// Checks a name from the image is safe to create on disk, so a doctored
+/// image can't write outside the directory it's extracted to.
+fn checked_name(name: &str) -> Result<&str, Error> {
+    match name {
+        "" | "." | ".." => Err(corrupt("file name")),
+        _ if name.contains(['/', '\\', ':', '\0']) => Err(corrupt("file name")),
+        _ => Ok(name),
+    }
+}
+
+/// Writes `entry` into the directory `dest`: a file, or a directory with
+/// everything below it. The root entry writes the contents of the image
+/// straight into `dest`. Returns the number of files written.
+pub fn extract<R: Read + Seek>(
+    volume: &mut Volume<R>,
+    entry: &DirEntry,
+    dest: &Path,
+    overwrite: bool,
+) -> Result<usize, Error> {
+    let pb = ProgressBar::new(0);
+    pb.set_style(ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap());
+    extract_with_progress(volume, entry, dest, overwrite, &pb)
+}
+
+/// Same as `extract`, but reports progress on `pb` (counting bytes of the
+/// files written).
+pub fn extract_with_progress<R: Read + Seek>(
+    volume: &mut Volume<R>,
+    entry: &DirEntry,
+    dest: &Path,
+    overwrite: bool,
+    pb: &ProgressBar,
+) -> Result<usize, Error> {
+    fs::create_dir_all(dest)?;
+    let base = match *entry == volume.root {
+        true => dest.to_path_buf(),
+        false => dest.join(checked_name(&entry.name)?),
+    };
+    let mut entries = vec![(base, entry.clone())];
+    if entry.is_dir() {
+        for (path, child) in volume.walk(entry)? {
+            let mut fp = entries[0].0.clone();
+            for name in path.split('/') {
+                fp.push(checked_name(name)?);
+            }
+            entries.push((fp, child));
+        }
+    }
+    pb.set_length(entries.iter().filter(|(_, x)| !x.is_dir()).map(|(_, x)| x.size as u64).sum());
+    pb.set_position(0);
+
+    let mut files = 0;
+    for (fp, entry) in entries.iter() {
+        if entry.is_dir() {
+            fs::create_dir_all(fp)?;
+            continue;
+        }
+        crate::trace!("extracting {}", fp.display());
+        let out = match overwrite {
+            true => File::create(fp)?,
+            false => File::options().write(true).create_new(true).open(fp).map_err(|e| match e.kind() {
+                ErrorKind::AlreadyExists => Error::new(e.kind(), format!("{} already exists", fp.display())),
+                _ => e,
+            })?,
+        };
+        // Most files are far smaller than the buffer
+        let mut out = BufWriter::with_capacity(DEFAULT_WRITE_BUFFER.min(entry.size as usize), out);
+        volume.copy_file(entry, &mut pb.wrap_write(&mut out))?;
+        out.flush()?;
+        files += 1;
+    }
+
+    pb.finish_and_clear();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::codec::encode_from;
+    use crate::compress::CompressOptions;
+    use crate::pack::{pack_dir_with_progress, PackOptions};
+    use crate::testing::Scratch;
+
+    fn contents(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(seed) ^ (i >> 8) as u8).collect()
+    }
+
+    /// Packs a small game into an image in memory.
+    fn packed() -> Vec<u8> {
+        let dir = Scratch::new("pack");
+        let game = dir.join("game");
+        fs::create_dir_all(game.join("media/sub")).unwrap();
+        fs::create_dir_all(game.join("empty")).unwrap();
+        fs::write(game.join("default.xbe"), contents(5000, 3)).unwrap();
+        fs::write(game.join("media/intro.xmv"), contents(70_000, 5)).unwrap();
+        fs::write(game.join("media/sub/Level1.bin"), contents(1, 7)).unwrap();
+        let iso = dir.join("game.iso");
+        pack_dir_with_progress(&game, &iso, &PackOptions::default(), &ProgressBar::hidden()).unwrap();
+        fs::read(&iso).unwrap()
+    }
+
+    fn check_volume<R: Read + Seek>(volume: &mut Volume<R>) {
+        let root = volume.root().clone();
+        let paths: Vec<String> = volume.walk(&root).unwrap().into_iter().map(|(path, _)| path).collect();
+        for path in ["default.xbe", "empty", "media", "media/intro.xmv", "media/sub", "media/sub/Level1.bin"] {
+            assert!(paths.iter().any(|x| x == path), "{} isn't in {:?}", path, paths);
+        }
+        assert_eq!(paths.len(), 6);
+
+        let xbe = volume.find("/DEFAULT.XBE").unwrap().unwrap();
+        assert_eq!(volume.read_file(&xbe).unwrap(), contents(5000, 3));
+        let xmv = volume.find("media\\intro.xmv").unwrap().unwrap();
+        let mut out = Vec::new();
+        assert_eq!(volume.copy_file(&xmv, &mut out).unwrap(), 70_000);
+        assert_eq!(out, contents(70_000, 5));
+        let level = volume.find("media/sub/level1.bin").unwrap().unwrap();
+        assert_eq!(volume.read_file(&level).unwrap(), contents(1, 7));
+
+        let empty = volume.find("empty").unwrap().unwrap();
+        assert!(empty.is_dir());
+        assert!(volume.read_dir(&empty).unwrap().is_empty());
+        assert!(volume.find("missing.bin").unwrap().is_none());
+        assert!(volume.find("default.xbe/inside").unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_a_packed_image() {
+        let mut volume = Volume::open(Cursor::new(packed()), 0).unwrap();
+        check_volume(&mut volume);
+    }
+
+    #[test]
+    fn reads_a_packed_image_through_a_cso() {
+        let mut cso = Cursor::new(Vec::new());
+        encode_from(&mut Cursor::new(packed()), &mut cso, &CompressOptions::default()).unwrap();
+        let reader = CsoReader::new(CsoFile::from_reader(Cursor::new(cso.into_inner())).unwrap());
+        check_volume(&mut Volume::open(reader, 0).unwrap());
+    }
+
+    #[test]
+    fn rejects_what_isnt_xdvdfs() {
+        assert!(Volume::open(Cursor::new(vec![0; 64 << 10]), 0).is_err());
+        assert!(Volume::open(Cursor::new(vec![0; 1000]), 0).is_err());
+    }
+}